@@ -0,0 +1,275 @@
+local log = require("log")
+
+-- Automation lanes live in project.channels[i].automation and are evaluated by the backend.
+-- Here we only mirror the values into the UI and record new points while playing.
+local automation = {}
+
+automation.MODES = { "read", "write", "touch", "latch" }
+
+-- in touch mode a lane is released when its control has not moved for this long
+local TOUCH_TIMEOUT = 0.5
+
+-- lanes that are being written, keyed by lane
+local recording = {}
+-- channels that need their automation resent after recording
+local changed = {}
+-- only record while playing live, not while rendering
+local active = false
+
+function automation.mode()
+	return project.transport.automation_mode or "read"
+end
+
+function automation.next_mode()
+	local mode = automation.mode()
+	local next_mode = automation.MODES[1]
+	for i, v in ipairs(automation.MODES) do
+		if v == mode then
+			next_mode = automation.MODES[i % #automation.MODES + 1]
+		end
+	end
+	project.transport.automation_mode = next_mode
+	log.info("Automation mode: " .. next_mode)
+end
+
+function automation.send(ch_index)
+	tessera.audio.set_automation(ch_index, project.channels[ch_index].automation or {})
+end
+
+local function find_lane(ch, kind, device, index)
+	for i, lane in ipairs(ch.automation or {}) do
+		if lane.kind == kind and (lane.device or 0) == device and (lane.index or 0) == index then
+			return lane, i
+		end
+	end
+end
+
+-- same interpolation as the backend
+function automation.value_at(lane, time)
+	local points = lane.points
+	if #points == 0 then
+		return
+	end
+	if time < points[1].time then
+		return points[1].value
+	end
+
+	for i = #points, 1, -1 do
+		local a = points[i]
+		if a.time <= time then
+			local b = points[i + 1]
+			if not b or lane.kind == "mute" or a.shape == "hold" then
+				return a.value
+			end
+			local t = (time - a.time) / (b.time - a.time)
+			if a.shape == "exponential" and a.value > 0 and b.value > 0 then
+				return a.value * (b.value / a.value) ^ t
+			end
+			return a.value + t * (b.value - a.value)
+		end
+	end
+end
+
+local function get_device(ch_index, device)
+	local ch = ui_channels[ch_index]
+	if device == 0 then
+		return ch.instrument
+	end
+	return ch.effects[device]
+end
+
+local function current_value(ch_index, lane)
+	local ch = project.channels[ch_index]
+	if lane.kind == "gain" then
+		return ch.gain
+	elseif lane.kind == "mute" then
+		return ch.mute and 1 or 0
+	end
+
+	local device = get_device(ch_index, lane.device)
+	local value = device and device.state[lane.index]
+	if type(value) == "boolean" then
+		return value and 1 or 0
+	end
+	return value
+end
+
+-- Set the UI state, and mark it as sent since the backend applies the lane itself
+local function apply_value(ch_index, lane, value)
+	local ch = ui_channels[ch_index]
+	if lane.kind == "gain" then
+		ch.data.gain = value
+		ch.gain_old = value
+	elseif lane.kind == "mute" then
+		ch.data.mute = value > 0.5
+		ch.mute_old = ch.data.mute
+	else
+		local device = get_device(ch_index, lane.device)
+		local old = device and device.state[lane.index]
+		if old == nil then
+			return
+		end
+		if type(old) == "boolean" then
+			value = value > 0.5
+		elseif device.discrete[lane.index] then
+			value = math.floor(value + 0.5)
+		end
+		device.state[lane.index] = value
+		device.state_old[lane.index] = value
+	end
+end
+
+local function add_point(rec, time, value)
+	local last = rec.points[#rec.points]
+	if last and last.time >= time then
+		last.value = value
+	elseif not last or last.value ~= value then
+		table.insert(rec.points, { time = time, value = value, shape = "linear" })
+	end
+end
+
+local function begin(ch_index, lane, lane_index, time)
+	local rec = { ch_index = ch_index, lane_index = lane_index, start = time, touched = time, points = {} }
+	local value = current_value(ch_index, lane)
+	if value then
+		add_point(rec, time, value)
+	end
+	recording[lane] = rec
+
+	-- lanes that were just created are not known by the backend yet
+	if lane_index then
+		tessera.audio.automation_bypass(ch_index, lane_index, true)
+	end
+	return rec
+end
+
+local function finish(lane, rec, time)
+	local value = current_value(rec.ch_index, lane)
+	if value then
+		add_point(rec, time, value)
+	end
+
+	-- continue with the old curve after the recorded range
+	local resume = automation.value_at(lane, time)
+	local has_later = false
+
+	local points = {}
+	for _, p in ipairs(lane.points) do
+		if p.time < rec.start then
+			table.insert(points, p)
+		elseif p.time > time then
+			table.insert(points, p)
+			has_later = true
+		end
+	end
+	for _, p in ipairs(rec.points) do
+		table.insert(points, p)
+	end
+	if has_later and resume then
+		table.insert(points, { time = time, value = resume, shape = "linear" })
+	end
+	table.sort(points, function(a, b)
+		return a.time < b.time
+	end)
+	lane.points = points
+
+	recording[lane] = nil
+	changed[rec.ch_index] = true
+
+	-- the part of the backend curve after this point is unchanged
+	if rec.lane_index then
+		tessera.audio.automation_bypass(rec.ch_index, rec.lane_index, false)
+	end
+end
+
+function automation.start(time, record)
+	recording = {}
+	changed = {}
+	active = record
+	if not active then
+		return
+	end
+
+	-- write mode replaces every existing lane from the play position onwards
+	if automation.mode() == "write" then
+		for ch_index, ch in ipairs(project.channels) do
+			for lane_index, lane in ipairs(ch.automation or {}) do
+				begin(ch_index, lane, lane_index, time)
+			end
+		end
+	end
+end
+
+-- Called for every parameter change that is sent to the backend
+function automation.record(time, ch_index, kind, device, index, value)
+	if not active or automation.mode() == "read" then
+		return
+	end
+
+	local ch = project.channels[ch_index]
+	ch.automation = ch.automation or {}
+	local lane, lane_index = find_lane(ch, kind, device, index)
+	if not lane then
+		lane = { kind = kind, device = device, index = index, points = {} }
+		table.insert(ch.automation, lane)
+	end
+
+	local rec = recording[lane] or begin(ch_index, lane, lane_index, time)
+	add_point(rec, time, value)
+	rec.touched = time
+end
+
+function automation.update(time)
+	if automation.mode() == "touch" then
+		for lane, rec in pairs(recording) do
+			if time - rec.touched > TOUCH_TIMEOUT then
+				finish(lane, rec, time)
+			end
+		end
+	end
+
+	for ch_index, ch in ipairs(project.channels) do
+		for _, lane in ipairs(ch.automation or {}) do
+			if not recording[lane] then
+				local value = automation.value_at(lane, time)
+				if value then
+					apply_value(ch_index, lane, value)
+				end
+			end
+		end
+	end
+end
+
+function automation.stop(time)
+	for lane, rec in pairs(recording) do
+		finish(lane, rec, time)
+	end
+	for ch_index in pairs(changed) do
+		automation.send(ch_index)
+	end
+	recording = {}
+	changed = {}
+	active = false
+end
+
+-- effect indices changed, `map` maps old device index to the new one or nil when removed
+function automation.remap_devices(ch_index, map)
+	local ch = project.channels[ch_index]
+	if not ch.automation then
+		return
+	end
+	for i = #ch.automation, 1, -1 do
+		local lane = ch.automation[i]
+		if lane.kind == "parameter" and lane.device > 0 then
+			local device = map(lane.device)
+			if device then
+				lane.device = device
+			else
+				table.remove(ch.automation, i)
+			end
+		end
+	end
+	automation.send(ch_index)
+end
+
+return automation
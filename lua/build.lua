@@ -4,6 +4,7 @@ local SliderValue = require("ui/slider_value")
 local device_list = require("device_list")
 local empty_project = require("default.empty_project")
 local engine = require("engine")
+local midi = require("midi")
 local tuning = require("tuning")
 
 local build = {}
@@ -14,6 +15,21 @@ local VST_ID = 0
 
 -- build the given "project" is set but nothing else is
 local function setup_project()
+	-- older projects have no controller mappings
+	project.midi_map = project.midi_map or {}
+
+	-- older projects have port names with the ALSA client and port number
+	for _, ch in ipairs(project.channels) do
+		if ch.midi_port then
+			ch.midi_port = midi.stable_name(ch.midi_port)
+		end
+	end
+	for _, m in ipairs(project.midi_map) do
+		if m.port then
+			m.port = midi.stable_name(m.port)
+		end
+	end
+
 	for i, v in ipairs(project.channels) do
 		build.channel(i, v)
 	end
@@ -110,6 +126,13 @@ function build.channel(ch_index, channel_data)
 		build.effect(ch_index, i, v)
 	end
 
+	if channel_data.clips and #channel_data.clips > 0 then
+		tessera.audio.set_clips(ch_index, channel_data.clips)
+	end
+	if channel_data.automation and #channel_data.automation > 0 then
+		tessera.audio.set_automation(ch_index, channel_data.automation)
+	end
+
 	build.refresh_channels()
 end
 
@@ -130,6 +153,7 @@ function build.refresh_channels()
 			v.roll.ch_index = i
 		end
 	end
+	engine.invalidate_routing()
 end
 
 function build.new_device_data(options)
@@ -193,7 +217,14 @@ function build.new_channel_data(options)
 		new.instrument = build.new_device_data(options.instrument)
 		new.notes = {}
 		new.control = {}
+		new.clips = {}
+	end
+	if not options.master then
+		new.sends = {}
 	end
+	new.automation = {}
+	new.cue = 0
+	new.direct_output = 0
 
 	return new
 end
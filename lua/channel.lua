@@ -83,6 +83,7 @@ function Channel.new(ch_index, data, instrument, meter_id)
 	self.data = data
 	self.mute_old = false
 	self.gain_old = nil
+	self.cue_old = nil
 
 	if instrument then
 		self.instrument = instrument
@@ -230,6 +231,7 @@ end
 function Channel:reset()
 	self.mute_old = false
 	self.gain_old = nil
+	self.cue_old = nil
 end
 
 return Channel
@@ -1,8 +1,16 @@
 local build = require("build")
+local engine = require("engine")
 local load_default_project = require("default.project")
 
 local command = {}
 
+-- let the backend know which channels have new notes
+local function notes_changed(notes)
+    for ch_index in pairs(notes) do
+        engine.sequence_changed(ch_index)
+    end
+end
+
 command.max_size = 50
 command.stack = {}
 command.index = 0
@@ -118,6 +126,21 @@ function NoteUpdate.new(prev_state, new_state)
     self.prev_state = prev_state
     self.new_state = util.clone(new_state)
 
+    -- channels the notes are on
+    local mask = {}
+    for _, v in ipairs(self.notes) do
+        mask[v] = true
+    end
+    self.channels = {}
+    for ch_index, channel in ipairs(project.channels) do
+        for _, note in ipairs(channel.notes or {}) do
+            if mask[note] then
+                self.channels[ch_index] = true
+                break
+            end
+        end
+    end
+
     return self
 end
 
@@ -127,6 +150,7 @@ function NoteUpdate:run()
             self.notes[i][key] = self.new_state[i][key]
         end
     end
+    notes_changed(self.channels)
 end
 
 function NoteUpdate:reverse()
@@ -135,6 +159,7 @@ function NoteUpdate:reverse()
             self.notes[i][key] = self.prev_state[i][key]
         end
     end
+    notes_changed(self.channels)
 end
 
 command.NoteUpdate = NoteUpdate
@@ -169,6 +194,7 @@ function NoteDelete:run()
             end
         end
     end
+    notes_changed(self.notes)
 end
 
 function NoteDelete:reverse()
@@ -177,6 +203,7 @@ function NoteDelete:reverse()
             table.insert(project.channels[ch_index].notes, note)
         end
     end
+    notes_changed(self.notes)
 end
 
 command.NoteDelete = NoteDelete
@@ -206,6 +233,7 @@ function NoteAdd:run()
             table.insert(project.channels[ch_index].notes, note)
         end
     end
+    notes_changed(self.notes)
 end
 
 function NoteAdd:reverse()
@@ -218,6 +246,7 @@ function NoteAdd:reverse()
             end
         end
     end
+    notes_changed(self.notes)
 end
 
 command.NoteAdd = NoteAdd
@@ -239,16 +268,17 @@ function TimeUpdate.new(prev_state, new_state)
 end
 
 function TimeUpdate:run()
-    project.time = self.new_state
+    project.tempo = self.new_state
 end
 
 function TimeUpdate:reverse()
-    project.time = self.prev_state
+    project.tempo = self.prev_state
 end
 
 command.TimeUpdate = TimeUpdate
 
 command.NewChannel, command.RemoveChannel, command.NewEffect, command.RemoveEffect, command.ReorderEffect =
     unpack(require("command_channel"))
+command.ImportChannels = require("command_channel")[6]
 
 return command
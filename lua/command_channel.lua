@@ -1,8 +1,52 @@
+local automation = require("automation")
 local build = require("build")
+local midi_map = require("midi_map")
+
+-- fix up outputs and sends that point past the removed channel
+local function remove_routing(ch_index)
+    for _, ch in ipairs(project.channels) do
+        if ch.output == ch_index then
+            ch.output = nil
+        elseif ch.output and ch.output > ch_index then
+            ch.output = ch.output - 1
+        end
+        for _, fx in ipairs(ch.effects) do
+            if fx.sidechain == ch_index then
+                fx.sidechain = 0
+            elseif fx.sidechain and fx.sidechain > ch_index then
+                fx.sidechain = fx.sidechain - 1
+            end
+        end
+        if ch.sends then
+            for i = #ch.sends, 1, -1 do
+                local send = ch.sends[i]
+                if send.target == ch_index then
+                    table.remove(ch.sends, i)
+                elseif send.target > ch_index then
+                    send.target = send.target - 1
+                end
+            end
+        end
+    end
+end
+
+local function get_routing()
+    local routing = {}
+    for i, ch in ipairs(project.channels) do
+        local sidechains = {}
+        for k, fx in ipairs(ch.effects) do
+            sidechains[k] = fx.sidechain
+        end
+        routing[i] = { output = ch.output, sends = util.clone(ch.sends), sidechains = sidechains }
+    end
+    return routing
+end
 
 local function remove_channel(ch_index)
     tessera.audio.remove_channel(ch_index)
     table.remove(project.channels, ch_index)
+    remove_routing(ch_index)
+    midi_map.remove_channel(ch_index)
     table.remove(ui_channels, ch_index)
     build.refresh_channels()
     if selection.ch_index == ch_index then
@@ -18,6 +62,17 @@ local function remove_effect(ch_index, effect_index)
     table.remove(project.channels[ch_index].effects, effect_index)
     table.remove(ui_channels[ch_index].effects, effect_index)
     tessera.audio.remove_effect(ch_index, effect_index)
+
+    local function map(device)
+        if device == effect_index then
+            return nil
+        elseif device > effect_index then
+            return device - 1
+        end
+        return device
+    end
+    automation.remap_devices(ch_index, map)
+    midi_map.remap_devices(ch_index, map)
 end
 
 local function reorder_effect(ch_index, old_index, new_index)
@@ -35,6 +90,19 @@ local function reorder_effect(ch_index, old_index, new_index)
 
             tessera.audio.reorder_effect(ch_index, old_index, new_index)
 
+            local function map(device)
+                if device == old_index then
+                    return new_index
+                elseif old_index < new_index and device > old_index and device <= new_index then
+                    return device - 1
+                elseif new_index < old_index and device >= new_index and device < old_index then
+                    return device + 1
+                end
+                return device
+            end
+            automation.remap_devices(ch_index, map)
+            midi_map.remap_devices(ch_index, map)
+
             if selection.ch_index == ch_index and selection.device_index == old_index then
                 selection.device_index = new_index
             end
@@ -90,6 +158,8 @@ function RemoveChannel.new(ch_index)
     end
 
     self.channel = util.clone(project.channels[ch_index])
+    self.routing = get_routing()
+    self.midi_map = util.clone(project.midi_map)
     return self
 end
 
@@ -100,6 +170,14 @@ end
 function RemoveChannel:reverse()
     local channel = util.clone(self.channel)
     table.insert(project.channels, self.ch_index, channel)
+    for i, ch in ipairs(project.channels) do
+        ch.output = self.routing[i].output
+        ch.sends = util.clone(self.routing[i].sends)
+        for k, fx in ipairs(ch.effects) do
+            fx.sidechain = self.routing[i].sidechains[k]
+        end
+    end
+    project.midi_map = util.clone(self.midi_map)
     build.channel(self.ch_index, channel)
 end
 
@@ -142,6 +220,8 @@ function RemoveEffect.new(ch_index, effect_index)
     self.ch_index = ch_index
     self.effect_index = effect_index
     self.effect = util.clone(project.channels[ch_index].effects[effect_index])
+    self.automation = util.clone(project.channels[ch_index].automation)
+    self.midi_map = util.clone(project.midi_map)
     return self
 end
 
@@ -153,6 +233,9 @@ function RemoveEffect:reverse()
     local effect = util.clone(self.effect)
     table.insert(project.channels[self.ch_index].effects, self.effect_index, effect)
     build.effect(self.ch_index, self.effect_index, effect)
+    project.channels[self.ch_index].automation = util.clone(self.automation)
+    project.midi_map = util.clone(self.midi_map)
+    automation.send(self.ch_index)
 end
 
 --
@@ -176,4 +259,45 @@ function ReorderEffect:reverse()
     reorder_effect(self.ch_index, self.new_index, self.old_index)
 end
 
-return { NewChannel, RemoveChannel, NewEffect, RemoveEffect, ReorderEffect }
+--
+-- Channels with notes from an imported file, optionally replacing the tempo map
+local ImportChannels = {}
+ImportChannels.__index = ImportChannels
+
+function ImportChannels.new(channels, tempo)
+    local self = setmetatable({}, ImportChannels)
+
+    self.ch_index = #project.channels + 1
+    self.channels = channels
+    self.tempo = tempo
+    self.prev_tempo = util.clone(project.tempo)
+    return self
+end
+
+function ImportChannels:run()
+    for i, v in ipairs(self.channels) do
+        local channel = build.new_channel_data(v.options)
+        channel.notes = util.clone(v.notes)
+        channel.control = util.clone(v.control)
+        local ch_index = self.ch_index + i - 1
+        table.insert(project.channels, ch_index, channel)
+        build.channel(ch_index, channel)
+    end
+    if self.tempo then
+        project.tempo = util.clone(self.tempo)
+    end
+
+    selection.ch_index = self.ch_index
+    selection.device_index = nil
+end
+
+function ImportChannels:reverse()
+    for i = #self.channels, 1, -1 do
+        remove_channel(self.ch_index + i - 1)
+    end
+    if self.tempo then
+        project.tempo = util.clone(self.prev_tempo)
+    end
+end
+
+return { NewChannel, RemoveChannel, NewEffect, RemoveEffect, ReorderEffect, ImportChannels }
@@ -8,6 +8,12 @@ local function empty_project()
 		transport = {
 			start_time = 0,
 			recording = true,
+			looping = false,
+			loop_start = 0,
+			loop_end = 4,
+			punch = false,
+			punch_in = 0,
+			punch_out = 4,
 		},
 
 		settings = {
@@ -22,12 +28,19 @@ local function empty_project()
 			notation_style = "ups",
 		},
 
-		time = {
-			{
-				0.0,
-				2.0,
+		tempo = {
+			-- time in seconds of beat 0
+			offset = 0.0,
+			points = {
+				{ beat = 0, bpm = 120, ramp = false },
+			},
+			signatures = {
+				{ beat = 0, numerator = 4, denominator = 4 },
 			},
 		},
+
+		-- controllers mapped to device parameters
+		midi_map = {},
 	}
 end
 
@@ -1,4 +1,5 @@
 local Ui = require("ui.ui")
+local midi_map = require("midi_map")
 local widgets = require("ui.widgets")
 
 local Device = {}
@@ -32,6 +33,9 @@ function Device.new(data, options, meter_id)
 	self.state_old = {}
 	self.mute_old = false
 
+	-- parameters that only take whole numbers
+	self.discrete = {}
+
 	-- UI stuff and parameter handlers
 	self.collapse = widgets.CollapseDevice.new(self)
 	self.elements = {}
@@ -69,11 +73,13 @@ function Device.new(data, options, meter_id)
 			elseif w_type == "selector" then
 				local default = w_options.default or 1
 				self.state[index] = valid_index(self.state[index], default)
+				self.discrete[index] = true
 
 				element.widget = widgets.Selector.new(self.state, index, w_options)
 			elseif w_type == "dropdown" then
 				local default = w_options.default or 1
 				self.state[index] = valid_index(self.state[index], default)
+				self.discrete[index] = true
 
 				element.widget = widgets.Dropdown.new(self.state, index, w_options)
 			elseif w_type == "toggle" then
@@ -94,6 +100,9 @@ function Device.new(data, options, meter_id)
 			else
 				error(w_type .. " not supported!")
 			end
+			if not element.vst_open then
+				element.index = index
+			end
 			index = index + 1
 		end
 		table.insert(self.elements, element)
@@ -129,7 +138,7 @@ function Device:update(ui, index, w)
 			else
 				ui.layout:col(w_label)
 				if v.label then
-					ui:label(v.label, { align = tessera.graphics.ALIGN_RIGHT })
+					self:update_label(ui, v, index)
 				end
 				ui.layout:col(w - w_label)
 				local hit = v.widget:update(ui)
@@ -150,9 +159,49 @@ function Device:update(ui, index, w)
 	return ui:hit_area(start_x, start_y, w, end_y - start_y) and mouse.button_released
 end
 
+-- Clicking the label of a parameter learns a controller mapping, shift-click removes it
+function Device:update_label(ui, element, device_index)
+	local ch_index = selection.ch_index
+	local color
+	if element.index and midi_map.is_learning(ch_index, device_index, element.index) then
+		color = theme.recording
+	elseif element.index and midi_map.find(ch_index, device_index, element.index) then
+		color = theme.highlight
+	end
+
+	local x, y, w, h = ui:label(element.label, { align = tessera.graphics.ALIGN_RIGHT, color = color })
+	if element.index and mouse.button_pressed == 1 and ui:hit_area(x, y, w, h) then
+		if modifier_keys.shift then
+			midi_map.remove(ch_index, device_index, element.index)
+		else
+			midi_map.learn(ch_index, device_index, element.index)
+		end
+	end
+end
+
+-- Default range of a controller mapped to a parameter: min, max, curve and whether it is discrete
+function Device:parameter_range(index)
+	for _, v in ipairs(self.elements) do
+		if v.index == index then
+			local value = v.widget.value
+			if value then
+				-- sliders with a dB or log scale need more resolution at the low end
+				local curve = (value.t == "dB" or value.t == "log") and 3 or 1
+				return value:from_normal(0), value:from_normal(1), curve, false
+			elseif v.widget.list then
+				return 1, #v.widget.list, 1, true
+			end
+			return 0, 1, 1, true
+		end
+	end
+end
+
 function Device:reset()
 	self.state_old = {}
 	self.mute_old = false
+
+	-- parameters that only take whole numbers
+	self.discrete = {}
 end
 
 return Device
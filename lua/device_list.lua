@@ -11,6 +11,7 @@ device_list.instruments.vst_instrument = {
 	parameters = {
 		{ "Show UI", "button" },
 		{ "Pitch Bend Range", "selector", { list = { "2", "48" }, default = 2 } },
+		{ "MTS", "selector", { list = { "Off", "Bulk", "Note", "Octave", "Oct. 2B" }, default = 1 } },
 	},
 }
 
@@ -198,6 +199,22 @@ device_list.instruments.epiano = {
 	},
 }
 
+device_list.instruments.audio_input = {
+	display_name = "Audio Input",
+	parameters = {
+		{ "Input", "selector", { list = { "Stereo", "Left", "Right" } } },
+		{ "Gain", "slider", { default = 0, max = 24, t = "dB" } },
+	},
+}
+
+device_list.instruments.midi_output = {
+	display_name = "MIDI Output",
+	parameters = {
+		{ "Pitch Bend Range", "slider", { default = 48, min = 1, max = 96, step = 1, fmt = "%0.0f st" } },
+		{ "MTS", "selector", { list = { "Off", "Bulk", "Note", "Octave", "Oct. 2B" }, default = 1 } },
+	},
+}
+
 device_list.effects = {}
 
 device_list.effects.pan = {
@@ -247,6 +264,7 @@ device_list.effects.chorus = {
 
 device_list.effects.tremolo = {
 	display_name = "Tremolo",
+	sidechain = true,
 	parameters = {
 		{ "Amount", "slider", { default = 0.4 } },
 		{ "Rate", "slider", { default = 1.5, min = 0.50, max = 15.0, t = "log" } },
@@ -370,6 +388,7 @@ device_list.effects.convolve = {
 
 device_list.effects.compressor = {
 	display_name = "Compressor",
+	sidechain = true,
 	parameters = {
 		{ "Dry/Wet", "slider", { default = 1.0 } },
 		{ "Treshold", "slider", { default = -24, min = -48, max = 0, fmt = "%0.1f dB" } },
@@ -382,11 +401,13 @@ device_list.effects.compressor = {
 
 device_list.effects.limiter = {
 	display_name = "Limiter",
+	sidechain = true,
 	parameters = {
 		{ "Release", "slider", { default = 200.0, min = 10.0, max = 2000.0, t = "log", fmt = "ms" } },
 		{ "Gain", "slider", { default = 0.0, min = -24.0, max = 24.0, fmt = "%0.1f dB", centered = true } },
 		{ "Ceiling", "slider", { default = -0.3, min = -12.0, max = 0.0, fmt = "%0.1f dB" } },
 		{ "Stereo Link", "toggle", { default = true } },
+		{ "Lookahead", "toggle", { default = false } },
 	},
 }
 
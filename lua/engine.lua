@@ -1,25 +1,107 @@
+local automation = require("automation")
 local log = require("log")
 local midi = require("midi")
+local midi_map = require("midi_map")
 local time = require("time")
+local tuning = require("tuning")
 
-local RENDER_BLOCK_SIZE = 64
+-- frames per call into the offline render
+local RENDER_CHUNK_SIZE = 16384
+local DEFAULT_SAMPLE_RATE = 44100
 
 local engine = {}
 
 engine.playing = false
-engine.render_progress = 0
 engine.render_end = 8
+engine.render_sample_rate = DEFAULT_SAMPLE_RATE
 engine.time = 0
 engine.frame_time = 0
+-- times playback wrapped around the loop
+engine.loops = 0
+
+local function record_start()
+	local channels = {}
+	-- the backend only records inside the punch range and stamps each take with where it started
+	local stamp = os.date("%Y%m%d-%H%M%S")
+	for i, ch in ipairs(project.channels) do
+		if ch.armed and ch.instrument and ch.instrument.name == "audio_input" then
+			local name = ch.name:gsub("[^%w%s%-_]", "_")
+			local path = string.format("out/recordings/%s %s.wav", name, stamp)
+			table.insert(channels, { i, path })
+		end
+	end
+	if #channels > 0 then
+		tessera.audio.record_start(channels)
+	end
+end
+
+local function record_stop()
+	local takes = tessera.audio.record_stop()
+	for _, take in ipairs(takes) do
+		local length = take.length / take.sample_rate
+		log.info(string.format('Recorded "%s" (%.2fs)', take.path, length))
+
+		local ch = project.channels[take.channel_index]
+		if ch and length > 0 then
+			ch.clips = ch.clips or {}
+			table.insert(ch.clips, {
+				path = take.path,
+				start = take.start_time,
+				offset = 0,
+				length = length,
+				gain = 1,
+				fade_in = 0,
+				fade_out = 0,
+			})
+			tessera.audio.set_clips(take.channel_index, ch.clips)
+		end
+	end
+	return takes
+end
+
+-- Edits bump the version of a channel, and only channels with a new version are sent again.
+-- Pitches follow the tuning, so loading another one changes all of them.
+local sequence_versions = {}
+local sent_versions = {}
+local sent_tuning
+
+function engine.sequence_changed(ch_index)
+	sequence_versions[ch_index] = (sequence_versions[ch_index] or 0) + 1
+end
+
+-- Hand a snapshot of all notes to the backend, which schedules them on the audio clock.
+-- With `changed_only`, only channels that were edited since the last one are sent.
+local function send_sequences(changed_only)
+	if tuning.key ~= sent_tuning then
+		sent_versions = {}
+		sent_tuning = tuning.key
+	end
+	for ch_index, ch in ipairs(project.channels) do
+		-- live input on a recording channel already plays, resending would chase the notes being added
+		local recording = changed_only and ch.armed and project.transport.recording
+		local version = sequence_versions[ch_index] or 0
+		local changed = sent_versions[ch_index] ~= version
+		if ui_channels[ch_index].instrument and not recording and (changed or not changed_only) then
+			local pitches = {}
+			for i, note in ipairs(ch.notes) do
+				pitches[i] = tuning.get_pitch(note.interval)
+			end
+			tessera.audio.set_sequence(ch_index, { notes = ch.notes, pitches = pitches, control = ch.control })
+			sent_versions[ch_index] = version
+		end
+	end
+end
 
 function engine.start()
 	engine.seek(project.transport.start_time)
 	engine.playing = true
+	engine.loops = 0
+	send_sequences()
+	tessera.audio.play(project.transport.start_time, project.settings.chase)
+	automation.start(project.transport.start_time, audio_status == "running")
 
-	for _, v in ipairs(ui_channels) do
-		if v.instrument then
-			v.roll:start(project.settings.chase)
-		end
+	if project.transport.recording and audio_status == "running" and tessera.audio.ok() then
+		record_start()
 	end
 end
 
@@ -28,6 +110,12 @@ function engine.stop()
 		return
 	end
 	engine.playing = false
+	tessera.audio.stop()
+	automation.stop(engine.time)
+
+	if tessera.audio.ok() then
+		record_stop()
+	end
 
 	local added_notes = {}
 	local total = 0
@@ -46,23 +134,62 @@ function engine.stop()
 	end
 end
 
-function engine.seek(time)
+function engine.seek(t)
 	if engine.playing then
-		log.warn("Engine was playing while seeking")
-		return
+		-- takes are written in one piece
+		if project.transport.recording then
+			log.warn("Can't seek while recording")
+			return
+		end
+		automation.stop(engine.time)
+		tessera.audio.seek(t)
+		automation.start(t, audio_status == "running")
 	end
-	engine.time = time
+	engine.time = t
+end
+
+-- playback jumped back to the start of the loop
+function engine.wrap(t)
+	automation.stop(engine.time)
+	for _, ch in ipairs(ui_channels) do
+		if ch.instrument then
+			ch.roll:close_notes()
+		end
+	end
+	engine.time = t
+	automation.start(t, audio_status == "running")
+end
+
+-- notes are only recorded inside the punch range
+function engine.punched_in()
+	local transport = project.transport
+	if not transport.punch then
+		return true
+	end
+	return engine.time >= transport.punch_in and engine.time < transport.punch_out
 end
 
 function engine.update(dt)
+	time.update()
 	if engine.playing then
 		engine.frame_time = engine.frame_time + dt
-		engine.time = time.next(engine.time, dt)
 
-		if tessera.audio.ok() then
-			for _, v in ipairs(ui_channels) do
-				if v.instrument then
-					v.roll:playback(v)
+		-- the backend owns the transport, we only follow it
+		local position, loops = tessera.audio.position()
+		if position and loops ~= engine.loops then
+			engine.loops = loops
+			engine.wrap(position)
+		elseif position then
+			engine.time = time.next(engine.time, position - engine.time)
+		elseif not (tessera.audio.ok() or tessera.audio.is_rendering()) then
+			engine.time = time.next(engine.time, dt)
+		end
+		automation.update(engine.time)
+
+		if project.transport.recording and not engine.punched_in() then
+			for _, ch in ipairs(ui_channels) do
+				if ch.instrument then
+					ch.roll:close_notes()
 				end
 			end
 		end
@@ -75,77 +202,77 @@ function engine.update_frame()
 	engine.frame_time = 0
 end
 
-function engine.render_start()
+function engine.render_start(path)
 	engine.stop()
 	tessera.audio.flush()
 	midi.flush()
 
-	if not tessera.audio.ok() then
-		log.error("Can't render, audio offline.")
-		return
-	end
-
 	assert(audio_status == "running")
-	audio_status = "render"
+
+	-- ensure all VST state is up to date in the snapshot
+	if tessera.audio.ok() then
+		for ch_index, channel in ipairs(project.channels) do
+			if channel.instrument and channel.instrument.plugin then
+				channel.instrument.plugin.state = tessera.audio.vst_get_state(ch_index)
+			end
+		end
+	end
 
 	engine.render_end = engine.end_time() + 2.0
-	engine.render_progress = project.transport.start_time
+	engine.render_sample_rate = engine.sample_rate or DEFAULT_SAMPLE_RATE
 
-	if engine.playing then
-		engine.stop()
+	local settings = util.clone(setup.export)
+	settings.path = path
+
+	local duration = engine.render_end - project.transport.start_time
+	if not tessera.audio.render_start(project, engine.render_sample_rate, duration, settings) then
+		log.error("Failed to start render.")
+		return
 	end
+
+	audio_status = "render"
 	engine.start()
 
 	log.info("Start render.")
 
 	mouse:set_cursor("wait")
 	mouse:end_frame()
-
-	tessera.audio.set_rendering(true)
-	tessera.audio.clear_messages()
 end
 
 function engine.get_progress_relative()
-	return (engine.render_progress - project.transport.start_time) / (engine.render_end - project.transport.start_time)
+	return tessera.audio.render_progress() or 0
 end
 
+-- Sequencer and automation run in the backend, so we only hand it large chunks
 function engine.render()
 	assert(tessera.audio.is_rendering())
 
-	local dt = RENDER_BLOCK_SIZE / engine.sample_rate
-
 	-- Try to hit 16 ms to keep things responsive
 	local target_ms = 16
 	local t_start = tessera.get_time()
-	for i = 1, 3000 do
-		local success = tessera.audio.render_block()
-		if not success then
+	repeat
+		if not tessera.audio.render_block(RENDER_CHUNK_SIZE) then
 			log.error("Failed to render block.")
 			engine.render_finish()
 			return
 		end
 
-		engine.update(dt)
-		engine.render_progress = engine.render_progress + dt
-		if engine.render_progress >= engine.render_end then
+		if tessera.audio.render_progress() >= 1 then
 			log.info("Finished render.")
 			tessera.audio.render_finish()
 			engine.render_finish()
-			break
+			return
 		end
+	until (tessera.get_time() - t_start) * 1000 > target_ms
 
-		local t_now = (tessera.get_time() - t_start) * 1000
-		if t_now > target_ms then
-			print(tostring(i) .. " blocks rendered")
-			break
-		end
-	end
+	-- follow the playhead once per frame
+	engine.update(tessera.get_time() - t_start)
 end
 
 function engine.render_finish()
 	midi.flush()
 
-	tessera.audio.set_rendering(false)
+	tessera.audio.render_cancel()
 	mouse:set_cursor("default")
 	audio_status = "running"
 	engine.stop()
@@ -157,15 +284,26 @@ function engine.setup_stream()
 	local buffer_size = setup.configs[host].buffer_size
 	if device then
 		tessera.audio.setup(device, buffer_size)
+		engine.setup_input()
 	else
 		log.error("No audio device configured")
 	end
 end
 
+function engine.setup_input()
+	local input_device = setup.configs[setup.host].input_device
+	if input_device and tessera.audio.ok() then
+		tessera.audio.open_input(input_device)
+	else
+		tessera.audio.close_input()
+	end
+end
+
 function engine.rebuild_stream()
 	engine.flush_messages()
 	engine.buffer_size = nil
 	engine.sample_rate = nil
+	engine.output_channels = nil
 	if tessera.audio.ok() then
 		log.info("Rebuilding stream")
 		local host = setup.host
@@ -173,6 +311,7 @@ function engine.rebuild_stream()
 		local buffer_size = setup.configs[host].buffer_size
 		if device then
 			tessera.audio.rebuild(device, buffer_size)
+			engine.setup_input()
 		else
 			log.error("No device configured")
 			tessera.audio.quit()
@@ -192,6 +331,7 @@ function engine.parse_messages()
 		if msg.tag == "StreamSettings" then
 			engine.buffer_size = msg.buffer_size
 			engine.sample_rate = msg.sample_rate
+			engine.output_channels = msg.output_channels
 		elseif msg.tag == "Log" then
 			log.append(msg.level, msg.message)
 		else
@@ -216,6 +356,20 @@ function engine.parse_errors()
 			log.warn(util.dump(msg))
 		end
 	end
+
+	-- input failing should not take down the output stream
+	while true do
+		local msg = tessera.audio.pop_input_error()
+		if msg == nil then
+			return
+		end
+		if msg.tag == "ResetRequest" then
+			engine.setup_input()
+		elseif msg.tag == "DeviceNotAvailable" then
+			log.warn("Input device not available")
+			tessera.audio.close_input()
+		end
+	end
 end
 
 function engine.flush_messages()
@@ -254,14 +408,280 @@ local function send_channel_parameters(ch, ch_index)
 	local mute = ch.data.mute
 	if ch.mute_old ~= mute then
 		tessera.audio.send_channel_mute(ch_index, mute)
+		if engine.playing then
+			automation.record(engine.time, ch_index, "mute", 0, 0, mute and 1 or 0)
+		end
 		ch.mute_old = mute
 	end
 
 	local gain = ch.data.gain
 	if ch.gain_old ~= gain then
 		tessera.audio.send_channel_gain(ch_index, gain)
+		if engine.playing then
+			automation.record(engine.time, ch_index, "gain", 0, 0, gain)
+		end
 		ch.gain_old = gain
 	end
+
+	local cue = ch.data.cue or 0
+	if ch.cue_old ~= cue then
+		tessera.audio.send_cue_gain(ch_index, cue)
+		ch.cue_old = cue
+	end
+end
+
+-- stereo pairs of the output device, 1 is outputs 1/2
+function engine.output_pairs()
+	local channels = engine.output_channels or 2
+	local list = {}
+	for i = 1, math.max(1, math.floor(channels / 2)) do
+		list[i] = string.format("%d/%d", 2 * i - 1, 2 * i)
+	end
+	return list
+end
+
+local output_map_key
+local function send_output_map()
+	local config = setup.configs[setup.host]
+	local map = { master = config.master_output or 1, cue = config.cue_output, direct = {} }
+	local parts = { tostring(map.master), tostring(map.cue) }
+	for i, ch in ipairs(project.channels) do
+		if ch.direct_output and ch.direct_output > 0 then
+			table.insert(map.direct, { channel = i, pair = ch.direct_output })
+			table.insert(parts, string.format("%d>%d", i, ch.direct_output))
+		end
+	end
+
+	local key = table.concat(parts, ",")
+	if key ~= output_map_key then
+		tessera.audio.set_output_map(map)
+		output_map_key = key
+	end
+end
+
+-- only the structure, send levels are sent separately
+local routing_key
+local function send_routing()
+	local routes = {}
+	local parts = {}
+	for i, ch in ipairs(project.channels) do
+		local sends = {}
+		for _, send in ipairs(ch.sends or {}) do
+			table.insert(sends, { target = send.target, gain = send.gain, pre_fader = send.pre_fader })
+			table.insert(parts, string.format("%d>%d%s", i, send.target, send.pre_fader and "p" or ""))
+		end
+		local sidechains = {}
+		for k, fx in ipairs(ch.effects) do
+			if fx.sidechain and fx.sidechain > 0 then
+				table.insert(sidechains, { effect = k, source = fx.sidechain })
+				table.insert(parts, string.format("%d.%d<%d", i, k, fx.sidechain))
+			end
+		end
+		routes[i] = { output = ch.output, sends = sends, sidechains = sidechains }
+		table.insert(parts, string.format("%d:%d", i, ch.output or 1))
+	end
+
+	local key = table.concat(parts, ",")
+	if key ~= routing_key then
+		routing_key = key
+		if not tessera.audio.set_routing(routes) then
+			log.warn("Invalid routing, check channel outputs and sends for loops.")
+		end
+		-- levels were sent along with the routing
+		for _, ch in ipairs(ui_channels) do
+			ch.send_gain_old = {}
+			for k, send in ipairs(ch.data.sends or {}) do
+				ch.send_gain_old[k] = send.gain
+			end
+		end
+	end
+end
+
+-- MIDI output channels and the port they send to, if it is open
+local midi_output_key
+local function send_midi_outputs()
+	local outputs = {}
+	-- a port that connected again is a new connection, channels have to attach to it again
+	local parts = { tostring(midi.output_generation) }
+	for i, ch in ipairs(project.channels) do
+		if ch.instrument and ch.instrument.name == "midi_output" then
+			local port = midi.open_outputs[ch.midi_port] and ch.midi_port or nil
+			table.insert(outputs, { channel = i, port = port })
+			table.insert(parts, string.format("%d>%s", i, tostring(port)))
+		end
+	end
+
+	local key = table.concat(parts, ",")
+	if key ~= midi_output_key then
+		for _, v in ipairs(outputs) do
+			tessera.midi.set_channel_output(v.channel, v.port)
+		end
+		midi_output_key = key
+	end
+end
+
+-- MTS format per selector index, and where the selector is for instruments that play midi keys
+local MTS_FORMATS = { false, "bulk", "single_note", "scale_octave", "scale_octave2" }
+local MTS_PARAMETER = { midi_output = 2, vst_instrument = 3 }
+
+-- 12-EDO, sent in the format that was used before when MTS gets switched off
+local EDO12 = {}
+for k = 0, 127 do
+	EDO12[k + 1] = k
+end
+
+-- Key tunings of channels that retune their synth with MTS
+local tuning_key
+-- Last format and port per channel, so the synth can be reset when MTS gets switched off
+local tuning_sent = {}
+local function send_tunings()
+	local channels = {}
+	local parts = { tostring(tuning.key), table.concat(tuning.center or {}, " "), tostring(midi.output_generation) }
+	for i, ch in ipairs(project.channels) do
+		local index = ch.instrument and MTS_PARAMETER[ch.instrument.name]
+		if index then
+			local format = MTS_FORMATS[ch.instrument.state and ch.instrument.state[index]] or false
+			local port
+			if ch.instrument.name == "midi_output" and midi.open_outputs[ch.midi_port] then
+				port = ch.midi_port
+			end
+			table.insert(channels, { channel = i, format = format, port = port })
+			table.insert(parts, string.format("%d:%s>%s", i, tostring(format), tostring(port)))
+		end
+	end
+
+	local key = table.concat(parts, ",")
+	if key ~= tuning_key then
+		local pitches = tuning.midi_pitches()
+		for _, v in ipairs(channels) do
+			local options = { format = v.format, name = tostring(tuning.key) }
+			local prev = tuning_sent[v.channel]
+			if not v.format and prev and prev.format then
+				-- otherwise the synth keeps playing the last tuning it got
+				local reset = { format = prev.format, name = "12-EDO" }
+				if prev.port then
+					tessera.midi.send_tuning(prev.port, EDO12, reset)
+					tessera.audio.set_tuning(v.channel)
+				else
+					tessera.audio.set_tuning(v.channel, EDO12, reset)
+				end
+			elseif not v.format then
+				tessera.audio.set_tuning(v.channel)
+			elseif v.port then
+				-- the synth gets retuned over the port, the channel only has to pick the keys
+				tessera.midi.send_tuning(v.port, pitches, options)
+				tessera.audio.set_tuning(v.channel, pitches)
+			else
+				tessera.audio.set_tuning(v.channel, pitches, options)
+			end
+			tuning_sent[v.channel] = v
+		end
+		tuning_key = key
+	end
+end
+
+-- Armed channels, where the backend plays live midi input
+local live_key
+local function send_live()
+	local channels = {}
+	for i, ch in ipairs(project.channels) do
+		if ch.armed then
+			table.insert(channels, i)
+		end
+	end
+
+	local center = table.concat(tuning.center or {}, " ")
+	local key = string.format("%s:%s|%s", tostring(tuning.key), center, table.concat(channels, ","))
+	if key ~= live_key then
+		tessera.midi.set_live(channels, tuning.midi_pitches())
+		live_key = key
+	end
+end
+
+-- Controller mappings, the backend applies them without waiting for the frame loop
+local midi_map_key
+local function send_midi_map()
+	local list = project.midi_map or {}
+	local parts = {}
+	for _, m in ipairs(list) do
+		local source = string.format(
+			"%s:%s:%d:%s",
+			tostring(m.port),
+			tostring(m.midi_channel),
+			m.controller,
+			tostring(m.high_res)
+		)
+		local target = string.format("%d:%d:%d", m.channel, m.device, m.index)
+		local range =
+			string.format("%s:%s:%s:%s:%s", m.min, m.max, tostring(m.curve), tostring(m.mode), tostring(m.discrete))
+		table.insert(parts, source .. ">" .. target .. "|" .. range)
+	end
+	local key = table.concat(parts, ",")
+	if key ~= midi_map_key then
+		local values = {}
+		for i, m in ipairs(list) do
+			values[i] = midi_map.value(m)
+		end
+		tessera.midi.set_mappings(list, values)
+		midi_map_key = key
+	end
+
+	if engine.playing then
+		for _, v in ipairs(midi_map.take_changes()) do
+			automation.record(engine.time, v.channel, "parameter", v.device, v.index, v.value)
+		end
+	else
+		midi_map.take_changes()
+	end
+end
+
+local tempo_key
+local function send_tempo()
+	time.update()
+	if time.key ~= tempo_key then
+		tessera.audio.set_tempo(project.tempo)
+		tempo_key = time.key
+	end
+end
+
+local transport_key
+local function send_transport()
+	local transport = project.transport
+	local key = string.format(
+		"%s:%s-%s,%s:%s-%s",
+		tostring(transport.looping),
+		transport.loop_start,
+		transport.loop_end,
+		tostring(transport.punch),
+		transport.punch_in,
+		transport.punch_out
+	)
+	if key ~= transport_key then
+		if transport.looping then
+			tessera.audio.set_loop(transport.loop_start, transport.loop_end)
+		else
+			tessera.audio.set_loop()
+		end
+		if transport.punch then
+			tessera.audio.set_punch(transport.punch_in, transport.punch_out)
+		else
+			tessera.audio.set_punch()
+		end
+		transport_key = key
+	end
+end
+
+local function send_send_gains(ch, ch_index)
+	if not ch.data.sends then
+		return
+	end
+	ch.send_gain_old = ch.send_gain_old or {}
+	for k, send in ipairs(ch.data.sends) do
+		if ch.send_gain_old[k] ~= send.gain then
+			tessera.audio.send_send_gain(ch_index, k, send.gain)
+			ch.send_gain_old[k] = send.gain
+		end
+	end
 end
 
 local function send_device_mute(device, ch_index, device_index)
@@ -319,8 +739,21 @@ local function to_float(x)
 end
 
 function engine.send_parameters()
+	send_routing()
+	send_output_map()
+	send_midi_outputs()
+	send_tunings()
+	send_live()
+	send_midi_map()
+	send_tempo()
+	send_transport()
+	if engine.playing then
+		send_sequences(true)
+	end
+
 	for ch_index, ch in ipairs(ui_channels) do
 		send_channel_parameters(ch, ch_index)
+		send_send_gains(ch, ch_index)
 
 		if ch.instrument then
 			send_device_mute(ch.instrument, ch_index, 0)
@@ -329,8 +762,12 @@ function engine.send_parameters()
 				local new_value = ch.instrument.state[l]
 				local old_value = ch.instrument.state_old[l]
 				if old_value ~= new_value then
-					local value = new_value
-					tessera.audio.send_parameter(ch_index, 0, l, to_float(value))
+					local value = to_float(new_value)
+					tessera.audio.send_parameter(ch_index, 0, l, value)
+					midi_map.value_changed(ch_index, 0, l, value)
+					if engine.playing then
+						automation.record(engine.time, ch_index, "parameter", 0, l, value)
+					end
 					ch.instrument.state_old[l] = new_value
 				end
 			end
@@ -343,8 +780,12 @@ function engine.send_parameters()
 				local new_value = fx.state[l]
 				local old_value = fx.state_old[l]
 				if old_value ~= new_value then
-					local value = new_value
-					tessera.audio.send_parameter(ch_index, fx_index, l, to_float(value))
+					local value = to_float(new_value)
+					tessera.audio.send_parameter(ch_index, fx_index, l, value)
+					midi_map.value_changed(ch_index, fx_index, l, value)
+					if engine.playing then
+						automation.record(engine.time, ch_index, "parameter", fx_index, l, value)
+					end
 					fx.state_old[l] = new_value
 				end
 			end
@@ -352,7 +793,27 @@ function engine.send_parameters()
 	end
 end
 
+-- channel indices changed, the backend falls back to default routing until we resend it
+function engine.invalidate_routing()
+	routing_key = nil
+	output_map_key = nil
+	midi_output_key = nil
+	tuning_key = nil
+	tuning_sent = {}
+	live_key = nil
+	midi_map_key = nil
+	sent_versions = {}
+end
+
 function engine.reset_parameters()
+	routing_key = nil
+	output_map_key = nil
+	midi_output_key = nil
+	tuning_key = nil
+	live_key = nil
+	midi_map_key = nil
+	tempo_key = nil
+	transport_key = nil
 	for _, ch in ipairs(ui_channels) do
 		ch:reset()
 		if ch.instrument then
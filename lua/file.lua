@@ -1,4 +1,7 @@
 local build = require("build")
+local engine = require("engine")
+local log = require("log")
+local midi_file = require("midi_file")
 local save = require("save")
 
 local file = {}
@@ -23,6 +26,19 @@ function file.poll_dialogs()
 				save.read(f)
 				dialog_pending = nil
 				overwrite_check = false
+			elseif dialog_pending == "import_midi" then
+				dialog_pending = nil
+				midi_file.import(f)
+			elseif dialog_pending == "export_midi" then
+				dialog_pending = nil
+				midi_file.export(f)
+			elseif dialog_pending == "render" then
+				dialog_pending = nil
+				if audio_status == "running" then
+					engine.render_start(f)
+				else
+					log.warn("Audio not running, can't render.")
+				end
 			end
 		end
 	end
@@ -69,4 +85,22 @@ function file.save_as()
 	end
 end
 
+function file.import_midi()
+	if tessera.dialog_import_midi() then
+		dialog_pending = "import_midi"
+	end
+end
+
+function file.export_midi()
+	if tessera.dialog_export_midi(project.name .. ".mid") then
+		dialog_pending = "export_midi"
+	end
+end
+
+function file.render()
+	if tessera.dialog_render("render") then
+		dialog_pending = "render"
+	end
+end
+
 return file
@@ -24,6 +24,7 @@ project = {}
 ui_channels = {}
 
 local Ui = require("ui.ui")
+local automation = require("automation")
 local build = require("build")
 local engine = require("engine")
 local file = require("file")
@@ -241,7 +242,7 @@ function tessera.keypressed(key, key_str, isrepeat)
 
 	if audio_status == "render" then
 		if (key == "c" and modifier_keys.ctrl) or key == "escape" then
-			tessera.audio.render_cancel()
+			log.info("Render cancelled.")
 			engine.render_finish()
 		end
 
@@ -286,7 +287,7 @@ function tessera.keypressed(key, key_str, isrepeat)
 	elseif key == "y" and modifier_keys.ctrl then
 		command.redo()
 	elseif key == "r" and modifier_keys.ctrl then
-		engine.render_start()
+		file.render()
 	elseif key == "n" and modifier_keys.ctrl then
 		file.new()
 	elseif key == "o" and modifier_keys.ctrl then
@@ -295,8 +296,14 @@ function tessera.keypressed(key, key_str, isrepeat)
 		file.save_as()
 	elseif key == "s" and modifier_keys.ctrl then
 		file.save()
+	elseif key == "b" and modifier_keys.shift then
+		project.transport.punch = not project.transport.punch
 	elseif key == "b" then
 		project.transport.recording = not project.transport.recording
+	elseif key == "l" then
+		project.transport.looping = not project.transport.looping
+	elseif key == "m" and modifier_keys.ctrl then
+		automation.next_mode()
 	elseif key == "down" and modifier_keys.shift then
 		if selection.ch_index and selection.device_index then
 			local new_index = selection.device_index + 1
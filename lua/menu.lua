@@ -1,5 +1,4 @@
 local Ui = require("ui/ui")
-local engine = require("engine")
 local file = require("file")
 local widgets = require("ui/widgets")
 
@@ -38,8 +37,16 @@ function Menu.file(x, y)
 		},
 		{ type = "separator" },
 		{
-			widget = widgets.Button.new("Render audio", options),
-			action = engine.render_start,
+			widget = widgets.Button.new("Import MIDI...", options),
+			action = file.import_midi,
+		},
+		{
+			widget = widgets.Button.new("Export MIDI...", options),
+			action = file.export_midi,
+		},
+		{
+			widget = widgets.Button.new("Render audio...", options),
+			action = file.render,
 			tooltip = "ctrl+R",
 		},
 		{
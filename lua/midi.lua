@@ -1,4 +1,5 @@
 local log = require("log")
+local midi_map = require("midi_map")
 local tuning = require("tuning")
 
 local midi = {}
@@ -9,17 +10,21 @@ midi.ports_changed = false
 midi.open_ports = {}
 -- set of currently available ports
 midi.available_ports = {}
-
--- this list should be in sync with backend midi_connections
+-- output ports, opened when a channel sends to them
+midi.output_ports = {}
+midi.open_outputs = {}
+-- bumped whenever an output connects or goes away, channels attach to it again
+midi.output_generation = 0
+-- outputs the backend keeps open for us
+local wanted_outputs = {}
+-- set of our own virtual ports, inputs and outputs
+midi.virtual_ports = {}
+
+-- connected input ports by name, in sync with the backend
 local devices = {}
 
 local scan_timer = 0
 
--- unique index for a midi note
-local function event_note_index(event)
-	return event.channel * 256 + event.note
-end
-
 local MidiDevice = {}
 MidiDevice.__index = MidiDevice
 
@@ -28,96 +33,155 @@ function midi.load()
 		return
 	end
 	devices = {}
+	wanted_outputs = {}
+
+	-- older setups have port names with the ALSA client and port number, keep the first of each
+	local seen = {}
+	local migrated = {}
+	for _, c in ipairs(setup.midi_devices) do
+		c.name = midi.stable_name(c.name)
+		if not seen[c.name] then
+			seen[c.name] = true
+			table.insert(migrated, c)
+		end
+	end
+	setup.midi_devices = migrated
+
 	midi.ok = tessera.midi.init()
 
 	if not midi.ok then
 		log.info("Midi failed to intialize.")
+		return
+	end
+	midi.set_virtual(setup.virtual_midi.enable)
+
+	-- the backend opens these as soon as they are there, and again whenever they come back
+	for _, c in ipairs(setup.midi_devices) do
+		if c.enable then
+			tessera.midi.open_connection(c.name, c)
+		end
 	end
 end
 
+-- Virtual ports are listed like any other port, they get created once something opens them
+function midi.set_virtual(enable)
+	local inputs, outputs = {}, {}
+	if enable then
+		inputs, outputs = setup.virtual_midi.inputs, setup.virtual_midi.outputs
+	end
+	if not tessera.midi.set_virtual_ports(inputs, outputs) then
+		return false
+	end
+
+	midi.virtual_ports = {}
+	for _, name in ipairs(inputs) do
+		midi.virtual_ports[name] = true
+	end
+	for _, name in ipairs(outputs) do
+		midi.virtual_ports[name] = true
+	end
+	return true
+end
+
 -- enable or disable a midi connection
 function midi.connect(enable, config)
 	if enable then
-		local index = tessera.midi.open_connection(config.name)
-		if index then
-			assert(not devices[index])
-			devices[index] = MidiDevice.new(config)
-			midi.open_ports[config.name] = true
-			return true
-		else
-			log.warn(("Midi device %q not found"):format(config.name))
-			return false
-		end
-	else
-		local index = tessera.midi.close_connection(config.name)
-		if index then
-			assert(devices[index])
-			table.remove(devices, index)
-			midi.open_ports[config.name] = nil
-			return true
-		else
-			log.warn(("Midi device %q not found"):format(config.name))
-			return false
+		tessera.midi.open_connection(config.name, config)
+	elseif tessera.midi.close_connection(config.name) then
+		devices[config.name] = nil
+		midi.open_ports[config.name] = nil
+		midi.ports_changed = true
+	end
+end
+
+local function add_device(name)
+	for _, c in ipairs(setup.midi_devices) do
+		if name == c.name then
+			return
 		end
 	end
+	log.info(("Found new midi device: %q"):format(name))
+	-- a virtual port is only there because it was asked for
+	local config = { name = name, enable = midi.virtual_ports[name] }
+	table.insert(setup.midi_devices, config)
+	if config.enable then
+		midi.connect(true, config)
+	end
 end
 
+-- the backend watches the ports, here we only follow the changes it reports
 function midi.scan_ports()
-	local available_ports = tessera.midi.ports()
-
-	midi.available_ports = {}
-	for _, name in ipairs(available_ports) do
-		midi.available_ports[name] = true
-
-		-- check if it is preset in setup
-		local found = false
-		for _, c in ipairs(setup.midi_devices) do
-			if name == c.name then
-				found = true
-				break
-			end
+	for _, change in ipairs(tessera.midi.scan()) do
+		local name = change.name
+		midi.ports_changed = true
+		if change.state == "added" then
+			midi.available_ports[name] = true
+			add_device(name)
+		elseif change.state == "removed" then
+			midi.available_ports[name] = nil
+		elseif change.state == "connected" then
+			devices[name] = MidiDevice.new(name)
+			midi.open_ports[name] = true
+		elseif change.state == "disconnected" then
+			devices[name] = nil
+			midi.open_ports[name] = nil
+			log.warn(("Midi device %q disconnected"):format(name))
+		elseif change.state == "failed" then
+			log.warn(("Failed to open midi device %q"):format(name))
 		end
+	end
 
-		-- if not, add it to setup
-		if not found then
-			midi.ports_changed = true
-			log.info(("Found new midi device: %q"):format(name))
-			table.insert(setup.midi_devices, { name = name })
+	midi.scan_outputs()
+end
+
+-- open the output ports that channels send to, and close the ones nobody uses
+function midi.scan_outputs()
+	local used = {}
+	for _, ch in ipairs(project.channels) do
+		if ch.midi_port and ch.midi_port ~= "" then
+			used[ch.midi_port] = true
 		end
 	end
 
-	for _, c in ipairs(setup.midi_devices) do
-		-- new ports to add
-		if c.enable and midi.available_ports[c.name] and not midi.open_ports[c.name] then
-			midi.ports_changed = true
-			local success = midi.connect(true, c)
-			if not success then
-				-- disable it so we don't try opening it again next scan
-				c.enable = false
-			end
+	for name in pairs(used) do
+		if not wanted_outputs[name] then
+			tessera.midi.open_output(name)
+			wanted_outputs[name] = true
 		end
-
-		-- stale ports to delete
-		if (not c.enable or not midi.available_ports[c.name]) and midi.open_ports[c.name] then
-			midi.ports_changed = true
-			midi.connect(false, c)
+	end
+	for name in pairs(wanted_outputs) do
+		if not used[name] then
+			tessera.midi.close_output(name)
+			wanted_outputs[name] = nil
+			midi.open_outputs[name] = nil
 		end
 	end
-end
 
-function midi.update_config(config)
-	local device
-	for _, v in ipairs(devices) do
-		if v.name == config.name then
-			device = v
+	-- like the inputs, the backend reconnects outputs whenever they come back
+	for _, change in ipairs(tessera.midi.scan_outputs()) do
+		local name = change.name
+		if change.state == "connected" then
+			midi.open_outputs[name] = true
+			midi.output_generation = midi.output_generation + 1
+		elseif change.state == "disconnected" then
+			midi.open_outputs[name] = nil
+			midi.output_generation = midi.output_generation + 1
+			log.warn(("Midi output %q disconnected"):format(name))
+		elseif change.state == "failed" then
+			log.warn(("Failed to open midi output %q"):format(name))
 		end
 	end
+	midi.output_ports = tessera.midi.output_ports()
+end
 
-	if device then
-		device:set_config(config)
-	else
-		log.warn(("Device %q not found"):format(config.name))
-	end
+-- ALSA adds the client and port number to port names, older setups and projects still have them
+function midi.stable_name(name)
+	return (name:gsub(" %d+:%d+$", ""))
+end
+
+-- also kept for when the device connects again
+function midi.update_config(config)
+	tessera.midi.set_config(config.name, config)
 end
 
 function midi.update(dt)
@@ -132,20 +196,20 @@ function midi.update(dt)
 		midi.scan_ports()
 	end
 
-	for i, device in ipairs(devices) do
-		midi.update_device(i, device)
+	for _, device in pairs(devices) do
+		midi.update_device(device)
 	end
 end
 
 function midi.flush()
 	-- clear buffers
-	for i in ipairs(devices) do
-		tessera.midi.poll(i)
+	for name in pairs(devices) do
+		tessera.midi.poll(name)
 	end
 end
 
-function midi.update_device(device_index, device)
-	local events = tessera.midi.poll(device_index)
+function midi.update_device(device)
+	local events = tessera.midi.poll(device.name)
 	if not events or #events == 0 then
 		return
 	end
@@ -159,6 +223,7 @@ function midi.update_device(device_index, device)
 	end
 
 	for _, event in ipairs(events) do
+		midi_map.event(device.name, event)
 		device:event(sink, event)
 	end
 end
@@ -167,104 +232,42 @@ function midi.quit()
 	devices = {}
 end
 
-function MidiDevice.new(config)
+-- zones and bend ranges are handled by the backend, which also hands out the tokens
+function MidiDevice.new(name)
 	local self = setmetatable({}, MidiDevice)
-
-	self:set_config(config)
-
-	self.offset = 0
-	self.notes = {}
-	self.offsets = {}
-	for i = 0, 16 do
-		self.offsets[i] = 0
-	end
+	self.name = name
 	return self
 end
 
-function MidiDevice:set_config(config)
-	self.mpe = config.mpe
-	self.name = config.name
-	self.pitchbend_range = 2
-	if self.mpe then
-		self.pitchbend_range = 48
-	end
-end
-
-local function send_event(sink, event)
-	for _, v in ipairs(sink) do
-		v:event(event)
+-- The backend already plays the events on the armed channels, here they only get recorded.
+-- `age` is how long ago the event arrived, so it lands where it was played.
+local function record_event(sink, event)
+	for _, ch in ipairs(sink) do
+		if ch.instrument then
+			ch.roll:event(event)
+		end
 	end
 end
 
 function MidiDevice:event(sink, event)
 	if event.name == "note_on" then
-		local n_index = event_note_index(event)
-
-		local token = tessera.audio.get_token()
-		self.notes[n_index] = token
-
 		local interval = tuning.from_midi(event.note)
-		local offset = self.offsets[event.channel]
-
-		send_event(sink, { name = "note_on", token = token, interval = interval, vel = event.vel, offset = offset })
+		record_event(sink, {
+			name = "note_on",
+			token = event.token,
+			interval = interval,
+			vel = event.vel,
+			offset = event.offset,
+			age = event.age,
+		})
 	elseif event.name == "note_off" then
-		local n_index = event_note_index(event)
-		local token = self.notes[n_index]
-
-		if token then
-			send_event(sink, { name = "note_off", token = token })
-		else
-			log.warn("Unhandled note off event.")
-			return
-		end
-
-		self.notes[n_index] = nil
-	elseif event.name == "pitchbend" then
-		local offset = self.pitchbend_range * event.pitchbend
-		self.offsets[event.channel] = offset
-		if self.mpe then
-			for k, token in pairs(self.notes) do
-				local midi_ch = math.floor(k / 256)
-				if midi_ch == event.channel then
-					send_event(sink, { name = "pitch", token = token, offset = offset })
-				end
-			end
-		else
-			for _, token in pairs(self.notes) do
-				send_event(sink, { name = "pitch", token = token, offset = offset })
-			end
-		end
+		record_event(sink, { name = "note_off", token = event.token, age = event.age })
+	elseif event.name == "pitch" then
+		record_event(sink, { name = "pitch", token = event.token, offset = event.offset, age = event.age })
 	elseif event.name == "pressure" then
-		if self.mpe then
-			for k, token in pairs(self.notes) do
-				local midi_ch = math.floor(k / 256)
-				if midi_ch == event.channel then
-					send_event(sink, { name = "pressure", token = token, pressure = event.pressure })
-				end
-			end
-		else
-			-- TODO: untested
-			for _, token in pairs(self.notes) do
-				send_event(sink, { name = "pressure", token = token, pressure = event.pressure })
-			end
-		end
-	elseif event.name == "controller" then
-		if event.controller == 64 then
-			-- sustain pedal
-			if event.value > 0 then
-				send_event(sink, { name = "sustain", sustain = true })
-			else
-				send_event(sink, { name = "sustain", sustain = false })
-			end
-		else
-			-- send pressure on modwheel (1) or foot pedal (2)
-			if event.controller == 1 or event.controller == 2 then
-				local p = event.value
-				for _, token in pairs(self.notes) do
-					send_event(sink, { name = "pressure", token = token, pressure = p })
-				end
-			end
-		end
+		record_event(sink, { name = "pressure", token = event.token, pressure = event.pressure, age = event.age })
+	elseif event.name == "sustain" then
+		record_event(sink, { name = "sustain", sustain = event.sustain, age = event.age })
 	end
 end
 
@@ -0,0 +1,80 @@
+local device_list = require("device_list")
+local log = require("log")
+local tuning = require("tuning")
+
+-- Standard MIDI Files. The backend reads and writes the file, here notes get mapped to and from the tuning.
+local midi_file = {}
+
+local function has_notes()
+	for _, ch in ipairs(project.channels) do
+		if ch.notes and #ch.notes > 0 then
+			return true
+		end
+	end
+	return false
+end
+
+function midi_file.import(filename)
+	local result = tessera.project.import_midi(filename)
+	if not result then
+		return false
+	end
+	if #result.tracks == 0 then
+		log.warn(("No notes in %q"):format(filename))
+		return false
+	end
+
+	local channels = {}
+	for _, track in ipairs(result.tracks) do
+		local notes = {}
+		for _, n in ipairs(track.notes) do
+			table.insert(notes, {
+				time = n.time,
+				interval = tuning.from_midi(n.key),
+				vel = n.vel,
+				verts = n.verts,
+			})
+		end
+		table.insert(channels, {
+			options = { name = track.name, instrument = device_list.instruments.epiano },
+			notes = notes,
+			control = track.control,
+		})
+	end
+
+	-- the tempo map of the file only replaces ours when there is nothing to move out of place
+	local tempo
+	if not has_notes() then
+		tempo = result.tempo
+	end
+
+	command.run_and_register(command.ImportChannels.new(channels, tempo))
+	log.info(("Imported %d tracks from %q"):format(#channels, filename))
+	return true
+end
+
+-- Channels with an instrument, pitches resolved by the tuning the same way as for the sequencer
+function midi_file.export(filename)
+	local channels = {}
+	for ch_index, ch in ipairs(project.channels) do
+		if ui_channels[ch_index].instrument and #ch.notes > 0 then
+			local pitches = {}
+			for i, note in ipairs(ch.notes) do
+				pitches[i] = tuning.get_pitch(note.interval)
+			end
+			table.insert(channels, { name = ch.name, notes = ch.notes, pitches = pitches, control = ch.control })
+		end
+	end
+	if #channels == 0 then
+		log.warn("No notes to export")
+		return false
+	end
+
+	if not tessera.project.export_midi(filename, channels, project.tempo, setup.midi_export) then
+		return false
+	end
+	log.info(("Exported %d channels to %q"):format(#channels, filename))
+	return true
+end
+
+return midi_file
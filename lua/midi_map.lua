@@ -0,0 +1,195 @@
+local log = require("log")
+
+-- Controllers mapped to device parameters, stored in project.midi_map.
+-- The backend applies them as soon as the controller moves, here we only keep the UI in sync.
+local midi_map = {}
+
+-- an LSB that follows the learned controller within this time makes it a 14 bit mapping
+local HIGH_RES_WINDOW = 0.5
+
+-- parameter waiting for a controller
+local learning
+-- last mapping that was learned, and when
+local learned
+local learned_time = 0
+
+-- parameters moved by a controller, recorded as automation by the engine
+local changes = {}
+
+local function get_device(ch_index, device_index)
+	local ch = ui_channels[ch_index]
+	if not ch then
+		return
+	end
+	if device_index == 0 then
+		return ch.instrument
+	end
+	return ch.effects[device_index]
+end
+
+local function same_target(m, ch_index, device_index, index)
+	return m.channel == ch_index and m.device == device_index and m.index == index
+end
+
+function midi_map.find(ch_index, device_index, index)
+	for i, m in ipairs(project.midi_map or {}) do
+		if same_target(m, ch_index, device_index, index) then
+			return m, i
+		end
+	end
+end
+
+function midi_map.is_learning(ch_index, device_index, index)
+	return learning ~= nil and same_target(learning, ch_index, device_index, index)
+end
+
+-- the next controller that moves gets mapped to the parameter, calling it again cancels
+function midi_map.learn(ch_index, device_index, index)
+	if midi_map.is_learning(ch_index, device_index, index) then
+		learning = nil
+	else
+		learning = { channel = ch_index, device = device_index, index = index }
+	end
+end
+
+function midi_map.remove(ch_index, device_index, index)
+	local _, i = midi_map.find(ch_index, device_index, index)
+	if i then
+		table.remove(project.midi_map, i)
+	end
+	if midi_map.is_learning(ch_index, device_index, index) then
+		learning = nil
+	end
+end
+
+local function bind(port, event)
+	local target = learning
+	learning = nil
+
+	local device = get_device(target.channel, target.device)
+	if not device then
+		return
+	end
+	local min, max, curve, discrete = device:parameter_range(target.index)
+	if not min then
+		return
+	end
+
+	midi_map.remove(target.channel, target.device, target.index)
+	local mapping = {
+		port = port,
+		midi_channel = event.channel,
+		controller = event.controller,
+		high_res = false,
+		channel = target.channel,
+		device = target.device,
+		index = target.index,
+		min = min,
+		max = max,
+		curve = curve,
+		mode = "absolute",
+		discrete = discrete,
+	}
+	project.midi_map = project.midi_map or {}
+	table.insert(project.midi_map, mapping)
+	learned = mapping
+	learned_time = tessera.get_time()
+	log.info(("Mapped controller %d on %q"):format(event.controller, port))
+end
+
+-- mirror a value the backend already set, and mark it as sent
+local function apply_mapped(event)
+	local device = get_device(event.channel, event.device)
+	local old = device and device.state[event.index]
+	if old == nil then
+		return
+	end
+	local value = event.value
+	if type(old) == "boolean" then
+		value = value > 0.5
+	elseif device.discrete[event.index] then
+		value = math.floor(value + 0.5)
+	end
+	device.state[event.index] = value
+	device.state_old[event.index] = value
+	table.insert(changes, event)
+end
+
+function midi_map.event(port, event)
+	if event.name == "mapped" then
+		apply_mapped(event)
+	elseif event.name == "controller" then
+		if learning and not event.high_res then
+			bind(port, event)
+		elseif
+			learned
+			and event.high_res
+			and learned.port == port
+			and learned.midi_channel == event.channel
+			and learned.controller == event.controller
+		then
+			if tessera.get_time() - learned_time < HIGH_RES_WINDOW then
+				learned.high_res = true
+			end
+			learned = nil
+		end
+	end
+end
+
+-- parameters that controllers changed since the last call
+function midi_map.take_changes()
+	local list = changes
+	changes = {}
+	return list
+end
+
+-- value of the mapped parameter as the backend sees it
+function midi_map.value(m)
+	local device = get_device(m.channel, m.device)
+	local value = device and device.state[m.index]
+	if type(value) == "boolean" then
+		return value and 1 or 0
+	end
+	return value or m.min
+end
+
+-- a mapped parameter was changed from the UI, so relative encoders continue from there
+function midi_map.value_changed(ch_index, device_index, index, value)
+	for i, m in ipairs(project.midi_map or {}) do
+		if same_target(m, ch_index, device_index, index) then
+			tessera.midi.set_mapping_value(i, value)
+		end
+	end
+end
+
+-- effect indices changed, `map` maps old device index to the new one or nil when removed
+function midi_map.remap_devices(ch_index, map)
+	local list = project.midi_map or {}
+	for i = #list, 1, -1 do
+		local m = list[i]
+		if m.channel == ch_index and m.device > 0 then
+			local device = map(m.device)
+			if device then
+				m.device = device
+			else
+				table.remove(list, i)
+			end
+		end
+	end
+	learning = nil
+end
+
+function midi_map.remove_channel(ch_index)
+	local list = project.midi_map or {}
+	for i = #list, 1, -1 do
+		local m = list[i]
+		if m.channel == ch_index then
+			table.remove(list, i)
+		elseif m.channel > ch_index then
+			m.channel = m.channel - 1
+		end
+	end
+	learning = nil
+end
+
+return midi_map
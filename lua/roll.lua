@@ -5,75 +5,21 @@ Roll.__index = Roll
 
 local DEFAULT_PRESSURE = 0.0
 
+-- Records live input into the channel while the transport is running.
+-- Playback of the notes happens in the backend.
 function Roll.new(ch_index)
 	local self = setmetatable({}, Roll)
 
 	self.ch_index = ch_index
 
-	self.n_index = 1
-	self.note_table = {}
-	self.control_table = {}
-	self.voices = {}
-
 	self.active_notes = {}
 	self.recorded_notes = {}
 
 	return self
 end
 
-local function sort_time(a, b)
-	return a.time < b.time
-end
-
-function Roll:start(chase)
-	self.note_table = {}
-	self.control_table = {}
-	self.voices = {}
-
-	for _, v in ipairs(project.channels[self.ch_index].notes) do
-		assert(v.verts[1][1] == 0)
-		table.insert(self.note_table, v)
-	end
-	table.sort(self.note_table, sort_time)
-
-	-- dump all control messages in one table
-	-- TODO: don't know if it's good to have two seperate tables
-	for k, c in pairs(project.channels[self.ch_index].control) do
-		for _, v in ipairs(c) do
-			table.insert(self.control_table, { name = k, value = v.value, time = v.time })
-		end
-	end
-	table.sort(self.control_table, sort_time)
-
-	self:seek(chase)
-end
-
-function Roll:seek(chase)
-	-- seek
-	self.n_index = 1
-	self.c_index = 1
-
-	-- skip notes already played
-	while self.note_table[self.n_index] do
-		local note = self.note_table[self.n_index]
-		local end_time = 0
-		if chase then
-			end_time = note.verts[#note.verts][1]
-		end
-		if note.time + end_time >= engine.time then
-			break
-		end
-		self.n_index = self.n_index + 1
-	end
-
-	-- TODO: find last relevant event and skip to there?
-	-- while self.control_table[self.c_index] and engine.time > self.control_table[self.c_index].time do
-	-- 	self.c_index = self.c_index + 1
-	-- end
-end
-
-function Roll:stop()
-	-- any hanging notes shoud get a note off
+-- any hanging notes shoud get a note off, e.g. at the end of the punch range or when the loop wraps
+function Roll:close_notes()
 	for _, note in pairs(self.active_notes) do
 		note.is_recording = nil
 		local t_offset = engine.time - note.time
@@ -81,67 +27,27 @@ function Roll:stop()
 	end
 
 	self.active_notes = {}
-	self.recorded_notes = {}
 end
 
-function Roll:playback(channel)
-	while self.control_table[self.c_index] and engine.time > self.control_table[self.c_index].time do
-		local c = self.control_table[self.c_index]
-		if c.name == "sustain" then
-			channel:send_event({ name = "sustain", sustain = c.value })
-		end
-		self.c_index = self.c_index + 1
-	end
-
-	while self.note_table[self.n_index] and engine.time > self.note_table[self.n_index].time do
-		local note = self.note_table[self.n_index]
-		local token = tessera.audio.get_token()
-		table.insert(self.voices, { n_index = self.n_index, v_index = 1, token = token })
-
-		channel:send_event({
-			name = "note_on",
-			token = token,
-			interval = note.interval,
-			vel = note.vel,
-			offset = note.verts[1][2],
-		})
-
-		self.n_index = self.n_index + 1
-	end
-
-	for i = #self.voices, 1, -1 do
-		local v = self.voices[i]
-		local note = self.note_table[v.n_index]
-
-		while v.v_index + 1 <= #note.verts and engine.time > note.time + note.verts[v.v_index + 1][1] do
-			v.v_index = v.v_index + 1
-		end
-
-		-- note off
-		if v.v_index >= #note.verts then
-			channel:send_event({ name = "note_off", token = v.token })
-			table.remove(self.voices, i)
-		else
-			local t0 = note.verts[v.v_index][1]
-			local t1 = note.verts[v.v_index + 1][1]
-			local alpha = (engine.time - (note.time + t0)) / (t1 - t0)
-
-			local offset = util.lerp(note.verts[v.v_index][2], note.verts[v.v_index + 1][2], alpha)
-			local pressure = util.lerp(note.verts[v.v_index][3], note.verts[v.v_index + 1][3], alpha)
-
-			channel:send_event({ name = "pressure", token = v.token, pressure = pressure })
-			channel:send_event({ name = "pitch", token = v.token, offset = offset })
-		end
-	end
+function Roll:stop()
+	self:close_notes()
+	self.recorded_notes = {}
 end
 
 -- TODO: cleanup!
 function Roll:event(event)
 	-- record events to timeline
 	if engine.playing and project.transport.recording then
-		local time = engine.time
+		-- live midi arrives up to a frame late
+		local time = engine.time - (event.age or 0)
+
+		-- outside the punch range only notes that are already recording continue
+		local punched_in = engine.punched_in()
 
 		if event.name == "note_on" then
+			if not punched_in then
+				return
+			end
 			local note = {
 				interval = event.interval,
 				time = time,
@@ -205,11 +111,15 @@ function Roll:event(event)
 				note.verts[#note.verts][3] = event.pressure
 			end
 		elseif event.name == "sustain" then
+			if not punched_in then
+				return
+			end
 			if not project.channels[self.ch_index].control.sustain then
 				project.channels[self.ch_index].control.sustain = {}
 			end
 			local c = { value = event.sustain, time = time }
 			table.insert(project.channels[self.ch_index].control.sustain, c)
+			engine.sequence_changed(self.ch_index)
 		else
 			print("unhandled event: ", util.dump(event))
 		end
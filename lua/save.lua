@@ -38,6 +38,18 @@ end
 local function do_patches(p)
 	-- patch any issues with save files from earlier versions
 	local default = require("default.empty_project")()
+
+	-- single time division -> tempo map
+	if p.time and not p.tempo then
+		local div = p.time[1]
+		p.tempo = {
+			offset = div[1],
+			points = { { beat = 0, bpm = 60 * div[2], ramp = false } },
+			signatures = { { beat = 0, numerator = 4, denominator = 4 } },
+		}
+	end
+	p.time = nil
+
 	util.copy_defaults(p, default)
 
 	-- 0.1.1 -> 0.1.2
@@ -57,6 +69,24 @@ local function do_patches(p)
 		end
 	end
 
+	-- add routing
+	for _, ch in ipairs(p.channels) do
+		if not ch.master and not ch.sends then
+			ch.sends = {}
+		end
+	end
+
+	-- add automation
+	for _, ch in ipairs(p.channels) do
+		ch.automation = ch.automation or {}
+	end
+
+	-- add cue mix and direct outs
+	for _, ch in ipairs(p.channels) do
+		ch.cue = ch.cue or 0
+		ch.direct_output = ch.direct_output or 0
+	end
+
 	-- fix projects with low rank
 	for _, ch in ipairs(p.channels) do
 		if ch.notes then
@@ -154,6 +184,36 @@ function save.init_setup()
 	if not setup.host then
 		setup.host = tessera.audio.get_default_host()
 	end
+
+	-- sample_rate = nil renders at the engine rate
+	if not setup.export then
+		setup.export = {
+			format = "int16",
+			dither = "tpdf",
+			normalize = "none",
+			peak_target = -1.0,
+			loudness_target = -14.0,
+			stems = false,
+			stems_pre_effects = false,
+		}
+	end
+
+	-- ports other applications can play us with, and that our channels can send to
+	if not setup.virtual_midi then
+		setup.virtual_midi = {
+			enable = false,
+			inputs = { "Tessera in" },
+			outputs = { "Tessera out" },
+		}
+	end
+
+	-- resolution in seconds of the pitch and pressure curves in exported MIDI files
+	if not setup.midi_export then
+		setup.midi_export = {
+			mpe = true,
+			resolution = 0.01,
+		}
+	end
 end
 
 function save.read_plugins()
@@ -1,40 +1,164 @@
 -- time / tempo / grid settings
+-- Beats are quarter notes. Conversions follow the tempo map in project.tempo, same as the backend.
 
 local time = {}
 
 local SNAP_TIMES = { 1, 1 / 4, 1 / 16 }
 time.snap_labels = { "1", "1/4", "1/16", "Off" }
 
-function time.get_div(t)
-	-- for now we just have a single div
-	return project.time[1]
+-- tempo map built from project.tempo, rebuilt when `time.key` changes
+local map
+time.key = nil
+
+-- seconds it takes to play `beats` from the start of segment `s`
+local function segment_seconds(s, beats)
+	if beats < 0 or s.slope == 0 then
+		return beats * 60 / s.bpm
+	end
+	return 60 / s.slope * math.log(1 + s.slope * beats / s.bpm)
+end
+
+local function segment_beats(s, seconds)
+	if seconds < 0 or s.slope == 0 then
+		return seconds * s.bpm / 60
+	end
+	return s.bpm / s.slope * (math.exp(s.slope * seconds / 60) - 1)
+end
+
+local function sort_beat(a, b)
+	return a.beat < b.beat
+end
+
+local function tempo_key(tempo)
+	local parts = { tostring(tempo.offset or 0) }
+	for _, p in ipairs(tempo.points) do
+		table.insert(parts, string.format("%s@%s%s", p.bpm, p.beat, p.ramp and "r" or ""))
+	end
+	for _, s in ipairs(tempo.signatures or {}) do
+		table.insert(parts, string.format("%d/%d@%s", s.numerator, s.denominator, s.beat))
+	end
+	return table.concat(parts, ",")
+end
+
+local function build(tempo)
+	local points = {}
+	for _, p in ipairs(tempo.points) do
+		if p.bpm > 0 then
+			table.insert(points, p)
+		end
+	end
+	table.sort(points, sort_beat)
+
+	local segments = {}
+	for i, p in ipairs(points) do
+		local prev = segments[#segments]
+		local t
+		if prev then
+			t = prev.time + segment_seconds(prev, p.beat - prev.beat)
+		else
+			t = (tempo.offset or 0) + p.beat * 60 / p.bpm
+		end
+		local slope = 0
+		local next_point = points[i + 1]
+		if p.ramp and next_point and next_point.beat > p.beat then
+			slope = (next_point.bpm - p.bpm) / (next_point.beat - p.beat)
+		end
+		table.insert(segments, { beat = p.beat, time = t, bpm = p.bpm, slope = slope })
+	end
+	if #segments == 0 then
+		segments[1] = { beat = 0, time = tempo.offset or 0, bpm = 120, slope = 0 }
+	end
+
+	local list = {}
+	for _, s in ipairs(tempo.signatures or {}) do
+		if s.numerator > 0 and s.denominator > 0 then
+			table.insert(list, s)
+		end
+	end
+	table.sort(list, sort_beat)
+
+	local signatures = {}
+	for _, s in ipairs(list) do
+		local prev = signatures[#signatures]
+		local bar = 0
+		if prev then
+			bar = prev.bar + (s.beat - prev.beat) / prev.length
+		end
+		local length = s.numerator * 4 / s.denominator
+		table.insert(signatures, { beat = s.beat, bar = bar, length = length, numerator = s.numerator })
+	end
+	if #signatures == 0 then
+		signatures[1] = { beat = 0, bar = 0, length = 4, numerator = 4 }
+	end
+
+	return { segments = segments, signatures = signatures }
+end
+
+-- Call once per frame, picks up edits of the tempo map
+function time.update()
+	local key = tempo_key(project.tempo)
+	if key ~= time.key or not map then
+		map = build(project.tempo)
+		time.key = key
+	end
 end
 
-function time.to_seconds(t)
-	local div = time.get_div(t)
-	local t_start = div[1]
-	local t_mul = div[2]
+local function get_map()
+	if not map then
+		time.update()
+	end
+	return map
+end
+
+-- last entry of `list` that starts at or before `x`
+local function find(list, field, x)
+	for i = #list, 2, -1 do
+		if list[i][field] <= x then
+			return list[i]
+		end
+	end
+	return list[1]
+end
 
-	return t_start + t / t_mul
+function time.to_seconds(beats)
+	local s = find(get_map().segments, "beat", beats)
+	return s.time + segment_seconds(s, beats - s.beat)
 end
 
 function time.from_seconds(t)
-	local div = time.get_div(t)
-	local t_start = div[1]
-	local t_mul = div[2]
+	local s = find(get_map().segments, "time", t)
+	return s.beat + segment_beats(s, t - s.time)
+end
+
+function time.bpm_at(t)
+	local s = find(get_map().segments, "time", t)
+	local beats = segment_beats(s, t - s.time)
+	if beats < 0 then
+		return s.bpm
+	end
+	return s.bpm + s.slope * beats
+end
+
+-- bar number counted from beat 0, with the fraction of the bar that has passed
+function time.bars(beats)
+	local s = find(get_map().signatures, "beat", beats)
+	return s.bar + (beats - s.beat) / s.length
+end
 
-	return (t - t_start) * t_mul
+local function is_bar_start(beats)
+	local bars = time.bars(beats)
+	return math.abs(bars - math.floor(bars + 0.5)) < 1e-6
 end
 
 function time.next(t, dt)
 	local t_prev = t
 	local t_new = t + dt
 
-	if tessera.audio.ok() then
+	if tessera.audio.ok() or tessera.audio.is_rendering() then
 		local beat_prev = math.ceil(time.from_seconds(t_prev))
 		local beat = math.ceil(time.from_seconds(t_new))
 		if project.settings.metronome and beat ~= beat_prev then
-			tessera.audio.metronome(beat % 4 == 1)
+			tessera.audio.metronome(is_bar_start(beat))
 		end
 	end
 
@@ -52,36 +176,40 @@ function time.snap(t)
 	return t
 end
 
+-- length of a snap step in seconds, around time `t`
 function time.snap_length(t)
 	if project.settings.snap_time < 4 then
-		local div = time.get_div(t)
-		local t_mul = div[2]
-		return SNAP_TIMES[project.settings.snap_time] / t_mul
+		local b = time.from_seconds(t or 0)
+		return time.to_seconds(b + SNAP_TIMES[project.settings.snap_time]) - time.to_seconds(b)
 	end
 	return 0
 end
 
 function time.get_grid(t0, t1, scale)
-	local div = time.get_div(t0)
-	local t_start = div[1]
-	local t_mul = div[2]
+	local t_mul = time.bpm_at(t0) / 60
 
 	-- target line density
 	local res_target = (80 / scale) * t_mul
 
 	-- round to nearest power of 4: 1/16, 1/4, 1, 4, 16, ...
 	local res = 4 ^ math.floor(math.log(res_target, 4) + 0.5)
-	-- local res = 1
 
-	local b0 = (t0 - t_start) * t_mul
-	local b1 = (t1 - t_start) * t_mul
+	local b0 = time.from_seconds(t0)
+	local b1 = time.from_seconds(t1)
 
 	local grid_major = {}
 	local grid_minor = {}
 
 	for i = math.ceil(b0 / res), math.floor(b1 / res) do
-		local t = t_start + (i * res) / t_mul
-		if i % 4 == 0 then
+		local beats = i * res
+		local t = time.to_seconds(beats)
+		local major
+		if res == 1 then
+			major = is_bar_start(beats)
+		else
+			major = i % 4 == 0
+		end
+		if major then
 			table.insert(grid_major, t)
 		else
 			table.insert(grid_minor, t)
@@ -91,4 +219,16 @@ function time.get_grid(t0, t1, scale)
 	return grid_major, grid_minor
 end
 
+-- tempo and time signature changes, for drawing
+function time.markers()
+	local list = {}
+	for _, p in ipairs(project.tempo.points) do
+		table.insert(list, time.to_seconds(p.beat))
+	end
+	for _, s in ipairs(project.tempo.signatures or {}) do
+		table.insert(list, time.to_seconds(s.beat))
+	end
+	return list
+end
+
 return time
@@ -0,0 +1,32 @@
+local time = require("time")
+
+-- Drag on the ribbon to set the loop region, or the punch range with ctrl
+local set_range = {}
+
+function set_range:mousepressed(canvas)
+	local mx, _ = canvas:get_mouse()
+	self.time = time.snap(canvas.transform:time_inv(mx))
+	if modifier_keys.ctrl then
+		self.from, self.to, self.enable = "punch_in", "punch_out", "punch"
+	else
+		self.from, self.to, self.enable = "loop_start", "loop_end", "looping"
+	end
+end
+
+function set_range:mousedown(canvas)
+	if mouse.drag then
+		local mx, _ = canvas:get_mouse()
+		local t = time.snap(canvas.transform:time_inv(mx))
+
+		local transport = project.transport
+		transport[self.from] = math.max(0, math.min(self.time, t))
+		transport[self.to] = math.max(0, math.max(self.time, t))
+		transport[self.enable] = transport[self.to] > transport[self.from]
+	end
+end
+
+function set_range:mousereleased(canvas) end
+
+function set_range:draw(canvas) end
+
+return set_range
@@ -1,11 +1,25 @@
--- TODO: undo
+local time = require("time")
 
 local tempo = {}
 
+-- tempo point that is in effect at `beats`
+local function point_at(beats)
+	local found = 1
+	for i, p in ipairs(project.tempo.points) do
+		if p.beat <= beats and p.beat >= project.tempo.points[found].beat then
+			found = i
+		end
+	end
+	return found
+end
+
 function tempo:mousepressed(canvas)
 	self.ix, self.iy = mouse.x, mouse.y
 	self.edit = false
-	self.prev_state = util.clone(project.time)
+	self.prev_state = util.clone(project.tempo)
+
+	local mx, _ = canvas:get_mouse()
+	self.point = point_at(time.from_seconds(canvas.transform:time_inv(mx)))
 end
 
 function tempo:mousedown(canvas)
@@ -23,14 +37,16 @@ function tempo:mousedown(canvas)
 
 	if mouse.drag then
 		self.edit = true
-		project.time[1][1] = self.prev_state[1][1] + x / canvas.transform.sx
-		project.time[1][2] = self.prev_state[1][2] * math.exp(-0.001 * y)
+		-- horizontal moves the whole grid, vertical changes the tempo under the mouse
+		project.tempo.offset = self.prev_state.offset + x / canvas.transform.sx
+		local bpm = self.prev_state.points[self.point].bpm
+		project.tempo.points[self.point].bpm = bpm * math.exp(-0.001 * y)
 	end
 end
 
 function tempo:mousereleased(canvas)
 	if self.edit then
-		local c = command.TimeUpdate.new(self.prev_state, project.time)
+		local c = command.TimeUpdate.new(self.prev_state, project.tempo)
 		command.register(c)
 		self.prev_state = nil
 		return true
@@ -365,6 +365,15 @@ function tuning.from_midi(n)
 	return tuning.from_table(tuning.chromatic, n - 60)
 end
 
+-- Pitch of every midi key in the current tuning, for retuning synths with MTS
+function tuning.midi_pitches()
+	local pitches = {}
+	for n = 0, 127 do
+		pitches[n + 1] = tuning.get_pitch(tuning.from_midi(n))
+	end
+	return pitches
+end
+
 -- Project an interval to an n-note scale via linear mapping.
 function tuning.get_index(n, p)
 	local sum = 0.0
@@ -122,6 +122,7 @@ function Ui:label(text, options)
 	local font = options.font or "main"
 	local align = options.align
 	self:push_draw(draw_label, { text, align, color, font, x, y, w, h })
+	return x, y, w, h
 end
 
 function Ui:separator(text, align, color)
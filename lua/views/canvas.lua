@@ -13,6 +13,7 @@ local edit = require("tools/edit")
 local pan = require("tools/pan")
 local pen = require("tools/pen")
 local scale = require("tools/scale")
+local set_range = require("tools/set_range")
 local set_transport_time = require("tools/set_transport_time")
 local tempo = require("tools/tempo")
 
@@ -304,8 +305,9 @@ function Canvas:draw()
 	-- time divs
 	tessera.graphics.set_line_width(1.5)
 	tessera.graphics.set_color(theme.grid_main)
-	for _, v in ipairs(project.time) do
-		local x = self.transform:time(v[1])
+	local markers = time.markers()
+	for _, t in ipairs(markers) do
+		local x = self.transform:time(t)
 		tessera.graphics.line(x, 0, x, self.h)
 	end
 
@@ -336,11 +338,26 @@ function Canvas:draw()
 	tessera.graphics.set_color(theme.background)
 	tessera.graphics.rectangle("line", 0, 0, self.w, RIBBON_H)
 
+	-- loop region on the top half, punch range on the bottom half
+	local transport = project.transport
+	if transport.looping then
+		local x0 = self.transform:time(transport.loop_start)
+		local x1 = self.transform:time(transport.loop_end)
+		tessera.graphics.set_color(theme.widget)
+		tessera.graphics.rectangle("fill", x0, 0, x1 - x0, 0.5 * RIBBON_H)
+	end
+	if transport.punch then
+		local x0 = self.transform:time(transport.punch_in)
+		local x1 = self.transform:time(transport.punch_out)
+		tessera.graphics.set_color(theme.recording)
+		tessera.graphics.rectangle("fill", x0, 0.5 * RIBBON_H, x1 - x0, 0.5 * RIBBON_H)
+	end
+
 	-- time divs (only top)
 	tessera.graphics.set_line_width(1.5)
 	tessera.graphics.set_color(theme.grid_main)
-	for _, v in ipairs(project.time) do
-		local x = self.transform:time(v[1])
+	for _, t in ipairs(markers) do
+		local x = self.transform:time(t)
 		tessera.graphics.line(x, 0, x, RIBBON_H + 1)
 	end
 
@@ -744,7 +761,11 @@ function Canvas:mousepressed()
 		elseif mouse.button == 1 then
 			if my < RIBBON_H then
 				-- clicked on top ribbon
-				self.current_tool = set_transport_time
+				if modifier_keys.shift or modifier_keys.ctrl then
+					self.current_tool = set_range
+				else
+					self.current_tool = set_transport_time
+				end
 			end
 		elseif mouse.button == 2 then
 			workspace:set_overlay(Menu.new(self))
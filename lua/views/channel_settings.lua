@@ -2,6 +2,8 @@ local Menu = require("menu")
 local Ui = require("ui/ui")
 local View = require("view")
 local device_list = require("device_list")
+local engine = require("engine")
+local midi = require("midi")
 local widgets = require("ui/widgets")
 
 local ChannelSettings = View.derive("Channel settings")
@@ -25,9 +27,197 @@ function ChannelSettings.new()
 
 	self.dropdown = widgets.Button.new("Add effect")
 
+	-- routing
+	self.state = { output = 1, direct_output = 1, midi_port = 1 }
+	self.output_dropdown = widgets.Dropdown.new(self.state, "output", { list = {}, no_undo = true })
+	self.direct_dropdown = widgets.Dropdown.new(self.state, "direct_output", { list = {}, no_undo = true })
+	self.midi_port_dropdown = widgets.Dropdown.new(self.state, "midi_port", { list = {}, no_undo = true })
+	-- cue sliders, keyed by the channel table
+	self.cue_sliders = setmetatable({}, { __mode = "k" })
+	self.send_button = widgets.Button.new("Add send")
+	-- widgets per send, keyed by the send table
+	self.send_widgets = setmetatable({}, { __mode = "k" })
+
 	return self
 end
 
+local function channel_names()
+	local names = {}
+	for i, v in ipairs(project.channels) do
+		names[i] = v.name
+	end
+	return names
+end
+
+function ChannelSettings:send_widget(send)
+	local w = self.send_widgets[send]
+	if not w then
+		w = {
+			slider = widgets.Slider.new(send, "gain", { default = 0, max = 12, t = "dB" }),
+			toggle = widgets.Toggle.new(send, "pre_fader", { label = "pre" }),
+			remove = widgets.Button.new("x"),
+		}
+		self.send_widgets[send] = w
+	end
+	return w
+end
+
+function ChannelSettings:sidechain_widget(fx)
+	local w = self.send_widgets[fx]
+	if not w then
+		local state = { index = 1 }
+		w = {
+			state = state,
+			dropdown = widgets.Dropdown.new(state, "index", { list = {}, no_undo = true }),
+		}
+		self.send_widgets[fx] = w
+	end
+	return w
+end
+
+function ChannelSettings:update_routing(ch_index)
+	local data = project.channels[ch_index]
+	local w_label = Ui.scale(60)
+	local w_widget = math.max(Ui.scale(120), self.w - w_label - Ui.scale(96))
+	local names = channel_names()
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(w_label)
+	self.ui:label("Output")
+	self.ui.layout:col(w_widget)
+	self.output_dropdown.list = names
+	self.state.output = data.output or 1
+	local output = self.output_dropdown:update(self.ui)
+	if output and output ~= ch_index and output ~= (data.output or 1) then
+		command.run_and_register(command.Change.new(data, "output", output))
+	end
+
+	-- sidechain source for effects that support it
+	local key_names = { "None" }
+	for i, name in ipairs(names) do
+		key_names[i + 1] = name
+	end
+	for k, fx in ipairs(data.effects) do
+		local options = device_list.effects[fx.name]
+		if options and options.sidechain then
+			local w = self:sidechain_widget(fx)
+			w.state.index = (fx.sidechain or 0) + 1
+			w.dropdown.list = key_names
+
+			self.ui.layout:new_row()
+			self.ui.layout:col(w_label)
+			self.ui:label("Key " .. k)
+			self.ui.layout:col(w_widget)
+			local index = w.dropdown:update(self.ui)
+			if index then
+				local source = index - 1
+				if source ~= ch_index and source ~= (fx.sidechain or 0) then
+					-- 0 means no sidechain, since Change does not accept nil
+					command.run_and_register(command.Change.new(fx, "sidechain", source))
+				end
+			end
+		end
+	end
+
+	local remove_index
+	for i, send in ipairs(data.sends) do
+		local w = self:send_widget(send)
+		self.ui.layout:new_row()
+		self.ui.layout:col(w_label)
+		self.ui:label(names[send.target] or "?")
+		self.ui.layout:col(w_widget)
+		w.slider:update(self.ui)
+		self.ui.layout:col(Ui.scale(64))
+		w.toggle:update(self.ui)
+		self.ui.layout:col(Ui.ROW_HEIGHT)
+		if w.remove:update(self.ui) then
+			remove_index = i
+		end
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(Ui.scale(120))
+	if self.send_button:update(self.ui) then
+		workspace:set_overlay(self:send_menu(ch_index))
+	end
+
+	if remove_index then
+		local sends = util.clone(data.sends)
+		table.remove(sends, remove_index)
+		command.run_and_register(command.Change.new(data, "sends", sends))
+	end
+
+	if self.add_send_target then
+		local sends = util.clone(data.sends)
+		table.insert(sends, { target = self.add_send_target, gain = 1.0, pre_fader = false })
+		command.run_and_register(command.Change.new(data, "sends", sends))
+		self.add_send_target = nil
+	end
+end
+
+-- cue level and direct out to the output device
+function ChannelSettings:update_outputs(ch_index)
+	local data = project.channels[ch_index]
+	local w_label = Ui.scale(60)
+	local w_widget = math.max(Ui.scale(120), self.w - w_label - Ui.scale(96))
+
+	local slider = self.cue_sliders[data]
+	if not slider then
+		slider = widgets.Slider.new(data, "cue", { default = -math.huge, max = 12, t = "dB" })
+		self.cue_sliders[data] = slider
+	end
+	self.ui.layout:new_row()
+	self.ui.layout:col(w_label)
+	self.ui:label("Cue")
+	self.ui.layout:col(w_widget)
+	slider:update(self.ui)
+
+	local list = { "Off" }
+	for i, name in ipairs(engine.output_pairs()) do
+		list[i + 1] = name
+	end
+	self.ui.layout:new_row()
+	self.ui.layout:col(w_label)
+	self.ui:label("Direct")
+	self.ui.layout:col(w_widget)
+	self.direct_dropdown.list = list
+	self.state.direct_output = (data.direct_output or 0) + 1
+	local index = self.direct_dropdown:update(self.ui)
+	if index and index - 1 ~= (data.direct_output or 0) then
+		-- 0 means off, since Change does not accept nil
+		command.run_and_register(command.Change.new(data, "direct_output", index - 1))
+	end
+
+	if data.instrument and data.instrument.name == "midi_output" then
+		-- keep the saved port in the list while it is unplugged
+		local ports = { "None" }
+		local current = 1
+		for _, name in ipairs(midi.output_ports) do
+			table.insert(ports, name)
+			if name == data.midi_port then
+				current = #ports
+			end
+		end
+		if current == 1 and data.midi_port and data.midi_port ~= "" then
+			table.insert(ports, data.midi_port)
+			current = #ports
+		end
+
+		self.ui.layout:new_row()
+		self.ui.layout:col(w_label)
+		self.ui:label("MIDI")
+		self.ui.layout:col(w_widget)
+		self.midi_port_dropdown.list = ports
+		self.state.midi_port = current
+		index = self.midi_port_dropdown:update(self.ui)
+		if index and index ~= current then
+			-- empty means no port, since Change does not accept nil
+			local port = index > 1 and ports[index] or ""
+			command.run_and_register(command.Change.new(data, "midi_port", port))
+		end
+	end
+end
+
 function ChannelSettings:update()
 	self.ui:start_frame()
 	self.ui.layout:col(Ui.scale(120))
@@ -51,6 +241,11 @@ function ChannelSettings:update()
 			end
 		end
 
+		if selection.ch_index > 1 and project.channels[selection.ch_index].sends then
+			self:update_routing(selection.ch_index)
+		end
+		self:update_outputs(selection.ch_index)
+
 		if self.add_effect_index then
 			local options = self.effect_list[self.add_effect_index]
 			command.run_and_register(command.NewEffect.new(selection.ch_index, options))
@@ -82,4 +277,25 @@ function ChannelSettings:menu()
 	return Menu.new(items)
 end
 
+-- any channel except master and the channel itself can be a send target
+function ChannelSettings:send_menu(ch_index)
+	local options = {
+		style = "menu",
+		align = tessera.graphics.ALIGN_LEFT,
+	}
+	local items = {}
+	for i, v in ipairs(project.channels) do
+		if i > 1 and i ~= ch_index then
+			table.insert(items, {
+				widget = widgets.Button.new(v.name, options),
+				action = function()
+					self.add_send_target = i
+				end,
+			})
+		end
+	end
+
+	return Menu.new(items)
+end
+
 return ChannelSettings
@@ -44,6 +44,9 @@ function Channels.update_list()
 	table.sort(intrument_list, function(a, b)
 		return a.name < b.name
 	end)
+
+	-- channel without instrument, used as return or group bus
+	table.insert(intrument_list, 1, { name = "Bus" })
 end
 
 function Channels.new()
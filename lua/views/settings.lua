@@ -10,6 +10,10 @@ local widgets = require("ui/widgets")
 local Settings = View.derive("Settings")
 Settings.__index = Settings
 
+-- pitch bend ranges of midi input, in semitones
+local BEND_RANGES = { 2, 12, 24, 48, 96 }
+local BEND_RANGE_LABELS = { "Auto", "2", "12", "24", "48", "96" }
+
 -- fix some capitalization, host_str has to match with backend
 local function host_display_name(host_str)
 	if host_str == "alsa" then
@@ -48,20 +52,55 @@ local function device_names()
 	return list
 end
 
+-- Build display name list of input devices, first entry disables input
+local function input_device_names()
+	local list = { "None" }
+
+	local devices = Settings.input_devices[setup.host]
+	if devices then
+		for _, v in ipairs(devices) do
+			table.insert(list, v.name)
+		end
+	end
+
+	return list
+end
+
+-- keys have to match with backend ExportSettings
+local export_formats = { "int16", "int24", "float32" }
+local export_dithers = { "none", "tpdf", "noise_shaped" }
+local export_normalize = { "none", "peak", "loudness" }
+local export_rates = { "engine", 44100, 48000, 88200, 96000 }
+local midi_export_resolutions = { 0.005, 0.01, 0.02, 0.05 }
+
 -- TODO: defer queries to first time we need them
 
 -- cache queries since they can take a long time on some hosts
 Settings.hosts = {}
 Settings.devices = {}
+Settings.input_devices = {}
 
 -- shared ui state
 Settings.state = {
 	host_index = 1,
 	device_index = 1,
+	input_device_index = 1,
+	master_output = 1,
+	cue_output = 1,
 	buffer_size = 128,
 	toggle_buffer = false,
 	midi_ports = {},
+	virtual_midi = false,
 	mpe = {},
+	mod_pressure = {},
+	bend_range = {},
+	export_format = 1,
+	export_dither = 1,
+	export_normalize = 1,
+	export_rate = 1,
+	export_stems = 1,
+	midi_export_mode = 1,
+	midi_export_resolution = 2,
 }
 
 function Settings.new()
@@ -93,6 +132,10 @@ function Settings.new()
 	)
 
 	self.select_device = widgets.Dropdown.new(self.state, "device_index", { list = device_names(), no_undo = true })
+	self.select_input_device =
+		widgets.Dropdown.new(self.state, "input_device_index", { list = input_device_names(), no_undo = true })
+	self.select_master_output = widgets.Dropdown.new(self.state, "master_output", { list = {}, no_undo = true })
+	self.select_cue_output = widgets.Dropdown.new(self.state, "cue_output", { list = {}, no_undo = true })
 
 	self.slider = widgets.Slider.new(
 		self.state,
@@ -106,6 +149,60 @@ function Settings.new()
 		{ label = "Request buffer size", style = "checkbox", pad = self.indent, no_undo = true }
 	)
 
+	self.state.export_format = util.find(export_formats, setup.export.format) or 1
+	self.state.export_dither = util.find(export_dithers, setup.export.dither) or 1
+	self.state.export_normalize = util.find(export_normalize, setup.export.normalize) or 1
+	self.state.export_rate = util.find(export_rates, setup.export.sample_rate) or 1
+
+	self.select_export_format = widgets.Selector.new(
+		self.state,
+		"export_format",
+		{ list = { "16 bit", "24 bit", "32 bit float" }, index = self.state.export_format, no_undo = true }
+	)
+	self.select_export_dither = widgets.Selector.new(
+		self.state,
+		"export_dither",
+		{ list = { "Off", "TPDF", "Noise shaped" }, index = self.state.export_dither, no_undo = true }
+	)
+	self.select_export_normalize = widgets.Selector.new(
+		self.state,
+		"export_normalize",
+		{ list = { "Off", "Peak", "Loudness" }, index = self.state.export_normalize, no_undo = true }
+	)
+	if setup.export.stems_pre_effects then
+		self.state.export_stems = 3
+	elseif setup.export.stems then
+		self.state.export_stems = 2
+	end
+	self.select_export_stems = widgets.Selector.new(
+		self.state,
+		"export_stems",
+		{ list = { "Off", "Post-fader", "Post-fader + dry" }, index = self.state.export_stems, no_undo = true }
+	)
+	self.select_export_rate = widgets.Dropdown.new(self.state, "export_rate", {
+		list = { "Same as engine", "44100 Hz", "48000 Hz", "88200 Hz", "96000 Hz" },
+		no_undo = true,
+	})
+
+	self.state.virtual_midi = setup.virtual_midi.enable
+	self.toggle_virtual_midi = widgets.Toggle.new(
+		self.state,
+		"virtual_midi",
+		{ label = "Virtual ports", style = "checkbox", pad = self.indent, no_undo = true }
+	)
+
+	self.state.midi_export_mode = setup.midi_export.mpe and 1 or 2
+	self.state.midi_export_resolution = util.find(midi_export_resolutions, setup.midi_export.resolution) or 2
+	self.select_midi_export_mode = widgets.Selector.new(
+		self.state,
+		"midi_export_mode",
+		{ list = { "MPE", "12-EDO + bend" }, index = self.state.midi_export_mode, no_undo = true }
+	)
+	self.select_midi_export_resolution = widgets.Dropdown.new(self.state, "midi_export_resolution", {
+		list = { "5 ms", "10 ms", "20 ms", "50 ms" },
+		no_undo = true,
+	})
+
 	self:rebuild()
 	self:rebuild_midi()
 
@@ -117,6 +214,10 @@ function Settings:rebuild_midi()
 	table.clear(self.state.midi_ports)
 	self.mpe_toggles = {}
 	table.clear(self.state.mpe)
+	self.mod_toggles = {}
+	table.clear(self.state.mod_pressure)
+	self.bend_dropdowns = {}
+	table.clear(self.state.bend_range)
 
 	for _, v in ipairs(setup.midi_devices) do
 		-- device on/off toggles
@@ -138,6 +239,26 @@ function Settings:rebuild_midi()
 		if v.mpe then
 			self.state.mpe[v.name] = true
 		end
+
+		-- mod wheel and breath as pressure
+		local toggle_mod = widgets.Toggle.new(self.state.mod_pressure, v.name, { style = "checkbox", no_undo = true })
+		table.insert(self.mod_toggles, toggle_mod)
+
+		if v.mod_pressure then
+			self.state.mod_pressure[v.name] = true
+		end
+
+		-- bend range, "Auto" follows what the device reports
+		local dropdown =
+			widgets.Dropdown.new(self.state.bend_range, v.name, { list = BEND_RANGE_LABELS, no_undo = true })
+		table.insert(self.bend_dropdowns, dropdown)
+
+		self.state.bend_range[v.name] = 1
+		for i, range in ipairs(BEND_RANGES) do
+			if v.bend_range == range then
+				self.state.bend_range[v.name] = i + 1
+			end
+		end
 	end
 end
 
@@ -148,7 +269,12 @@ function Settings:rebuild()
 		Settings.devices[setup.host] = tessera.audio.get_output_devices(setup.host)
 	end
 
+	if not Settings.input_devices[setup.host] then
+		Settings.input_devices[setup.host] = tessera.audio.get_input_devices(setup.host)
+	end
+
 	self.select_device.list = device_names()
+	self.select_input_device.list = input_device_names()
 
 	-- Find currently selected device index by its unique id
 	self.state.device_index = 1
@@ -158,6 +284,16 @@ function Settings:rebuild()
 		end
 	end
 
+	self.state.input_device_index = 1
+	local input_device = setup.configs[setup.host].input_device
+	if input_device then
+		for i, v in ipairs(Settings.input_devices[setup.host]) do
+			if v.id == input_device.id then
+				self.state.input_device_index = i + 1
+			end
+		end
+	end
+
 	if setup.configs[setup.host].buffer_size then
 		self.state.toggle_buffer = true
 		self.state.buffer_size = setup.configs[setup.host].buffer_size
@@ -245,6 +381,58 @@ function Settings:update()
 		end
 	end
 
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Input device")
+	self.ui.layout:col(c3)
+
+	local input_device_index = self.select_input_device:update(self.ui)
+	if input_device_index then
+		if input_device_index == 1 then
+			setup.configs[setup.host].input_device = nil
+		else
+			setup.configs[setup.host].input_device = Settings.input_devices[setup.host][input_device_index - 1]
+		end
+		engine.setup_input()
+	end
+
+	if tessera.audio.input_ok() then
+		self.ui.layout:col(c4)
+		self.ui:label("Active", { color = theme.text_dim })
+	end
+
+	local config = setup.configs[setup.host]
+	local output_pairs = engine.output_pairs()
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Main output")
+	self.ui.layout:col(c3)
+	self.select_master_output.list = output_pairs
+	self.state.master_output = config.master_output or 1
+	local master_output = self.select_master_output:update(self.ui)
+	if master_output then
+		config.master_output = master_output
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Cue output")
+	self.ui.layout:col(c3)
+	local cue_list = { "Off" }
+	for i, name in ipairs(output_pairs) do
+		cue_list[i + 1] = name
+	end
+	self.select_cue_output.list = cue_list
+	self.state.cue_output = (config.cue_output or 0) + 1
+	local cue_output = self.select_cue_output:update(self.ui)
+	if cue_output then
+		config.cue_output = cue_output > 1 and cue_output - 1 or nil
+	end
+
 	self.ui.layout:new_row()
 	self.ui.layout:col(c1 + c2)
 	local update_buffer_size = self.toggle_buffer_size:update(self.ui)
@@ -288,8 +476,8 @@ function Settings:update()
 	-- MIDI
 
 	c2 = 0.3 * (lw - c1)
-	c3 = 0.2 * (lw - c1)
-	c4 = 0.4 * (lw - c1)
+	c3 = 0.3 * (lw - c1)
+	c4 = 0.3 * (lw - c1)
 
 	if midi.ports_changed then
 		self:rebuild_midi()
@@ -300,8 +488,12 @@ function Settings:update()
 	self.ui.layout:new_row()
 	self.ui.layout:col(c1 + c2)
 	self.ui:label("Midi devices")
-	self.ui.layout:col(c3)
+	self.ui.layout:col(c3 / 3)
 	self.ui:label("MPE")
+	self.ui.layout:col(c3 / 3)
+	self.ui:label("Mod")
+	self.ui.layout:col(c3 / 3)
+	self.ui:label("Bend")
 	self.ui.layout:col(c4)
 	self.ui:label("Status")
 	self.ui:background(theme.bg_nested)
@@ -318,27 +510,57 @@ function Settings:update()
 				if self.midi_toggles[i]:update(self.ui) then
 					local enable = self.state.midi_ports[v.name]
 					setup.midi_devices[i].enable = enable
-					if midi.available_ports[v.name] then
-						midi.connect(enable, setup.midi_devices[i])
-					end
+					midi.connect(enable, setup.midi_devices[i])
+					midi.scan_ports()
 				end
-				self.ui.layout:col(c3)
+				self.ui.layout:col(c3 / 3)
 				if self.mpe_toggles[i]:update(self.ui) then
 					setup.midi_devices[i].mpe = self.state.mpe[v.name]
 					midi.update_config(setup.midi_devices[i])
 				end
+				self.ui.layout:col(c3 / 3)
+				if self.mod_toggles[i]:update(self.ui) then
+					setup.midi_devices[i].mod_pressure = self.state.mod_pressure[v.name]
+					midi.update_config(setup.midi_devices[i])
+				end
+				self.ui.layout:col(c3 / 3)
+				if self.bend_dropdowns[i]:update(self.ui) then
+					setup.midi_devices[i].bend_range = BEND_RANGES[self.state.bend_range[v.name] - 1]
+					midi.update_config(setup.midi_devices[i])
+				end
 
 				self.ui.layout:col(c4)
 
 				if midi.open_ports[v.name] then
 					self.ui:label("Active")
-				elseif midi.available_ports[v.name] then
-					self.ui:label("Disabled", { color = theme.text_dim })
+				elseif not midi.available_ports[v.name] then
+					-- enabled devices reconnect when they are plugged in again
+					self.ui:label(v.enable and "Waiting" or "Not found", { color = theme.text_dim })
+				elseif v.enable then
+					self.ui:label("Failed", { color = theme.text_dim })
 				else
-					self.ui:label("Not found", { color = theme.text_dim })
+					self.ui:label("Disabled", { color = theme.text_dim })
 				end
 			end
 		end
+
+		if tessera.midi.virtual_supported() then
+			self.ui.layout:new_row()
+			self.ui.layout:col(c1 + c2)
+			if self.toggle_virtual_midi:update(self.ui) then
+				setup.virtual_midi.enable = self.state.virtual_midi
+				if midi.set_virtual(setup.virtual_midi.enable) then
+					midi.scan_ports()
+				end
+			end
+			self.ui.layout:col(c3 + c4)
+			local names = {}
+			for name in pairs(midi.virtual_ports) do
+				table.insert(names, name)
+			end
+			table.sort(names)
+			self.ui:label(table.concat(names, ", "), { color = theme.text_dim })
+		end
 	else
 		self.ui.layout:new_row()
 		self.ui.layout:col(c1)
@@ -346,7 +568,97 @@ function Settings:update()
 		self.ui:label("MIDI not available")
 	end
 
+	-- EXPORT
+	c2 = 0.3 * (lw - c1)
+	c3 = 0.4 * (lw - c1)
+	c4 = 0.3 * (lw - c1)
+
+	self.ui:background(theme.background)
+	self.ui.layout:new_row()
+	self.ui.layout:col(lw)
+	self.ui:label("Export settings")
+	self.ui:background(theme.bg_nested)
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Format")
+	self.ui.layout:col(c3)
+	if self.select_export_format:update(self.ui) then
+		setup.export.format = export_formats[self.state.export_format]
+	end
+
+	if setup.export.format ~= "float32" then
+		self.ui.layout:new_row()
+		self.ui.layout:col(c1)
+		self.ui.layout:col(c2)
+		self.ui:label("Dither")
+		self.ui.layout:col(c3)
+		if self.select_export_dither:update(self.ui) then
+			setup.export.dither = export_dithers[self.state.export_dither]
+		end
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Sample rate")
+	self.ui.layout:col(c3)
+	if self.select_export_rate:update(self.ui) then
+		local rate = export_rates[self.state.export_rate]
+		if rate == "engine" then
+			setup.export.sample_rate = nil
+		else
+			setup.export.sample_rate = rate
+		end
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Normalize")
+	self.ui.layout:col(c3)
+	if self.select_export_normalize:update(self.ui) then
+		setup.export.normalize = export_normalize[self.state.export_normalize]
+	end
+	self.ui.layout:col(c4)
+	if setup.export.normalize == "peak" then
+		self.ui:label(string.format("%.1f dBFS", setup.export.peak_target), { color = theme.text_dim })
+	elseif setup.export.normalize == "loudness" then
+		self.ui:label(string.format("%.1f LUFS", setup.export.loudness_target), { color = theme.text_dim })
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Channel stems")
+	self.ui.layout:col(c3)
+	if self.select_export_stems:update(self.ui) then
+		setup.export.stems = self.state.export_stems >= 2
+		setup.export.stems_pre_effects = self.state.export_stems == 3
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("MIDI files")
+	self.ui.layout:col(c3)
+	if self.select_midi_export_mode:update(self.ui) then
+		setup.midi_export.mpe = self.state.midi_export_mode == 1
+	end
+
+	self.ui.layout:new_row()
+	self.ui.layout:col(c1)
+	self.ui.layout:col(c2)
+	self.ui:label("Curve resolution")
+	self.ui.layout:col(c3)
+	if self.select_midi_export_resolution:update(self.ui) then
+		setup.midi_export.resolution = midi_export_resolutions[self.state.midi_export_resolution]
+	end
+
 	-- PLUGINS
+	c2 = 0.3 * (lw - c1)
+	c3 = 0.2 * (lw - c1)
 	self.ui:background(theme.background)
 	self.ui.layout:new_row()
 	self.ui.layout:col(c1 + c2)
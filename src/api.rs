@@ -196,6 +196,86 @@ pub fn create_lua(scale_factor: f64) -> LuaResult<Lua> {
 		})?,
 	)?;
 
+	tessera.set(
+		"dialog_import_midi",
+		lua.create_function(|lua: &Lua, ()| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+
+			if state.dialog_rx.is_some() {
+				log_warn!("Dialog already open!");
+				return Ok(false);
+			}
+
+			let (tx, rx) = mpsc::channel();
+			state.dialog_rx = Some(rx);
+
+			std::thread::spawn(move || {
+				let file = rfd::FileDialog::new()
+					.add_filter("midi", &["mid", "midi"])
+					.set_directory(std::path::absolute("./out").unwrap())
+					.pick_file();
+
+				tx.send(file).unwrap();
+			});
+
+			Ok(true)
+		})?,
+	)?;
+
+	tessera.set(
+		"dialog_export_midi",
+		lua.create_function(|lua: &Lua, name: String| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+
+			if state.dialog_rx.is_some() {
+				log_warn!("Dialog already open!");
+				return Ok(false);
+			}
+
+			let (tx, rx) = mpsc::channel();
+			state.dialog_rx = Some(rx);
+
+			std::thread::spawn(move || {
+				let file = rfd::FileDialog::new()
+					.add_filter("midi", &["mid", "midi"])
+					.set_file_name(name)
+					.set_directory(std::path::absolute("./out").unwrap())
+					.save_file();
+
+				tx.send(file).unwrap();
+			});
+
+			Ok(true)
+		})?,
+	)?;
+
+	tessera.set(
+		"dialog_render",
+		lua.create_function(|lua: &Lua, name: String| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+
+			if state.dialog_rx.is_some() {
+				log_warn!("Dialog already open!");
+				return Ok(false);
+			}
+
+			let (tx, rx) = mpsc::channel();
+			state.dialog_rx = Some(rx);
+
+			std::thread::spawn(move || {
+				let file = rfd::FileDialog::new()
+					.add_filter("wav", &["wav"])
+					.set_file_name(name)
+					.set_directory(std::path::absolute("./out").unwrap())
+					.save_file();
+
+				tx.send(file).unwrap();
+			});
+
+			Ok(true)
+		})?,
+	)?;
+
 	tessera.set(
 		"open_url",
 		lua.create_function(|_, url_str: String| {
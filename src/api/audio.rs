@@ -1,14 +1,23 @@
 use crate::api::lua_serde;
+use crate::api::project::{self, Clip, Project};
 use crate::app::State;
-use crate::audio;
 use crate::audio::CPU_LOAD;
 use crate::audio::{
-	check_architecture, get_default_host, get_default_output_device, get_hosts, get_output_devices,
-	open_control_panel,
+	check_architecture, get_default_host, get_default_output_device, get_hosts, get_input_devices,
+	get_output_devices, open_control_panel,
 };
+use crate::automation::AutomationList;
 use crate::context::{AudioContext, AudioMessage};
-use crate::log::{log_error, log_info};
+use crate::export::ExportSettings;
+use crate::log::{log_error, log_info, log_warn};
+use crate::midi_input::LiveOutput;
+use crate::mts::{self, Tuning};
+use crate::offline::OfflineRender;
 use crate::opengl::UserEvent;
+use crate::recorder::Recorder;
+use crate::routing::{OutputMap, Route, Routing};
+use crate::sequencer::Sequence;
+use crate::tempo::TempoMap;
 use crate::voice_manager::Token;
 use crate::vst3;
 use crate::vst3::Vst3State;
@@ -16,9 +25,9 @@ use crate::vst3::scan::PluginDescriptor;
 use cpal::Device;
 use cpal::traits::DeviceTrait;
 use mlua::prelude::*;
-use no_denormals::no_denormals;
 use ringbuf::traits::*;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
 pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	let audio = lua.create_table()?;
@@ -37,6 +46,17 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		})?,
 	)?;
 
+	audio.set(
+		"get_input_devices",
+		lua.create_function(|_, host_name: String| match get_input_devices(&host_name) {
+			Ok(devices) => Ok(devices),
+			Err(e) => {
+				log_error!("{e}");
+				Ok(vec![])
+			},
+		})?,
+	)?;
+
 	audio.set(
 		"get_default_output_device",
 		lua.create_function(|_, host_name: String| match get_default_output_device(&host_name) {
@@ -58,7 +78,12 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 			let lua_tx = state.lua_tx.clone();
 
 			match AudioContext::new(&device_info, buffer_size, lua_tx) {
-				Ok(ctx) => {
+				Ok(mut ctx) => {
+					let output = ctx
+						.live_tx
+						.take()
+						.map(|tx| LiveOutput { tx, clock: Arc::clone(&ctx.clock) });
+					state.live_input.lock().set_output(output);
 					state.audio = Some(ctx);
 					Ok(())
 				},
@@ -86,6 +111,82 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		})?,
 	)?;
 
+	audio.set(
+		"open_input",
+		lua.create_function(|lua, device_info: DeviceInfo| {
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				match ctx.open_input(&device_info) {
+					Ok(()) => return Ok(true),
+					Err(e) => {
+						log_error!("Failed to open input: {e}");
+						ctx.close_input();
+					},
+				}
+			}
+			Ok(false)
+		})?,
+	)?;
+
+	audio.set(
+		"close_input",
+		lua.create_function(|lua, ()| {
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				ctx.close_input();
+			}
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"input_ok",
+		lua.create_function(|lua, ()| {
+			let state = lua.app_data_ref::<State>().unwrap();
+			Ok(state.audio.as_ref().is_some_and(|ctx| ctx.input_stream.is_some()))
+		})?,
+	)?;
+
+	audio.set(
+		"record_start",
+		lua.create_function(|lua, channels: LuaValue| {
+			let channels: Vec<(usize, String)> = lua.from_value(channels)?;
+			let channels: Vec<_> = channels.into_iter().map(|(i, path)| (i - 1, path)).collect();
+
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				// Finish previous take first
+				ctx.recorder = None;
+
+				match Recorder::start(Arc::clone(&ctx.render), &channels, ctx.sample_rate) {
+					Ok(recorder) => {
+						ctx.recorder = Some(recorder);
+						return Ok(true);
+					},
+					Err(e) => log_error!("Failed to start recording: {e}"),
+				}
+			}
+			Ok(false)
+		})?,
+	)?;
+
+	audio.set(
+		"record_stop",
+		lua.create_function(|lua, ()| {
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio
+				&& let Some(recorder) = ctx.recorder.take()
+			{
+				match recorder.stop() {
+					Ok(mut takes) => {
+						for take in &mut takes {
+							take.channel_index += 1;
+						}
+						return Ok(takes);
+					},
+					Err(e) => log_error!("{e}"),
+				}
+			}
+			Ok(Vec::new())
+		})?,
+	)?;
+
 	audio.set(
 		"quit",
 		lua.create_function(|lua, ()| {
@@ -98,9 +199,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"panic",
 		lua.create_function(|lua, ()| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::Panic);
-			}
+			send_message(lua, AudioMessage::Panic);
 			Ok(())
 		})?,
 	)?;
@@ -133,9 +232,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"all_notes_off",
 		lua.create_function(|lua, ()| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::AllNotesOff);
-			}
+			send_message(lua, AudioMessage::AllNotesOff);
 			Ok(())
 		})?,
 	)?;
@@ -155,15 +252,10 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		"note_on",
 		lua.create_function(
 			|lua, (channel_index, pitch, offset, vel, token): (usize, f32, f32, f32, Token)| {
-				if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-					ctx.send_message(AudioMessage::NoteOn(
-						channel_index - 1,
-						token,
-						pitch,
-						offset,
-						vel,
-					));
-				}
+				send_message(
+					lua,
+					AudioMessage::NoteOn(channel_index - 1, token, pitch, offset, vel),
+				);
 				Ok(())
 			},
 		)?,
@@ -172,9 +264,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"note_off",
 		lua.create_function(|lua, (channel_index, token): (usize, Token)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::NoteOff(channel_index - 1, token));
-			}
+			send_message(lua, AudioMessage::NoteOff(channel_index - 1, token));
 			Ok(())
 		})?,
 	)?;
@@ -182,9 +272,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"pitch",
 		lua.create_function(|lua, (channel_index, pitch, token): (usize, f32, Token)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::Pitch(channel_index - 1, token, pitch));
-			}
+			send_message(lua, AudioMessage::Pitch(channel_index - 1, token, pitch));
 			Ok(())
 		})?,
 	)?;
@@ -192,9 +280,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"pressure",
 		lua.create_function(|lua, (channel_index, pressure, token): (usize, f32, Token)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::Pressure(channel_index - 1, token, pressure));
-			}
+			send_message(lua, AudioMessage::Pressure(channel_index - 1, token, pressure));
 			Ok(())
 		})?,
 	)?;
@@ -202,9 +288,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"sustain",
 		lua.create_function(|lua, (channel_index, sustain): (usize, bool)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::Sustain(channel_index - 1, sustain));
-			}
+			send_message(lua, AudioMessage::Sustain(channel_index - 1, sustain));
 			Ok(())
 		})?,
 	)?;
@@ -212,9 +296,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"send_channel_mute",
 		lua.create_function(|lua, (channel_index, mute): (usize, bool)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::ChannelMute(channel_index - 1, mute));
-			}
+			send_message(lua, AudioMessage::ChannelMute(channel_index - 1, mute));
 			Ok(())
 		})?,
 	)?;
@@ -222,8 +304,68 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"send_channel_gain",
 		lua.create_function(|lua, (channel_index, gain): (usize, f32)| {
+			send_message(lua, AudioMessage::ChannelGain(channel_index - 1, gain));
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"send_send_gain",
+		lua.create_function(|lua, (channel_index, send_index, gain): (usize, usize, f32)| {
+			send_message(lua, AudioMessage::SendGain(channel_index - 1, send_index - 1, gain));
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"set_routing",
+		lua.create_function(|lua, routes: LuaValue| {
+			let routes: Vec<ChannelRoute> = lua.from_value(routes)?;
+			let routes: Vec<Route> = routes
+				.iter()
+				.map(|r| {
+					let sidechains = r.sidechains.iter().map(|s| (s.effect, s.source));
+					Route::new(r.output, &r.sends, sidechains)
+				})
+				.collect();
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				// Delay lines and the processing order are built here, the audio thread only swaps them in
+				match Routing::new(&routes, ctx.sample_rate as f32) {
+					Ok(routing) => ctx.send_message(AudioMessage::SetRouting(Box::new(routing))),
+					Err(e) => {
+						log_error!("{e}");
+						return Ok(false);
+					},
+				}
+			}
+			Ok(true)
+		})?,
+	)?;
+
+	audio.set(
+		"send_cue_gain",
+		lua.create_function(|lua, (channel_index, gain): (usize, f32)| {
+			send_message(lua, AudioMessage::CueGain(channel_index - 1, gain));
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"set_output_map",
+		lua.create_function(|lua, map: LuaValue| {
+			let map: OutputMapData = lua.from_value(map)?;
+			let output_map = OutputMap {
+				master: map.master.map(|p| p.saturating_sub(1)),
+				cue: map.cue.map(|p| p.saturating_sub(1)),
+				channels: map
+					.direct
+					.iter()
+					.map(|d| (d.channel.saturating_sub(1), d.pair.saturating_sub(1)))
+					.collect(),
+			};
 			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::ChannelGain(channel_index - 1, gain));
+				let old = ctx.render.lock().set_output_map(output_map);
+				drop(old);
 			}
 			Ok(())
 		})?,
@@ -232,9 +374,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"send_device_mute",
 		lua.create_function(|lua, (channel_index, device_index, mute): (usize, usize, bool)| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::DeviceMute(channel_index - 1, device_index, mute));
-			}
+			send_message(lua, AudioMessage::DeviceMute(channel_index - 1, device_index, mute));
 			Ok(())
 		})?,
 	)?;
@@ -243,14 +383,15 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		"send_parameter",
 		lua.create_function(
 			|lua, (channel_index, device_index, index, value): (usize, usize, usize, f32)| {
-				if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-					ctx.send_message(AudioMessage::Parameter(
+				send_message(
+					lua,
+					AudioMessage::Parameter(
 						channel_index - 1,
 						device_index, // don't need -1 here since device index is 0 for instrument and 1.. for fx
 						index - 1,
 						value,
-					));
-				}
+					),
+				);
 				Ok(())
 			},
 		)?,
@@ -259,23 +400,78 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"metronome",
 		lua.create_function(|lua, accent: bool| {
+			send_message(lua, AudioMessage::Metronome(accent));
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"play",
+		lua.create_function(|lua, (time, chase): (f64, Option<bool>)| {
+			send_message(lua, AudioMessage::Play(time, chase.unwrap_or(false)));
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"seek",
+		lua.create_function(|lua, time: f64| {
+			send_message(lua, AudioMessage::Seek(time));
+			Ok(())
+		})?,
+	)?;
+
+	// Returns the position and how often playback wrapped around the loop
+	audio.set(
+		"position",
+		lua.create_function(|lua, ()| {
+			let state = lua.app_data_ref::<State>().unwrap();
+			if let Some(offline) = &state.offline {
+				Ok((offline.position(), 0))
+			} else if let Some(ctx) = &state.audio {
+				let loops = ctx.playhead.loops();
+				Ok((ctx.position(), loops))
+			} else {
+				Ok((None, 0))
+			}
+		})?,
+	)?;
+
+	// Offline renders play straight through, so these only go to the stream
+	audio.set(
+		"set_loop",
+		lua.create_function(|lua, (start, end): (Option<f64>, Option<f64>)| {
 			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_message(AudioMessage::Metronome(accent));
+				ctx.send_message(AudioMessage::SetLoop(start.zip(end)));
 			}
 			Ok(())
 		})?,
 	)?;
 
 	audio.set(
-		"reorder_effect",
+		"set_punch",
+		lua.create_function(|lua, (start, end): (Option<f64>, Option<f64>)| {
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				ctx.send_message(AudioMessage::SetPunch(start.zip(end)));
+			}
+			Ok(())
+		})?,
+	)?;
+
+	// Key tuning of a channel that plays MIDI keys, nil resets it to 12-EDO.
+	// With options, the MTS sysex is passed on to VST3 plugins that accept it.
+	audio.set(
+		"set_tuning",
 		lua.create_function(
-			|lua, (channel_index, old_index, new_index): (usize, usize, usize)| {
+			|lua, (channel_index, pitches, options): (usize, Option<Vec<f32>>, Option<mts::Options>)| {
 				if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-					ctx.send_message(AudioMessage::ReorderEffect(
-						channel_index - 1,
-						old_index - 1,
-						new_index - 1,
-					));
+					let tuning = match (pitches, options) {
+						(Some(pitches), Some(options)) => Tuning::new(pitches, &options),
+						(Some(pitches), None) => Tuning { pitches, sysex: Vec::new() },
+						(None, _) => Tuning::default(),
+					};
+					let old = ctx.render.lock().set_tuning(channel_index - 1, Box::new(tuning));
+					drop(old);
 				}
 				Ok(())
 			},
@@ -283,26 +479,98 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	)?;
 
 	audio.set(
-		"set_rendering",
-		lua.create_function(|lua, rendering: bool| {
+		"set_tempo",
+		lua.create_function(|lua, tempo: project::Tempo| {
 			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.send_rendering(rendering);
+				let tempo = Box::new(TempoMap::new(&tempo));
+				let old = ctx.render.lock().set_tempo(tempo);
+				drop(old);
 			}
 			Ok(())
 		})?,
 	)?;
 
 	audio.set(
-		"is_rendering",
+		"set_sequence",
+		lua.create_function(|lua, (channel_index, data): (usize, LuaValue)| {
+			let data: SequenceData = lua.from_value(data)?;
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			if let Some(offline) = &mut state.offline {
+				let sample_rate = offline.sample_rate as f32;
+				let sequence = Sequence::new(data.notes, data.pitches, data.control, sample_rate);
+				offline.set_sequence(channel_index - 1, Box::new(sequence));
+			} else if let Some(ctx) = &mut state.audio {
+				let sample_rate = ctx.sample_rate as f32;
+				let sequence = Sequence::new(data.notes, data.pitches, data.control, sample_rate);
+				let old = ctx.render.lock().set_sequence(channel_index - 1, Box::new(sequence));
+				drop(old);
+			}
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"stop",
 		lua.create_function(|lua, ()| {
+			send_message(lua, AudioMessage::Stop);
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"set_automation",
+		lua.create_function(|lua, (channel_index, lanes): (usize, LuaValue)| {
+			let lanes: Vec<project::Lane> = lua.from_value(lanes)?;
 			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				Ok(ctx.is_rendering)
-			} else {
-				Ok(true)
+				let automation = Box::new(AutomationList::new(&lanes, ctx.sample_rate as f32));
+				let old = ctx.render.lock().set_automation(channel_index - 1, automation);
+				drop(old);
+			}
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"automation_bypass",
+		lua.create_function(|lua, (channel_index, lane_index, bypass): (usize, usize, bool)| {
+			send_message(
+				lua,
+				AudioMessage::AutomationBypass(channel_index - 1, lane_index - 1, bypass),
+			);
+			Ok(())
+		})?,
+	)?;
+
+	audio.set(
+		"set_clips",
+		lua.create_function(|lua, (channel_index, clips): (usize, LuaValue)| {
+			let clips: Vec<Clip> = lua.from_value(clips)?;
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
+				let mut render = ctx.render.lock();
+				render.load_clips(channel_index - 1, clips);
 			}
+			Ok(())
 		})?,
 	)?;
 
+	audio.set(
+		"reorder_effect",
+		lua.create_function(
+			|lua, (channel_index, old_index, new_index): (usize, usize, usize)| {
+				send_message(
+					lua,
+					AudioMessage::ReorderEffect(channel_index - 1, old_index - 1, new_index - 1),
+				);
+				Ok(())
+			},
+		)?,
+	)?;
+
+	audio.set(
+		"is_rendering",
+		lua.create_function(|lua, ()| Ok(lua.app_data_mut::<State>().unwrap().offline.is_some()))?,
+	)?;
+
 	audio.set(
 		"insert_channel",
 		lua.create_function(|lua, index: usize| {
@@ -487,23 +755,53 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set("get_cpu_load", lua.create_function(|_, ()| Ok(CPU_LOAD.load()))?)?;
 
 	audio.set(
-		"render_block",
-		lua.create_function(|lua, ()| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				let len = 64;
-				let buffer: &mut [&mut [f32]; 2] = &mut [&mut vec![0.0; len], &mut vec![0.0; len]];
-
-				let mut render = ctx.render.lock();
-				// TODO: need to check here if the stream is *actually* paused
-
-				render.parse_messages();
-				unsafe { no_denormals(|| render.process(buffer)) };
-
-				// interlace
-				for i in 0..len {
-					ctx.render_buffer.push(buffer[0][i]);
-					ctx.render_buffer.push(buffer[1][i]);
+		"render_start",
+		lua.create_function(
+			|lua,
+			 (project, sample_rate, duration, settings): (
+				LuaValue,
+				u32,
+				f64,
+				Option<ExportSettings>,
+			)| {
+				let project: Project = match lua.from_value(project) {
+					Ok(project) => project,
+					Err(e) => {
+						log_error!("Failed to read project: {e}");
+						return Ok(false);
+					},
+				};
+
+				let state = &mut *lua.app_data_mut::<State>().unwrap();
+				let length = (duration.max(0.0) * f64::from(sample_rate)).ceil() as usize;
+
+				let settings = settings.unwrap_or_default();
+				match OfflineRender::new(
+					&project,
+					sample_rate,
+					length,
+					settings,
+					state.lua_tx.clone(),
+				) {
+					Ok(offline) => {
+						state.offline = Some(offline);
+						Ok(true)
+					},
+					Err(e) => {
+						log_error!("Failed to start render: {e}");
+						state.offline = None;
+						Ok(false)
+					},
 				}
+			},
+		)?,
+	)?;
+
+	audio.set(
+		"render_block",
+		lua.create_function(|lua, len: usize| {
+			if let Some(offline) = &mut lua.app_data_mut::<State>().unwrap().offline {
+				offline.process_block(len);
 				Ok(true)
 			} else {
 				Ok(false)
@@ -511,26 +809,33 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		})?,
 	)?;
 
+	audio.set(
+		"render_progress",
+		lua.create_function(|lua, ()| {
+			let state = lua.app_data_ref::<State>().unwrap();
+			Ok(state.offline.as_ref().map(|offline| offline.progress()))
+		})?,
+	)?;
+
 	audio.set(
 		"render_finish",
 		lua.create_function(|lua, ()| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				let filename = "out/render.wav";
-				let sample_rate = ctx.sample_rate;
+			if let Some(mut offline) = lua.app_data_mut::<State>().unwrap().offline.take() {
+				if !offline.is_finished() {
+					log_warn!("Render finished early.");
+				}
 
-				match audio::write_wav(filename, &ctx.render_buffer, sample_rate) {
+				match offline.write() {
 					Ok(()) => {
-						log_info!("Wrote \"{filename}\".");
+						log_info!("Wrote \"{}\".", offline.settings.path);
 					},
 					Err(e) => {
 						log_error!("Failed to write wav!");
 						log_error!("{e}");
 					},
 				}
-				// reset the buffer
-				ctx.render_buffer = Vec::new();
 			} else {
-				log_error!("Failed to write wav, backend offline.");
+				log_error!("Failed to write wav, no render in progress.");
 			}
 			Ok(())
 		})?,
@@ -539,9 +844,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	audio.set(
 		"render_cancel",
 		lua.create_function(|lua, ()| {
-			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio {
-				ctx.render_buffer = Vec::new();
-			}
+			lua.app_data_mut::<State>().unwrap().offline = None;
 			Ok(())
 		})?,
 	)?;
@@ -567,6 +870,19 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		})?,
 	)?;
 
+	audio.set(
+		"pop_input_error",
+		lua.create_function(|lua, ()| {
+			if let Some(ctx) = &mut lua.app_data_mut::<State>().unwrap().audio
+				&& let Some(rx) = &mut ctx.input_error_rx
+			{
+				Ok(rx.try_pop())
+			} else {
+				Ok(None)
+			}
+		})?,
+	)?;
+
 	audio.set(
 		"pop_error",
 		lua.create_function(|lua, ()| {
@@ -581,6 +897,61 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	Ok(audio)
 }
 
+// While rendering, messages go to the offline renderer instead of the live stream.
+fn send_message(lua: &Lua, m: AudioMessage) {
+	let state = &mut *lua.app_data_mut::<State>().unwrap();
+	if let Some(offline) = &mut state.offline {
+		offline.send_message(m);
+	} else if let Some(ctx) = &mut state.audio {
+		ctx.send_message(m);
+	}
+}
+
+// Routing of one channel as sent from Lua
+#[derive(Debug, Deserialize)]
+struct ChannelRoute {
+	#[serde(default)]
+	output: Option<usize>,
+	#[serde(default)]
+	sends: Vec<project::Send>,
+	#[serde(default)]
+	sidechains: Vec<SidechainData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SidechainData {
+	effect: usize,
+	source: usize,
+}
+
+// Notes of a channel with the pitch of every note resolved by the tuning
+#[derive(Debug, Deserialize)]
+struct SequenceData {
+	#[serde(default)]
+	notes: Vec<project::Note>,
+	#[serde(default)]
+	pitches: Vec<f32>,
+	#[serde(default)]
+	control: project::Control,
+}
+
+// Output pairs as sent from Lua, all 1-based
+#[derive(Debug, Deserialize)]
+struct OutputMapData {
+	#[serde(default)]
+	master: Option<usize>,
+	#[serde(default)]
+	cue: Option<usize>,
+	#[serde(default)]
+	direct: Vec<DirectOutData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DirectOutData {
+	channel: usize,
+	pair: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceInfo {
 	pub name: String,
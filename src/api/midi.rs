@@ -1,8 +1,13 @@
+use crate::api::project::MidiMapping;
 use crate::app::State;
-use crate::log::{log_error, log_info};
+use crate::log::log_error;
 use crate::midi;
+use crate::midi_input::InputConfig;
+use crate::mts;
 use mlua::prelude::*;
 use ringbuf::traits::*;
+use std::sync::Arc;
+use std::time::Instant;
 
 pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	let midi = lua.create_table()?;
@@ -16,6 +21,7 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 				return Ok(false);
 			}
 			state.midi_session = midi::open_midi();
+			state.midi_output_session = midi::open_midi_output();
 			Ok(state.midi_session.is_some())
 		})?,
 	)?;
@@ -24,25 +30,33 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		"ports",
 		lua.create_function(|lua, ()| {
 			let state = &mut *lua.app_data_mut::<State>().unwrap();
-			if let Some(midi_session) = &state.midi_session {
-				let list = midi::port_names(midi_session);
-				return Ok(list);
+			Ok(state.midi_inputs.names())
+		})?,
+	)?;
+
+	midi.set("virtual_supported", lua.create_function(|_, ()| Ok(midi::VIRTUAL_PORTS))?)?;
+
+	// Virtual ports show up in `ports` and `output_ports`, they are created when opened
+	midi.set(
+		"set_virtual_ports",
+		lua.create_function(|lua, (inputs, outputs): (Vec<String>, Vec<String>)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			if !midi::VIRTUAL_PORTS {
+				return Ok(false);
 			}
-			Ok(vec![])
+			state.virtual_inputs = inputs;
+			state.virtual_outputs = outputs;
+			Ok(true)
 		})?,
 	)?;
 
+	// Keep an input port open whenever it is there, reconnecting when it comes back
 	midi.set(
 		"open_connection",
-		lua.create_function(|lua, port_name: String| {
+		lua.create_function(|lua, (port_name, config): (String, Option<InputConfig>)| {
 			let state = &mut *lua.app_data_mut::<State>().unwrap();
-			let connection = midi::connect(&port_name);
-			if let Some(c) = connection {
-				let index = state.midi_connections.len() + 1;
-				state.midi_connections.push(c);
-				return Ok(Some(index));
-			}
-			Ok(None)
+			state.midi_inputs.open(port_name, config.unwrap_or_default());
+			Ok(())
 		})?,
 	)?;
 
@@ -50,33 +64,166 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		"close_connection",
 		lua.create_function(|lua, port_name: String| {
 			let state = &mut *lua.app_data_mut::<State>().unwrap();
-			let index = state.midi_connections.iter().position(|v| v.name == port_name);
+			Ok(state.midi_inputs.close(&port_name))
+		})?,
+	)?;
 
-			if let Some(index) = index {
-				let connection = state.midi_connections.remove(index);
-				connection.connection.close();
-				log_info!("Closed connection \"{}\"", connection.name);
-				return Ok(Some(index + 1));
-			}
-			Ok(None)
+	// Ports that appeared or went away since the last scan, and connections that opened or closed
+	midi.set(
+		"scan",
+		lua.create_function(|lua, ()| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			let Some(midi_session) = &state.midi_session else {
+				return Ok(Vec::new());
+			};
+			let mut listed = midi::input_ports(midi_session);
+			listed.extend(midi::virtual_ports(&state.virtual_inputs));
+			Ok(state.midi_inputs.scan(listed, |id, name, config| {
+				let tokens = Arc::clone(&state.tokens);
+				let live = Arc::clone(&state.live_input);
+				match midi::virtual_name(id) {
+					Some(name) => midi::connect_virtual(name, config.clone(), tokens, live),
+					None => midi::connect(id, name, config.clone(), tokens, live),
+				}
+			}))
+		})?,
+	)?;
+
+	// Zones and bend ranges of an input port
+	midi.set(
+		"set_config",
+		lua.create_function(|lua, (port_name, config): (String, InputConfig)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			Ok(state.midi_inputs.set_config(&port_name, config))
+		})?,
+	)?;
+
+	// Channels that live input plays on, and the pitch of every key in the current tuning
+	midi.set(
+		"set_live",
+		lua.create_function(|lua, (channels, pitches): (Vec<usize>, Vec<f32>)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			let mut live = state.live_input.lock();
+			live.set_channels(channels.iter().map(|ch| ch - 1).collect());
+			live.set_pitches(pitches);
+			Ok(())
+		})?,
+	)?;
+
+	// Controller mappings, with the current value of each mapped parameter
+	midi.set(
+		"set_mappings",
+		lua.create_function(|lua, (mappings, values): (Vec<MidiMapping>, Vec<f64>)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			state.live_input.lock().set_mappings(mappings, &values);
+			Ok(())
+		})?,
+	)?;
+
+	// Parameter of a mapping was changed from the frontend, relative encoders continue from there
+	midi.set(
+		"set_mapping_value",
+		lua.create_function(|lua, (index, value): (usize, f64)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			state.live_input.lock().set_mapping_value(index - 1, value);
+			Ok(())
 		})?,
 	)?;
 
+	midi.set(
+		"output_ports",
+		lua.create_function(|lua, ()| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			Ok(state.midi_outputs.names())
+		})?,
+	)?;
+
+	// Keep an output port open whenever it is there, like `open_connection`
+	midi.set(
+		"open_output",
+		lua.create_function(|lua, port_name: String| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			state.midi_outputs.open(port_name, ());
+			Ok(())
+		})?,
+	)?;
+
+	midi.set(
+		"close_output",
+		lua.create_function(|lua, port_name: String| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			Ok(state.midi_outputs.close(&port_name))
+		})?,
+	)?;
+
+	// Same as `scan`, for output ports
+	midi.set(
+		"scan_outputs",
+		lua.create_function(|lua, ()| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			let Some(midi_session) = &state.midi_output_session else {
+				return Ok(Vec::new());
+			};
+			let mut listed = midi::output_ports(midi_session);
+			listed.extend(midi::virtual_ports(&state.virtual_outputs));
+			Ok(state
+				.midi_outputs
+				.scan(listed, |id, name, _| match midi::virtual_name(id) {
+					Some(name) => midi::connect_virtual_output(name),
+					None => midi::connect_output(id, name),
+				}))
+		})?,
+	)?;
+
+	// Connect a MIDI output channel to an open output port, or disconnect it with nil
+	midi.set(
+		"set_channel_output",
+		lua.create_function(|lua, (channel_index, port_name): (usize, Option<String>)| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			let Some(ctx) = &mut state.audio else {
+				return Ok(false);
+			};
+			let tx = port_name
+				.and_then(|name| state.midi_outputs.get(&name))
+				.map(|c| c.add_source(Arc::clone(&ctx.clock)));
+			Ok(ctx.render.lock().set_midi_output(channel_index - 1, tx))
+		})?,
+	)?;
+
+	// Retune a synth on an open output port with MTS sysex
+	midi.set(
+		"send_tuning",
+		lua.create_function(
+			|lua, (port_name, pitches, options): (String, Vec<f32>, mts::Options)| {
+				let state = &mut *lua.app_data_mut::<State>().unwrap();
+				let Some(connection) = state.midi_outputs.get(&port_name) else {
+					return Ok(false);
+				};
+				for message in mts::messages(&pitches, &options) {
+					connection.send(message);
+				}
+				Ok(true)
+			},
+		)?,
+	)?;
+
 	midi.set(
 		"poll",
-		lua.create_function(|lua, connection_index: usize| {
-			let state = &mut *lua.app_data_mut::<State>().unwrap();
-			let connection = state.midi_connections.get_mut(connection_index - 1);
-			match connection {
-				Some(c) => {
-					let events: Vec<midi::Event> = c.midi_rx.pop_iter().collect();
-					return Ok(Some(events));
-				},
-				None => {
-					log_error!("Bad midi connection index: {connection_index}");
-				},
+		lua.create_function(|lua, port_name: String| {
+			let state = &mut *lua.app_data_mut::<State>().unwrap();
+			let Some(connection) = state.midi_inputs.get_mut(&port_name) else {
+				return Ok(None);
+			};
+			// Age lets recording put events where they were played, not where the frame was
+			let now = Instant::now();
+			let events = lua.create_table()?;
+			for (instant, event) in connection.midi_rx.pop_iter() {
+				let age = now.saturating_duration_since(instant).as_secs_f64();
+				let table = event.into_table(lua)?;
+				table.set("age", age)?;
+				events.push(table)?;
 			}
-			Ok(None)
+			Ok(Some(events))
 		})?,
 	)?;
 
@@ -1,9 +1,12 @@
-// Currently broken! Not going to fix until more stable
-
 use crate::api::lua_serde;
 use crate::app::State;
+use crate::log::log_error;
+use crate::midi_input::InputConfig;
+use crate::smf::{self, ExportChannel, ExportOptions};
+use crate::vst3::scan::PluginDescriptor;
 use mlua::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::path::Path;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
@@ -12,6 +15,10 @@ pub struct Project {
 	pub version: Version,
 	pub channels: Vec<Channel>,
 	pub transport: Transport,
+	#[serde(default)]
+	pub tempo: Tempo,
+	#[serde(default)]
+	pub midi_map: Vec<MidiMapping>,
 }
 
 lua_serde!(Project);
@@ -19,28 +26,62 @@ lua_serde!(Project);
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Channel {
 	pub name: String,
-	pub instrument: Instrument,
+	#[serde(default)]
+	pub master: bool,
+	pub instrument: Option<Instrument>,
+	#[serde(default)]
 	pub notes: Vec<Note>,
+	#[serde(default)]
 	pub control: Control,
+	#[serde(default)]
+	pub clips: Vec<Clip>,
+	// 1-based index of the channel this one feeds into, master if not set
+	#[serde(default)]
+	pub output: Option<usize>,
+	#[serde(default)]
+	pub sends: Vec<Send>,
+	#[serde(default)]
+	pub automation: Vec<Lane>,
 	pub effects: Vec<Effect>,
 	pub hue: f64,
+	#[serde(default = "default_gain")]
+	pub gain: f64,
 	pub visible: bool,
 	pub mute: bool,
-	pub solo: bool,
 	pub armed: bool,
 	pub lock: bool,
 }
 
+fn default_gain() -> f64 {
+	1.0
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instrument {
 	pub name: String,
+	#[serde(deserialize_with = "deserialize_state")]
 	pub state: Vec<f64>,
+	#[serde(default)]
+	pub mute: bool,
+	pub plugin: Option<Plugin>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Plugin {
+	pub descriptor: PluginDescriptor,
+	pub state: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Effect {
 	pub name: String,
+	#[serde(deserialize_with = "deserialize_state")]
 	pub state: Vec<f64>,
+	#[serde(default)]
+	pub mute: bool,
+	// 1-based index of the channel keying this effect, 0 or missing if none
+	#[serde(default)]
+	pub sidechain: Option<usize>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -58,11 +99,132 @@ pub struct SustainEvent {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Note {
 	pub time: f64,
-	pub pitch: Vec<i32>,
+	#[serde(alias = "pitch")]
+	pub interval: Vec<i32>,
 	pub vel: f64,
 	pub verts: Vec<Vertex>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Send {
+	// 1-based channel index
+	pub target: usize,
+	#[serde(default = "default_gain")]
+	pub gain: f64,
+	// Tap the signal before the fader instead of after
+	#[serde(default)]
+	pub pre_fader: bool,
+}
+
+// Hardware controller bound to a device parameter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MidiMapping {
+	// Input port, any port if not set
+	#[serde(default)]
+	pub port: Option<String>,
+	// 0-based, any channel if not set
+	#[serde(default)]
+	pub midi_channel: Option<u8>,
+	pub controller: u8,
+	// 14 bit controller, with the LSB on controller + 32
+	#[serde(default)]
+	pub high_res: bool,
+	// 1-based channel index
+	pub channel: usize,
+	// 0 is the instrument, 1.. are effects
+	pub device: usize,
+	// 1-based parameter index
+	pub index: usize,
+	pub min: f64,
+	pub max: f64,
+	// Exponent applied to the controller position, 1 is linear
+	#[serde(default = "default_curve")]
+	pub curve: f64,
+	#[serde(default)]
+	pub mode: EncoderMode,
+	// Round to whole numbers, for selectors and toggles
+	#[serde(default)]
+	pub discrete: bool,
+}
+
+lua_serde!(MidiMapping);
+
+fn default_curve() -> f64 {
+	1.0
+}
+
+// How a controller value is read. Endless encoders send steps instead of a position.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderMode {
+	#[default]
+	Absolute,
+	// 1 to 63 up, 127 down to 65 is -1 to -63
+	TwosComplement,
+	// 64 is no change
+	BinaryOffset,
+	// Bit 6 is the sign
+	SignedBit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LaneKind {
+	Parameter,
+	Gain,
+	Mute,
+}
+
+// Automation of a single parameter of a channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lane {
+	pub kind: LaneKind,
+	// For parameters: 0 is the instrument, 1.. are effects
+	#[serde(default)]
+	pub device: usize,
+	// 1-based parameter index
+	#[serde(default)]
+	pub index: usize,
+	pub points: Vec<AutomationPoint>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+	#[default]
+	Linear,
+	Exponential,
+	Hold,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutomationPoint {
+	// Seconds
+	pub time: f64,
+	pub value: f64,
+	// Shape of the segment that starts at this point
+	#[serde(default)]
+	pub shape: Shape,
+}
+
+// Audio file placed on the timeline. All times are in seconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clip {
+	pub path: String,
+	// Position on the timeline
+	pub start: f64,
+	// Offset into the source file
+	#[serde(default)]
+	pub offset: f64,
+	pub length: f64,
+	#[serde(default = "default_gain")]
+	pub gain: f64,
+	#[serde(default)]
+	pub fade_in: f64,
+	#[serde(default)]
+	pub fade_out: f64,
+}
+
 #[derive(Debug, Clone)]
 pub struct Vertex {
 	pub x: f64,
@@ -74,8 +236,61 @@ pub struct Vertex {
 pub struct Transport {
 	pub start_time: f64,
 	pub recording: bool,
+	// Loop region in seconds
+	#[serde(default)]
+	pub looping: bool,
+	#[serde(default)]
+	pub loop_start: f64,
+	#[serde(default)]
+	pub loop_end: f64,
+	// Recording is limited to this range, in seconds
+	#[serde(default)]
+	pub punch: bool,
+	#[serde(default)]
+	pub punch_in: f64,
+	#[serde(default)]
+	pub punch_out: f64,
 }
 
+// Tempo track and time signatures. Positions are in quarter notes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tempo {
+	// Time in seconds of beat 0
+	#[serde(default)]
+	pub offset: f64,
+	pub points: Vec<TempoPoint>,
+	#[serde(default)]
+	pub signatures: Vec<TimeSignature>,
+}
+
+impl Default for Tempo {
+	fn default() -> Self {
+		Self {
+			offset: 0.0,
+			points: vec![TempoPoint { beat: 0.0, bpm: 120.0, ramp: false }],
+			signatures: vec![TimeSignature { beat: 0.0, numerator: 4, denominator: 4 }],
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TempoPoint {
+	pub beat: f64,
+	pub bpm: f64,
+	// Change linearly to the tempo of the next point, instead of jumping there
+	#[serde(default)]
+	pub ramp: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeSignature {
+	pub beat: f64,
+	pub numerator: u32,
+	pub denominator: u32,
+}
+
+lua_serde!(Tempo);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Version {
 	#[serde(rename = "MAJOR")]
@@ -112,6 +327,29 @@ impl<'de> Deserialize<'de> for Vertex {
 
 lua_serde!(Vertex);
 
+// Device state in Lua mixes numbers and booleans (toggles, buttons).
+// Booleans are sent to the backend as 0 or 1, so do the same here.
+fn deserialize_state<'de, D>(deserializer: D) -> Result<Vec<f64>, D::Error>
+where
+	D: Deserializer<'de>,
+{
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Value {
+		Number(f64),
+		Bool(bool),
+	}
+
+	let values: Vec<Value> = Deserialize::deserialize(deserializer)?;
+	Ok(values
+		.into_iter()
+		.map(|v| match v {
+			Value::Number(x) => x,
+			Value::Bool(b) => f64::from(u8::from(b)),
+		})
+		.collect())
+}
+
 pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 	let m = lua.create_table()?;
 
@@ -133,6 +371,39 @@ pub fn create(lua: &Lua) -> LuaResult<LuaTable> {
 		})?,
 	)?;
 
+	// Tracks and tempo map of a Standard MIDI File
+	m.set(
+		"import_midi",
+		lua.create_function(|_, (path, config): (String, Option<InputConfig>)| match smf::import(
+			Path::new(&path),
+			&config.unwrap_or_default(),
+		) {
+			Ok(import) => Ok(Some(import)),
+			Err(e) => {
+				log_error!("Failed to import \"{path}\": {e}");
+				Ok(None)
+			},
+		})?,
+	)?;
+
+	// Channels with their tuned pitches and the tempo map as a Standard MIDI File
+	m.set(
+		"export_midi",
+		lua.create_function(
+			|_, args: (String, Vec<ExportChannel>, Tempo, Option<ExportOptions>)| {
+				let (path, channels, tempo, options) = args;
+				let options = options.unwrap_or_default();
+				match smf::export(Path::new(&path), &channels, &tempo, &options) {
+					Ok(()) => Ok(true),
+					Err(e) => {
+						log_error!("Failed to export \"{path}\": {e}");
+						Ok(false)
+					},
+				}
+			},
+		)?,
+	)?;
+
 	Ok(m)
 }
 
@@ -148,6 +419,17 @@ mod tests {
 		let project = lua.create_function(|_, project: Project| {
 			let project_data = project;
 			assert!(project_data.name == "Test Project");
+			assert_eq!(project_data.tempo.points[1].bpm, 90.0);
+			assert!(project_data.tempo.points[0].ramp);
+			assert_eq!(project_data.tempo.signatures[0].numerator, 3);
+			assert!(project_data.transport.looping);
+			assert_eq!(project_data.transport.loop_end, 8.0);
+			assert!(!project_data.transport.punch);
+			let mapping = &project_data.midi_map[0];
+			assert_eq!(mapping.controller, 74);
+			assert_eq!(mapping.midi_channel, None);
+			assert_eq!(mapping.curve, 1.0);
+			assert_eq!(mapping.mode, EncoderMode::TwosComplement);
 			Ok(())
 		})?;
 
@@ -160,6 +442,9 @@ mod tests {
 				transport = {
 					recording = true,
 					start_time = 0,
+					looping = true,
+					loop_start = 4,
+					loop_end = 8,
 				},
 				name = "Test Project",
 				VERSION = {
@@ -169,6 +454,14 @@ mod tests {
 				},
 				channels = {
 				},
+				tempo = {
+					offset = 0.5,
+					points = { { beat = 0, bpm = 120, ramp = true }, { beat = 16, bpm = 90 } },
+					signatures = { { beat = 0, numerator = 3, denominator = 4 } },
+				},
+				midi_map = {
+					{ controller = 74, channel = 1, device = 1, index = 2, min = 0, max = 1, mode = "twos_complement" },
+				},
 			}
             load_project(project)
         "#,
@@ -186,13 +479,86 @@ mod tests {
 			channels: Vec::new(),
 			name: "Example Project".into(),
 			version: Version { major: 1, minor: 0, patch: 0 },
-			transport: Transport { recording: false, start_time: 0.0 },
+			transport: Transport {
+				recording: false,
+				start_time: 0.0,
+				looping: false,
+				loop_start: 0.0,
+				loop_end: 4.0,
+				punch: false,
+				punch_in: 0.0,
+				punch_out: 4.0,
+			},
+			tempo: Tempo::default(),
+			midi_map: Vec::new(),
 		};
 		lua.globals().set("p", project)?;
 
 		Ok(())
 	}
 
+	#[test]
+	fn test_channel_from_lua() -> LuaResult<()> {
+		let lua = Lua::new();
+		lua.load(
+			r#"
+			channel = {
+				name = "Channel",
+				hue = 120,
+				gain = 0.5,
+				visible = true,
+				mute = false,
+				armed = true,
+				lock = false,
+				instrument = { name = "sine", state = { true, 523.25, -24, false } },
+				effects = {
+					{ name = "gain", state = { 0.0 }, mute = true },
+					{ name = "compressor", state = { 1.0, -24, 4, 0.012, 0.15, 0 }, sidechain = 2 },
+				},
+				notes = {
+					{ time = 1.0, interval = { 0, 1, 0 }, vel = 0.5, verts = { { 0, 0, 0.1 }, { 1, 0, 0 } } },
+				},
+				control = {},
+				clips = {
+					{ path = "take.wav", start = 2, length = 1.5, fade_out = 0.1 },
+				},
+				output = 3,
+				automation = {
+					{ kind = "parameter", device = 1, index = 2, points = { { time = 0, value = 1 }, { time = 1, value = 0.5, shape = "hold" } } },
+					{ kind = "gain", points = {} },
+				},
+				sends = {
+					{ target = 4, gain = 0.25, pre_fader = true },
+					{ target = 5 },
+				},
+			}
+        "#,
+		)
+		.exec()?;
+
+		let channel: Channel = lua.from_value(lua.globals().get("channel")?)?;
+		assert!(!channel.master);
+		assert_eq!(channel.gain, 0.5);
+		assert_eq!(channel.instrument.unwrap().state, vec![1.0, 523.25, -24.0, 0.0]);
+		assert!(channel.effects[0].mute);
+		assert_eq!(channel.effects[0].sidechain, None);
+		assert_eq!(channel.effects[1].sidechain, Some(2));
+		assert_eq!(channel.notes[0].interval, vec![0, 1, 0]);
+		assert!(channel.control.sustain.is_empty());
+		assert_eq!(channel.clips[0].start, 2.0);
+		assert_eq!(channel.clips[0].offset, 0.0);
+		assert_eq!(channel.clips[0].gain, 1.0);
+		assert_eq!(channel.output, Some(3));
+		assert_eq!(channel.automation[0].kind, LaneKind::Parameter);
+		assert_eq!(channel.automation[0].points[0].shape, Shape::Linear);
+		assert_eq!(channel.automation[0].points[1].shape, Shape::Hold);
+		assert_eq!(channel.automation[1].kind, LaneKind::Gain);
+		assert!(channel.sends[0].pre_fader);
+		assert_eq!(channel.sends[1].gain, 1.0);
+		assert!(!channel.sends[1].pre_fader);
+		Ok(())
+	}
+
 	#[test]
 	fn test_vertex_to_lua() -> LuaResult<()> {
 		let lua = Lua::new();
@@ -3,17 +3,21 @@ use crate::audio::AUDIO_PANIC;
 use crate::context::{AudioContext, LuaMessage};
 use crate::log::*;
 use crate::midi;
+use crate::midi_input::LiveInput;
+use crate::offline::OfflineRender;
 use crate::opengl::{Renderer, UserEvent};
 use crate::text::{Font, TextEngine};
 use crate::voice_manager::Token;
 use crate::vst3::Vst3Editor;
 use femtovg::{Canvas, Color, ImageId, Path};
+use parking_lot::Mutex;
 use semver::Version;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::mpsc::{Receiver, SyncSender};
-use std::sync::{LazyLock, OnceLock, RwLock, atomic::Ordering, mpsc};
+use std::sync::{LazyLock, OnceLock, RwLock, mpsc};
 use std::time::Instant;
 use winit::event_loop::EventLoopProxy;
 use winit::window::Window;
@@ -40,13 +44,21 @@ pub struct State {
 	pub transform_stack: Vec<femtovg::Transform2D>,
 	pub current_scissor: Option<(f32, f32, f32, f32)>,
 	pub audio: Option<AudioContext>,
+	pub offline: Option<OfflineRender>,
 	pub project: Option<Project>,
 	pub canvas: Canvas<Renderer>,
 	pub window: Window,
 	pub scale_factor: f32,
 	pub dialog_rx: Option<mpsc::Receiver<Option<PathBuf>>>,
 	pub midi_session: Option<midir::MidiInput>,
-	pub midi_connections: Vec<midi::Connection>,
+	pub midi_inputs: midi::InputPorts,
+	// Where midi input plays, shared with all input ports
+	pub live_input: Arc<Mutex<LiveInput>>,
+	pub midi_output_session: Option<midir::MidiOutput>,
+	pub midi_outputs: midi::OutputPorts,
+	// Names of the virtual input and output ports we offer to other applications
+	pub virtual_inputs: Vec<String>,
+	pub virtual_outputs: Vec<String>,
 	pub vst_editors: HashMap<usize, Vst3Editor>,
 	pub vst_windows: HashMap<WindowId, (usize, Arc<Window>)>,
 	pub vst_cleanup_tx: mpsc::SyncSender<usize>,
@@ -54,7 +66,8 @@ pub struct State {
 	pub event_loop: EventLoopProxy<UserEvent>,
 	pub lua_tx: SyncSender<LuaMessage>,
 	pub lua_rx: Receiver<LuaMessage>,
-	token: Token,
+	// Shared with midi input, which hands out tokens for incoming notes
+	pub tokens: Arc<AtomicU32>,
 }
 
 impl State {
@@ -82,14 +95,20 @@ impl State {
 			transform_stack: Vec::new(),
 			current_scissor: None,
 			audio: None,
+			offline: None,
 			project: None,
-			token: 0,
+			tokens: Arc::new(AtomicU32::new(0)),
 			canvas,
 			window,
 			scale_factor,
 			dialog_rx: None,
 			midi_session: None,
-			midi_connections: Vec::new(),
+			midi_inputs: midi::InputPorts::default(),
+			live_input: Arc::new(Mutex::new(LiveInput::default())),
+			midi_output_session: None,
+			midi_outputs: midi::OutputPorts::default(),
+			virtual_inputs: Vec::new(),
+			virtual_outputs: Vec::new(),
 			vst_editors: HashMap::new(),
 			vst_windows: HashMap::new(),
 			vst_cleanup_tx,
@@ -108,9 +127,8 @@ impl State {
 		}
 	}
 
-	pub fn next_token(&mut self) -> Token {
-		self.token = self.token.wrapping_add(1);
-		self.token
+	pub fn next_token(&self) -> Token {
+		self.tokens.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
 	}
 }
 
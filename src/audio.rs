@@ -16,6 +16,7 @@ use std::str::FromStr;
 use std::sync::{Arc, atomic, atomic::AtomicBool};
 
 use crate::api::audio::DeviceInfo;
+use crate::clock::SampleClock;
 use crate::context::{ErrorMessage, LuaMessage};
 use crate::dsp::atomic_float::AtomicFloat;
 use crate::dsp::env::AttackRelease;
@@ -72,6 +73,22 @@ pub fn get_output_devices(host_str: &str) -> Result<Vec<DeviceInfo>> {
 	Ok(devices)
 }
 
+pub fn get_input_devices(host_str: &str) -> Result<Vec<DeviceInfo>> {
+	let host_id = HostId::from_str(host_str)?;
+	let host = cpal::host_from_id(host_id)?;
+
+	let mut devices = Vec::new();
+
+	for device in host.input_devices()? {
+		match DeviceInfo::from_device(&device) {
+			Ok(device_info) => devices.push(device_info),
+			Err(e) => log_error!("Failed to get device info: {}", e),
+		}
+	}
+
+	Ok(devices)
+}
+
 pub fn get_default_output_device(host_str: &str) -> Result<DeviceInfo> {
 	let host_id = HostId::from_str(host_str)?;
 	let host = cpal::host_from_id(host_id)?;
@@ -85,6 +102,15 @@ pub fn get_default_output_device(host_str: &str) -> Result<DeviceInfo> {
 
 // search output device by id
 pub fn find_output_device(info: &DeviceInfo) -> Result<Device> {
+	find_device(info, "output")
+}
+
+// search input device by id
+pub fn find_input_device(info: &DeviceInfo) -> Result<Device> {
+	find_device(info, "input")
+}
+
+fn find_device(info: &DeviceInfo, kind: &str) -> Result<Device> {
 	let device_id = DeviceId::from_str(&info.id)?;
 
 	let host_id = device_id.0;
@@ -93,8 +119,9 @@ pub fn find_output_device(info: &DeviceInfo) -> Result<Device> {
 	match host.device_by_id(&device_id) {
 		Some(device) => {
 			log_info!(
-				"Using: host: \"{}\", output device: \"{}\" ({})",
+				"Using: host: \"{}\", {} device: \"{}\" ({})",
 				host.id().name(),
+				kind,
 				info.name,
 				info.id
 			);
@@ -154,11 +181,18 @@ pub fn build_config(
 ) -> Result<(StreamConfig, SampleFormat)> {
 	let supported_configs = device.supported_output_configs()?;
 
+	// At least stereo comes first, a mono config with a better sample format should not win.
+	// After that prefer more channels, so every output of the interface can be used.
+	let stereo = |c: &SupportedStreamConfigRange| c.channels() >= 2;
 	let best_config = supported_configs
-		.filter(|c| c.channels() == 2) // only stereo
 		// .inspect(|c| println!("{c:?}"))
-		.max_by(config_cmp)
-		.ok_or_else(|| anyhow!("No supported stereo configuration found on this device."))?;
+		.max_by(|a, b| {
+			stereo(a)
+				.cmp(&stereo(b))
+				.then_with(|| config_cmp(a, b))
+				.then_with(|| a.channels().cmp(&b.channels()))
+		})
+		.ok_or_else(|| anyhow!("No supported configuration found on this device."))?;
 
 	let min_rate = best_config.min_sample_rate();
 	let max_rate = best_config.max_sample_rate();
@@ -181,7 +215,7 @@ pub fn build_config(
 		BufferSize::Default
 	};
 
-	let config = StreamConfig { channels: 2, sample_rate, buffer_size };
+	let config = StreamConfig { channels: best_config.channels(), sample_rate, buffer_size };
 
 	log_info!(
 		"Selected config: channels: {}, sample rate: {}Hz, buffer size: {:?}, format: {:?}",
@@ -194,33 +228,128 @@ pub fn build_config(
 	Ok((config, best_config.sample_format()))
 }
 
+// Input config has to run at the same rate as the output stream.
+// Mono or stereo preferred, for more channels we only take the first two.
+pub fn build_input_config(
+	device: &cpal::Device,
+	sample_rate: u32,
+) -> Result<(StreamConfig, SampleFormat)> {
+	let best_config = device
+		.supported_input_configs()?
+		.filter(|c| c.min_sample_rate() <= sample_rate && c.max_sample_rate() >= sample_rate)
+		.max_by(|a, b| {
+			(a.channels() <= 2)
+				.cmp(&(b.channels() <= 2))
+				.then_with(|| config_cmp(a, b))
+		})
+		.ok_or_else(|| anyhow!("No input configuration found with sample rate {sample_rate}Hz."))?;
+
+	let config = StreamConfig {
+		channels: best_config.channels(),
+		sample_rate,
+		buffer_size: BufferSize::Default,
+	};
+
+	log_info!(
+		"Selected input config: channels: {}, sample rate: {}Hz, buffer size: {:?}, format: {:?}",
+		config.channels,
+		config.sample_rate,
+		config.buffer_size,
+		best_config.sample_format(),
+	);
+
+	Ok((config, best_config.sample_format()))
+}
+
 pub fn build_stream(
 	device: &Device,
 	config: &StreamConfig,
 	format: SampleFormat,
 	render: Arc<Mutex<Render>>,
-) -> Result<(Stream, HeapProd<bool>, HeapCons<ErrorMessage>)> {
-	let (stream_tx, stream_rx) = HeapRb::<bool>::new(8).split();
+	clock: Arc<SampleClock>,
+) -> Result<(Stream, HeapCons<ErrorMessage>)> {
 	let (error_tx, error_rx) = HeapRb::<ErrorMessage>::new(8).split();
 
 	use SampleFormat::*;
 	let stream = match format {
-		F64 => build_stream_inner::<f64>(device, config, render, stream_rx, error_tx),
-		F32 => build_stream_inner::<f32>(device, config, render, stream_rx, error_tx),
-		I64 => build_stream_inner::<i64>(device, config, render, stream_rx, error_tx),
-		U64 => build_stream_inner::<u64>(device, config, render, stream_rx, error_tx),
-		I32 => build_stream_inner::<i32>(device, config, render, stream_rx, error_tx),
-		U32 => build_stream_inner::<u32>(device, config, render, stream_rx, error_tx),
-		I16 => build_stream_inner::<i16>(device, config, render, stream_rx, error_tx),
-		U16 => build_stream_inner::<u16>(device, config, render, stream_rx, error_tx),
-		I24 => build_stream_inner::<cpal::I24>(device, config, render, stream_rx, error_tx),
+		F64 => build_stream_inner::<f64>(device, config, render, clock, error_tx),
+		F32 => build_stream_inner::<f32>(device, config, render, clock, error_tx),
+		I64 => build_stream_inner::<i64>(device, config, render, clock, error_tx),
+		U64 => build_stream_inner::<u64>(device, config, render, clock, error_tx),
+		I32 => build_stream_inner::<i32>(device, config, render, clock, error_tx),
+		U32 => build_stream_inner::<u32>(device, config, render, clock, error_tx),
+		I16 => build_stream_inner::<i16>(device, config, render, clock, error_tx),
+		U16 => build_stream_inner::<u16>(device, config, render, clock, error_tx),
+		I24 => build_stream_inner::<cpal::I24>(device, config, render, clock, error_tx),
 		f => Err(anyhow!("Unsupported sample format '{f}'")),
 	}?;
 
 	// immediately start the stream
 	stream.play()?;
 
-	Ok((stream, stream_tx, error_rx))
+	Ok((stream, error_rx))
+}
+
+// Captured audio is sent as interlaced stereo frames over `input_tx`
+pub fn build_input_stream(
+	device: &Device,
+	config: &StreamConfig,
+	format: SampleFormat,
+	input_tx: HeapProd<f32>,
+) -> Result<(Stream, HeapCons<ErrorMessage>)> {
+	let (error_tx, error_rx) = HeapRb::<ErrorMessage>::new(8).split();
+
+	use SampleFormat::*;
+	let stream = match format {
+		F64 => build_input_stream_inner::<f64>(device, config, input_tx, error_tx),
+		F32 => build_input_stream_inner::<f32>(device, config, input_tx, error_tx),
+		I64 => build_input_stream_inner::<i64>(device, config, input_tx, error_tx),
+		U64 => build_input_stream_inner::<u64>(device, config, input_tx, error_tx),
+		I32 => build_input_stream_inner::<i32>(device, config, input_tx, error_tx),
+		U32 => build_input_stream_inner::<u32>(device, config, input_tx, error_tx),
+		I16 => build_input_stream_inner::<i16>(device, config, input_tx, error_tx),
+		U16 => build_input_stream_inner::<u16>(device, config, input_tx, error_tx),
+		I24 => build_input_stream_inner::<cpal::I24>(device, config, input_tx, error_tx),
+		f => Err(anyhow!("Unsupported sample format '{f}'")),
+	}?;
+
+	stream.play()?;
+
+	Ok((stream, error_rx))
+}
+
+fn build_input_stream_inner<T>(
+	device: &Device,
+	config: &StreamConfig,
+	mut input_tx: HeapProd<f32>,
+	error_tx: HeapProd<ErrorMessage>,
+) -> Result<Stream>
+where
+	T: 'static + cpal::SizedSample,
+	f32: cpal::FromSample<T>,
+{
+	let channels = usize::from(config.channels);
+	let convert = |x: T| <f32 as cpal::Sample>::from_sample(x);
+
+	let input_closure = move |cpal_buffer: &[T], _: &cpal::InputCallbackInfo| {
+		assert_no_alloc(|| {
+			for frame in cpal_buffer.chunks_exact(channels) {
+				let l = convert(frame[0]);
+				let r = if channels > 1 { convert(frame[1]) } else { l };
+
+				// If render is not keeping up, just drop the frame
+				if input_tx.vacant_len() >= 2 {
+					let _ = input_tx.try_push(l);
+					let _ = input_tx.try_push(r);
+				}
+			}
+		});
+	};
+
+	let stream =
+		device.build_input_stream(*config, input_closure, error_closure(error_tx), None)?;
+
+	Ok(stream)
 }
 
 pub fn open_control_panel(device: &Device) {
@@ -244,13 +373,13 @@ pub fn build_stream_inner<T>(
 	device: &Device,
 	config: &StreamConfig,
 	render: Arc<Mutex<Render>>,
-	stream_rx: HeapCons<bool>,
+	clock: Arc<SampleClock>,
 	error_tx: HeapProd<ErrorMessage>,
 ) -> Result<Stream>
 where
 	T: 'static + cpal::SizedSample + cpal::FromSample<f32>,
 {
-	let audio_closure = build_closure::<T>(stream_rx, render);
+	let audio_closure = build_closure::<T>(render, clock, usize::from(config.channels));
 
 	let stream =
 		device.build_output_stream(*config, audio_closure, error_closure(error_tx), None)?;
@@ -259,16 +388,18 @@ where
 }
 
 fn build_closure<T>(
-	mut stream_rx: HeapCons<bool>,
 	render: Arc<Mutex<Render>>,
+	clock: Arc<SampleClock>,
+	channels: usize,
 ) -> impl FnMut(&mut [T], &cpal::OutputCallbackInfo)
 where
 	T: cpal::Sample + cpal::FromSample<f32>,
 {
 	// Callback data
 	let mut start = false;
-	let mut is_rendering = false;
 	let mut process_buffer = [[0.0f32; MAX_BUF_SIZE]; 2];
+	// Interlaced with the device channel count, where all output pairs get mixed
+	let mut mix_buffer = vec![0.0f32; MAX_BUF_SIZE * channels];
 	let mut cpu_load = AttackRelease::new_direct(0.05, 0.01);
 
 	move |cpal_buffer: &mut [T], _: &cpal::OutputCallbackInfo| {
@@ -276,14 +407,18 @@ where
 			assert_no_alloc(|| {
 				enable_fpu_traps();
 
-				assert!(cpal_buffer.len().is_multiple_of(2));
-				let buffer_size = cpal_buffer.len() / 2;
+				assert!(cpal_buffer.len().is_multiple_of(channels));
+				let buffer_size = cpal_buffer.len() / channels;
 				match render.try_lock() {
-					Some(mut render) if !is_rendering => {
+					Some(mut render) => {
 						if !start {
 							start = true;
 							let sample_rate = render.sample_rate;
-							render.send(LuaMessage::StreamSettings { buffer_size, sample_rate });
+							render.send(LuaMessage::StreamSettings {
+								buffer_size,
+								sample_rate,
+								output_channels: channels,
+							});
 
 							// init fastrand on this thread (allocates)
 							permit_alloc(|| {
@@ -292,17 +427,13 @@ where
 						}
 
 						let time = std::time::Instant::now();
+						clock.update(render.frames(), buffer_size, render.sample_rate as u32);
 
 						// parse all messages
-						for m in stream_rx.pop_iter() {
-							is_rendering = m;
-						}
 						render.parse_messages();
 
-						for buffer_chunk in cpal_buffer.chunks_mut(MAX_BUF_SIZE * 2) {
-							assert!(buffer_chunk.len().is_multiple_of(2));
-
-							let chunk_size = buffer_chunk.len() / 2;
+						for buffer_chunk in cpal_buffer.chunks_mut(MAX_BUF_SIZE * channels) {
+							let chunk_size = buffer_chunk.len() / channels;
 							let (l, r) = process_buffer.split_at_mut(1);
 							let buf_slice = &mut [&mut l[0][..chunk_size], &mut r[0][..chunk_size]];
 
@@ -312,10 +443,19 @@ where
 								});
 							};
 
-							// interlace and convert
-							for (i, outsample) in buffer_chunk.chunks_exact_mut(2).enumerate() {
-								outsample[0] = T::from_sample(buf_slice[0][i]);
-								outsample[1] = T::from_sample(buf_slice[1][i]);
+							let mix = &mut mix_buffer[..buffer_chunk.len()];
+							mix.fill(0.0);
+							if let Some(pair) = render.master_output() {
+								mix_pair(mix, channels, pair, buf_slice[0], buf_slice[1]);
+							}
+							for (pair, bus) in render.extra_outputs() {
+								let [bl, br] = bus;
+								mix_pair(mix, channels, pair, &bl[..chunk_size], &br[..chunk_size]);
+							}
+
+							// convert
+							for (outsample, s) in buffer_chunk.iter_mut().zip(mix.iter()) {
+								*outsample = T::from_sample(*s);
 							}
 						}
 
@@ -326,13 +466,8 @@ where
 						let load = cpu_load.process();
 						CPU_LOAD.store(load);
 					},
-					_ => {
+					None => {
 						// Output silence as a fallback when lock fails.
-
-						for m in stream_rx.pop_iter() {
-							is_rendering = m;
-						}
-						// Output silence
 						cpal_buffer.fill(T::EQUILIBRIUM);
 					},
 				}
@@ -355,6 +490,28 @@ where
 	}
 }
 
+// Add a stereo signal to output pair `pair` of an interlaced buffer.
+// Pairs that the device does not have are dropped, mono devices get a downmix of the first pair.
+fn mix_pair(mix: &mut [f32], channels: usize, pair: usize, left: &[f32], right: &[f32]) {
+	if channels == 1 {
+		if pair == 0 {
+			for (m, (l, r)) in mix.iter_mut().zip(left.iter().zip(right)) {
+				*m += 0.5 * (l + r);
+			}
+		}
+		return;
+	}
+
+	let i = 2 * pair;
+	if i + 1 >= channels {
+		return;
+	}
+	for (frame, (l, r)) in mix.chunks_exact_mut(channels).zip(left.iter().zip(right)) {
+		frame[i] += l;
+		frame[i + 1] += r;
+	}
+}
+
 pub fn die() {
 	AUDIO_PANIC.store(true, atomic::Ordering::Relaxed);
 }
@@ -378,30 +535,6 @@ fn error_closure(mut error_tx: HeapProd<ErrorMessage>) -> impl FnMut(cpal::Error
 	}
 }
 
-pub fn write_wav(filename: &str, samples: &[f32], sample_rate: u32) -> Result<()> {
-	let spec = hound::WavSpec {
-		channels: 2,
-		sample_rate,
-		bits_per_sample: 16,
-		sample_format: hound::SampleFormat::Int,
-	};
-
-	let mut writer = hound::WavWriter::create(filename, spec)?;
-	for s in samples {
-		writer.write_sample(convert_sample_wav(*s))?;
-	}
-	writer.finalize()?;
-
-	Ok(())
-}
-
-fn convert_sample_wav(x: f32) -> i16 {
-	// TPDF dither in range [-1, 1] quantization levels
-	let dither = (fastrand::f32() - fastrand::f32()) / f32::from(u16::MAX);
-	let x = (x + dither).clamp(-1.0, 1.0);
-	(if x >= 0.0 { x * f32::from(i16::MAX) } else { -x * f32::from(i16::MIN) }) as i16
-}
-
 #[cfg(all(target_arch = "x86_64", feature = "fpu_traps"))]
 #[allow(deprecated)]
 fn enable_fpu_traps() {
@@ -0,0 +1,178 @@
+use crate::api::project::{self, LaneKind, Shape};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+	// 0-based parameter index
+	Parameter { device: usize, index: usize },
+	Gain,
+	Mute,
+}
+
+#[derive(Debug)]
+struct Point {
+	// Position in samples
+	time: usize,
+	value: f32,
+	shape: Shape,
+}
+
+// Automation lane with times converted to samples
+#[derive(Debug)]
+pub struct Lane {
+	pub target: Target,
+	points: Vec<Point>,
+	// Last value that was applied, so we only send changes
+	last: Option<f32>,
+	// Set while the lane is being recorded, so the user has control
+	pub bypass: bool,
+}
+
+impl Lane {
+	pub fn new(lane: &project::Lane, sample_rate: f32) -> Self {
+		let target = match lane.kind {
+			LaneKind::Parameter => {
+				Target::Parameter { device: lane.device, index: lane.index.saturating_sub(1) }
+			},
+			LaneKind::Gain => Target::Gain,
+			LaneKind::Mute => Target::Mute,
+		};
+
+		let mut points: Vec<Point> = lane
+			.points
+			.iter()
+			.map(|p| Point {
+				time: (p.time.max(0.0) * f64::from(sample_rate)).round() as usize,
+				value: p.value as f32,
+				// Mute is a switch, so never interpolate it
+				shape: if lane.kind == LaneKind::Mute { Shape::Hold } else { p.shape },
+			})
+			.collect();
+		points.sort_by_key(|p| p.time);
+
+		Self { target, points, last: None, bypass: false }
+	}
+
+	pub fn value_at(&self, position: usize) -> Option<f32> {
+		// Index of the first point after position
+		let next = self.points.partition_point(|p| p.time <= position);
+
+		let Some(a) = next.checked_sub(1).map(|i| &self.points[i]) else {
+			return self.points.first().map(|p| p.value);
+		};
+		let Some(b) = self.points.get(next) else {
+			return Some(a.value);
+		};
+
+		let t = (position - a.time) as f32 / (b.time - a.time) as f32;
+		let value = match a.shape {
+			Shape::Hold => a.value,
+			Shape::Linear => a.value + t * (b.value - a.value),
+			Shape::Exponential if a.value > 0.0 && b.value > 0.0 => {
+				a.value * (b.value / a.value).powf(t)
+			},
+			// Exponential segments through zero or a sign change fall back to linear
+			Shape::Exponential => a.value + t * (b.value - a.value),
+		};
+		Some(value)
+	}
+
+	// Returns the value if it changed since the last call
+	pub fn update(&mut self, position: usize) -> Option<f32> {
+		if self.bypass {
+			self.last = None;
+			return None;
+		}
+		let value = self.value_at(position)?;
+		if self.last == Some(value) {
+			return None;
+		}
+		self.last = Some(value);
+		Some(value)
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct AutomationList {
+	pub lanes: Vec<Lane>,
+	// Lanes as received from Lua, so they can be converted again at another sample rate
+	source: Vec<project::Lane>,
+}
+
+impl AutomationList {
+	pub fn new(lanes: &[project::Lane], sample_rate: f32) -> Self {
+		Self {
+			lanes: lanes.iter().map(|l| Lane::new(l, sample_rate)).collect(),
+			source: lanes.to_vec(),
+		}
+	}
+
+	pub fn with_sample_rate(&self, sample_rate: f32) -> Self {
+		Self::new(&self.source, sample_rate)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use project::AutomationPoint;
+
+	fn lane(kind: LaneKind, points: &[(f64, f64, Shape)]) -> Lane {
+		let points = points
+			.iter()
+			.map(|&(time, value, shape)| AutomationPoint { time, value, shape })
+			.collect();
+		Lane::new(&project::Lane { kind, device: 0, index: 1, points }, 1000.0)
+	}
+
+	#[test]
+	fn test_ends() {
+		let lane = lane(LaneKind::Gain, &[(1.0, 0.5, Shape::Linear), (2.0, 1.0, Shape::Linear)]);
+		// Holds the first value before the first point, and the last one after the last
+		assert_eq!(lane.value_at(0), Some(0.5));
+		assert_eq!(lane.value_at(999), Some(0.5));
+		assert_eq!(lane.value_at(2000), Some(1.0));
+		assert_eq!(lane.value_at(10_000), Some(1.0));
+
+		let lane = self::lane(LaneKind::Gain, &[]);
+		assert_eq!(lane.value_at(0), None);
+	}
+
+	#[test]
+	fn test_on_point() {
+		let lane = lane(
+			LaneKind::Gain,
+			&[(0.0, 0.0, Shape::Linear), (1.0, 1.0, Shape::Hold), (2.0, 0.25, Shape::Linear)],
+		);
+		assert_eq!(lane.value_at(0), Some(0.0));
+		assert_eq!(lane.value_at(500), Some(0.5));
+		assert_eq!(lane.value_at(1000), Some(1.0));
+		assert_eq!(lane.value_at(1999), Some(1.0));
+		assert_eq!(lane.value_at(2000), Some(0.25));
+	}
+
+	#[test]
+	fn test_exponential() {
+		let lane = lane(
+			LaneKind::Gain,
+			&[
+				(0.0, 1.0, Shape::Exponential),
+				(1.0, 4.0, Shape::Exponential),
+				(2.0, 0.0, Shape::Exponential),
+				(3.0, -2.0, Shape::Exponential),
+				(4.0, 2.0, Shape::Linear),
+			],
+		);
+		assert_eq!(lane.value_at(500), Some(2.0));
+		// Zero or negative endpoints fall back to linear
+		assert_eq!(lane.value_at(1500), Some(2.0));
+		assert_eq!(lane.value_at(2500), Some(-1.0));
+		assert_eq!(lane.value_at(3500), Some(0.0));
+	}
+
+	#[test]
+	fn test_mute_holds() {
+		let lane = lane(LaneKind::Mute, &[(0.0, 0.0, Shape::Linear), (1.0, 1.0, Shape::Linear)]);
+		assert_eq!(lane.value_at(999), Some(0.0));
+		assert_eq!(lane.value_at(1000), Some(1.0));
+	}
+}
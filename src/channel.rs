@@ -1,18 +1,71 @@
 use crate::audio::MAX_BUF_SIZE;
+use crate::automation::AutomationList;
+use crate::clip::ClipPlayer;
 use crate::dsp;
 use crate::dsp::PeakMeter;
+use crate::dsp::latency::LatencyDelay;
 use crate::dsp::smooth::Smooth;
 use crate::dsp::{MuteState, time_constant};
 use crate::effect::*;
+use crate::input::AudioInput;
 use crate::meters::MeterHandle;
+use crate::routing::{Route, SidechainRoute};
+use crate::sequencer::Sequencer;
 use crate::voice_manager::VoiceManager;
+use crate::worker::RequestData;
+
+// Stereo buffer that other channels sum into
+pub type Bus = [[f32; MAX_BUF_SIZE]; 2];
+
+pub struct Send {
+	target: usize,
+	pre_fader: bool,
+	gain: Smooth,
+	// Latency compensation for this path
+	delay: LatencyDelay,
+}
+
+// Route of a channel with its sends built, so the audio thread can swap it in without allocating
+pub struct PreparedRoute {
+	output: usize,
+	sends: Vec<Send>,
+	sidechains: Vec<SidechainRoute>,
+}
+
+impl PreparedRoute {
+	pub fn new(route: &Route, sample_rate: f32) -> Self {
+		let sends = route
+			.sends
+			.iter()
+			.map(|s| Send {
+				target: s.target,
+				pre_fader: s.pre_fader,
+				gain: Smooth::new(s.gain, 25., sample_rate),
+				delay: LatencyDelay::new(),
+			})
+			.collect();
+		Self { output: route.output, sends, sidechains: route.sidechains.clone() }
+	}
+}
 
 pub struct Channel {
 	pub instrument: Option<VoiceManager>,
+	pub input: Option<AudioInput>,
+	pub clips: ClipPlayer,
+	pub sequencer: Sequencer,
+	pub automation: Box<AutomationList>,
 	pub effects: Vec<Bypass>,
+	// Index of the channel we sum into
+	pub output: usize,
+	sends: Vec<Send>,
+	// Latency compensation for our own source, and for the path to the output
+	source_delay: LatencyDelay,
+	output_delay: LatencyDelay,
 	peak: PeakMeter,
 	meter_handle: MeterHandle,
 	gain: Smooth,
+	// Level in the cue mix, taken pre-fader
+	cue: Smooth,
 
 	mute: bool,
 	state: MuteState,
@@ -28,10 +81,19 @@ impl Channel {
 	) -> Self {
 		Self {
 			instrument,
+			input: None,
+			clips: ClipPlayer::default(),
+			sequencer: Sequencer::new(),
+			automation: Box::default(),
 			effects: Vec::new(),
+			output: 0,
+			sends: Vec::new(),
+			source_delay: LatencyDelay::new(),
+			output_delay: LatencyDelay::new(),
 			peak: PeakMeter::new(sample_rate),
 			meter_handle,
 			gain: Smooth::new(1., 25., sample_rate),
+			cue: Smooth::new(0., 25., sample_rate),
 			mute: false,
 			state: MuteState::Active,
 			value: 1.0,
@@ -39,50 +101,71 @@ impl Channel {
 		}
 	}
 
-	pub fn process(&mut self, buffer_in: &mut [&mut [f32]; 2], buffer_out: &mut [&mut [f32]; 2]) {
+	// Fill buffer with the channel source: instrument or live input, plus clips
+	pub fn process_instrument(
+		&mut self,
+		buffer: &mut [&mut [f32]; 2],
+		input: &[[f32; MAX_BUF_SIZE]; 2],
+		position: Option<usize>,
+	) {
 		if let Some(instrument) = &mut self.instrument {
-			buffer_in[0].fill(0.0);
-			buffer_in[1].fill(0.0);
-			instrument.process(buffer_in);
+			buffer[0].fill(0.0);
+			buffer[1].fill(0.0);
+			instrument.process(buffer);
+		} else if let Some(audio_input) = &mut self.input {
+			audio_input.process(input, buffer);
+		} else {
+			buffer[0].fill(0.0);
+			buffer[1].fill(0.0);
+		}
+
+		if let Some(position) = position {
+			self.clips.process(buffer, position);
 		}
+
+		self.source_delay.process(buffer);
+	}
+
+	pub fn process_effects(&mut self, buffer: &mut [&mut [f32]; 2], outputs: &[Bus]) {
 		for fx in &mut self.effects {
-			fx.process(buffer_in);
+			fx.process(buffer, outputs);
 		}
+	}
 
+	// Apply fader and mute in place
+	pub fn fader(&mut self, buffer: &mut [&mut [f32]; 2]) {
 		match self.state {
 			MuteState::Off => {
+				buffer[0].fill(0.0);
+				buffer[1].fill(0.0);
 				self.meter_handle.set([0., 0.]);
 			},
 			MuteState::Active => {
-				let samples = buffer_in[0].len();
+				let samples = buffer[0].len();
 				assert!(samples <= MAX_BUF_SIZE);
 				for i in 0..samples {
 					let gain = self.gain.process();
-					buffer_in[0][i] *= gain;
-					buffer_in[1][i] *= gain;
-					buffer_out[0][i] += buffer_in[0][i];
-					buffer_out[1][i] += buffer_in[1][i];
+					buffer[0][i] *= gain;
+					buffer[1][i] *= gain;
 				}
 
-				let peak = self.peak.process_block(buffer_in);
+				let peak = self.peak.process_block(buffer);
 				self.meter_handle.set(peak);
 			},
 			MuteState::Transition => {
 				let target = if self.mute { 0.0 } else { 1.0 };
 
-				let samples = buffer_in[0].len();
+				let samples = buffer[0].len();
 				assert!(samples <= MAX_BUF_SIZE);
 
 				for i in 0..samples {
 					self.value += self.smoothing_f * (target - self.value);
 					let gain = self.value * self.gain.process();
-					buffer_in[0][i] *= gain;
-					buffer_in[1][i] *= gain;
-					buffer_out[0][i] += buffer_in[0][i];
-					buffer_out[1][i] += buffer_in[1][i];
+					buffer[0][i] *= gain;
+					buffer[1][i] *= gain;
 				}
 
-				let peak = dsp::peak(buffer_in);
+				let peak = dsp::peak(buffer);
 				self.meter_handle.set(peak);
 
 				// Check state transition
@@ -98,6 +181,110 @@ impl Channel {
 		}
 	}
 
+	// Add the signal to the send targets. Called once before and once after the fader.
+	pub fn process_sends(&mut self, buffer: &[&mut [f32]; 2], buses: &mut [Bus], pre_fader: bool) {
+		let samples = buffer[0].len();
+		for send in &mut self.sends {
+			if send.pre_fader != pre_fader {
+				continue;
+			}
+			let bus = &mut buses[send.target];
+			for i in 0..samples {
+				let gain = send.gain.process();
+				let [l, r] = send.delay.tick([buffer[0][i], buffer[1][i]]);
+				bus[0][i] += l * gain;
+				bus[1][i] += r * gain;
+			}
+		}
+	}
+
+	pub fn process_cue(&mut self, buffer: &[&mut [f32]; 2], cue: &mut Bus) {
+		let samples = buffer[0].len();
+		for i in 0..samples {
+			let gain = self.cue.process();
+			cue[0][i] += buffer[0][i] * gain;
+			cue[1][i] += buffer[1][i] * gain;
+		}
+	}
+
+	// Add the post-fader signal to the bus of the output channel
+	pub fn write_output(&mut self, buffer: &[&mut [f32]; 2], bus: &mut Bus) {
+		let samples = buffer[0].len();
+		for i in 0..samples {
+			let [l, r] = self.output_delay.tick([buffer[0][i], buffer[1][i]]);
+			bus[0][i] += l;
+			bus[1][i] += r;
+		}
+	}
+
+	// Latency of the instrument, or zero for other sources
+	pub fn source_latency(&self) -> usize {
+		self.instrument.as_ref().map_or(0, |i| i.instrument.latency())
+	}
+
+	pub fn effects_latency(&self) -> usize {
+		self.effects.iter().map(Bypass::latency).sum()
+	}
+
+	// Channels that receive our output
+	pub fn targets(&self) -> impl Iterator<Item = usize> {
+		std::iter::once(self.output).chain(self.sends.iter().map(|s| s.target))
+	}
+
+	// `input` is the latency this channel aligns its inputs to, `arrival` holds that for every channel.
+	// Every path gets delayed so it arrives at its target with the target's input latency,
+	// and sidechains get delayed to line up with the signal at the effect they key.
+	pub fn set_compensation(&mut self, input: usize, arrival: &[usize], output_latency: &[usize]) {
+		self.source_delay.set_delay(input - self.source_latency());
+
+		let mut before = input;
+		for fx in &mut self.effects {
+			if let Some(source) = fx.sidechain {
+				fx.set_sidechain_delay(before.saturating_sub(output_latency[source]));
+			}
+			before += fx.latency();
+		}
+
+		let latency = input + self.effects_latency();
+		self.output_delay
+			.set_delay(arrival[self.output].saturating_sub(latency));
+		for send in &mut self.sends {
+			send.delay.set_delay(arrival[send.target].saturating_sub(latency));
+		}
+	}
+
+	// Swap in a new route. The old sends end up in `route`, so they can be dropped elsewhere.
+	pub fn set_route(&mut self, route: &mut PreparedRoute) {
+		for fx in &mut self.effects {
+			fx.sidechain = None;
+		}
+		for sidechain in &route.sidechains {
+			if let Some(fx) = self.effects.get_mut(sidechain.effect)
+				&& fx.effect.has_sidechain()
+			{
+				fx.sidechain = Some(sidechain.source);
+			}
+		}
+
+		// Keep smoothing and delay state of sends that already existed
+		for send in &mut route.sends {
+			if let Some(old) = self.sends.iter_mut().find(|o| o.target == send.target) {
+				let gain = send.gain.target();
+				std::mem::swap(&mut send.gain, &mut old.gain);
+				std::mem::swap(&mut send.delay, &mut old.delay);
+				send.gain.set(gain);
+			}
+		}
+		self.output = route.output;
+		std::mem::swap(&mut self.sends, &mut route.sends);
+	}
+
+	pub fn set_send_gain(&mut self, send_index: usize, gain: f32) {
+		if let Some(send) = self.sends.get_mut(send_index) {
+			send.gain.set(gain);
+		}
+	}
+
 	pub fn set_mute(&mut self, mute: bool) {
 		self.mute = mute;
 		self.state = MuteState::Transition;
@@ -106,4 +293,44 @@ impl Channel {
 	pub fn set_gain(&mut self, gain: f32) {
 		self.gain.set(gain);
 	}
+
+	pub fn set_cue_gain(&mut self, gain: f32) {
+		self.cue.set(gain);
+	}
+
+	// Recreate all devices at the new rate, keeping parameters, routing and automation.
+	// Returns the data devices need to load again, by device index.
+	pub fn set_sample_rate(&mut self, sample_rate: f32) -> Vec<(usize, RequestData)> {
+		let mut requests = Vec::new();
+		if let Some(instrument) = &mut self.instrument {
+			// Before the instrument gets rebuilt, so its notes are released
+			self.sequencer.stop(instrument);
+			requests.extend(instrument.set_sample_rate(sample_rate).into_iter().map(|r| (0, r)));
+		}
+		if let Some(input) = &mut self.input {
+			input.set_sample_rate(sample_rate);
+		}
+		for (i, fx) in self.effects.iter_mut().enumerate() {
+			requests.extend(fx.set_sample_rate(sample_rate).into_iter().map(|r| (i + 1, r)));
+		}
+
+		self.sequencer.set_sample_rate(sample_rate);
+		self.automation = Box::new(self.automation.with_sample_rate(sample_rate));
+
+		for send in &mut self.sends {
+			send.gain = Smooth::new(send.gain.target(), 25., sample_rate);
+			send.delay = LatencyDelay::new();
+		}
+		self.source_delay = LatencyDelay::new();
+		self.output_delay = LatencyDelay::new();
+		self.peak = PeakMeter::new(sample_rate);
+		self.gain = Smooth::new(self.gain.target(), 25., sample_rate);
+		self.cue = Smooth::new(self.cue.target(), 25., sample_rate);
+		self.smoothing_f = time_constant(15.0, sample_rate);
+		if self.mute {
+			self.state = MuteState::Off;
+			self.value = 0.0;
+		}
+		requests
+	}
 }
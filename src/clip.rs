@@ -0,0 +1,95 @@
+use crate::api::project::Clip;
+use std::sync::Arc;
+
+// Clip with all times converted to samples at the stream rate
+#[derive(Debug)]
+pub struct ClipData {
+	samples: Arc<[Vec<f32>; 2]>,
+	start: usize,
+	offset: usize,
+	length: usize,
+	gain: f32,
+	fade_in: usize,
+	fade_out: usize,
+}
+
+impl ClipData {
+	// `samples` should already be resampled to `sample_rate`
+	pub fn new(clip: &Clip, samples: Arc<[Vec<f32>; 2]>, sample_rate: f32) -> Self {
+		let to_samples = |t: f64| (t.max(0.0) * f64::from(sample_rate)).round() as usize;
+
+		let offset = to_samples(clip.offset);
+		let available = samples[0].len().saturating_sub(offset);
+		let length = to_samples(clip.length).min(available);
+
+		Self {
+			samples,
+			start: to_samples(clip.start),
+			offset,
+			length,
+			gain: clip.gain as f32,
+			fade_in: to_samples(clip.fade_in),
+			fade_out: to_samples(clip.fade_out),
+		}
+	}
+
+	fn end(&self) -> usize {
+		self.start + self.length
+	}
+
+	// Linear fades, overlapping fades multiply
+	fn fade(&self, t: usize) -> f32 {
+		let mut g = 1.0;
+		if t < self.fade_in {
+			g *= t as f32 / self.fade_in as f32;
+		}
+		let remaining = self.length - t;
+		if remaining < self.fade_out {
+			g *= remaining as f32 / self.fade_out as f32;
+		}
+		g
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ClipList(pub Vec<ClipData>);
+
+// Plays back audio clips of a channel.
+// Sample data is loaded by the worker, so processing never allocates.
+#[derive(Default)]
+pub struct ClipPlayer {
+	clips: Box<ClipList>,
+	// Last clips requested from the worker, so they can be loaded again at another sample rate
+	pub requested: Vec<Clip>,
+}
+
+impl ClipPlayer {
+	// Returns the old clips so they can be dropped off the audio thread
+	pub fn set_clips(&mut self, clips: Box<ClipList>) -> Box<ClipList> {
+		std::mem::replace(&mut self.clips, clips)
+	}
+
+	// Add clips playing in [position, position + len) to the buffer
+	pub fn process(&self, buffer: &mut [&mut [f32]; 2], position: usize) {
+		let len = buffer[0].len();
+		let buffer_end = position + len;
+
+		for clip in &self.clips.0 {
+			if clip.end() <= position || clip.start >= buffer_end {
+				continue;
+			}
+
+			let from = clip.start.max(position);
+			let to = clip.end().min(buffer_end);
+			for i in from..to {
+				let t = i - clip.start;
+				let src = clip.offset + t;
+				let gain = clip.gain * clip.fade(t);
+
+				let j = i - position;
+				buffer[0][j] += clip.samples[0][src] * gain;
+				buffer[1][j] += clip.samples[1][src] * gain;
+			}
+		}
+	}
+}
@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
+
+// Relates wall clock time on other threads to the sample time of the audio callback.
+// The callback stores its start time, everyone else extrapolates from there.
+pub struct SampleClock {
+	epoch: Instant,
+	// Render frame count at the start of the last callback
+	frames: AtomicU64,
+	// Start of the last callback, in nanoseconds since `epoch`
+	nanos: AtomicU64,
+	// Frames in the last callback, zero when the stream is not running
+	buffer_size: AtomicU64,
+	sample_rate: AtomicU32,
+}
+
+impl SampleClock {
+	pub fn new() -> Self {
+		Self {
+			epoch: Instant::now(),
+			frames: AtomicU64::new(0),
+			nanos: AtomicU64::new(0),
+			buffer_size: AtomicU64::new(0),
+			sample_rate: AtomicU32::new(0),
+		}
+	}
+
+	// Called by the audio callback before it renders anything
+	pub fn update(&self, frames: u64, buffer_size: usize, sample_rate: u32) {
+		let nanos = self.epoch.elapsed().as_nanos() as u64;
+		self.nanos.store(nanos, Ordering::Relaxed);
+		self.sample_rate.store(sample_rate, Ordering::Relaxed);
+		self.buffer_size.store(buffer_size as u64, Ordering::Relaxed);
+		self.frames.store(frames, Ordering::Release);
+	}
+
+	// Stream stopped, don't extrapolate from a callback that is not coming back
+	pub fn reset(&self) {
+		self.buffer_size.store(0, Ordering::Release);
+	}
+
+	// Sample time corresponding to `instant`
+	pub fn frames_at(&self, instant: Instant, sample_rate: u32) -> u64 {
+		let frames = self.frames.load(Ordering::Acquire);
+		let buffer_size = self.buffer_size.load(Ordering::Relaxed);
+		if buffer_size == 0 {
+			return 0;
+		}
+		let nanos = self.nanos.load(Ordering::Relaxed);
+		let now = instant.saturating_duration_since(self.epoch).as_nanos() as u64;
+		let elapsed = now.saturating_sub(nanos);
+		let elapsed = (elapsed as f64 * f64::from(sample_rate) * 1e-9) as u64;
+
+		// Callbacks are at most a buffer apart, anything more means the clock is stale
+		frames + elapsed.min(2 * buffer_size)
+	}
+
+	// Time to schedule an event that happens now.
+	// Events are delayed by one buffer, so they are never late and callback jitter becomes a constant latency.
+	pub fn schedule_time(&self, sample_rate: u32) -> u64 {
+		self.schedule_time_at(Instant::now(), sample_rate)
+	}
+
+	// Same for an event that happened at `instant`, so it keeps its distance to the events around it
+	pub fn schedule_time_at(&self, instant: Instant, sample_rate: u32) -> u64 {
+		let buffer_size = self.buffer_size.load(Ordering::Relaxed);
+		self.frames_at(instant, sample_rate) + buffer_size
+	}
+
+	// Sample rate of the running stream
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate.load(Ordering::Relaxed)
+	}
+
+	// Sample time that is being played right now, a buffer behind the callback.
+	// None when the stream is not running.
+	pub fn playback_time(&self) -> Option<u64> {
+		let buffer_size = self.buffer_size.load(Ordering::Acquire);
+		if buffer_size == 0 {
+			return None;
+		}
+		let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+		let frames = self.frames_at(Instant::now(), sample_rate);
+		Some(frames.saturating_sub(buffer_size))
+	}
+}
@@ -1,10 +1,17 @@
 use crate::api::audio::DeviceInfo;
 use crate::api::lua_serde;
-use crate::audio::{build_config, build_stream, die, find_output_device};
+use crate::audio::{
+	build_config, build_input_config, build_input_stream, build_stream, find_input_device,
+	find_output_device,
+};
+use crate::clock::SampleClock;
 use crate::log::*;
 use crate::meters::Meters;
+use crate::recorder::Recorder;
 use crate::render::Render;
+use crate::routing::Routing;
 use crate::scope::Scope;
+use crate::sequencer::Playhead;
 use crate::voice_manager::Token;
 use crate::worker::spawn_worker;
 use anyhow::Result;
@@ -15,17 +22,24 @@ use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::sync::mpsc::SyncSender;
 
+// Frames of input we can buffer between the input and output callbacks
+const INPUT_BUFFER_SIZE: usize = 8192;
+
 pub struct AudioContext {
 	pub stream: Option<cpal::Stream>,
 	pub device: Option<cpal::Device>,
-	pub audio_tx: HeapProd<AudioMessage>,
-	pub stream_tx: HeapProd<bool>,
+	pub audio_tx: HeapProd<AudioEvent>,
+	// Taken by midi input, which schedules its events itself
+	pub live_tx: Option<HeapProd<AudioEvent>>,
 	pub error_rx: HeapCons<ErrorMessage>,
+	pub input_stream: Option<cpal::Stream>,
+	pub input_error_rx: Option<HeapCons<ErrorMessage>>,
+	pub recorder: Option<Recorder>,
 	pub render: Arc<Mutex<Render>>,
+	pub clock: Arc<SampleClock>,
+	pub playhead: Arc<Playhead>,
 	pub scope: Scope,
-	pub is_rendering: bool,
 	pub sample_rate: u32,
-	pub render_buffer: Vec<f32>,
 	pub meters: Meters,
 }
 
@@ -41,30 +55,38 @@ impl AudioContext {
 
 		// TODO: can we merge worker_rx and audio_rx?
 		let (worker_tx, worker_rx) = spawn_worker(sample_rate);
-		let (audio_tx, audio_rx) = HeapRb::<AudioMessage>::new(1024).split();
+		let (audio_tx, audio_rx) = HeapRb::<AudioEvent>::new(1024).split();
 		let (scope_tx, scope_rx) = HeapRb::<f32>::new(2048).split();
 		let scope = Scope::new(scope_rx);
 
 		let meters = Meters::new();
 
-		let render =
+		let mut render =
 			Render::new(sample_rate as f32, audio_rx, lua_tx, worker_tx, worker_rx, scope_tx);
+		let (live_tx, live_rx) = HeapRb::<AudioEvent>::new(1024).split();
+		render.set_live_input(live_rx);
+		let playhead = render.playhead();
 		let render = Arc::new(Mutex::new(render));
 
-		let (stream, stream_tx, error_rx) =
-			build_stream(&device, &config, format, Arc::clone(&render))?;
+		let clock = Arc::new(SampleClock::new());
+
+		let (stream, error_rx) =
+			build_stream(&device, &config, format, Arc::clone(&render), Arc::clone(&clock))?;
 
 		Ok(AudioContext {
 			stream: Some(stream),
 			device: Some(device),
 			audio_tx,
-			stream_tx,
+			live_tx: Some(live_tx),
 			error_rx,
+			input_stream: None,
+			input_error_rx: None,
+			recorder: None,
 			render,
+			clock,
+			playhead,
 			scope,
-			is_rendering: false,
 			sample_rate,
-			render_buffer: Vec::new(),
 			meters,
 		})
 	}
@@ -77,21 +99,30 @@ impl AudioContext {
 		// drop old stream
 		self.stream = None;
 		self.device = None;
+		self.clock.reset();
 
 		let device = find_output_device(device_info)?;
 		let (config, format) = build_config(&device, buffer_size)?;
 
-		// TODO: handle this properly
+		// The stream is gone, so we can rebuild the engine without blocking the audio thread
 		if config.sample_rate != self.sample_rate {
-			log_error!("Sample rate mismatch!");
+			log_info!(
+				"Sample rate changed from {}Hz to {}Hz.",
+				self.sample_rate,
+				config.sample_rate
+			);
+			self.render.lock().set_sample_rate(config.sample_rate as f32);
 			self.sample_rate = config.sample_rate;
-			die();
 		}
 
-		let (stream, stream_tx, error_rx) =
-			build_stream(&device, &config, format, Arc::clone(&self.render))?;
+		let (stream, error_rx) = build_stream(
+			&device,
+			&config,
+			format,
+			Arc::clone(&self.render),
+			Arc::clone(&self.clock),
+		)?;
 
-		self.stream_tx = stream_tx;
 		self.error_rx = error_rx;
 		self.stream = Some(stream);
 		self.device = Some(device);
@@ -99,22 +130,50 @@ impl AudioContext {
 		Ok(())
 	}
 
-	pub fn send_message(&mut self, m: AudioMessage) {
-		if self.audio_tx.try_push(m).is_err() {
-			log_warn!("Queue full. Dropped message!");
-		}
+	pub fn open_input(&mut self, device_info: &DeviceInfo) -> Result<()> {
+		self.close_input();
+
+		let device = find_input_device(device_info)?;
+		let (config, format) = build_input_config(&device, self.sample_rate)?;
+
+		let (input_tx, input_rx) = HeapRb::<f32>::new(2 * INPUT_BUFFER_SIZE).split();
+		let (stream, error_rx) = build_input_stream(&device, &config, format, input_tx)?;
+
+		self.render.lock().set_input(Some(input_rx));
+		self.input_stream = Some(stream);
+		self.input_error_rx = Some(error_rx);
+
+		Ok(())
 	}
 
-	pub fn send_rendering(&mut self, is_rendering: bool) {
-		self.is_rendering = is_rendering;
-		if self.stream_tx.try_push(is_rendering).is_err() {
-			log_warn!("Stream queue full. Dropped message!");
+	pub fn close_input(&mut self) {
+		self.input_stream = None;
+		self.input_error_rx = None;
+		self.render.lock().set_input(None);
+	}
+
+	// Playback position in seconds, None when stopped
+	pub fn position(&self) -> Option<f64> {
+		self.playhead.load().map(|p| p as f64 / f64::from(self.sample_rate))
+	}
+
+	pub fn send_message(&mut self, message: AudioMessage) {
+		let time = self.clock.schedule_time(self.sample_rate);
+		if self.audio_tx.try_push(AudioEvent { time, message }).is_err() {
+			log_warn!("Queue full. Dropped message!");
 		}
 	}
 }
 
-// Message struct to pass to audio thread
-// Should not contain any boxed values
+// Message with the sample time at which the audio thread should apply it
+#[derive(Debug)]
+pub struct AudioEvent {
+	pub time: u64,
+	pub message: AudioMessage,
+}
+
+// Message struct to pass to audio thread.
+// Boxed values are built on the main thread, and sent to the worker to be dropped when replaced.
 #[derive(Debug)]
 pub enum AudioMessage {
 	Panic,
@@ -128,14 +187,28 @@ pub enum AudioMessage {
 	DeviceMute(usize, usize, bool),
 	ChannelMute(usize, bool),
 	ChannelGain(usize, f32),
+	SendGain(usize, usize, f32),
+	CueGain(usize, f32),
+	// Suspend playback of an automation lane while it is being recorded
+	AutomationBypass(usize, usize, bool),
 	ReorderEffect(usize, usize, usize),
+	SetRouting(Box<Routing>),
 	Metronome(bool),
+	// Start playback at a time in seconds, optionally chasing notes that are already sounding
+	Play(f64, bool),
+	// Jump to a time in seconds while playing
+	Seek(f64),
+	Stop,
+	// Loop region in seconds, playback jumps back to the start when it reaches the end
+	SetLoop(Option<(f64, f64)>),
+	// Audio inputs only record inside this range, in seconds
+	SetPunch(Option<(f64, f64)>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "tag")]
 pub enum LuaMessage {
-	StreamSettings { buffer_size: usize, sample_rate: f32 },
+	StreamSettings { buffer_size: usize, sample_rate: f32, output_channels: usize },
 	Log { level: String, message: String },
 }
 
@@ -5,6 +5,8 @@ use std::f32::consts::*;
 pub mod atomic_float;
 pub mod delayline;
 pub mod env;
+pub mod latency;
+pub mod loudness;
 pub mod onepole;
 pub mod resample;
 pub mod simper;
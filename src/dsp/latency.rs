@@ -0,0 +1,57 @@
+// Largest latency we can compensate for, in samples
+pub const MAX_LATENCY: usize = 8192;
+
+// Stereo delay with an integer length in samples.
+// Used to line up signal paths that have different latencies.
+#[derive(Debug)]
+pub struct LatencyDelay {
+	buf: Vec<[f32; 2]>,
+	pos: usize,
+	len: usize,
+}
+
+impl LatencyDelay {
+	pub fn new() -> Self {
+		Self { buf: vec![[0.0; 2]; MAX_LATENCY], pos: 0, len: 0 }
+	}
+
+	pub fn delay(&self) -> usize {
+		self.len
+	}
+
+	pub fn set_delay(&mut self, len: usize) {
+		self.len = len.min(MAX_LATENCY);
+	}
+
+	#[must_use]
+	pub fn tick(&mut self, s: [f32; 2]) -> [f32; 2] {
+		if self.len == 0 {
+			return s;
+		}
+		let read = (self.pos + MAX_LATENCY - self.len) % MAX_LATENCY;
+		let out = self.buf[read];
+		self.buf[self.pos] = s;
+		self.pos = (self.pos + 1) % MAX_LATENCY;
+		out
+	}
+
+	pub fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+		if self.len == 0 {
+			return;
+		}
+		let [bl, br] = buffer;
+		for (l, r) in bl.iter_mut().zip(br.iter_mut()) {
+			[*l, *r] = self.tick([*l, *r]);
+		}
+	}
+
+	pub fn flush(&mut self) {
+		self.buf.fill([0.0; 2]);
+	}
+}
+
+impl Default for LatencyDelay {
+	fn default() -> Self {
+		Self::new()
+	}
+}
@@ -16,6 +16,8 @@ mod tremolo;
 mod wide;
 
 use crate::audio::MAX_BUF_SIZE;
+use crate::channel::Bus;
+use crate::dsp::latency::LatencyDelay;
 use crate::dsp::{MuteState, PeakMeter, time_constant};
 use crate::effect;
 use crate::effect::{
@@ -25,6 +27,8 @@ use crate::effect::{
 };
 use crate::log::log_warn;
 use crate::meters::MeterHandle;
+use crate::parameters::ParameterCache;
+use crate::tempo::TransportInfo;
 use crate::worker::{RequestData, ResponseData};
 
 // list of effects
@@ -58,8 +62,25 @@ pub trait Effect {
 	where
 		Self: Sized;
 	fn process(&mut self, buffer: &mut [&mut [f32]; 2]);
+	// Effects that can be keyed from another channel override this and `has_sidechain`
+	fn process_sidechain(&mut self, buffer: &mut [&mut [f32]; 2], _sidechain: &[&[f32]; 2]) {
+		self.process(buffer);
+	}
+	fn has_sidechain(&self) -> bool {
+		false
+	}
+	// Latency in samples that processing adds to the signal
+	fn latency(&self) -> usize {
+		0
+	}
+	// Upper bound for `latency`, so the bypass can reserve a delay for the dry signal
+	fn max_latency(&self) -> usize {
+		self.latency()
+	}
 	#[must_use]
 	fn set_parameter(&mut self, index: usize, val: f32) -> Option<RequestData>;
+	// Called before every process call, for tempo-synced effects
+	fn set_transport(&mut self, _transport: &TransportInfo) {}
 	fn flush(&mut self) {}
 	#[must_use]
 	fn receive_data(&mut self, _data: ResponseData) -> Option<Box<dyn std::any::Any + Send>> {
@@ -70,6 +91,10 @@ pub trait Effect {
 
 pub struct Bypass {
 	pub effect: Box<dyn Effect + Send>,
+	name: String,
+	parameters: ParameterCache,
+	// Channel whose output is used as sidechain
+	pub sidechain: Option<usize>,
 
 	peak: PeakMeter,
 	meter_handle: MeterHandle,
@@ -80,12 +105,32 @@ pub struct Bypass {
 	smoothing_f: f32,
 	dry_temp: [[f32; MAX_BUF_SIZE]; 2],
 	wet_gain: [f32; MAX_BUF_SIZE],
+	// Keeps the dry signal aligned with the wet signal for effects with latency
+	dry_delay: Option<LatencyDelay>,
+	// Lines up the sidechain signal with our input, for effects that have one
+	sidechain_delay: Option<Box<SidechainDelay>>,
+}
+
+struct SidechainDelay {
+	delay: LatencyDelay,
+	buffer: [[f32; MAX_BUF_SIZE]; 2],
 }
 
 impl Bypass {
 	pub fn new(sample_rate: f32, name: &str, meter_handle: MeterHandle) -> Self {
+		let effect = effect::new(sample_rate, name);
+		let dry_delay = (effect.max_latency() > 0).then(LatencyDelay::new);
+		let sidechain_delay = effect.has_sidechain().then(|| {
+			Box::new(SidechainDelay {
+				delay: LatencyDelay::new(),
+				buffer: [[0.0; MAX_BUF_SIZE]; 2],
+			})
+		});
 		Bypass {
-			effect: effect::new(sample_rate, name),
+			effect,
+			name: name.to_string(),
+			parameters: ParameterCache::new(),
+			sidechain: None,
 
 			peak: PeakMeter::new(sample_rate),
 			meter_handle,
@@ -96,17 +141,33 @@ impl Bypass {
 			smoothing_f: time_constant(15.0, sample_rate),
 			dry_temp: [[0.0; MAX_BUF_SIZE]; 2],
 			wet_gain: [0.0; MAX_BUF_SIZE],
+			dry_delay,
+			sidechain_delay,
 		}
 	}
 
-	pub fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+	// `outputs` holds the output of every channel that was already processed this block
+	pub fn process(&mut self, buffer: &mut [&mut [f32]; 2], outputs: &[Bus]) {
 		match self.state {
 			MuteState::Active => {
-				self.effect.process(buffer);
+				// Keep the dry delay running so it is ready when we switch to bypass
+				if let Some(dry_delay) = &mut self.dry_delay {
+					let samples = buffer[0].len();
+					let [dl, dr] = &mut self.dry_temp;
+					dl[..samples].copy_from_slice(buffer[0]);
+					dr[..samples].copy_from_slice(buffer[1]);
+					dry_delay.set_delay(self.effect.latency());
+					dry_delay.process(&mut [&mut dl[..samples], &mut dr[..samples]]);
+				}
+				self.process_effect(buffer, outputs);
 				let peak = self.peak.process_block(buffer);
 				self.meter_handle.set(peak);
 			},
 			MuteState::Off => {
+				if let Some(dry_delay) = &mut self.dry_delay {
+					dry_delay.set_delay(self.effect.latency());
+					dry_delay.process(buffer);
+				}
 				self.meter_handle.set([0., 0.]);
 			},
 			MuteState::Transition => {
@@ -116,6 +177,11 @@ impl Bypass {
 
 				self.dry_temp[0][..samples].copy_from_slice(buffer[0]);
 				self.dry_temp[1][..samples].copy_from_slice(buffer[1]);
+				if let Some(dry_delay) = &mut self.dry_delay {
+					let [dl, dr] = &mut self.dry_temp;
+					dry_delay.set_delay(self.effect.latency());
+					dry_delay.process(&mut [&mut dl[..samples], &mut dr[..samples]]);
+				}
 
 				for i in 0..samples {
 					self.gain += self.smoothing_f * (target - self.gain);
@@ -134,7 +200,7 @@ impl Bypass {
 					self.dry_temp[1][i] *= dry_gain;
 				}
 
-				self.effect.process(buffer);
+				self.process_effect(buffer, outputs);
 
 				// Post gain
 				for i in 0..samples {
@@ -165,11 +231,63 @@ impl Bypass {
 		}
 	}
 
+	fn process_effect(&mut self, buffer: &mut [&mut [f32]; 2], outputs: &[Bus]) {
+		let output = self.sidechain.and_then(|i| outputs.get(i));
+		match (output, &mut self.sidechain_delay) {
+			(Some(output), Some(sidechain_delay)) => {
+				let samples = buffer[0].len();
+				let [l, r] = &mut sidechain_delay.buffer;
+				l[..samples].copy_from_slice(&output[0][..samples]);
+				r[..samples].copy_from_slice(&output[1][..samples]);
+				sidechain_delay
+					.delay
+					.process(&mut [&mut l[..samples], &mut r[..samples]]);
+				self.effect.process_sidechain(buffer, &[&l[..samples], &r[..samples]]);
+			},
+			_ => self.effect.process(buffer),
+		}
+	}
+
+	pub fn set_sidechain_delay(&mut self, delay: usize) {
+		if let Some(sidechain_delay) = &mut self.sidechain_delay {
+			sidechain_delay.delay.set_delay(delay);
+		}
+	}
+
 	pub fn set_mute(&mut self, mute: bool) {
 		self.mute = mute;
 		self.state = MuteState::Transition;
 	}
 
+	pub fn set_parameter(&mut self, index: usize, val: f32) -> Option<RequestData> {
+		self.parameters.set(index, val);
+		self.effect.set_parameter(index, val)
+	}
+
+	// Recreate the effect at the new rate with the same parameters.
+	// Returns the data the effect needs to load again.
+	pub fn set_sample_rate(&mut self, sample_rate: f32) -> Vec<RequestData> {
+		let mut bypass = Bypass::new(sample_rate, &self.name, self.meter_handle.clone());
+		bypass.sidechain = self.sidechain;
+		if self.mute {
+			bypass.mute = true;
+			bypass.state = MuteState::Off;
+			bypass.gain = 0.0;
+		}
+
+		let mut requests = Vec::new();
+		for (index, val) in self.parameters.iter() {
+			requests.extend(bypass.set_parameter(index, val));
+		}
+		*self = bypass;
+		requests
+	}
+
+	// Reported even when bypassed, since the dry signal gets delayed by the same amount
+	pub fn latency(&self) -> usize {
+		self.effect.latency()
+	}
+
 	pub fn flush(&mut self) {
 		self.effect.flush();
 		self.meter_handle.set([0., 0.]);
@@ -44,34 +44,21 @@ impl Track {
 	}
 }
 
-impl Effect for Compressor {
-	fn new(sample_rate: f32) -> Self {
-		Compressor {
-			tracks: [Track::new(sample_rate), Track::new(sample_rate)],
-			sample_rate,
-			threshold: 0.,
-			ratio: 1.,
-			attack: 0.1,
-			attack_slow: 0.1,
-			release: 0.1,
-			make_up: Smooth::new(0., 25.0, sample_rate),
-			balance: Smooth::new(1., 25.0, sample_rate),
-
-			gain_a: 0.,
-			gain_b: 0.,
-			gain_c: 0.,
-		}
-	}
-
-	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+impl Compressor {
+	// Detector listens to the sidechain if there is one, otherwise to the input
+	fn process_keyed(&mut self, buffer: &mut [&mut [f32]; 2], sidechain: Option<&[&[f32]; 2]>) {
 		let [bl, br] = buffer;
-		for (l, r) in bl.iter_mut().zip(br.iter_mut()) {
+		for (i, (l, r)) in bl.iter_mut().zip(br.iter_mut()).enumerate() {
 			let s_in = [*l, *r];
+			let key = match sidechain {
+				Some(sc) => [sc[0][i], sc[1][i]],
+				None => s_in,
+			};
 
 			let mut side = [0., 0.];
 			for ch in 0..2 {
 				// sidechain loudness weight
-				side[ch] = self.tracks[ch].highpass.process(s_in[ch]);
+				side[ch] = self.tracks[ch].highpass.process(key[ch]);
 				side[ch] = self.tracks[ch].shelf.process(side[ch]);
 			}
 
@@ -114,6 +101,39 @@ impl Effect for Compressor {
 			*r = s_in[1] * g;
 		}
 	}
+}
+
+impl Effect for Compressor {
+	fn new(sample_rate: f32) -> Self {
+		Compressor {
+			tracks: [Track::new(sample_rate), Track::new(sample_rate)],
+			sample_rate,
+			threshold: 0.,
+			ratio: 1.,
+			attack: 0.1,
+			attack_slow: 0.1,
+			release: 0.1,
+			make_up: Smooth::new(0., 25.0, sample_rate),
+			balance: Smooth::new(1., 25.0, sample_rate),
+
+			gain_a: 0.,
+			gain_b: 0.,
+			gain_c: 0.,
+		}
+	}
+
+	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+		self.process_keyed(buffer, None);
+	}
+
+	fn process_sidechain(&mut self, buffer: &mut [&mut [f32]; 2], sidechain: &[&[f32]; 2]) {
+		self.process_keyed(buffer, Some(sidechain));
+	}
+
+	fn has_sidechain(&self) -> bool {
+		true
+	}
+
 	fn flush(&mut self) {}
 
 	fn set_parameter(&mut self, index: usize, value: f32) -> Option<RequestData> {
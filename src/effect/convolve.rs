@@ -82,6 +82,11 @@ impl Effect for Convolve {
 		}
 	}
 
+	// The two-stage convolver runs its head segment directly, so there is no block latency
+	fn latency(&self) -> usize {
+		0
+	}
+
 	fn flush(&mut self) {
 		if let Some(convolver) = &mut self.convolver {
 			convolver.iter_mut().for_each(|c| c.reset());
@@ -1,11 +1,13 @@
+use crate::dsp::latency::LatencyDelay;
 use crate::dsp::smooth::Smooth;
 use crate::dsp::*;
 use crate::effect::Effect;
 use crate::log::log_warn;
 use crate::worker::RequestData;
 
-// Analog-style limiter
-// TODO: Add a "modern" mode with lookahead
+// Analog-style limiter, with optional lookahead
+
+const LOOKAHEAD_MS: f32 = 5.0;
 
 #[derive(Debug)]
 pub struct Limiter {
@@ -18,6 +20,10 @@ pub struct Limiter {
 	stereo_link: bool,
 
 	gain: [f32; 2],
+	// Delays the signal so gain reduction can start before the peak
+	lookahead: LatencyDelay,
+	lookahead_len: usize,
+	lookahead_enabled: bool,
 }
 
 fn clip(x: f32) -> f32 {
@@ -25,30 +31,25 @@ fn clip(x: f32) -> f32 {
 	x - (256. / 3125.) * x.powi(5)
 }
 
-impl Effect for Limiter {
-	fn new(sample_rate: f32) -> Self {
-		Self {
-			sample_rate,
-			// Fixed attack
-			attack: time_constant(5.0, sample_rate),
-			release: time_constant(400.0, sample_rate),
-			input_gain: Smooth::new(1.0, 50.0, sample_rate),
-			ceiling: Smooth::new(1.0, 50.0, sample_rate),
-			stereo_link: true,
-			gain: [1.0, 1.0],
-		}
-	}
+impl Limiter {
+	// With a sidechain, gain reduction follows the peaks of the key signal instead of the input.
+	// Only the input goes through the clipper.
+	fn process_keyed(&mut self, buffer: &mut [&mut [f32]; 2], sidechain: Option<&[&[f32]; 2]>) {
+		self.lookahead.set_delay(self.latency());
 
-	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
 		let [bl, br] = buffer;
 
-		for (l, r) in bl.iter_mut().zip(br.iter_mut()) {
+		for (i, (l, r)) in bl.iter_mut().zip(br.iter_mut()).enumerate() {
 			let mut in_gain = self.input_gain.process();
 			let ceiling = self.ceiling.process();
 			in_gain /= ceiling;
 
-			let x = [*l * in_gain, *r * in_gain];
-			let mut peak = x.map(f32::abs);
+			let input = [*l * in_gain, *r * in_gain];
+			let mut peak = match sidechain {
+				Some(sc) => [sc[0][i] * in_gain, sc[1][i] * in_gain].map(f32::abs),
+				None => input.map(f32::abs),
+			};
+			let x = self.lookahead.tick(input);
 
 			if self.stereo_link {
 				let max_peak = f32::max(peak[0], peak[1]);
@@ -73,9 +74,48 @@ impl Effect for Limiter {
 			*r = out[1] * ceiling;
 		}
 	}
+}
+
+impl Effect for Limiter {
+	fn new(sample_rate: f32) -> Self {
+		Self {
+			sample_rate,
+			// Fixed attack
+			attack: time_constant(5.0, sample_rate),
+			release: time_constant(400.0, sample_rate),
+			input_gain: Smooth::new(1.0, 50.0, sample_rate),
+			ceiling: Smooth::new(1.0, 50.0, sample_rate),
+			stereo_link: true,
+			gain: [1.0, 1.0],
+			lookahead: LatencyDelay::new(),
+			lookahead_len: (LOOKAHEAD_MS * 0.001 * sample_rate) as usize,
+			lookahead_enabled: false,
+		}
+	}
+
+	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+		self.process_keyed(buffer, None);
+	}
+
+	fn process_sidechain(&mut self, buffer: &mut [&mut [f32]; 2], sidechain: &[&[f32]; 2]) {
+		self.process_keyed(buffer, Some(sidechain));
+	}
+
+	fn has_sidechain(&self) -> bool {
+		true
+	}
+
+	fn latency(&self) -> usize {
+		if self.lookahead_enabled { self.lookahead_len } else { 0 }
+	}
+
+	fn max_latency(&self) -> usize {
+		self.lookahead_len
+	}
 
 	fn flush(&mut self) {
 		self.gain = [1.0, 1.0];
+		self.lookahead.flush();
 	}
 
 	fn set_parameter(&mut self, index: usize, value: f32) -> Option<RequestData> {
@@ -84,6 +124,7 @@ impl Effect for Limiter {
 			1 => self.input_gain.set(from_db(value)),
 			2 => self.ceiling.set(from_db(value)),
 			3 => self.stereo_link = value > 0.5,
+			4 => self.lookahead_enabled = value > 0.5,
 			_ => log_warn!("Parameter with index {index} not found"),
 		}
 		None
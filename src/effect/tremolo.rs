@@ -11,6 +11,9 @@ pub struct Tremolo {
 	amount: Smooth,
 	lfo_rate: Smooth,
 	phase: Smooth,
+	// Envelope of the sidechain, replaces the LFO when keyed
+	envelope: f32,
+	attack: f32,
 }
 
 impl Effect for Tremolo {
@@ -21,6 +24,8 @@ impl Effect for Tremolo {
 			amount: Smooth::new(0., 25.0, sample_rate),
 			lfo_rate: Smooth::new(0., 25.0, sample_rate),
 			phase: Smooth::new(0., 25.0, sample_rate),
+			envelope: 0.,
+			attack: time_constant(2.0, sample_rate),
 		}
 	}
 
@@ -43,6 +48,38 @@ impl Effect for Tremolo {
 		}
 	}
 
+	// Duck the signal following the envelope of the sidechain.
+	// The rate controls how fast the gain recovers.
+	fn process_sidechain(&mut self, buffer: &mut [&mut [f32]; 2], sidechain: &[&[f32]; 2]) {
+		let [bl, br] = buffer;
+
+		self.lfo_rate.immediate();
+		let release = time_constant(1000.0 / self.lfo_rate.target().max(0.5), self.sample_rate);
+
+		for (i, (l, r)) in zip(bl.iter_mut(), br.iter_mut()).enumerate() {
+			let amount = self.amount.process();
+
+			let key = f32::max(sidechain[0][i].abs(), sidechain[1][i].abs()).min(1.0);
+			if key > self.envelope {
+				self.envelope += (key - self.envelope) * self.attack;
+			} else {
+				self.envelope += (key - self.envelope) * release;
+			}
+
+			let gain = 1.0 - amount * self.envelope;
+			*l *= gain;
+			*r *= gain;
+		}
+	}
+
+	fn has_sidechain(&self) -> bool {
+		true
+	}
+
+	fn flush(&mut self) {
+		self.envelope = 0.;
+	}
+
 	fn set_parameter(&mut self, index: usize, value: f32) -> Option<RequestData> {
 		match index {
 			0 => self.amount.set(value),
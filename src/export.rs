@@ -0,0 +1,231 @@
+use crate::api::lua_serde;
+use crate::dsp::loudness::integrated_loudness;
+use crate::dsp::resample::Resampler;
+use crate::log::*;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// Error feedback filter for noise shaped dither.
+// Pushes the requantization noise up towards nyquist, about 12dB lower at DC.
+const NOISE_SHAPING: [f32; 3] = [1.623, -0.982, 0.109];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+	Int16,
+	Int24,
+	Float32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dither {
+	None,
+	Tpdf,
+	NoiseShaped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalize {
+	None,
+	Peak,
+	Loudness,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportSettings {
+	pub path: String,
+	pub format: SampleFormat,
+	pub dither: Dither,
+	// Resample to this rate if it differs from the render rate
+	pub sample_rate: Option<u32>,
+	pub normalize: Normalize,
+	// dBFS
+	pub peak_target: f32,
+	// LUFS
+	pub loudness_target: f32,
+	// Also write the post-fader signal of every channel
+	pub stems: bool,
+	// Also write the instrument signal before effects of every channel
+	pub stems_pre_effects: bool,
+}
+
+impl Default for ExportSettings {
+	fn default() -> Self {
+		Self {
+			path: "out/render.wav".to_string(),
+			format: SampleFormat::Int16,
+			dither: Dither::Tpdf,
+			sample_rate: None,
+			normalize: Normalize::None,
+			peak_target: -1.0,
+			loudness_target: -14.0,
+			stems: false,
+			stems_pre_effects: false,
+		}
+	}
+}
+
+lua_serde!(ExportSettings);
+
+// Write interlaced stereo samples to `settings.path`, and each stem to a separate file next to it.
+// Stems get the same normalization gain as the master, so they still sum to the mix.
+pub fn write_render(
+	master: &[f32],
+	stems: &[(String, &[f32])],
+	sample_rate: u32,
+	settings: &ExportSettings,
+) -> Result<()> {
+	let (channels, out_rate) = prepare(master, sample_rate, settings);
+	let gain = normalize_gain(&channels, out_rate, settings);
+	write_file(Path::new(&settings.path), &channels, out_rate, gain, settings)?;
+
+	for (name, samples) in stems {
+		let path = stem_path(&settings.path, name);
+		let (channels, out_rate) = prepare(samples, sample_rate, settings);
+		write_file(&path, &channels, out_rate, gain, settings)?;
+		log_info!("Wrote \"{}\".", path.display());
+	}
+
+	Ok(())
+}
+
+// "out/song.wav" + "02 Bass" -> "out/song_02 Bass.wav"
+fn stem_path(path: &str, name: &str) -> PathBuf {
+	let path = Path::new(path);
+	let base = path.file_stem().unwrap_or_default().to_string_lossy();
+	let name: String = name
+		.chars()
+		.map(|c| if c.is_alphanumeric() || " -_".contains(c) { c } else { '_' })
+		.collect();
+	path.with_file_name(format!("{base}_{name}.wav"))
+}
+
+// Deinterlace and resample if needed
+fn prepare(samples: &[f32], sample_rate: u32, settings: &ExportSettings) -> ([Vec<f32>; 2], u32) {
+	let mut channels =
+		[Vec::with_capacity(samples.len() / 2), Vec::with_capacity(samples.len() / 2)];
+	for frame in samples.chunks_exact(2) {
+		channels[0].push(frame[0]);
+		channels[1].push(frame[1]);
+	}
+
+	if let Some(target_rate) = settings.sample_rate
+		&& target_rate != sample_rate
+	{
+		let resampler = Resampler::new(sample_rate as f32, target_rate as f32);
+		for c in &mut channels {
+			*c = resampler.process(c);
+		}
+		return (channels, target_rate);
+	}
+
+	(channels, sample_rate)
+}
+
+fn write_file(
+	path: &Path,
+	channels: &[Vec<f32>; 2],
+	sample_rate: u32,
+	gain: f32,
+	settings: &ExportSettings,
+) -> Result<()> {
+	let (bits_per_sample, sample_format) = match settings.format {
+		SampleFormat::Int16 => (16, hound::SampleFormat::Int),
+		SampleFormat::Int24 => (24, hound::SampleFormat::Int),
+		SampleFormat::Float32 => (32, hound::SampleFormat::Float),
+	};
+	let spec = hound::WavSpec { channels: 2, sample_rate, bits_per_sample, sample_format };
+
+	let mut writer = hound::WavWriter::create(path, spec)?;
+	let len = channels[0].len().min(channels[1].len());
+
+	if settings.format == SampleFormat::Float32 {
+		for i in 0..len {
+			writer.write_sample(gain * channels[0][i])?;
+			writer.write_sample(gain * channels[1][i])?;
+		}
+	} else {
+		let mut quantizers = [
+			Quantizer::new(bits_per_sample, settings.dither),
+			Quantizer::new(bits_per_sample, settings.dither),
+		];
+		for i in 0..len {
+			writer.write_sample(quantizers[0].process(gain * channels[0][i]))?;
+			writer.write_sample(quantizers[1].process(gain * channels[1][i]))?;
+		}
+	}
+	writer.finalize()?;
+
+	Ok(())
+}
+
+fn normalize_gain(channels: &[Vec<f32>; 2], sample_rate: u32, settings: &ExportSettings) -> f32 {
+	let peak = channels.iter().flatten().fold(0.0f32, |m, x| m.max(x.abs()));
+
+	let gain_db = match settings.normalize {
+		Normalize::None => return 1.0,
+		Normalize::Peak => {
+			if peak == 0.0 {
+				log_warn!("Render is silent, skipping normalization.");
+				return 1.0;
+			}
+			settings.peak_target - 20.0 * peak.log10()
+		},
+		Normalize::Loudness => {
+			let loudness = integrated_loudness(&[&channels[0], &channels[1]], sample_rate as f32);
+			if !loudness.is_finite() {
+				log_warn!("Render is silent, skipping normalization.");
+				return 1.0;
+			}
+			log_info!("Integrated loudness: {loudness:.1} LUFS");
+			settings.loudness_target - loudness as f32
+		},
+	};
+
+	let gain = 10.0f32.powf(gain_db / 20.0);
+	if peak * gain > 1.0 {
+		log_warn!("Normalized render clips ({:.1} dBFS peak).", 20.0 * (peak * gain).log10());
+	}
+	gain
+}
+
+// Converts float samples to integers with the requested dither
+struct Quantizer {
+	scale: f32,
+	dither: Dither,
+	error: [f32; 3],
+}
+
+impl Quantizer {
+	fn new(bits: u16, dither: Dither) -> Self {
+		Self { scale: (1u32 << (bits - 1)) as f32, dither, error: [0.0; 3] }
+	}
+
+	fn process(&mut self, x: f32) -> i32 {
+		let x = x * self.scale;
+
+		// TPDF noise in range [-1, 1] quantization levels
+		let tpdf = || fastrand::f32() - fastrand::f32();
+
+		let y = match self.dither {
+			Dither::None => x.round(),
+			Dither::Tpdf => (x + tpdf()).round(),
+			Dither::NoiseShaped => {
+				let shaped: f32 = NOISE_SHAPING.iter().zip(&self.error).map(|(h, e)| h * e).sum();
+				let v = x - shaped;
+				let y = (v + tpdf()).round().clamp(-self.scale, self.scale - 1.0);
+
+				// clamp so clipped samples don't blow up the feedback loop
+				self.error.rotate_right(1);
+				self.error[0] = (y - v).clamp(-2.0, 2.0);
+				y
+			},
+		};
+
+		y.clamp(-self.scale, self.scale - 1.0) as i32
+	}
+}
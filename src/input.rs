@@ -0,0 +1,148 @@
+use crate::audio::MAX_BUF_SIZE;
+use crate::dsp::smooth::Smooth;
+use crate::dsp::{MuteState, PeakMeter, time_constant};
+use crate::log::log_warn;
+use crate::meters::MeterHandle;
+use crate::recorder::RecordSink;
+use ringbuf::traits::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputMode {
+	Stereo,
+	Left,
+	Right,
+}
+
+// Channel source that takes live audio from the input stream.
+// Takes the place of the instrument, so it sits before the effect chain.
+pub struct AudioInput {
+	mode: InputMode,
+	gain: Smooth,
+	record: Option<RecordSink>,
+	// Position of the current segment while recording.
+	// Set by the transport, so only the part inside the punch range ends up in the take.
+	record_position: Option<usize>,
+
+	peak: PeakMeter,
+	meter_handle: MeterHandle,
+
+	mute: bool,
+	state: MuteState,
+	value: f32,
+	smoothing_f: f32,
+}
+
+impl AudioInput {
+	pub fn new(sample_rate: f32, meter_handle: MeterHandle) -> Self {
+		Self {
+			mode: InputMode::Stereo,
+			gain: Smooth::new(1., 25., sample_rate),
+			record: None,
+			record_position: None,
+			peak: PeakMeter::new(sample_rate),
+			meter_handle,
+			mute: false,
+			state: MuteState::Active,
+			value: 1.0,
+			smoothing_f: time_constant(15.0, sample_rate),
+		}
+	}
+
+	pub fn process(&mut self, input: &[[f32; MAX_BUF_SIZE]; 2], buffer: &mut [&mut [f32]; 2]) {
+		let samples = buffer[0].len();
+		assert!(samples <= MAX_BUF_SIZE);
+
+		for i in 0..samples {
+			let (l, r) = match self.mode {
+				InputMode::Stereo => (input[0][i], input[1][i]),
+				InputMode::Left => (input[0][i], input[0][i]),
+				InputMode::Right => (input[1][i], input[1][i]),
+			};
+			let gain = self.gain.process();
+			buffer[0][i] = l * gain;
+			buffer[1][i] = r * gain;
+		}
+
+		// Record before muting, so muting the input only affects monitoring
+		if let Some(position) = self.record_position
+			&& let Some(record) = &mut self.record
+		{
+			record.status.started(position);
+			for i in 0..samples {
+				// Disk writer fell behind, drop the rest and let the recorder know
+				if record.tx.vacant_len() < 2 {
+					record.status.add_dropped(samples - i);
+					break;
+				}
+				let _ = record.tx.try_push(buffer[0][i]);
+				let _ = record.tx.try_push(buffer[1][i]);
+			}
+		}
+
+		match self.state {
+			MuteState::Off => {
+				buffer[0].fill(0.0);
+				buffer[1].fill(0.0);
+				self.meter_handle.set([0., 0.]);
+			},
+			MuteState::Active => {
+				let peak = self.peak.process_block(buffer);
+				self.meter_handle.set(peak);
+			},
+			MuteState::Transition => {
+				let target = if self.mute { 0.0 } else { 1.0 };
+
+				for i in 0..samples {
+					self.value += self.smoothing_f * (target - self.value);
+					buffer[0][i] *= self.value;
+					buffer[1][i] *= self.value;
+				}
+
+				let peak = self.peak.process_block(buffer);
+				self.meter_handle.set(peak);
+
+				if (self.value - target).abs() < 1e-4 {
+					self.value = target;
+					if self.mute {
+						self.state = MuteState::Off;
+					} else {
+						self.state = MuteState::Active;
+					}
+				}
+			},
+		}
+	}
+
+	pub fn set_parameter(&mut self, index: usize, value: f32) {
+		match index {
+			0 => {
+				self.mode = match value as usize {
+					2 => InputMode::Left,
+					3 => InputMode::Right,
+					_ => InputMode::Stereo,
+				};
+			},
+			1 => self.gain.set(value),
+			_ => log_warn!("Parameter with index {index} not found"),
+		}
+	}
+
+	pub fn set_record(&mut self, record: Option<RecordSink>) {
+		self.record = record;
+	}
+
+	pub fn set_record_gate(&mut self, position: Option<usize>) {
+		self.record_position = position;
+	}
+
+	pub fn set_mute(&mut self, mute: bool) {
+		self.mute = mute;
+		self.state = MuteState::Transition;
+	}
+
+	pub fn set_sample_rate(&mut self, sample_rate: f32) {
+		self.gain = Smooth::new(self.gain.target(), 25., sample_rate);
+		self.peak = PeakMeter::new(sample_rate);
+		self.smoothing_f = time_constant(15.0, sample_rate);
+	}
+}
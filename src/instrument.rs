@@ -1,6 +1,7 @@
 mod analog;
 mod epiano;
 mod fm;
+pub mod midi_output;
 mod pluck;
 mod polysine;
 mod sampler;
@@ -9,10 +10,13 @@ pub mod vst_instrument;
 mod wavetable;
 
 use crate::instrument::{
-	analog::Analog, epiano::Epiano, fm::Fm, pluck::Pluck, polysine::Polysine, sampler::Sampler,
-	sine::Sine, vst_instrument::VstInstrument, wavetable::Wavetable,
+	analog::Analog, epiano::Epiano, fm::Fm, midi_output::MidiOutput, pluck::Pluck,
+	polysine::Polysine, sampler::Sampler, sine::Sine, vst_instrument::VstInstrument,
+	wavetable::Wavetable,
 };
 use crate::log::log_warn;
+use crate::mts::Tuning;
+use crate::tempo::TransportInfo;
 use crate::worker::RequestData;
 use crate::worker::ResponseData;
 use std::any::Any;
@@ -23,6 +27,7 @@ pub fn new(sample_rate: f32, name: &str) -> Box<dyn Instrument + Send> {
 		"analog" => Box::new(Analog::new(sample_rate)),
 		"epiano" => Box::new(Epiano::new(sample_rate)),
 		"fm" => Box::new(Fm::new(sample_rate)),
+		"midi_output" => Box::new(MidiOutput::new(sample_rate)),
 		"pluck" => Box::new(Pluck::new(sample_rate)),
 		"polysine" => Box::new(Polysine::new(sample_rate)),
 		"sampler" => Box::new(Sampler::new(sample_rate)),
@@ -48,6 +53,16 @@ pub trait Instrument {
 	fn set_parameter(&mut self, index: usize, val: f32) -> Option<RequestData>;
 	fn flush(&mut self);
 	fn voice_count(&self) -> usize;
+	// Latency in samples between receiving a note and producing sound
+	fn latency(&self) -> usize {
+		0
+	}
+	// Instruments that can switch sample rate in place return true, the others get recreated
+	fn set_sample_rate(&mut self, _sample_rate: f32) -> bool {
+		false
+	}
+	// Called before every process call, for tempo-synced instruments
+	fn set_transport(&mut self, _transport: &TransportInfo) {}
 	#[must_use]
 	fn receive_data(&mut self, _data: ResponseData) -> Option<Box<dyn Any + Send>> {
 		log_warn!("Instrument received data with no handler");
@@ -56,4 +71,12 @@ pub trait Instrument {
 	fn as_vst(&mut self) -> &mut VstInstrument {
 		unimplemented!();
 	}
+	fn as_midi_output(&mut self) -> Option<&mut MidiOutput> {
+		None
+	}
+	// Instruments that play MIDI keys take a key tuning, and pick the nearest key for a note.
+	// Returns the tuning that is no longer used, so it can be dropped off the audio thread.
+	fn set_tuning(&mut self, tuning: Box<Tuning>) -> Box<Tuning> {
+		tuning
+	}
 }
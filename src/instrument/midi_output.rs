@@ -0,0 +1,165 @@
+use crate::instrument::*;
+use crate::log::*;
+use crate::midi::OutputEvent;
+use crate::mts::Tuning;
+use ringbuf::HeapProd;
+use ringbuf::traits::*;
+
+// Member channels of an MPE lower zone, channel 1 is the master channel
+const MEMBER_CHANNELS: usize = 15;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROLLER: u8 = 0xb0;
+const CHANNEL_PRESSURE: u8 = 0xd0;
+const PITCH_BEND: u8 = 0xe0;
+
+// Plays an external synth as MPE instead of making sound.
+// Every voice gets its own member channel, so pitch bend and pressure are per note.
+pub struct MidiOutput {
+	tx: Option<HeapProd<OutputEvent>>,
+	// Render frame of the next sample, events are stamped with it
+	frames: u64,
+	bend_range: f32,
+	// Tuning of the synth, set up by sysex sent to the port
+	tuning: Box<Tuning>,
+	// Note number each voice was started with, the rest of the pitch goes into the bend
+	notes: [u8; MEMBER_CHANNELS],
+	// Zone layout and bend range still have to be sent
+	configure: bool,
+}
+
+impl MidiOutput {
+	pub fn set_output(&mut self, tx: Option<HeapProd<OutputEvent>>) {
+		self.tx = tx;
+		self.configure = true;
+	}
+
+	fn send(&mut self, data: &[u8]) {
+		if let Some(tx) = &mut self.tx
+			&& tx.try_push(OutputEvent::new(self.frames, data)).is_err()
+		{
+			log_warn!("Midi output queue full!");
+		}
+	}
+
+	fn channel(id: usize) -> u8 {
+		// Member channels start right after the master channel
+		(id + 1) as u8
+	}
+
+	fn rpn(&mut self, channel: u8, rpn: u8, msb: u8, lsb: u8) {
+		let status = CONTROLLER | channel;
+		self.send(&[status, 101, 0]);
+		self.send(&[status, 100, rpn]);
+		self.send(&[status, 6, msb]);
+		self.send(&[status, 38, lsb]);
+		// Null RPN, so stray data entry messages don't change anything
+		self.send(&[status, 101, 127]);
+		self.send(&[status, 100, 127]);
+	}
+
+	// MPE configuration message for the lower zone, then the bend range of every member channel
+	fn send_configuration(&mut self) {
+		self.rpn(0, 6, MEMBER_CHANNELS as u8, 0);
+		let semitones = self.bend_range.trunc();
+		let cents = ((self.bend_range - semitones) * 100.0).round();
+		for id in 0..MEMBER_CHANNELS {
+			self.rpn(Self::channel(id), 0, semitones as u8, cents as u8);
+		}
+		self.configure = false;
+	}
+
+	fn send_bend(&mut self, pitch: f32, id: usize) {
+		let offset = (pitch - self.tuning.pitch(self.notes[id])) / self.bend_range;
+		let value = (8192.0 + offset * 8192.0).round().clamp(0.0, 16383.0) as u16;
+		let lsb = (value & 0x7f) as u8;
+		let msb = (value >> 7) as u8;
+		self.send(&[PITCH_BEND | Self::channel(id), lsb, msb]);
+	}
+}
+
+impl Instrument for MidiOutput {
+	fn new(_sample_rate: f32) -> Self {
+		MidiOutput {
+			tx: None,
+			frames: 0,
+			bend_range: 48.0,
+			tuning: Box::default(),
+			notes: [0; MEMBER_CHANNELS],
+			configure: true,
+		}
+	}
+
+	fn voice_count(&self) -> usize {
+		MEMBER_CHANNELS
+	}
+
+	fn set_sample_rate(&mut self, _sample_rate: f32) -> bool {
+		true
+	}
+
+	fn set_transport(&mut self, transport: &TransportInfo) {
+		self.frames = transport.frames;
+	}
+
+	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
+		if self.configure && self.tx.is_some() {
+			self.send_configuration();
+		}
+		// Notes that arrive before the next transport update belong to the next segment
+		self.frames += buffer[0].len() as u64;
+	}
+
+	fn note_on(&mut self, pitch: f32, vel: f32, id: usize) {
+		let note = self.tuning.nearest_key(pitch);
+		self.notes[id] = note;
+		// Bend first, so the note doesn't start at the wrong pitch
+		self.send_bend(pitch, id);
+		let vel = (vel * 127.0).round().clamp(1.0, 127.0) as u8;
+		self.send(&[NOTE_ON | Self::channel(id), note, vel]);
+	}
+
+	fn note_off(&mut self, id: usize) {
+		let note = self.notes[id];
+		self.send(&[NOTE_OFF | Self::channel(id), note, 64]);
+	}
+
+	fn pitch(&mut self, pitch: f32, id: usize) {
+		self.send_bend(pitch, id);
+	}
+
+	fn pressure(&mut self, pressure: f32, id: usize) {
+		let value = (pressure * 127.0).round().clamp(0.0, 127.0) as u8;
+		self.send(&[CHANNEL_PRESSURE | Self::channel(id), value]);
+	}
+
+	fn flush(&mut self) {
+		// All notes off on every member channel
+		for id in 0..MEMBER_CHANNELS {
+			self.send(&[CONTROLLER | Self::channel(id), 123, 0]);
+		}
+	}
+
+	fn set_parameter(&mut self, index: usize, value: f32) -> Option<RequestData> {
+		match index {
+			0 => {
+				self.bend_range = value.clamp(1.0, 96.0);
+				self.configure = true;
+			},
+			1 => {
+				// MTS format, the sysex is sent by the frontend
+			},
+			_ => log_warn!("Parameter with index {index} not found"),
+		}
+		None
+	}
+
+	fn as_midi_output(&mut self) -> Option<&mut MidiOutput> {
+		Some(self)
+	}
+
+	fn set_tuning(&mut self, tuning: Box<Tuning>) -> Box<Tuning> {
+		std::mem::replace(&mut self.tuning, tuning)
+	}
+}
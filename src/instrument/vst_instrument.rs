@@ -1,5 +1,6 @@
 use crate::instrument::*;
 use crate::log::*;
+use crate::mts::Tuning;
 use crate::vst3;
 use crate::vst3::Vst3Processor;
 use crate::vst3::Vst3State;
@@ -11,6 +12,9 @@ pub struct VstInstrument {
 	voice_pitches: [i16; N_CHANNELS],
 	mpe_initialized: bool,
 	pb_range: f64,
+	tuning: Box<Tuning>,
+	// Tuning sysex still has to be passed to the plugin
+	send_tuning: bool,
 }
 
 impl VstInstrument {
@@ -44,6 +48,8 @@ impl Instrument for VstInstrument {
 			voice_pitches: [0; N_CHANNELS],
 			mpe_initialized: false,
 			pb_range: 48.0,
+			tuning: Box::default(),
+			send_tuning: false,
 		}
 	}
 
@@ -51,12 +57,38 @@ impl Instrument for VstInstrument {
 		N_CHANNELS
 	}
 
+	fn latency(&self) -> usize {
+		self.processor.as_ref().map_or(0, Vst3Processor::latency)
+	}
+
+	fn set_sample_rate(&mut self, sample_rate: f32) -> bool {
+		if let Some(processor) = &mut self.processor
+			&& let Err(e) = processor.set_sample_rate(sample_rate)
+		{
+			log_error!("Failed to change sample rate: {e}");
+		}
+		true
+	}
+
+	fn set_transport(&mut self, transport: &TransportInfo) {
+		if let Some(processor) = &mut self.processor {
+			processor.set_transport(transport);
+		}
+	}
+
 	fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
 		if let Some(processor) = &mut self.processor {
 			if !self.mpe_initialized {
 				processor.parameters.mpe_init();
 				self.mpe_initialized = true;
 			}
+			if self.send_tuning {
+				// Events point into the tuning, which outlives this process call
+				for message in &self.tuning.sysex {
+					processor.events.push(vst3::event::sysex(message));
+				}
+				self.send_tuning = false;
+			}
 
 			let [bl, br] = buffer;
 			processor.process(bl, br);
@@ -65,7 +97,7 @@ impl Instrument for VstInstrument {
 
 	fn pitch(&mut self, pitch: f32, id: usize) {
 		if let Some(processor) = &mut self.processor {
-			let base_pitch = f32::from(self.voice_pitches[id]);
+			let base_pitch = self.tuning.pitch(self.voice_pitches[id] as u8);
 			let pitch_offset = f64::from(pitch - base_pitch);
 
 			// normalize pitchbend value
@@ -82,13 +114,13 @@ impl Instrument for VstInstrument {
 
 	fn note_on(&mut self, pitch: f32, vel: f32, id: usize) {
 		if let Some(processor) = &mut self.processor {
-			let base_pitch = pitch.round();
-			let pitch_offset = f64::from(pitch - base_pitch);
+			let key = self.tuning.nearest_key(pitch);
+			let pitch_offset = f64::from(pitch - self.tuning.pitch(key));
 
-			let base_pitch = base_pitch as i16;
-			self.voice_pitches[id] = base_pitch;
+			let key = i16::from(key);
+			self.voice_pitches[id] = key;
 
-			processor.events.push(vst3::event::note_on(id, base_pitch, vel));
+			processor.events.push(vst3::event::note_on(id, key, vel));
 
 			// normalize pitchbend value
 			let pitchbend = 0.5 + pitch_offset * (0.5 / self.pb_range);
@@ -119,6 +151,9 @@ impl Instrument for VstInstrument {
 					_ => 48.0,
 				};
 			},
+			2 => {
+				// MTS format, the sysex is built by the frontend
+			},
 			_ => log_warn!("Parameter with index {index} not found"),
 		}
 		None
@@ -127,4 +162,9 @@ impl Instrument for VstInstrument {
 	fn as_vst(&mut self) -> &mut VstInstrument {
 		self
 	}
+
+	fn set_tuning(&mut self, tuning: Box<Tuning>) -> Box<Tuning> {
+		self.send_tuning = !tuning.sysex.is_empty();
+		std::mem::replace(&mut self.tuning, tuning)
+	}
 }
@@ -24,16 +24,30 @@
 #![allow(clippy::too_many_arguments)]
 
 pub mod audio;
+mod automation;
 mod channel;
+mod clip;
+mod clock;
 pub mod context;
 mod effect;
 pub mod embed;
+pub mod export;
+mod input;
 mod instrument;
 mod meters;
 mod metronome;
 pub mod midi;
+mod midi_input;
+mod mts;
+pub mod offline;
+mod parameters;
+mod recorder;
 mod render;
+mod routing;
 mod scope;
+mod sequencer;
+mod smf;
+mod tempo;
 mod voice_manager;
 pub mod vst3;
 mod worker;
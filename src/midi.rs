@@ -1,11 +1,30 @@
+use crate::api::lua_serde;
+use crate::clock::SampleClock;
 use crate::log::{log_error, log_info, log_warn};
-use midir::{Ignore, MidiInput, MidiInputConnection};
-use mlua::Value;
-use mlua::prelude::*;
+use crate::midi_input::{InputConfig, InputEvent, InputProcessor, LiveInput, StampClock};
+use midir::{
+	Ignore, MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection,
+	MidiOutputPort,
+};
+use parking_lot::Mutex;
 use ringbuf::traits::*;
 use ringbuf::{HeapCons, HeapProd, HeapRb};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-// TODO: some kind of de-duplication logic in case we have two ports with the same name. (port.id() is unreliable)
+// Events a channel can queue for an output port before they get dropped
+const OUTPUT_QUEUE_SIZE: usize = 1024;
+const OUTPUT_INTERVAL: Duration = Duration::from_millis(1);
+
+// Client name of our virtual ports, as other applications see them
+const VIRTUAL_CLIENT: &str = "Tessera";
+// Prefix of the port ids we give our virtual ports
+const VIRTUAL_ID: &str = "virtual:";
+pub const VIRTUAL_PORTS: bool = cfg!(target_os = "linux");
 
 #[derive(Debug)]
 pub struct Event {
@@ -18,15 +37,38 @@ pub enum Message {
 	NoteOff { note: u8 },
 	NoteOn { note: u8, vel: f32 },
 	Aftertouch { note: u8, pressure: f32 },
-	Controller { controller: u8, value: f32 },
+	// Raw value, so 14 bit pairs and data entry can be put together
+	Controller { controller: u8, value: u8 },
+	ProgramChange { program: u8 },
 	Pressure(f32),
 	PitchBend(f32),
 }
 
 pub struct Connection {
-	pub connection: MidiInputConnection<HeapProd<Event>>,
-	pub midi_rx: HeapCons<Event>,
+	pub connection: MidiInputConnection<InputState>,
+	// Events with the time they arrived, for recording
+	pub midi_rx: HeapCons<(Instant, InputEvent)>,
+	config_tx: Sender<InputConfig>,
 	pub name: String,
+	id: String,
+}
+
+impl Connection {
+	pub fn set_config(&self, config: InputConfig) {
+		if self.config_tx.send(config).is_err() {
+			log_error!("Midi port \"{}\" is closed.", self.name);
+		}
+	}
+}
+
+// Owned by the midir callback
+pub struct InputState {
+	port: String,
+	processor: InputProcessor,
+	config_rx: Receiver<InputConfig>,
+	stamps: StampClock,
+	live: Arc<Mutex<LiveInput>>,
+	midi_tx: HeapProd<(Instant, InputEvent)>,
 }
 
 // Create a midi input "session" and keep it alive for querying port_names
@@ -40,50 +82,613 @@ pub fn open_midi() -> Option<MidiInput> {
 	}
 }
 
+// Our own virtual ports show up in the port lists as well, connecting to them would loop back
+fn is_own_port(name: &str) -> bool {
+	name.strip_prefix(VIRTUAL_CLIENT)
+		.is_some_and(|rest| rest.starts_with(':'))
+}
+
+// ALSA adds the client and port number, which change when a device is plugged in again
+fn stable_name(name: &str) -> &str {
+	let Some((rest, numbers)) = name.rsplit_once(' ') else {
+		return name;
+	};
+	let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+	match numbers.split_once(':') {
+		Some((client, port)) if is_number(client) && is_number(port) => rest,
+		_ => name,
+	}
+}
+
+// Ports of one direction as (id, name) the way the system lists them.
+// Our own virtual ports are left out, they get added by name.
+fn list_ports<P>(
+	ports: Vec<P>,
+	port_id: impl Fn(&P) -> String,
+	port_name: impl Fn(&P) -> Option<String>,
+) -> Vec<(String, String)> {
+	ports
+		.iter()
+		.filter_map(|p| Some((port_id(p), port_name(p)?)))
+		.filter(|(_, name)| !is_own_port(name))
+		.collect()
+}
+
+pub fn input_ports(midi_in: &MidiInput) -> Vec<(String, String)> {
+	list_ports(midi_in.ports(), MidiInputPort::id, |p| midi_in.port_name(p).ok())
+}
+
 pub fn port_names(midi_in: &MidiInput) -> Vec<String> {
-	let ports = midi_in.ports();
-	ports.iter().map(|p| midi_in.port_name(p).unwrap()).collect()
-}
-
-pub fn connect(port_name: &str) -> Option<Connection> {
-	if let Some(mut midi_in) = open_midi() {
-		// ignore sysex and such
-		midi_in.ignore(Ignore::All);
-
-		for p in &midi_in.ports() {
-			let name = midi_in.port_name(p).unwrap();
-
-			if name == port_name {
-				let (midi_tx, midi_rx) = HeapRb::<Event>::new(256).split();
-
-				let connect_result = midi_in.connect(
-					p,
-					"midir-test",
-					|_stamp, message, midi_rx| {
-						let event = Event::from_bytes(message);
-						if let Some(e) = event
-							&& midi_rx.try_push(e).is_err()
-						{
-							log_warn!("Midi queue full!");
-						}
-					},
-					midi_tx,
-				);
-
-				match connect_result {
-					Ok(connection) => {
-						log_info!("Opened midi port \"{name}\".");
-						return Some(Connection { connection, midi_rx, name });
-					},
-					Err(err) => {
-						log_error!("Failed to open midi port \"{port_name}\".");
-						log_error!("\t{err}");
-						return None;
-					},
-				}
+	let mut list = PortList::default();
+	list.update(input_ports(midi_in), &mut Vec::new());
+	list.names()
+}
+
+// Virtual ports listed like the others, with an id no system port has
+pub fn virtual_ports(names: &[String]) -> Vec<(String, String)> {
+	names
+		.iter()
+		.map(|name| (format!("{VIRTUAL_ID}{name}"), name.clone()))
+		.collect()
+}
+
+// Name a virtual port was created with, from its id
+pub fn virtual_name(id: &str) -> Option<&str> {
+	id.strip_prefix(VIRTUAL_ID)
+}
+
+#[derive(Debug, Clone)]
+struct Port {
+	// Stays the same while the port is there, a device that is plugged in again gets a new one
+	id: String,
+	// As the system lists it
+	system_name: String,
+	// As we know it
+	name: String,
+}
+
+// Ports with the names we know them by.
+// A port keeps its name while it is there, also when an identical device before it goes away.
+// New ports get the first free name of their device: "Keys", "Keys #2" and so on,
+// so a device that comes back gets the name it had before.
+#[derive(Default)]
+struct PortList {
+	ports: Vec<Port>,
+}
+
+impl PortList {
+	// `listed` is (id, name) of every port that is there now
+	fn update(&mut self, listed: Vec<(String, String)>, changes: &mut Vec<PortChange>) {
+		let old = std::mem::take(&mut self.ports);
+		let mut new = Vec::new();
+		for (id, system_name) in listed {
+			match old.iter().find(|p| p.id == id && p.system_name == system_name) {
+				Some(port) => self.ports.push(port.clone()),
+				None => new.push((id, system_name)),
+			}
+		}
+		for (id, system_name) in new {
+			let base = stable_name(&system_name);
+			let mut name = base.to_string();
+			let mut n = 1;
+			while self.ports.iter().any(|p| p.name == name) {
+				n += 1;
+				name = format!("{base} #{n}");
+			}
+			self.ports.push(Port { id, system_name, name });
+		}
+
+		for port in self.ports.iter().filter(|p| !old.iter().any(|o| o.name == p.name)) {
+			changes.push(PortChange { name: port.name.clone(), state: PortState::Added });
+		}
+		for port in old.iter().filter(|o| !self.ports.iter().any(|p| p.name == o.name)) {
+			changes.push(PortChange { name: port.name.clone(), state: PortState::Removed });
+		}
+	}
+
+	fn get(&self, name: &str) -> Option<&Port> {
+		self.ports.iter().find(|p| p.name == name)
+	}
+
+	fn names(&self) -> Vec<String> {
+		self.ports.iter().map(|p| p.name.clone()).collect()
+	}
+}
+
+fn input_state(
+	port_name: &str,
+	config: InputConfig,
+	tokens: Arc<AtomicU32>,
+	live: Arc<Mutex<LiveInput>>,
+) -> (InputState, HeapCons<(Instant, InputEvent)>, Sender<InputConfig>) {
+	let (midi_tx, midi_rx) = HeapRb::<(Instant, InputEvent)>::new(256).split();
+	let (config_tx, config_rx) = mpsc::channel();
+	let state = InputState {
+		port: port_name.to_string(),
+		processor: InputProcessor::new(config, tokens),
+		config_rx,
+		stamps: StampClock::new(),
+		live,
+		midi_tx,
+	};
+	(state, midi_rx, config_tx)
+}
+
+// Midir callback of an input port
+fn receive(stamp: u64, message: &[u8], state: &mut InputState) {
+	if let Some(config) = state.config_rx.try_iter().last() {
+		state.processor.set_config(config);
+	}
+	let Some(event) = Event::from_bytes(message) else {
+		return;
+	};
+	let instant = state.stamps.instant(stamp);
+	let midi_tx = &mut state.midi_tx;
+	let mut live = state.live.lock();
+	let port = &state.port;
+	state.processor.process(&event, &mut |e| {
+		live.send(instant, &e);
+		live.map(instant, port, &e, &mut |mapped| {
+			if midi_tx.try_push((instant, mapped)).is_err() {
+				log_warn!("Midi queue full!");
+			}
+		});
+		if midi_tx.try_push((instant, e)).is_err() {
+			log_warn!("Midi queue full!");
+		}
+	});
+}
+
+// Port `id` is what we call `port_name`
+pub fn connect(
+	port_id: &str,
+	port_name: &str,
+	config: InputConfig,
+	tokens: Arc<AtomicU32>,
+	live: Arc<Mutex<LiveInput>>,
+) -> Option<Connection> {
+	let mut midi_in = open_midi()?;
+	// ignore sysex and such
+	midi_in.ignore(Ignore::All);
+
+	let Some(port) = midi_in.ports().into_iter().find(|p| p.id() == port_id) else {
+		log_error!("Midi port \"{port_name}\" not found.");
+		return None;
+	};
+
+	let (state, midi_rx, config_tx) = input_state(port_name, config, tokens, live);
+	match midi_in.connect(&port, "midir-test", receive, state) {
+		Ok(connection) => {
+			log_info!("Opened midi port \"{port_name}\".");
+			let (name, id) = (port_name.to_string(), port_id.to_string());
+			Some(Connection { connection, midi_rx, config_tx, name, id })
+		},
+		Err(err) => {
+			log_error!("Failed to open midi port \"{port_name}\".");
+			log_error!("\t{err}");
+			None
+		},
+	}
+}
+
+// Input port that other applications can connect to and play on
+#[cfg(target_os = "linux")]
+pub fn connect_virtual(
+	port_name: &str,
+	config: InputConfig,
+	tokens: Arc<AtomicU32>,
+	live: Arc<Mutex<LiveInput>>,
+) -> Option<Connection> {
+	use midir::os::unix::VirtualInput;
+
+	let mut midi_in = match MidiInput::new(VIRTUAL_CLIENT) {
+		Ok(midi_in) => midi_in,
+		Err(e) => {
+			log_error!("{e}");
+			return None;
+		},
+	};
+	midi_in.ignore(Ignore::All);
+
+	let (state, midi_rx, config_tx) = input_state(port_name, config, tokens, live);
+	match midi_in.create_virtual(port_name, receive, state) {
+		Ok(connection) => {
+			log_info!("Created virtual midi port \"{port_name}\".");
+			let name = port_name.to_string();
+			let id = format!("{VIRTUAL_ID}{port_name}");
+			Some(Connection { connection, midi_rx, config_tx, name, id })
+		},
+		Err(err) => {
+			log_error!("Failed to create virtual midi port \"{port_name}\".");
+			log_error!("\t{err}");
+			None
+		},
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn connect_virtual(
+	port_name: &str,
+	_config: InputConfig,
+	_tokens: Arc<AtomicU32>,
+	_live: Arc<Mutex<LiveInput>>,
+) -> Option<Connection> {
+	log_error!("Virtual midi port \"{port_name}\" is not supported on this platform.");
+	None
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortState {
+	Added,
+	Removed,
+	Connected,
+	Disconnected,
+	// Not retried until the port is plugged in again
+	Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortChange {
+	pub name: String,
+	pub state: PortState,
+}
+
+lua_serde!(PortChange);
+
+// Port that should be open.
+// It is kept while the device is unplugged, so the connection comes back when the device does.
+struct Wanted<T> {
+	name: String,
+	config: T,
+	// Id of the port that failed to open, not retried until the device is plugged in again
+	failed: Option<String>,
+}
+
+pub trait PortConnection {
+	type Config: Clone;
+
+	fn name(&self) -> &str;
+	// Id of the port it was opened on
+	fn id(&self) -> &str;
+}
+
+impl PortConnection for Connection {
+	type Config = InputConfig;
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn id(&self) -> &str {
+		&self.id
+	}
+}
+
+impl PortConnection for OutputConnection {
+	type Config = ();
+
+	fn name(&self) -> &str {
+		&self.name
+	}
+
+	fn id(&self) -> &str {
+		&self.id
+	}
+}
+
+// Ports of one direction that are there, and connections to the ones that should be open
+pub struct Ports<C: PortConnection> {
+	list: PortList,
+	wanted: Vec<Wanted<C::Config>>,
+	connections: Vec<C>,
+}
+
+pub type InputPorts = Ports<Connection>;
+pub type OutputPorts = Ports<OutputConnection>;
+
+impl<C: PortConnection> Default for Ports<C> {
+	fn default() -> Self {
+		Self { list: PortList::default(), wanted: Vec::new(), connections: Vec::new() }
+	}
+}
+
+impl<C: PortConnection> Ports<C> {
+	// Connects on the next scan, or whenever the port shows up
+	pub fn open(&mut self, name: String, config: C::Config) {
+		match self.wanted.iter_mut().find(|w| w.name == name) {
+			Some(w) => {
+				w.config = config;
+				w.failed = None;
+			},
+			None => self.wanted.push(Wanted { name, config, failed: None }),
+		}
+	}
+
+	pub fn close(&mut self, name: &str) -> bool {
+		self.wanted.retain(|w| w.name != name);
+		let Some(index) = self.connections.iter().position(|c| c.name() == name) else {
+			return false;
+		};
+		// Dropping it closes the port
+		let connection = self.connections.remove(index);
+		log_info!("Closed connection \"{}\"", connection.name());
+		true
+	}
+
+	pub fn get(&self, name: &str) -> Option<&C> {
+		self.connections.iter().find(|c| c.name() == name)
+	}
+
+	pub fn get_mut(&mut self, name: &str) -> Option<&mut C> {
+		self.connections.iter_mut().find(|c| c.name() == name)
+	}
+
+	// Ports that were there on the last scan
+	pub fn names(&self) -> Vec<String> {
+		self.list.names()
+	}
+
+	// Compare with the ports that are there now, `listed` as (id, name).
+	// Connections to ports that went away are dropped, wanted ports that are there get opened
+	// with `connect(id, name, config)`.
+	pub fn scan(
+		&mut self,
+		listed: Vec<(String, String)>,
+		mut connect: impl FnMut(&str, &str, &C::Config) -> Option<C>,
+	) -> Vec<PortChange> {
+		let mut changes = Vec::new();
+		self.list.update(listed, &mut changes);
+
+		// A device that was plugged in again is a new port, the old connection is dead
+		let mut i = 0;
+		while i < self.connections.len() {
+			let c = &self.connections[i];
+			if self.list.get(c.name()).is_some_and(|p| p.id == c.id()) {
+				i += 1;
+				continue;
 			}
+			let connection = self.connections.remove(i);
+			log_warn!("Midi port \"{}\" disconnected.", connection.name());
+			let name = connection.name().to_string();
+			changes.push(PortChange { name, state: PortState::Disconnected });
 		}
+
+		for w in &mut self.wanted {
+			let Some(port) = self.list.get(&w.name) else {
+				continue;
+			};
+			if w.failed.as_ref() == Some(&port.id)
+				|| self.connections.iter().any(|c| c.name() == w.name)
+			{
+				continue;
+			}
+			let name = w.name.clone();
+			match connect(&port.id, &port.name, &w.config) {
+				Some(connection) => {
+					self.connections.push(connection);
+					w.failed = None;
+					changes.push(PortChange { name, state: PortState::Connected });
+				},
+				None => {
+					w.failed = Some(port.id.clone());
+					changes.push(PortChange { name, state: PortState::Failed });
+				},
+			}
+		}
+		changes
 	}
+}
+
+impl InputPorts {
+	pub fn set_config(&mut self, name: &str, config: InputConfig) -> bool {
+		if let Some(w) = self.wanted.iter_mut().find(|w| w.name == name) {
+			w.config = config.clone();
+		}
+		match self.get(name) {
+			Some(connection) => {
+				connection.set_config(config);
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+// Create a midi output "session" and keep it alive for querying port_names
+pub fn open_midi_output() -> Option<MidiOutput> {
+	match MidiOutput::new("midir output") {
+		Ok(midi_out) => Some(midi_out),
+		Err(e) => {
+			log_error!("{e}");
+			None
+		},
+	}
+}
+
+pub fn output_ports(midi_out: &MidiOutput) -> Vec<(String, String)> {
+	list_ports(midi_out.ports(), MidiOutputPort::id, |p| midi_out.port_name(p).ok())
+}
+
+// Channel message for an output port, stamped with the render frame it belongs to
+#[derive(Debug, Clone, Copy)]
+pub struct OutputEvent {
+	pub time: u64,
+	data: [u8; 3],
+	len: usize,
+}
+
+impl OutputEvent {
+	pub fn new(time: u64, data: &[u8]) -> Self {
+		assert!(data.len() <= 3);
+		let mut event = Self { time, data: [0; 3], len: data.len() };
+		event.data[..data.len()].copy_from_slice(data);
+		event
+	}
+
+	pub fn bytes(&self) -> &[u8] {
+		&self.data[..self.len]
+	}
+}
+
+// Queue of one channel that sends to a port
+struct OutputSource {
+	rx: HeapCons<OutputEvent>,
+	clock: Arc<SampleClock>,
+}
+
+impl OutputSource {
+	// Send everything that is audible by now.
+	// Without a running stream there is nothing to wait for, so the rest goes out right away.
+	fn send_due(&mut self, connection: &mut MidiOutputConnection) {
+		let now = self.clock.playback_time();
+		while let Some(event) = self.rx.try_peek()
+			&& now.is_none_or(|now| event.time <= now)
+		{
+			if let Err(e) = connection.send(event.bytes()) {
+				log_error!("{e}");
+			}
+			self.rx.skip(1);
+		}
+	}
+}
+
+enum OutputCommand {
+	AddSource(OutputSource),
+	// Not tied to the audio, goes out right away. Used for sysex.
+	Send(Vec<u8>),
+}
+
+// An open output port.
+// Channels on the audio thread push into their own queue, a separate thread
+// sends the events once the audio they belong to is being played.
+pub struct OutputConnection {
+	pub name: String,
+	id: String,
+	command_tx: Sender<OutputCommand>,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<()>>,
+}
+
+impl OutputConnection {
+	fn new(name: &str, id: String, connection: MidiOutputConnection) -> Self {
+		let (command_tx, command_rx) = mpsc::channel();
+		let stop = Arc::new(AtomicBool::new(false));
+		let thread = {
+			let stop = Arc::clone(&stop);
+			std::thread::spawn(move || output_thread(connection, &command_rx, &stop))
+		};
+		Self { name: name.to_string(), id, command_tx, stop, thread: Some(thread) }
+	}
+
+	// New queue for a channel that sends to this port
+	pub fn add_source(&self, clock: Arc<SampleClock>) -> HeapProd<OutputEvent> {
+		let (tx, rx) = HeapRb::<OutputEvent>::new(OUTPUT_QUEUE_SIZE).split();
+		if self
+			.command_tx
+			.send(OutputCommand::AddSource(OutputSource { rx, clock }))
+			.is_err()
+		{
+			log_error!("Midi output \"{}\" is closed.", self.name);
+		}
+		tx
+	}
+
+	pub fn send(&self, message: Vec<u8>) {
+		if self.command_tx.send(OutputCommand::Send(message)).is_err() {
+			log_error!("Midi output \"{}\" is closed.", self.name);
+		}
+	}
+}
+
+impl Drop for OutputConnection {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Release);
+		if let Some(thread) = self.thread.take()
+			&& thread.join().is_err()
+		{
+			log_error!("Midi output thread panicked.");
+		}
+	}
+}
+
+fn output_thread(
+	mut connection: MidiOutputConnection,
+	command_rx: &Receiver<OutputCommand>,
+	stop: &AtomicBool,
+) {
+	let mut sources: Vec<OutputSource> = Vec::new();
+	while !stop.load(Ordering::Acquire) {
+		for command in command_rx.try_iter() {
+			match command {
+				OutputCommand::AddSource(source) => sources.push(source),
+				OutputCommand::Send(message) => {
+					if let Err(e) = connection.send(&message) {
+						log_error!("{e}");
+					}
+				},
+			}
+		}
+		for source in &mut sources {
+			source.send_due(&mut connection);
+		}
+		// Channel was removed or switched to another port
+		sources.retain(|s| s.rx.write_is_held() || !s.rx.is_empty());
+		std::thread::sleep(OUTPUT_INTERVAL);
+	}
+	connection.close();
+}
+
+// Port `id` is what we call `port_name`
+pub fn connect_output(port_id: &str, port_name: &str) -> Option<OutputConnection> {
+	let midi_out = open_midi_output()?;
+	let Some(port) = midi_out.ports().into_iter().find(|p| p.id() == port_id) else {
+		log_error!("Midi output \"{port_name}\" not found.");
+		return None;
+	};
+
+	match midi_out.connect(&port, "tessera-output") {
+		Ok(connection) => {
+			log_info!("Opened midi output \"{port_name}\".");
+			Some(OutputConnection::new(port_name, port_id.to_string(), connection))
+		},
+		Err(err) => {
+			log_error!("Failed to open midi output \"{port_name}\".");
+			log_error!("\t{err}");
+			None
+		},
+	}
+}
+
+// Output port that other applications can connect to, to be played by our channels
+#[cfg(target_os = "linux")]
+pub fn connect_virtual_output(port_name: &str) -> Option<OutputConnection> {
+	use midir::os::unix::VirtualOutput;
+
+	let midi_out = match MidiOutput::new(VIRTUAL_CLIENT) {
+		Ok(midi_out) => midi_out,
+		Err(e) => {
+			log_error!("{e}");
+			return None;
+		},
+	};
+	match midi_out.create_virtual(port_name) {
+		Ok(connection) => {
+			log_info!("Created virtual midi output \"{port_name}\".");
+			let id = format!("{VIRTUAL_ID}{port_name}");
+			Some(OutputConnection::new(port_name, id, connection))
+		},
+		Err(err) => {
+			log_error!("Failed to create virtual midi output \"{port_name}\".");
+			log_error!("\t{err}");
+			None
+		},
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn connect_virtual_output(port_name: &str) -> Option<OutputConnection> {
+	log_error!("Virtual midi output \"{port_name}\" is not supported on this platform.");
 	None
 }
 
@@ -115,8 +720,8 @@ impl Event {
 				}
 			},
 			10 => Aftertouch { note: a, pressure: f32::from(b) / 127.0 },
-			11 => Controller { controller: a, value: f32::from(b) / 127.0 },
-			12 => return None, // Program change
+			11 => Controller { controller: a, value: b },
+			12 => ProgramChange { program: a },
 			13 => Pressure(f32::from(a) / 127.0),
 			14 => PitchBend((i32::from(a) + i32::from(b) * 128 - 8192) as f32 / 8192.0),
 			s => {
@@ -131,42 +736,59 @@ impl Event {
 	}
 }
 
-impl IntoLua for Event {
-	fn into_lua(self, lua: &Lua) -> LuaResult<Value> {
-		use Message::*;
-		let table = Lua::create_table(lua)?;
-		table.set("channel", self.channel)?;
-
-		match self.message {
-			NoteOn { note, vel } => {
-				table.set("name", "note_on")?;
-				table.set("note", note)?;
-				table.set("vel", vel)?;
-			},
-			NoteOff { note } => {
-				table.set("name", "note_off")?;
-				table.set("note", note)?;
-			},
-			Aftertouch { note, pressure } => {
-				table.set("name", "aftertouch")?;
-				table.set("note", note)?;
-				table.set("pressure", pressure)?;
-			},
-			Controller { controller, value } => {
-				table.set("name", "controller")?;
-				table.set("controller", controller)?;
-				table.set("value", value)?;
-			},
-			Pressure(p) => {
-				table.set("name", "pressure")?;
-				table.set("pressure", p)?;
-			},
-			PitchBend(p) => {
-				table.set("name", "pitchbend")?;
-				table.set("pitchbend", p)?;
-			},
-		}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn listed(ports: &[(&str, &str)]) -> Vec<(String, String)> {
+		ports
+			.iter()
+			.map(|(id, name)| (id.to_string(), name.to_string()))
+			.collect()
+	}
+
+	fn update(list: &mut PortList, ports: &[(&str, &str)]) -> Vec<(String, PortState)> {
+		let mut changes = Vec::new();
+		list.update(listed(ports), &mut changes);
+		changes.into_iter().map(|c| (c.name, c.state)).collect()
+	}
+
+	#[test]
+	fn test_stable_name() {
+		assert_eq!(stable_name("Keys 20:0"), "Keys");
+		assert_eq!(stable_name("Keys MIDI 1"), "Keys MIDI 1");
+		assert_eq!(stable_name("Keys 20:x"), "Keys 20:x");
+		assert_eq!(stable_name("Keys"), "Keys");
+	}
+
+	#[test]
+	fn test_identical_devices() {
+		let mut list = PortList::default();
+		let changes = update(&mut list, &[("20:0", "Keys 20:0"), ("24:0", "Keys 24:0")]);
+		assert_eq!(
+			changes,
+			[("Keys".to_string(), PortState::Added), ("Keys #2".to_string(), PortState::Added)]
+		);
+
+		// unplugging the first one leaves the second one as it is
+		let changes = update(&mut list, &[("24:0", "Keys 24:0")]);
+		assert_eq!(changes, [("Keys".to_string(), PortState::Removed)]);
+		assert_eq!(list.get("Keys #2").unwrap().id, "24:0");
+
+		// it comes back with a new client number and gets the free name
+		let changes = update(&mut list, &[("24:0", "Keys 24:0"), ("28:0", "Keys 28:0")]);
+		assert_eq!(changes, [("Keys".to_string(), PortState::Added)]);
+		assert_eq!(list.get("Keys").unwrap().id, "28:0");
+		assert_eq!(list.get("Keys #2").unwrap().id, "24:0");
+	}
 
-		Ok(Value::Table(table))
+	#[test]
+	fn test_replugged_device() {
+		let mut list = PortList::default();
+		update(&mut list, &[("20:0", "Keys 20:0")]);
+		// plugged in again between two scans, same name on a new port
+		let changes = update(&mut list, &[("28:0", "Keys 28:0")]);
+		assert!(changes.is_empty());
+		assert_eq!(list.get("Keys").unwrap().id, "28:0");
 	}
 }
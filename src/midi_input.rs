@@ -0,0 +1,976 @@
+// Turns raw MIDI input into notes with tokens and absolute pitch.
+// Keeps track of MPE zones, bend ranges and RPN/NRPN state per channel, so the frontend
+// doesn't have to know how a controller is set up.
+
+use crate::api::lua_serde;
+use crate::api::project::{EncoderMode, MidiMapping};
+use crate::clock::SampleClock;
+use crate::context::{AudioEvent, AudioMessage};
+use crate::log::log_warn;
+use crate::midi::{Event, Message};
+use crate::voice_manager::Token;
+use mlua::prelude::*;
+use ringbuf::HeapProd;
+use ringbuf::traits::*;
+use serde::{Deserialize, Serialize};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+const N_CHANNELS: usize = 16;
+const LOWER_MASTER: u8 = 0;
+const UPPER_MASTER: u8 = 15;
+
+// Defaults from the MPE spec, also used after a zone gets configured
+const MEMBER_BEND_RANGE: f32 = 48.0;
+const MASTER_BEND_RANGE: f32 = 2.0;
+const BEND_RANGE: f32 = 2.0;
+
+const RPN_BEND_RANGE: u16 = 0;
+const RPN_MPE_CONFIGURATION: u16 = 6;
+const RPN_NULL: u16 = 0x3fff;
+
+const CC_MOD_WHEEL: u8 = 1;
+const CC_BREATH: u8 = 2;
+const CC_DATA_ENTRY: u8 = 6;
+const CC_DATA_ENTRY_LSB: u8 = 38;
+const CC_SUSTAIN: u8 = 64;
+const CC_DATA_INCREMENT: u8 = 96;
+const CC_DATA_DECREMENT: u8 = 97;
+const CC_NRPN_LSB: u8 = 98;
+const CC_NRPN_MSB: u8 = 99;
+const CC_RPN_LSB: u8 = 100;
+const CC_RPN_MSB: u8 = 101;
+
+// How fast the timestamp offset may follow a port clock that runs slower than ours
+const STAMP_DRIFT: i64 = 1_000;
+
+// Range of a mapped parameter covered by one step of a relative encoder
+const RELATIVE_STEP: f64 = 0.01;
+
+// Input settings of a device, stored in the setup file.
+// The device can still change the zones and bend ranges with RPN messages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InputConfig {
+	// Start with a lower zone on all channels, for devices that don't send their configuration
+	#[serde(default)]
+	pub mpe: bool,
+	// Bend range of member channels, or of all channels without MPE.
+	// Overrides whatever the device reports.
+	#[serde(default)]
+	pub bend_range: Option<f32>,
+	#[serde(default)]
+	pub master_bend_range: Option<f32>,
+	// Mod wheel and breath act as pressure, for keyboards that don't send any
+	#[serde(default)]
+	pub mod_pressure: bool,
+}
+
+lua_serde!(InputConfig);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ZoneId {
+	Lower,
+	Upper,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+	Master(ZoneId),
+	Member(ZoneId),
+	// Not part of a zone, bend and pressure apply to the notes of the channel
+	Conventional,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Parameter {
+	Registered(u16),
+	NonRegistered(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+	// Normalized to [-1, 1]
+	bend: f32,
+	bend_range: f32,
+	// Last MSB of controllers 0-31, for 14 bit pairs. None until one was received,
+	// controllers 32-63 are plain 7 bit controllers until then.
+	msb: [Option<u8>; 32],
+	parameter_msb: u8,
+	parameter_lsb: u8,
+	parameter: Option<Parameter>,
+	data: u16,
+}
+
+impl Default for ChannelState {
+	fn default() -> Self {
+		Self {
+			bend: 0.0,
+			bend_range: BEND_RANGE,
+			msb: [None; 32],
+			parameter_msb: 0x7f,
+			parameter_lsb: 0x7f,
+			parameter: None,
+			data: 0,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ActiveNote {
+	channel: u8,
+	note: u8,
+	token: Token,
+}
+
+#[derive(Debug)]
+pub enum InputEvent {
+	// `pitch` is absolute, `offset` is the bend relative to the key
+	NoteOn { token: Token, note: u8, pitch: f32, offset: f32, vel: f32 },
+	NoteOff { token: Token },
+	Pitch { token: Token, pitch: f32, offset: f32 },
+	Pressure { token: Token, pressure: f32 },
+	Sustain(bool),
+	// 14 bit for controllers 0-31 once the LSB arrives, reported under the MSB number
+	// with `high_res` set
+	Controller { channel: u8, controller: u8, value: f32, high_res: bool },
+	// RPN and NRPN that are not handled here, the value is 14 bit
+	Parameter { channel: u8, registered: bool, number: u16, value: f32 },
+	ProgramChange { channel: u8, program: u8 },
+	// Parameter that a controller mapping already sent to the audio thread.
+	// 1-based channel and parameter, like in the project.
+	Mapped { channel: usize, device: usize, index: usize, value: f64 },
+}
+
+pub struct InputProcessor {
+	config: InputConfig,
+	// Number of member channels
+	lower: u8,
+	upper: u8,
+	channels: [ChannelState; N_CHANNELS],
+	notes: Vec<ActiveNote>,
+	tokens: Arc<AtomicU32>,
+}
+
+impl InputProcessor {
+	pub fn new(config: InputConfig, tokens: Arc<AtomicU32>) -> Self {
+		let mut new = Self {
+			config: InputConfig::default(),
+			lower: 0,
+			upper: 0,
+			channels: [ChannelState::default(); N_CHANNELS],
+			notes: Vec::with_capacity(128),
+			tokens,
+		};
+		new.set_config(config);
+		new
+	}
+
+	pub fn set_config(&mut self, config: InputConfig) {
+		let mpe = config.mpe;
+		self.config = config;
+		self.upper = 0;
+		self.configure_zone(ZoneId::Lower, if mpe { 15 } else { 0 });
+	}
+
+	fn next_token(&self) -> Token {
+		self.tokens.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+	}
+
+	fn role(&self, channel: u8) -> Role {
+		if self.lower > 0 {
+			if channel == LOWER_MASTER {
+				return Role::Master(ZoneId::Lower);
+			}
+			if channel <= self.lower {
+				return Role::Member(ZoneId::Lower);
+			}
+		}
+		if self.upper > 0 {
+			if channel == UPPER_MASTER {
+				return Role::Master(ZoneId::Upper);
+			}
+			if channel >= UPPER_MASTER - self.upper {
+				return Role::Member(ZoneId::Upper);
+			}
+		}
+		Role::Conventional
+	}
+
+	fn zone_of(&self, channel: u8) -> Option<ZoneId> {
+		match self.role(channel) {
+			Role::Master(zone) | Role::Member(zone) => Some(zone),
+			Role::Conventional => None,
+		}
+	}
+
+	fn master(zone: ZoneId) -> u8 {
+		match zone {
+			ZoneId::Lower => LOWER_MASTER,
+			ZoneId::Upper => UPPER_MASTER,
+		}
+	}
+
+	fn members(&self, zone: ZoneId) -> RangeInclusive<u8> {
+		match zone {
+			ZoneId::Lower => 1..=self.lower,
+			ZoneId::Upper => UPPER_MASTER - self.upper..=UPPER_MASTER - 1,
+		}
+	}
+
+	// MPE configuration message. Zones that would overlap get shrunk.
+	fn configure_zone(&mut self, zone: ZoneId, members: u8) {
+		let members = members.min(15);
+		match zone {
+			ZoneId::Lower => {
+				self.lower = members;
+				self.upper = self.upper.min(14 - members.min(14));
+			},
+			ZoneId::Upper => {
+				self.upper = members;
+				self.lower = self.lower.min(14 - members.min(14));
+			},
+		}
+		if members > 0 {
+			let member_range = self.config.bend_range.unwrap_or(MEMBER_BEND_RANGE);
+			let master_range = self.config.master_bend_range.unwrap_or(MASTER_BEND_RANGE);
+			for ch in self.members(zone) {
+				self.channels[usize::from(ch)].bend_range = member_range;
+			}
+			self.channels[usize::from(Self::master(zone))].bend_range = master_range;
+		}
+		// Channels that left a zone
+		for ch in 0..N_CHANNELS as u8 {
+			if self.role(ch) == Role::Conventional {
+				let range = self.config.bend_range.unwrap_or(BEND_RANGE);
+				self.channels[usize::from(ch)].bend_range = range;
+			}
+		}
+	}
+
+	fn set_bend_range(&mut self, channel: u8, range: f32) {
+		match self.role(channel) {
+			Role::Master(_) => {
+				let range = self.config.master_bend_range.unwrap_or(range);
+				self.channels[usize::from(channel)].bend_range = range;
+			},
+			// Applies to the whole zone
+			Role::Member(zone) => {
+				let range = self.config.bend_range.unwrap_or(range);
+				for ch in self.members(zone) {
+					self.channels[usize::from(ch)].bend_range = range;
+				}
+			},
+			Role::Conventional => {
+				let range = self.config.bend_range.unwrap_or(range);
+				self.channels[usize::from(channel)].bend_range = range;
+			},
+		}
+	}
+
+	// Bend of a note in semitones, member channels add the bend of their master channel
+	fn offset(&self, channel: u8) -> f32 {
+		let ch = &self.channels[usize::from(channel)];
+		let mut offset = ch.bend * ch.bend_range;
+		if let Role::Member(zone) = self.role(channel) {
+			let master = &self.channels[usize::from(Self::master(zone))];
+			offset += master.bend * master.bend_range;
+		}
+		offset
+	}
+
+	// Notes that a channel message applies to
+	fn affects(&self, channel: u8, note: &ActiveNote) -> bool {
+		match self.role(channel) {
+			Role::Master(zone) => self.zone_of(note.channel) == Some(zone),
+			Role::Member(_) | Role::Conventional => note.channel == channel,
+		}
+	}
+
+	pub fn process(&mut self, event: &Event, out: &mut impl FnMut(InputEvent)) {
+		let channel = event.channel;
+		match event.message {
+			Message::NoteOn { note, vel } => {
+				let token = self.next_token();
+				self.notes.push(ActiveNote { channel, note, token });
+				let offset = self.offset(channel);
+				let pitch = f32::from(note) + offset;
+				out(InputEvent::NoteOn { token, note, pitch, offset, vel });
+			},
+			Message::NoteOff { note } => {
+				if let Some(i) =
+					self.notes.iter().position(|n| n.channel == channel && n.note == note)
+				{
+					let n = self.notes.remove(i);
+					out(InputEvent::NoteOff { token: n.token });
+				}
+			},
+			Message::Aftertouch { note, pressure } => {
+				for n in self.notes.iter().filter(|n| n.channel == channel && n.note == note) {
+					out(InputEvent::Pressure { token: n.token, pressure });
+				}
+			},
+			Message::Pressure(pressure) => {
+				for n in self.notes.iter().filter(|n| self.affects(channel, n)) {
+					out(InputEvent::Pressure { token: n.token, pressure });
+				}
+			},
+			Message::PitchBend(bend) => {
+				self.channels[usize::from(channel)].bend = bend;
+				for n in self.notes.iter().filter(|n| self.affects(channel, n)) {
+					let offset = self.offset(n.channel);
+					let pitch = f32::from(n.note) + offset;
+					out(InputEvent::Pitch { token: n.token, pitch, offset });
+				}
+			},
+			Message::Controller { controller, value } => {
+				self.controller(channel, controller, value, out)
+			},
+			Message::ProgramChange { program } => {
+				out(InputEvent::ProgramChange { channel, program })
+			},
+		}
+	}
+
+	fn controller(
+		&mut self,
+		channel: u8,
+		controller: u8,
+		value: u8,
+		out: &mut impl FnMut(InputEvent),
+	) {
+		let ch = &mut self.channels[usize::from(channel)];
+		match controller {
+			CC_RPN_MSB | CC_RPN_LSB | CC_NRPN_MSB | CC_NRPN_LSB => {
+				if controller == CC_RPN_MSB || controller == CC_NRPN_MSB {
+					ch.parameter_msb = value;
+				} else {
+					ch.parameter_lsb = value;
+				}
+				let number = (u16::from(ch.parameter_msb) << 7) | u16::from(ch.parameter_lsb);
+				ch.parameter = if controller == CC_RPN_MSB || controller == CC_RPN_LSB {
+					Some(Parameter::Registered(number))
+				} else {
+					Some(Parameter::NonRegistered(number))
+				};
+			},
+			CC_DATA_ENTRY => {
+				ch.data = u16::from(value) << 7;
+				self.data_entry(channel, out);
+			},
+			CC_DATA_ENTRY_LSB => {
+				ch.data = (ch.data & !0x7f) | u16::from(value);
+				self.data_entry(channel, out);
+			},
+			CC_DATA_INCREMENT => {
+				ch.data = (ch.data + 1).min(0x3fff);
+				self.data_entry(channel, out);
+			},
+			CC_DATA_DECREMENT => {
+				ch.data = ch.data.saturating_sub(1);
+				self.data_entry(channel, out);
+			},
+			CC_SUSTAIN => out(InputEvent::Sustain(value >= 64)),
+			0..32 => {
+				ch.msb[usize::from(controller)] = Some(value);
+				let value = f32::from(value) / 127.0;
+				self.controller_event(channel, controller, value, false, out);
+			},
+			32..64 => {
+				let msb_controller = controller - 32;
+				if let Some(msb) = ch.msb[usize::from(msb_controller)] {
+					let value = f32::from((u16::from(msb) << 7) | u16::from(value)) / 16383.0;
+					self.controller_event(channel, msb_controller, value, true, out);
+				} else {
+					let value = f32::from(value) / 127.0;
+					self.controller_event(channel, controller, value, false, out);
+				}
+			},
+			_ => {
+				let value = f32::from(value) / 127.0;
+				self.controller_event(channel, controller, value, false, out);
+			},
+		}
+	}
+
+	fn controller_event(
+		&self,
+		channel: u8,
+		controller: u8,
+		value: f32,
+		high_res: bool,
+		out: &mut impl FnMut(InputEvent),
+	) {
+		if self.config.mod_pressure && (controller == CC_MOD_WHEEL || controller == CC_BREATH) {
+			for n in self.notes.iter().filter(|n| self.affects(channel, n)) {
+				out(InputEvent::Pressure { token: n.token, pressure: value });
+			}
+		}
+		out(InputEvent::Controller { channel, controller, value, high_res });
+	}
+
+	fn data_entry(&mut self, channel: u8, out: &mut impl FnMut(InputEvent)) {
+		let ch = &self.channels[usize::from(channel)];
+		let data = ch.data;
+		match ch.parameter {
+			None | Some(Parameter::Registered(RPN_NULL)) => {},
+			Some(Parameter::Registered(RPN_BEND_RANGE)) => {
+				// Semitones in the MSB, cents in the LSB
+				let range = f32::from(data >> 7) + f32::from(data & 0x7f) / 100.0;
+				self.set_bend_range(channel, range);
+			},
+			Some(Parameter::Registered(RPN_MPE_CONFIGURATION)) => match channel {
+				LOWER_MASTER => self.configure_zone(ZoneId::Lower, (data >> 7) as u8),
+				UPPER_MASTER => self.configure_zone(ZoneId::Upper, (data >> 7) as u8),
+				_ => {},
+			},
+			Some(Parameter::Registered(number)) => out(InputEvent::Parameter {
+				channel,
+				registered: true,
+				number,
+				value: f32::from(data) / 16383.0,
+			}),
+			Some(Parameter::NonRegistered(number)) => out(InputEvent::Parameter {
+				channel,
+				registered: false,
+				number,
+				value: f32::from(data) / 16383.0,
+			}),
+		}
+	}
+}
+
+// Maps the timestamps of a port to our clock.
+// They count from an unknown point, so the offset is estimated from the smallest delay
+// between timestamp and arrival seen so far.
+pub struct StampClock {
+	epoch: Instant,
+	// Nanoseconds from a timestamp to `epoch`
+	offset: Option<i64>,
+}
+
+impl StampClock {
+	pub fn new() -> Self {
+		Self { epoch: Instant::now(), offset: None }
+	}
+
+	// `stamp` is in microseconds, as midir reports it
+	pub fn instant(&mut self, stamp: u64) -> Instant {
+		let now = self.epoch.elapsed().as_nanos() as i64;
+		let stamp = stamp as i64 * 1000;
+		let delay = now - stamp;
+		let offset = match self.offset {
+			Some(offset) => delay.min(offset + STAMP_DRIFT),
+			None => delay,
+		};
+		self.offset = Some(offset);
+		let nanos = (stamp + offset).clamp(0, now);
+		self.epoch + Duration::from_nanos(nanos as u64)
+	}
+}
+
+// Sends live input straight to the audio thread, instead of waiting for the frontend
+// to pick it up. Shared by all input ports.
+pub struct LiveInput {
+	output: Option<LiveOutput>,
+	// Armed channels
+	channels: Vec<usize>,
+	// Pitch of every key in the current tuning
+	pitches: Vec<f32>,
+	mappings: Vec<Mapping>,
+}
+
+struct Mapping {
+	source: MidiMapping,
+	// Where the parameter is in [0, 1], before the curve.
+	// Relative encoders move it from wherever the parameter was last set.
+	position: f64,
+}
+
+impl Mapping {
+	fn new(source: MidiMapping, value: f64) -> Self {
+		let mut new = Self { source, position: 0.0 };
+		new.set_value(value);
+		new
+	}
+
+	fn set_value(&mut self, value: f64) {
+		let MidiMapping { min, max, curve, .. } = self.source;
+		let x = if max == min { 0.0 } else { ((value - min) / (max - min)).clamp(0.0, 1.0) };
+		self.position = if curve > 0.0 { x.powf(1.0 / curve) } else { x };
+	}
+
+	fn matches(&self, port: &str, channel: u8, controller: u8, high_res: bool) -> bool {
+		let m = &self.source;
+		m.controller == controller
+			&& m.high_res == high_res
+			&& m.midi_channel.is_none_or(|c| c == channel)
+			&& m.port.as_ref().is_none_or(|p| p == port)
+	}
+
+	// Encoder steps in a controller value, from all 7 or 14 bits
+	fn steps(&self, value: f32) -> i32 {
+		let (raw, half) = if self.source.high_res {
+			((value * 16383.0).round() as i32, 0x2000)
+		} else {
+			((value * 127.0).round() as i32, 0x40)
+		};
+		match self.source.mode {
+			EncoderMode::Absolute => 0,
+			EncoderMode::TwosComplement => {
+				if raw < half {
+					raw
+				} else {
+					raw - 2 * half
+				}
+			},
+			EncoderMode::BinaryOffset => raw - half,
+			EncoderMode::SignedBit => {
+				if raw & half == 0 {
+					raw & (half - 1)
+				} else {
+					-(raw & (half - 1))
+				}
+			},
+		}
+	}
+
+	// Controller value in [0, 1] to parameter value
+	fn apply(&mut self, value: f32) -> f64 {
+		self.position = match self.source.mode {
+			EncoderMode::Absolute => f64::from(value),
+			_ => {
+				let steps = self.steps(value);
+				(self.position + f64::from(steps) * RELATIVE_STEP).clamp(0.0, 1.0)
+			},
+		};
+
+		let m = &self.source;
+
+		let x = if m.curve > 0.0 { self.position.powf(m.curve) } else { self.position };
+		let value = m.min + (m.max - m.min) * x;
+		if m.discrete { value.round() } else { value }
+	}
+}
+
+pub struct LiveOutput {
+	pub tx: HeapProd<AudioEvent>,
+	pub clock: Arc<SampleClock>,
+}
+
+impl Default for LiveInput {
+	fn default() -> Self {
+		Self {
+			output: None,
+			channels: Vec::new(),
+			pitches: (0..128).map(|k| k as f32).collect(),
+			mappings: Vec::new(),
+		}
+	}
+}
+
+impl LiveInput {
+	pub fn set_output(&mut self, output: Option<LiveOutput>) {
+		self.output = output;
+	}
+
+	pub fn set_channels(&mut self, channels: Vec<usize>) {
+		self.channels = channels;
+	}
+
+	pub fn set_pitches(&mut self, pitches: Vec<f32>) {
+		self.pitches = pitches;
+	}
+
+	// `values` are the current values of the mapped parameters, where relative encoders start
+	pub fn set_mappings(&mut self, mappings: Vec<MidiMapping>, values: &[f64]) {
+		self.mappings = mappings
+			.into_iter()
+			.enumerate()
+			.map(|(i, m)| {
+				let value = values.get(i).copied().unwrap_or(m.min);
+				Mapping::new(m, value)
+			})
+			.collect();
+	}
+
+	// Parameter got changed by something else than the controller
+	pub fn set_mapping_value(&mut self, index: usize, value: f64) {
+		if let Some(mapping) = self.mappings.get_mut(index) {
+			mapping.set_value(value);
+		}
+	}
+
+	// Only borrows the output, so the other fields stay usable while sending
+	fn connected(output: &mut Option<LiveOutput>) -> Option<&mut LiveOutput> {
+		// Audio context is gone
+		if output.as_ref().is_some_and(|o| !o.tx.read_is_held()) {
+			*output = None;
+		}
+		output.as_mut()
+	}
+
+	pub fn send(&mut self, instant: Instant, event: &InputEvent) {
+		let Some(output) = Self::connected(&mut self.output) else {
+			return;
+		};
+		let time = output.clock.schedule_time_at(instant, output.clock.sample_rate());
+		for &ch in &self.channels {
+			let message = match *event {
+				InputEvent::NoteOn { token, note, offset, vel, .. } => {
+					let pitch = self
+						.pitches
+						.get(usize::from(note))
+						.copied()
+						.unwrap_or(f32::from(note));
+					AudioMessage::NoteOn(ch, token, pitch, offset, vel)
+				},
+				InputEvent::NoteOff { token } => AudioMessage::NoteOff(ch, token),
+				InputEvent::Pitch { token, offset, .. } => AudioMessage::Pitch(ch, token, offset),
+				InputEvent::Pressure { token, pressure } => {
+					AudioMessage::Pressure(ch, token, pressure)
+				},
+				InputEvent::Sustain(sustain) => AudioMessage::Sustain(ch, sustain),
+				_ => return,
+			};
+			if output.tx.try_push(AudioEvent { time, message }).is_err() {
+				log_warn!("Live input queue full!");
+			}
+		}
+	}
+
+	// Sets the parameters mapped to a controller, and reports them with `out` so the
+	// frontend can follow along
+	pub fn map(
+		&mut self,
+		instant: Instant,
+		port: &str,
+		event: &InputEvent,
+		out: &mut impl FnMut(InputEvent),
+	) {
+		let InputEvent::Controller { channel, controller, value, high_res } = *event else {
+			return;
+		};
+		for mapping in &mut self.mappings {
+			if !mapping.matches(port, channel, controller, high_res) {
+				continue;
+			}
+			let value = mapping.apply(value);
+			let MidiMapping { channel: ch, device, index, .. } = mapping.source;
+			if ch == 0 || index == 0 {
+				continue;
+			}
+			if let Some(output) = Self::connected(&mut self.output) {
+				let time = output.clock.schedule_time_at(instant, output.clock.sample_rate());
+				let message = AudioMessage::Parameter(ch - 1, device, index - 1, value as f32);
+				if output.tx.try_push(AudioEvent { time, message }).is_err() {
+					log_warn!("Live input queue full!");
+				}
+			}
+			out(InputEvent::Mapped { channel: ch, device, index, value });
+		}
+	}
+}
+
+impl InputEvent {
+	pub fn into_table(self, lua: &Lua) -> LuaResult<LuaTable> {
+		use InputEvent::*;
+		let table = Lua::create_table(lua)?;
+
+		match self {
+			NoteOn { token, note, pitch, offset, vel } => {
+				table.set("name", "note_on")?;
+				table.set("token", token)?;
+				table.set("note", note)?;
+				table.set("pitch", pitch)?;
+				table.set("offset", offset)?;
+				table.set("vel", vel)?;
+			},
+			NoteOff { token } => {
+				table.set("name", "note_off")?;
+				table.set("token", token)?;
+			},
+			Pitch { token, pitch, offset } => {
+				table.set("name", "pitch")?;
+				table.set("token", token)?;
+				table.set("pitch", pitch)?;
+				table.set("offset", offset)?;
+			},
+			Pressure { token, pressure } => {
+				table.set("name", "pressure")?;
+				table.set("token", token)?;
+				table.set("pressure", pressure)?;
+			},
+			Sustain(sustain) => {
+				table.set("name", "sustain")?;
+				table.set("sustain", sustain)?;
+			},
+			Controller { channel, controller, value, high_res } => {
+				table.set("name", "controller")?;
+				table.set("channel", channel)?;
+				table.set("controller", controller)?;
+				table.set("value", value)?;
+				table.set("high_res", high_res)?;
+			},
+			Parameter { channel, registered, number, value } => {
+				table.set("name", "parameter")?;
+				table.set("channel", channel)?;
+				table.set("registered", registered)?;
+				table.set("number", number)?;
+				table.set("value", value)?;
+			},
+			ProgramChange { channel, program } => {
+				table.set("name", "program")?;
+				table.set("channel", channel)?;
+				table.set("program", program)?;
+			},
+			Mapped { channel, device, index, value } => {
+				table.set("name", "mapped")?;
+				table.set("channel", channel)?;
+				table.set("device", device)?;
+				table.set("index", index)?;
+				table.set("value", value)?;
+			},
+		}
+
+		Ok(table)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn processor(config: InputConfig) -> InputProcessor {
+		InputProcessor::new(config, Arc::new(AtomicU32::new(0)))
+	}
+
+	fn send(p: &mut InputProcessor, channel: u8, message: Message) -> Vec<InputEvent> {
+		let mut events = Vec::new();
+		p.process(&Event { channel, message }, &mut |e| events.push(e));
+		events
+	}
+
+	fn cc(p: &mut InputProcessor, channel: u8, controller: u8, value: u8) -> Vec<InputEvent> {
+		send(p, channel, Message::Controller { controller, value })
+	}
+
+	fn rpn(p: &mut InputProcessor, channel: u8, number: u16, msb: u8) -> Vec<InputEvent> {
+		cc(p, channel, CC_RPN_MSB, (number >> 7) as u8);
+		cc(p, channel, CC_RPN_LSB, (number & 0x7f) as u8);
+		cc(p, channel, CC_DATA_ENTRY, msb)
+	}
+
+	#[test]
+	fn test_mpe_configuration() {
+		let mut p = processor(InputConfig::default());
+		assert_eq!(p.role(1), Role::Conventional);
+
+		assert!(rpn(&mut p, LOWER_MASTER, RPN_MPE_CONFIGURATION, 7).is_empty());
+		assert_eq!(p.role(0), Role::Master(ZoneId::Lower));
+		assert_eq!(p.role(7), Role::Member(ZoneId::Lower));
+		assert_eq!(p.role(8), Role::Conventional);
+		assert_eq!(p.channels[1].bend_range, MEMBER_BEND_RANGE);
+		assert_eq!(p.channels[0].bend_range, MASTER_BEND_RANGE);
+
+		rpn(&mut p, UPPER_MASTER, RPN_MPE_CONFIGURATION, 4);
+		assert_eq!(p.role(15), Role::Master(ZoneId::Upper));
+		assert_eq!(p.role(11), Role::Member(ZoneId::Upper));
+		assert_eq!(p.role(10), Role::Conventional);
+
+		// Overlapping zones shrink the other one
+		rpn(&mut p, UPPER_MASTER, RPN_MPE_CONFIGURATION, 10);
+		assert_eq!(p.lower, 4);
+		assert_eq!(p.role(5), Role::Member(ZoneId::Upper));
+
+		// Only master channels configure zones
+		rpn(&mut p, 3, RPN_MPE_CONFIGURATION, 0);
+		assert_eq!(p.lower, 4);
+
+		rpn(&mut p, LOWER_MASTER, RPN_MPE_CONFIGURATION, 0);
+		assert_eq!(p.role(1), Role::Conventional);
+		assert_eq!(p.channels[1].bend_range, BEND_RANGE);
+	}
+
+	#[test]
+	fn test_bend_range() {
+		let mut p = processor(InputConfig::default());
+		rpn(&mut p, 2, RPN_BEND_RANGE, 12);
+		assert_eq!(p.channels[2].bend_range, 12.0);
+		assert_eq!(p.channels[3].bend_range, BEND_RANGE);
+
+		// Cents in the LSB
+		cc(&mut p, 2, CC_DATA_ENTRY_LSB, 50);
+		assert_eq!(p.channels[2].bend_range, 12.5);
+
+		// Applies to the whole zone when sent on a member channel
+		rpn(&mut p, LOWER_MASTER, RPN_MPE_CONFIGURATION, 15);
+		rpn(&mut p, 4, RPN_BEND_RANGE, 24);
+		assert!(p.channels[1..15].iter().all(|ch| ch.bend_range == 24.0));
+		assert_eq!(p.channels[0].bend_range, MASTER_BEND_RANGE);
+
+		// The config overrides what the device reports
+		let config = InputConfig { bend_range: Some(2.0), ..Default::default() };
+		let mut p = processor(config);
+		rpn(&mut p, 0, RPN_BEND_RANGE, 12);
+		assert_eq!(p.channels[0].bend_range, 2.0);
+	}
+
+	#[test]
+	fn test_lsb_pairing() {
+		let mut p = processor(InputConfig::default());
+
+		// No MSB yet, so it is a controller of its own
+		let events = cc(&mut p, 0, 33, 10);
+		assert!(matches!(
+			events[..],
+			[InputEvent::Controller { controller: 33, high_res: false, .. }]
+		));
+
+		let events = cc(&mut p, 0, 1, 64);
+		assert!(matches!(
+			events[..],
+			[InputEvent::Controller { controller: 1, high_res: false, .. }]
+		));
+
+		let events = cc(&mut p, 0, 33, 1);
+		let [InputEvent::Controller { controller: 1, value, high_res: true, .. }] = events[..]
+		else {
+			panic!("{events:?}");
+		};
+		assert_eq!(value, f32::from((64u16 << 7) | 1) / 16383.0);
+
+		// The MSB is kept per channel
+		let events = cc(&mut p, 1, 33, 10);
+		assert!(matches!(
+			events[..],
+			[InputEvent::Controller { controller: 33, high_res: false, .. }]
+		));
+	}
+
+	#[test]
+	fn test_nrpn() {
+		let mut p = processor(InputConfig::default());
+		cc(&mut p, 3, CC_NRPN_MSB, 1);
+		cc(&mut p, 3, CC_NRPN_LSB, 2);
+		let events = cc(&mut p, 3, CC_DATA_ENTRY, 100);
+		let [InputEvent::Parameter { channel: 3, registered: false, number: 130, value }] =
+			events[..]
+		else {
+			panic!("{events:?}");
+		};
+		assert_eq!(value, f32::from(100u16 << 7) / 16383.0);
+
+		let events = cc(&mut p, 3, CC_DATA_INCREMENT, 0);
+		let [InputEvent::Parameter { number: 130, value, .. }] = events[..] else {
+			panic!("{events:?}");
+		};
+		assert_eq!(value, f32::from((100u16 << 7) + 1) / 16383.0);
+
+		// Registered parameters that aren't handled here go through as well
+		let events = rpn(&mut p, 3, 2, 64);
+		assert!(matches!(events[..], [InputEvent::Parameter { registered: true, number: 2, .. }]));
+	}
+
+	#[test]
+	fn test_rpn_null() {
+		let mut p = processor(InputConfig::default());
+		rpn(&mut p, 0, RPN_BEND_RANGE, 12);
+		cc(&mut p, 0, CC_RPN_MSB, 0x7f);
+		cc(&mut p, 0, CC_RPN_LSB, 0x7f);
+		assert!(cc(&mut p, 0, CC_DATA_ENTRY, 24).is_empty());
+		assert!(cc(&mut p, 0, CC_DATA_INCREMENT, 0).is_empty());
+		assert_eq!(p.channels[0].bend_range, 12.0);
+	}
+
+	fn mapping(mode: EncoderMode, high_res: bool) -> MidiMapping {
+		MidiMapping {
+			port: None,
+			midi_channel: None,
+			controller: 1,
+			high_res,
+			channel: 1,
+			device: 0,
+			index: 1,
+			min: 0.0,
+			max: 1.0,
+			curve: 1.0,
+			mode,
+			discrete: false,
+		}
+	}
+
+	#[test]
+	fn test_encoder_steps() {
+		use EncoderMode::*;
+		let table = [
+			(TwosComplement, [1, 63, -63, -1]),
+			(BinaryOffset, [-63, -1, 1, 63]),
+			(SignedBit, [1, 63, -1, -63]),
+		];
+		for (mode, expected) in table {
+			let m = Mapping::new(mapping(mode, false), 0.5);
+			for (raw, steps) in [1u8, 63, 65, 127].into_iter().zip(expected) {
+				assert_eq!(m.steps(f32::from(raw) / 127.0), steps, "{mode:?} {raw}");
+			}
+		}
+
+		// 14 bit controllers use the whole range
+		let table = [
+			(TwosComplement, [1, -1, 2]),
+			(BinaryOffset, [-8191, 8191, -8190]),
+			(SignedBit, [1, -8191, 2]),
+		];
+		for (mode, expected) in table {
+			let m = Mapping::new(mapping(mode, true), 0.5);
+			for (raw, steps) in [1u16, 16383, 2].into_iter().zip(expected) {
+				assert_eq!(m.steps(f32::from(raw) / 16383.0), steps, "{mode:?} {raw}");
+			}
+		}
+	}
+
+	#[test]
+	fn test_mapping_apply() {
+		let mut m = Mapping::new(mapping(EncoderMode::TwosComplement, false), 0.5);
+		assert!((m.apply(3.0 / 127.0) - 0.53).abs() < 1e-9);
+		assert!((m.apply(125.0 / 127.0) - 0.50).abs() < 1e-9);
+		// Clamped at the ends of the range
+		m.apply(63.0 / 127.0);
+		assert_eq!(m.apply(63.0 / 127.0), 1.0);
+
+		// The curve is applied to the position, and undone when the value is set
+		let mut m = Mapping {
+			source: MidiMapping { max: 8.0, curve: 2.0, ..mapping(EncoderMode::Absolute, false) },
+			position: 0.0,
+		};
+		assert_eq!(m.apply(0.5), 2.0);
+		m.set_value(2.0);
+		assert_eq!(m.position, 0.5);
+
+		let mut m = Mapping::new(
+			MidiMapping { max: 4.0, discrete: true, ..mapping(EncoderMode::Absolute, false) },
+			0.0,
+		);
+		assert_eq!(m.apply(0.6), 2.0);
+		assert_eq!(m.apply(0.65), 3.0);
+	}
+
+	#[test]
+	fn test_mod_pressure() {
+		let mut p = processor(InputConfig::default());
+		send(&mut p, 0, Message::NoteOn { note: 60, vel: 1.0 });
+		let events = cc(&mut p, 0, CC_MOD_WHEEL, 127);
+		assert!(matches!(events[..], [InputEvent::Controller { .. }]));
+
+		let config = InputConfig { mod_pressure: true, ..Default::default() };
+		let mut p = processor(config);
+		send(&mut p, 0, Message::NoteOn { note: 60, vel: 1.0 });
+		send(&mut p, 1, Message::NoteOn { note: 62, vel: 1.0 });
+		let events = cc(&mut p, 1, CC_BREATH, 127);
+		assert!(matches!(
+			events[..],
+			[InputEvent::Pressure { token: 2, pressure: 1.0 }, InputEvent::Controller { .. }]
+		));
+	}
+}
@@ -0,0 +1,271 @@
+// MIDI Tuning Standard.
+// Retunes the keys of a synth with sysex, so notes don't need a pitch bend channel each.
+
+use crate::api::lua_serde;
+use serde::{Deserialize, Serialize};
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+const NON_REALTIME: u8 = 0x7e;
+const REALTIME: u8 = 0x7f;
+const MIDI_TUNING: u8 = 0x08;
+
+const BULK_DUMP: u8 = 0x01;
+const SINGLE_NOTE: u8 = 0x02;
+const SCALE_OCTAVE_1: u8 = 0x08;
+const SCALE_OCTAVE_2: u8 = 0x09;
+
+// Key changes per single note message, the count is a single data byte
+const MAX_CHANGES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+	// All 128 keys in one message, stored in a tuning program
+	Bulk,
+	// Real-time changes of individual keys, takes effect on sounding notes
+	SingleNote,
+	// Offset of each pitch class in cents, the same for every octave
+	ScaleOctave,
+	// Same with 14 bit resolution
+	ScaleOctave2,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Options {
+	pub format: Format,
+	// 0x7f addresses all devices
+	#[serde(default = "all_devices")]
+	pub device_id: u8,
+	#[serde(default)]
+	pub program: u8,
+	#[serde(default)]
+	pub name: String,
+}
+
+lua_serde!(Options);
+
+fn all_devices() -> u8 {
+	0x7f
+}
+
+// Pitch of every MIDI key, and the sysex that sets it up on the receiving end
+#[derive(Debug, Clone)]
+pub struct Tuning {
+	// In semitones, 60 is middle C
+	pub pitches: Vec<f32>,
+	pub sysex: Vec<Vec<u8>>,
+}
+
+impl Default for Tuning {
+	// 12-EDO, which is what a synth plays when nobody retuned it
+	fn default() -> Self {
+		Self { pitches: (0..128).map(|k| k as f32).collect(), sysex: Vec::new() }
+	}
+}
+
+impl Tuning {
+	pub fn new(pitches: Vec<f32>, options: &Options) -> Self {
+		let sysex = messages(&pitches, options);
+		Self { pitches, sysex }
+	}
+
+	// Key that plays closest to `pitch`
+	pub fn nearest_key(&self, pitch: f32) -> u8 {
+		let mut key = 0;
+		let mut min_dist = f32::MAX;
+		for (k, p) in self.pitches.iter().enumerate() {
+			let dist = (p - pitch).abs();
+			if dist < min_dist {
+				min_dist = dist;
+				key = k;
+			}
+		}
+		key as u8
+	}
+
+	pub fn pitch(&self, key: u8) -> f32 {
+		self.pitches.get(usize::from(key)).copied().unwrap_or(f32::from(key))
+	}
+}
+
+pub fn messages(pitches: &[f32], options: &Options) -> Vec<Vec<u8>> {
+	let device_id = options.device_id & 0x7f;
+	let program = options.program & 0x7f;
+	match options.format {
+		Format::Bulk => vec![bulk_dump(pitches, device_id, program, &options.name)],
+		Format::SingleNote => {
+			let changes: Vec<(u8, f32)> = pitches
+				.iter()
+				.take(128)
+				.enumerate()
+				.map(|(k, &p)| (k as u8, p))
+				.collect();
+			changes
+				.chunks(MAX_CHANGES)
+				.map(|c| single_note(c, device_id, program))
+				.collect()
+		},
+		Format::ScaleOctave => vec![scale_octave(&octave_offsets(pitches), device_id, false)],
+		Format::ScaleOctave2 => vec![scale_octave(&octave_offsets(pitches), device_id, true)],
+	}
+}
+
+// Semitone and 14 bit fraction, in 100/16384 cent steps
+pub fn frequency_data(pitch: f32) -> [u8; 3] {
+	let pitch = f64::from(pitch).clamp(0.0, 127.0 + 16382.0 / 16384.0);
+	let mut semitone = pitch.floor() as u16;
+	let mut fraction = ((pitch - pitch.floor()) * 16384.0).round() as u16;
+	if fraction >= 16384 {
+		semitone += 1;
+		fraction = 0;
+	}
+	// 7f 7f 7f means "no change", so stay just below it
+	if semitone >= 127 {
+		semitone = 127;
+		fraction = fraction.min(16382);
+	}
+	[semitone as u8, (fraction >> 7) as u8, (fraction & 0x7f) as u8]
+}
+
+pub fn bulk_dump(pitches: &[f32], device_id: u8, program: u8, name: &str) -> Vec<u8> {
+	let mut data = vec![NON_REALTIME, device_id, MIDI_TUNING, BULK_DUMP, program];
+
+	// Name is 16 ASCII characters, padded with spaces
+	let mut name_bytes = [b' '; 16];
+	for (b, c) in name_bytes.iter_mut().zip(name.bytes().filter(u8::is_ascii)) {
+		*b = c & 0x7f;
+	}
+	data.extend_from_slice(&name_bytes);
+
+	for key in 0..128 {
+		let pitch = pitches.get(key).copied().unwrap_or(key as f32);
+		data.extend_from_slice(&frequency_data(pitch));
+	}
+
+	let checksum = data.iter().fold(0, |acc, b| acc ^ b) & 0x7f;
+
+	let mut message = Vec::with_capacity(data.len() + 3);
+	message.push(SYSEX_START);
+	message.extend_from_slice(&data);
+	message.push(checksum);
+	message.push(SYSEX_END);
+	message
+}
+
+// `changes` are (key, pitch) pairs
+pub fn single_note(changes: &[(u8, f32)], device_id: u8, program: u8) -> Vec<u8> {
+	assert!(changes.len() < 128);
+	let mut message = vec![
+		SYSEX_START,
+		REALTIME,
+		device_id,
+		MIDI_TUNING,
+		SINGLE_NOTE,
+		program,
+		changes.len() as u8,
+	];
+	for &(key, pitch) in changes {
+		message.push(key & 0x7f);
+		message.extend_from_slice(&frequency_data(pitch));
+	}
+	message.push(SYSEX_END);
+	message
+}
+
+// Offsets in cents of C to B, applied on all channels
+pub fn scale_octave(offsets: &[f32; 12], device_id: u8, two_byte: bool) -> Vec<u8> {
+	let sub_id = if two_byte { SCALE_OCTAVE_2 } else { SCALE_OCTAVE_1 };
+	// Channel bitmap: 15-16, 8-14, 1-7
+	let mut message = vec![SYSEX_START, REALTIME, device_id, MIDI_TUNING, sub_id, 0x03, 0x7f, 0x7f];
+	for &cents in offsets {
+		if two_byte {
+			// 0x2000 is no change, the range is -100 to +100 cents
+			let value = (8192.0 + cents / 100.0 * 8192.0).round().clamp(0.0, 16383.0) as u16;
+			message.push((value >> 7) as u8);
+			message.push((value & 0x7f) as u8);
+		} else {
+			// 0x40 is no change, the range is -64 to +63 cents
+			message.push((64.0 + cents.round()).clamp(0.0, 127.0) as u8);
+		}
+	}
+	message.push(SYSEX_END);
+	message
+}
+
+// Deviation from 12-EDO of the octave starting at middle C.
+// Tunings that don't repeat at the octave only get approximated.
+fn octave_offsets(pitches: &[f32]) -> [f32; 12] {
+	let mut offsets = [0.0; 12];
+	for (i, offset) in offsets.iter_mut().enumerate() {
+		let key = 60 + i;
+		if let Some(&pitch) = pitches.get(key) {
+			*offset = (pitch - key as f32) * 100.0;
+		}
+	}
+	offsets
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn edo12() -> Vec<f32> {
+		(0..128).map(|k| k as f32).collect()
+	}
+
+	#[test]
+	fn test_bulk_dump_checksum() {
+		// Keys and padding cancel out, leaving 7e ^ 7f ^ 08 ^ 01 ^ 00
+		let message = bulk_dump(&edo12(), 0x7f, 0, "");
+		assert_eq!(message.len(), 408);
+		assert_eq!(message[..6], [SYSEX_START, NON_REALTIME, 0x7f, MIDI_TUNING, BULK_DUMP, 0]);
+		assert_eq!(message[6..22], [b' '; 16]);
+		assert_eq!(message[22 + 69 * 3..25 + 69 * 3], [69, 0, 0]);
+		assert_eq!(message[406..], [0x08, SYSEX_END]);
+
+		let message = bulk_dump(&edo12(), 0x7f, 0, "A");
+		assert_eq!(message[406], 0x08 ^ b' ' ^ b'A');
+	}
+
+	#[test]
+	fn test_frequency_data() {
+		assert_eq!(frequency_data(0.0), [0, 0, 0]);
+		// Half a semitone is 8192 steps
+		assert_eq!(frequency_data(69.5), [69, 0x40, 0x00]);
+		// 0.99 * 16384 = 16220
+		assert_eq!(frequency_data(127.99), [127, 126, 92]);
+		// Never 7f 7f 7f
+		assert_eq!(frequency_data(200.0), [127, 127, 126]);
+		assert_eq!(frequency_data(-1.0), [0, 0, 0]);
+		// Rounds up to the next semitone
+		assert_eq!(frequency_data(59.99999), [60, 0, 0]);
+	}
+
+	#[test]
+	fn test_scale_octave() {
+		let mut pitches = edo12();
+		pitches[61] += 0.1;
+		pitches[62] -= 0.5;
+		pitches[63] += 1.0;
+		pitches[64] -= 1.0;
+		let offsets = octave_offsets(&pitches);
+
+		let message = scale_octave(&offsets, 0x7f, false);
+		assert_eq!(message.len(), 21);
+		assert_eq!(
+			message[..8],
+			[SYSEX_START, REALTIME, 0x7f, MIDI_TUNING, 0x08, 0x03, 0x7f, 0x7f]
+		);
+		// 0x40 is no change, clamped at +63 cents
+		assert_eq!(message[8..13], [64, 74, 14, 127, 0]);
+		assert_eq!(message[20], SYSEX_END);
+
+		let message = scale_octave(&offsets, 0x7f, true);
+		assert_eq!(message.len(), 33);
+		assert_eq!(message[4], 0x09);
+		// 0x2000 is no change, -50 cents is 0x1000
+		assert_eq!(message[8..18], [0x40, 0x00, 0x46, 0x33, 0x20, 0x00, 0x7f, 0x7f, 0x00, 0x00]);
+		assert_eq!(message[32], SYSEX_END);
+	}
+}
@@ -0,0 +1,273 @@
+use crate::api::project::Project;
+use crate::audio::MAX_BUF_SIZE;
+use crate::automation::AutomationList;
+use crate::context::{AudioEvent, AudioMessage, LuaMessage};
+use crate::export;
+use crate::export::ExportSettings;
+use crate::log::*;
+use crate::meters::Meters;
+use crate::render::Render;
+use crate::routing::{Route, Routing};
+use crate::sequencer::Sequence;
+use crate::tempo::TempoMap;
+use crate::vst3;
+use crate::vst3::{Vst3Editor, Vst3State};
+use crate::worker::spawn_worker;
+use anyhow::Result;
+use no_denormals::no_denormals;
+use ringbuf::traits::*;
+use ringbuf::{HeapProd, HeapRb};
+use std::sync::mpsc;
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant};
+
+// How long we are willing to wait for the worker to load samples and IRs
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Renders a project without touching the audio device.
+// Has its own Render, worker and message queue, so the live stream can keep running (or not exist at all).
+pub struct OfflineRender {
+	render: Render,
+	// Dropping an editor terminates the controller, so keep them alive as long as the processors.
+	// Declared after `render` so the processors are dropped first.
+	#[allow(unused)]
+	vst_editors: Vec<Vst3Editor>,
+	audio_tx: HeapProd<AudioEvent>,
+	process_buffer: [[f32; MAX_BUF_SIZE]; 2],
+	buffer: Vec<f32>,
+	length: usize,
+	position: usize,
+	pub sample_rate: u32,
+	pub settings: ExportSettings,
+	channel_names: Vec<String>,
+}
+
+impl OfflineRender {
+	pub fn new(
+		project: &Project,
+		sample_rate: u32,
+		length: usize,
+		settings: ExportSettings,
+		lua_tx: SyncSender<LuaMessage>,
+	) -> Result<Self> {
+		let (worker_tx, worker_rx) = spawn_worker(sample_rate);
+		let (audio_tx, audio_rx) = HeapRb::<AudioEvent>::new(1024).split();
+		// Nobody is looking at the scope while rendering
+		let (scope_tx, _) = HeapRb::<f32>::new(1).split();
+
+		let mut render =
+			Render::new(sample_rate as f32, audio_rx, lua_tx, worker_tx, worker_rx, scope_tx);
+
+		let mut meters = Meters::new();
+		let mut vst_editors = Vec::new();
+
+		// Processors notify this when dropped, but there is nothing for us to clean up.
+		let (vst_cleanup_tx, _) = mpsc::sync_channel::<usize>(1);
+
+		for (channel_index, channel) in project.channels.iter().enumerate() {
+			let (meter_handle, _) = meters.register();
+			render.insert_channel(channel_index, meter_handle);
+
+			if let Some(instrument) = &channel.instrument {
+				let (meter_handle, _) = meters.register();
+				render.insert_instrument(channel_index, &instrument.name, meter_handle);
+
+				if let Some(plugin) = &instrument.plugin {
+					let (editor, processor) = vst3::load(
+						&plugin.descriptor,
+						sample_rate as f32,
+						channel_index,
+						vst_cleanup_tx.clone(),
+					)?;
+					render.vst_set_processor(channel_index, processor);
+
+					if let Some(state) = &plugin.state {
+						let vst_state = Vst3State::from_string(state.clone())?;
+						render.vst_set_state(channel_index, &vst_state);
+						editor.set_state(&vst_state)?;
+					}
+					vst_editors.push(editor);
+				}
+
+				for (index, &value) in instrument.state.iter().enumerate() {
+					render.set_parameter(channel_index, 0, index, value as f32);
+				}
+				if instrument.mute {
+					render.set_device_mute(channel_index, 0, true);
+				}
+			}
+
+			for (effect_index, effect) in channel.effects.iter().enumerate() {
+				let (meter_handle, _) = meters.register();
+				render.insert_effect(channel_index, effect_index, &effect.name, meter_handle);
+
+				let device_index = effect_index + 1;
+				for (index, &value) in effect.state.iter().enumerate() {
+					render.set_parameter(channel_index, device_index, index, value as f32);
+				}
+				if effect.mute {
+					render.set_device_mute(channel_index, device_index, true);
+				}
+			}
+
+			if !channel.automation.is_empty() {
+				let automation = AutomationList::new(&channel.automation, sample_rate as f32);
+				render.set_automation(channel_index, Box::new(automation));
+			}
+
+			if !channel.clips.is_empty() {
+				render.load_clips(channel_index, channel.clips.clone());
+			}
+
+			render.set_channel_gain(channel_index, channel.gain as f32);
+			if channel.mute {
+				render.set_channel_mute(channel_index, true);
+			}
+		}
+
+		let routes: Vec<Route> = project
+			.channels
+			.iter()
+			.map(|ch| {
+				let sidechains = ch
+					.effects
+					.iter()
+					.enumerate()
+					.filter_map(|(i, fx)| fx.sidechain.filter(|&s| s > 0).map(|s| (i + 1, s)));
+				Route::new(ch.output, &ch.sends, sidechains)
+			})
+			.collect();
+		drop(render.set_routing(Box::new(Routing::new(&routes, sample_rate as f32)?)));
+		drop(render.set_tempo(Box::new(TempoMap::new(&project.tempo))));
+
+		if settings.stems || settings.stems_pre_effects {
+			render.enable_stems(length, settings.stems_pre_effects);
+		}
+
+		// Block until the worker has sent all samples and IRs, otherwise the start of the render is silent.
+		let start = Instant::now();
+		render.parse_messages();
+		while render.is_loading() {
+			if start.elapsed() > LOAD_TIMEOUT {
+				log_warn!("Timed out waiting for worker, render may be incomplete.");
+				break;
+			}
+			std::thread::sleep(Duration::from_millis(1));
+			render.parse_messages();
+		}
+
+		log_info!(
+			"Offline render: {} channels, {} samples at {}Hz",
+			project.channels.len(),
+			length,
+			sample_rate
+		);
+
+		Ok(Self {
+			render,
+			vst_editors,
+			audio_tx,
+			process_buffer: [[0.0; MAX_BUF_SIZE]; 2],
+			buffer: Vec::with_capacity(2 * length),
+			length,
+			position: 0,
+			sample_rate,
+			settings,
+			channel_names: project.channels.iter().map(|c| c.name.clone()).collect(),
+		})
+	}
+
+	pub fn position(&self) -> Option<f64> {
+		self.render.position().map(|p| p as f64 / f64::from(self.sample_rate))
+	}
+
+	pub fn set_sequence(&mut self, channel_index: usize, sequence: Box<Sequence>) {
+		drop(self.render.set_sequence(channel_index, sequence));
+	}
+
+	// Rendering is not realtime, so messages apply at the start of the next block
+	pub fn send_message(&mut self, message: AudioMessage) {
+		let time = self.render.frames();
+		if self.audio_tx.try_push(AudioEvent { time, message }).is_err() {
+			log_warn!("Queue full. Dropped message!");
+		}
+	}
+
+	// The output is late by the latency, so that much more gets rendered and cut from the start
+	fn total_length(&self) -> usize {
+		self.length + self.render.latency()
+	}
+
+	// Render the next `len` frames, or whatever is left.
+	pub fn process_block(&mut self, len: usize) {
+		self.render.parse_messages();
+
+		let end = self.position + len;
+		loop {
+			// Latency is only known once processing started, and can change with the plugins
+			let remaining = end.min(self.total_length()).saturating_sub(self.position);
+			if remaining == 0 {
+				break;
+			}
+			let chunk_size = remaining.min(MAX_BUF_SIZE);
+
+			let (l, r) = self.process_buffer.split_at_mut(1);
+			let buf_slice = &mut [&mut l[0][..chunk_size], &mut r[0][..chunk_size]];
+
+			unsafe { no_denormals(|| self.render.process(buf_slice)) };
+
+			// interlace
+			for i in 0..chunk_size {
+				self.buffer.push(buf_slice[0][i]);
+				self.buffer.push(buf_slice[1][i]);
+			}
+			self.position += chunk_size;
+		}
+	}
+
+	pub fn progress(&self) -> f64 {
+		let length = self.total_length();
+		if length == 0 {
+			return 1.0;
+		}
+		self.position as f64 / length as f64
+	}
+
+	pub fn is_finished(&self) -> bool {
+		self.position >= self.total_length()
+	}
+
+	// Interlaced stereo samples rendered so far
+	pub fn samples(&self) -> &[f32] {
+		&self.buffer
+	}
+
+	// Write the master and stems to disk.
+	// Latency is cut from the start, so everything lines up with the timeline.
+	pub fn write(&mut self) -> Result<()> {
+		let stems = self.render.take_stems().unwrap_or_default();
+
+		let mut named = Vec::new();
+		for (i, stem) in stems.iter().enumerate() {
+			// stems start after the master channel
+			let index = i + 1;
+			let name = &self.channel_names[index];
+			let latency = self.render.channel_latency(index);
+			if self.settings.stems {
+				named.push((format!("{index:02} {name}"), skip_frames(&stem.post, latency)));
+			}
+			if let Some(pre) = &stem.pre {
+				// Effects latency is not part of the dry signal
+				let latency = latency - self.render.effects_latency(index);
+				named.push((format!("{index:02} {name} dry"), skip_frames(pre, latency)));
+			}
+		}
+
+		let master = skip_frames(&self.buffer, self.render.latency());
+		export::write_render(master, &named, self.sample_rate, &self.settings)
+	}
+}
+
+fn skip_frames(interlaced: &[f32], frames: usize) -> &[f32] {
+	&interlaced[(2 * frames).min(interlaced.len())..]
+}
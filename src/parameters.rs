@@ -0,0 +1,23 @@
+// Last value of every parameter of a device, so it can be recreated at another sample rate.
+// Parameters are set on the audio thread, so the storage is allocated up front.
+const MAX_PARAMETERS: usize = 64;
+
+#[derive(Debug)]
+pub struct ParameterCache(Vec<Option<f32>>);
+
+impl ParameterCache {
+	pub fn new() -> Self {
+		Self(vec![None; MAX_PARAMETERS])
+	}
+
+	pub fn set(&mut self, index: usize, value: f32) {
+		if let Some(p) = self.0.get_mut(index) {
+			*p = Some(value);
+		}
+	}
+
+	// Parameters that were set at least once
+	pub fn iter(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+		self.0.iter().enumerate().filter_map(|(i, v)| v.map(|v| (i, v)))
+	}
+}
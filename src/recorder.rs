@@ -0,0 +1,236 @@
+use crate::api::lua_serde;
+use crate::log::*;
+use crate::render::Render;
+use anyhow::{Result, anyhow};
+use parking_lot::Mutex;
+use ringbuf::traits::*;
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// How much audio we can buffer before the disk writer has to catch up
+const RECORD_BUFFER_SECONDS: usize = 4;
+const WRITE_INTERVAL: Duration = Duration::from_millis(10);
+
+// A finished recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Take {
+	pub channel_index: usize,
+	pub path: String,
+	// Position on the timeline in seconds
+	pub start_time: f64,
+	// Length in frames
+	pub length: usize,
+	pub sample_rate: u32,
+	// Frames lost because the disk writer fell behind, the take is shorter by this much
+	pub dropped: usize,
+}
+
+lua_serde!(Take);
+
+// Written by the audio thread, read once the take is finished
+pub struct RecordStatus {
+	// Position in samples of the first recorded frame
+	start: AtomicU64,
+	dropped: AtomicU64,
+}
+
+impl RecordStatus {
+	const NOT_STARTED: u64 = u64::MAX;
+
+	fn new() -> Self {
+		Self { start: AtomicU64::new(Self::NOT_STARTED), dropped: AtomicU64::new(0) }
+	}
+
+	// Only the first call counts
+	pub fn started(&self, position: usize) {
+		let _ = self.start.compare_exchange(
+			Self::NOT_STARTED,
+			position as u64,
+			Ordering::Relaxed,
+			Ordering::Relaxed,
+		);
+	}
+
+	pub fn add_dropped(&self, frames: usize) {
+		self.dropped.fetch_add(frames as u64, Ordering::Relaxed);
+	}
+
+	fn start(&self) -> Option<u64> {
+		let start = self.start.load(Ordering::Relaxed);
+		(start != Self::NOT_STARTED).then_some(start)
+	}
+}
+
+// Where an audio input sends the frames of a take
+pub struct RecordSink {
+	pub tx: HeapProd<f32>,
+	pub status: Arc<RecordStatus>,
+}
+
+struct TakeWriter {
+	take: Take,
+	rx: HeapCons<f32>,
+	status: Arc<RecordStatus>,
+	writer: hound::WavWriter<BufWriter<File>>,
+}
+
+impl TakeWriter {
+	fn write(&mut self, scratch: &mut [f32]) -> Result<()> {
+		loop {
+			let n = self.rx.pop_slice(scratch);
+			if n == 0 {
+				return Ok(());
+			}
+			for s in &scratch[..n] {
+				self.writer.write_sample(*s)?;
+			}
+			self.take.length += n / 2;
+		}
+	}
+}
+
+// Streams the input of armed channels to disk.
+// The audio thread pushes into a ring buffer per channel, a separate thread drains them into wav files.
+pub struct Recorder {
+	render: Arc<Mutex<Render>>,
+	channels: Vec<usize>,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<Vec<Take>>>,
+}
+
+impl Recorder {
+	// `channels` is a list of (channel_index, path).
+	// Takes start where the first frame was recorded, which depends on the transport and punch range.
+	pub fn start(
+		render: Arc<Mutex<Render>>,
+		channels: &[(usize, String)],
+		sample_rate: u32,
+	) -> Result<Self> {
+		let spec = hound::WavSpec {
+			channels: 2,
+			sample_rate,
+			bits_per_sample: 32,
+			sample_format: hound::SampleFormat::Float,
+		};
+
+		let mut writers = Vec::new();
+		let mut producers = Vec::new();
+		for (channel_index, path) in channels {
+			if let Some(dir) = Path::new(path).parent() {
+				std::fs::create_dir_all(dir)?;
+			}
+			let writer = hound::WavWriter::create(path, spec)?;
+
+			let capacity = 2 * RECORD_BUFFER_SECONDS * sample_rate as usize;
+			let (tx, rx) = HeapRb::<f32>::new(capacity).split();
+			let status = Arc::new(RecordStatus::new());
+			producers.push((*channel_index, RecordSink { tx, status: Arc::clone(&status) }));
+
+			let take = Take {
+				channel_index: *channel_index,
+				path: path.clone(),
+				start_time: 0.0,
+				length: 0,
+				sample_rate,
+				dropped: 0,
+			};
+			writers.push(TakeWriter { take, rx, status, writer });
+		}
+
+		// Only start writing once every file was created
+		{
+			let mut render = render.lock();
+			for (channel_index, sink) in producers {
+				if !render.set_record(channel_index, Some(sink)) {
+					log_warn!("Channel {channel_index} has no audio input, not recording.");
+				}
+			}
+		}
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let stop_thread = Arc::clone(&stop);
+
+		let thread = std::thread::Builder::new()
+			.name("recorder".to_string())
+			.spawn(move || {
+				let mut scratch = vec![0.0; 4096];
+				loop {
+					// Check before draining so nothing pushed before the stop gets lost
+					let done = stop_thread.load(Ordering::Acquire);
+					for w in &mut writers {
+						if let Err(e) = w.write(&mut scratch) {
+							log_error!("Failed writing \"{}\": {e}", w.take.path);
+						}
+					}
+					if done {
+						break;
+					}
+					std::thread::sleep(WRITE_INTERVAL);
+				}
+
+				let mut takes = Vec::new();
+				for mut w in writers {
+					if let Some(start) = w.status.start() {
+						w.take.start_time = start as f64 / f64::from(sample_rate);
+					}
+					w.take.dropped = w.status.dropped.load(Ordering::Relaxed) as usize;
+					if w.take.dropped > 0 {
+						log_warn!(
+							"Recording \"{}\" dropped {} frames, the disk could not keep up.",
+							w.take.path,
+							w.take.dropped
+						);
+					}
+					match w.writer.finalize() {
+						Ok(()) => takes.push(w.take),
+						Err(e) => log_error!("Failed to finalize \"{}\": {e}", w.take.path),
+					}
+				}
+				takes
+			})?;
+
+		Ok(Self {
+			render,
+			channels: channels.iter().map(|(i, _)| *i).collect(),
+			stop,
+			thread: Some(thread),
+		})
+	}
+
+	// Blocks until all buffered audio is written
+	pub fn stop(mut self) -> Result<Vec<Take>> {
+		self.finish()
+	}
+
+	fn finish(&mut self) -> Result<Vec<Take>> {
+		{
+			let mut render = self.render.lock();
+			for &channel_index in &self.channels {
+				render.set_record(channel_index, None);
+			}
+		}
+		self.stop.store(true, Ordering::Release);
+
+		match self.thread.take() {
+			Some(thread) => thread.join().map_err(|_| anyhow!("Recorder thread panicked.")),
+			None => Ok(Vec::new()),
+		}
+	}
+}
+
+impl Drop for Recorder {
+	fn drop(&mut self) {
+		if self.thread.is_some()
+			&& let Err(e) = self.finish()
+		{
+			log_error!("{e}");
+		}
+	}
+}
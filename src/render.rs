@@ -1,35 +1,93 @@
+use crate::api::project::Clip;
 use crate::audio::MAX_BUF_SIZE;
-use crate::channel::Channel;
-use crate::context::{AudioMessage, LuaMessage};
+use crate::automation::{AutomationList, Target};
+use crate::channel::{Bus, Channel};
+use crate::context::{AudioEvent, AudioMessage, LuaMessage};
 use crate::effect::*;
-use crate::instrument;
+use crate::input::AudioInput;
 use crate::log::*;
 use crate::meters::MeterHandle;
 use crate::metronome::Metronome;
+use crate::midi::OutputEvent;
+use crate::mts::Tuning;
+use crate::recorder::RecordSink;
+use crate::routing::{OutputMap, Routing, default_routes};
+use crate::sequencer::{Playhead, Sequence};
+use crate::tempo::TempoMap;
 use crate::voice_manager::VoiceManager;
 use crate::vst3::{Vst3Processor, Vst3State};
-use crate::worker::{Request, Response};
+use crate::worker::{Request, RequestData, Response, ResponseData};
 use ringbuf::traits::*;
 use ringbuf::{HeapCons, HeapProd};
+use std::sync::Arc;
 use std::sync::mpsc::{Receiver, SyncSender};
 
+// Maximum number of frames we let the input lag behind before dropping
+const MAX_INPUT_LATENCY: usize = 2048;
+
 pub struct Render {
-	audio_rx: HeapCons<AudioMessage>,
+	audio_rx: HeapCons<AudioEvent>,
+	// First queued message that is not due yet
+	pending: Option<AudioEvent>,
+	// Live midi input, scheduled by the midi threads
+	live_rx: Option<HeapCons<AudioEvent>>,
+	live_pending: Option<AudioEvent>,
 	lua_tx: SyncSender<LuaMessage>,
 	worker_tx: SyncSender<Request>,
 	worker_rx: Receiver<Response>,
 	scope_tx: HeapProd<f32>,
 	channels: Vec<Channel>,
+	// Channel indices in the order they need to be processed, master last
+	order: Vec<usize>,
+	// Input bus for every channel, where outputs and sends of other channels are summed
+	buses: Vec<Bus>,
+	// Post-fader output of every channel, used as sidechain source
+	outputs: Vec<Bus>,
+	// Latency each channel aligns its inputs to, in samples
+	arrival: Vec<usize>,
+	// Latency of what ends up in `outputs`
+	output_latency: Vec<usize>,
+	// Headphone mix, summed from the cue level of every channel
+	cue: Bus,
+	output_map: OutputMap,
 	buffer: [[f32; MAX_BUF_SIZE]; 2],
+	input_rx: Option<HeapCons<f32>>,
+	input_buffer: [[f32; MAX_BUF_SIZE]; 2],
 	pub sample_rate: f32,
+	pending_requests: usize,
+	stems: Option<Vec<Stem>>,
+	// Playback position in samples, None when stopped
+	position: Option<usize>,
+	playhead: Arc<Playhead>,
+	// Start notes that are already sounding when playback starts or jumps
+	chase: bool,
+	// Loop region in samples, playback jumps back to the start when it reaches the end
+	loop_range: Option<(usize, usize)>,
+	// Inputs only record inside this range
+	punch: Option<(usize, usize)>,
+	// Times playback wrapped around the loop since it started
+	loops: u64,
+	tempo: Box<TempoMap>,
+	// Frames rendered since creation, the time base of queued messages
+	frames: u64,
 
 	metronome: Metronome,
 }
 
+// Per-channel recording of the signal, used for stem export.
+// Only enabled for offline renders.
+#[derive(Debug, Default)]
+pub struct Stem {
+	// interlaced, post-fader
+	pub post: Vec<f32>,
+	// interlaced, instrument output before effects
+	pub pre: Option<Vec<f32>>,
+}
+
 impl Render {
 	pub fn new(
 		sample_rate: f32,
-		audio_rx: HeapCons<AudioMessage>,
+		audio_rx: HeapCons<AudioEvent>,
 		lua_tx: SyncSender<LuaMessage>,
 		worker_tx: SyncSender<Request>,
 		worker_rx: Receiver<Response>,
@@ -37,13 +95,35 @@ impl Render {
 	) -> Render {
 		Render {
 			audio_rx,
+			pending: None,
+			live_rx: None,
+			live_pending: None,
 			lua_tx,
 			worker_tx,
 			worker_rx,
 			scope_tx,
 			channels: Vec::new(),
+			order: Vec::new(),
+			buses: Vec::new(),
+			outputs: Vec::new(),
+			arrival: Vec::new(),
+			output_latency: Vec::new(),
+			cue: [[0.0; MAX_BUF_SIZE]; 2],
+			output_map: OutputMap::default(),
 			buffer: [[0.0f32; MAX_BUF_SIZE]; 2],
+			input_rx: None,
+			input_buffer: [[0.0f32; MAX_BUF_SIZE]; 2],
 			sample_rate,
+			pending_requests: 0,
+			stems: None,
+			position: None,
+			playhead: Arc::new(Playhead::new()),
+			chase: false,
+			loop_range: None,
+			punch: None,
+			loops: 0,
+			tempo: Box::default(),
+			frames: 0,
 			metronome: Metronome::new(sample_rate),
 		}
 	}
@@ -59,6 +139,7 @@ impl Render {
 		}
 		let channel = Channel::new(self.sample_rate, None, meter_handle_channel);
 		self.channels.insert(channel_index, channel);
+		self.reset_routing();
 	}
 
 	pub fn insert_instrument(
@@ -68,14 +149,102 @@ impl Render {
 		meter_handle_instrument: MeterHandle,
 	) {
 		assert!(channel_index > 0, "Trying to insert instrument on master channel");
-		let instrument = instrument::new(self.sample_rate, instrument_name);
+		let ch = &mut self.channels[channel_index];
+
+		if instrument_name == "audio_input" {
+			ch.instrument = None;
+			ch.input = Some(AudioInput::new(self.sample_rate, meter_handle_instrument));
+			return;
+		}
+
 		let voice_manager =
-			VoiceManager::new(self.sample_rate, instrument, meter_handle_instrument);
+			VoiceManager::new(self.sample_rate, instrument_name, meter_handle_instrument);
 
-		let ch = &mut self.channels[channel_index];
+		ch.input = None;
 		ch.instrument = Some(voice_manager);
 	}
 
+	// Clips are loaded on the worker, and swapped in when ready
+	pub fn load_clips(&mut self, channel_index: usize, clips: Vec<Clip>) {
+		self.channels[channel_index].clips.requested.clone_from(&clips);
+		let request = Request::LoadClips { channel_index, clips };
+		match self.worker_tx.try_send(request) {
+			Ok(()) => self.pending_requests += 1,
+			Err(e) => log_error!("{e}"),
+		}
+	}
+
+	// Interlaced stereo frames from the input stream, or None to disconnect
+	pub fn set_input(&mut self, input_rx: Option<HeapCons<f32>>) {
+		self.input_rx = input_rx;
+	}
+
+	// Send the input of a channel to the recorder.
+	// Returns false if the channel doesn't have an audio input.
+	pub fn set_record(&mut self, channel_index: usize, record: Option<RecordSink>) -> bool {
+		if let Some(ch) = self.channels.get_mut(channel_index)
+			&& let Some(audio_input) = &mut ch.input
+		{
+			audio_input.set_record(record);
+			true
+		} else {
+			false
+		}
+	}
+
+	// Send the notes of a MIDI output channel to a port, or None to disconnect.
+	// Returns false if the channel is not a MIDI output.
+	pub fn set_midi_output(
+		&mut self,
+		channel_index: usize,
+		tx: Option<HeapProd<OutputEvent>>,
+	) -> bool {
+		if let Some(ch) = self.channels.get_mut(channel_index)
+			&& let Some(instrument) = &mut ch.instrument
+			&& let Some(midi_output) = instrument.instrument.as_midi_output()
+		{
+			midi_output.set_output(tx);
+			true
+		} else {
+			false
+		}
+	}
+
+	// Returns the tuning that is no longer used, so it gets dropped outside of the audio thread
+	pub fn set_tuning(&mut self, channel_index: usize, tuning: Box<Tuning>) -> Box<Tuning> {
+		if let Some(ch) = self.channels.get_mut(channel_index)
+			&& let Some(instrument) = &mut ch.instrument
+		{
+			instrument.instrument.set_tuning(tuning)
+		} else {
+			tuning
+		}
+	}
+
+	fn read_input(&mut self, len: usize) {
+		let Some(input_rx) = &mut self.input_rx else {
+			self.input_buffer[0][..len].fill(0.0);
+			self.input_buffer[1][..len].fill(0.0);
+			return;
+		};
+
+		// Input and output clocks drift, so drop frames when we fall too far behind
+		let available = input_rx.occupied_len() / 2;
+		if available > len + MAX_INPUT_LATENCY {
+			input_rx.skip(2 * (available - len));
+		}
+
+		for i in 0..len {
+			if let (Some(l), Some(r)) = (input_rx.try_pop(), input_rx.try_pop()) {
+				self.input_buffer[0][i] = l;
+				self.input_buffer[1][i] = r;
+			} else {
+				self.input_buffer[0][i] = 0.0;
+				self.input_buffer[1][i] = 0.0;
+			}
+		}
+	}
+
 	pub fn vst_set_processor(&mut self, channel_index: usize, processor: Vst3Processor) {
 		let channel = &mut self.channels[channel_index];
 		let instrument = &mut channel.instrument.as_mut().unwrap();
@@ -96,6 +265,146 @@ impl Render {
 
 	pub fn remove_channel(&mut self, index: usize) {
 		self.channels.remove(index);
+		self.reset_routing();
+	}
+
+	// Indices shift when channels get inserted or removed, so we route everything
+	// to master until the new routing is set.
+	fn reset_routing(&mut self) {
+		let n = self.channels.len();
+		self.buses.resize(n, [[0.0; MAX_BUF_SIZE]; 2]);
+		self.outputs.resize(n, [[0.0; MAX_BUF_SIZE]; 2]);
+		self.arrival.resize(n, 0);
+		self.output_latency.resize(n, 0);
+		match Routing::new(&default_routes(n), self.sample_rate) {
+			Ok(routing) => drop(self.set_routing(Box::new(routing))),
+			Err(e) => log_error!("{e}"),
+		}
+	}
+
+	// Swap in routing that was built on the main thread. Returns the old one, so it can be
+	// dropped off the audio thread. A routing for another number of channels is left out.
+	pub fn set_routing(&mut self, mut routing: Box<Routing>) -> Box<Routing> {
+		if routing.routes.len() != self.channels.len() {
+			log_warn!(
+				"Expected routing for {} channels, got {}.",
+				self.channels.len(),
+				routing.routes.len()
+			);
+			return routing;
+		}
+		for (channel, route) in self.channels.iter_mut().zip(&mut routing.routes) {
+			channel.set_route(route);
+		}
+		std::mem::swap(&mut self.order, &mut routing.order);
+		routing
+	}
+
+	// Plugin delay compensation. Latencies can change at any time (e.g. toggling lookahead),
+	// so this is cheap enough to run every block.
+	fn update_latency(&mut self) {
+		self.arrival.fill(0);
+		for &index in &self.order {
+			let ch = &self.channels[index];
+			let mut input = self.arrival[index].max(ch.source_latency());
+			// Sidechain sources are processed before us. The main path waits for them,
+			// so their signal only ever needs to be delayed to line up.
+			let mut before = 0;
+			for fx in &ch.effects {
+				if let Some(source) = fx.sidechain {
+					input = input.max(self.output_latency[source].saturating_sub(before));
+				}
+				before += fx.latency();
+			}
+			self.arrival[index] = input;
+			let latency = input + ch.effects_latency();
+			self.output_latency[index] = latency;
+			if index > 0 {
+				for target in ch.targets() {
+					self.arrival[target] = self.arrival[target].max(latency);
+				}
+			}
+		}
+		for &index in &self.order {
+			let input = self.arrival[index];
+			self.channels[index].set_compensation(input, &self.arrival, &self.output_latency);
+		}
+	}
+
+	// Total latency at the master output, in samples
+	pub fn latency(&self) -> usize {
+		self.channel_latency(0)
+	}
+
+	// Latency of the post-fader output of a channel
+	pub fn channel_latency(&self, index: usize) -> usize {
+		match (self.arrival.get(index), self.channels.get(index)) {
+			(Some(input), Some(ch)) => input + ch.effects_latency(),
+			_ => 0,
+		}
+	}
+
+	// Returns the old automation so it can be dropped outside of the lock
+	pub fn set_automation(
+		&mut self,
+		channel_index: usize,
+		automation: Box<AutomationList>,
+	) -> Box<AutomationList> {
+		std::mem::replace(&mut self.channels[channel_index].automation, automation)
+	}
+
+	// Evaluated once per block, so at most MAX_BUF_SIZE samples apart
+	fn apply_automation(&mut self, position: usize) {
+		for channel_index in 0..self.channels.len() {
+			for lane_index in 0..self.channels[channel_index].automation.lanes.len() {
+				let ch = &mut self.channels[channel_index];
+				let lane = &mut ch.automation.lanes[lane_index];
+				let Some(value) = lane.update(position) else {
+					continue;
+				};
+				let target = lane.target;
+				match target {
+					Target::Parameter { device, index } => {
+						// Lanes can outlive their device until Lua sends the new automation
+						if self.device_exists(channel_index, device) {
+							self.set_parameter(channel_index, device, index, value);
+						}
+					},
+					Target::Gain => self.channels[channel_index].set_gain(value),
+					Target::Mute => self.channels[channel_index].set_mute(value > 0.5),
+				}
+			}
+		}
+	}
+
+	// Returns the old map so it can be dropped outside of the lock
+	pub fn set_output_map(&mut self, output_map: OutputMap) -> OutputMap {
+		std::mem::replace(&mut self.output_map, output_map)
+	}
+
+	// Output pair of the master channel
+	pub fn master_output(&self) -> Option<usize> {
+		self.output_map.master
+	}
+
+	// Everything besides master that goes to the output device, as (pair, signal).
+	// Only valid for the block that was just processed.
+	pub fn extra_outputs(&self) -> impl Iterator<Item = (usize, &Bus)> {
+		let cue = self.output_map.cue.map(|pair| (pair, &self.cue));
+		let direct = self
+			.output_map
+			.channels
+			.iter()
+			.filter_map(|&(channel, pair)| self.outputs.get(channel).map(|o| (pair, o)));
+		cue.into_iter().chain(direct)
+	}
+
+	pub fn effects_latency(&self, index: usize) -> usize {
+		self.channels.get(index).map_or(0, Channel::effects_latency)
+	}
+
+	pub fn set_send_gain(&mut self, channel_index: usize, send_index: usize, gain: f32) {
+		self.channels[channel_index].set_send_gain(send_index, gain);
 	}
 
 	pub fn insert_effect(
@@ -115,32 +424,255 @@ impl Render {
 		ch.effects.remove(effect_index);
 	}
 
-	pub fn process<'a>(&'a mut self, buffer_out: &mut [&'a mut [f32]; 2]) {
+	// Start recording stems for all non-master channels.
+	// `capacity` is the expected length in frames.
+	pub fn enable_stems(&mut self, capacity: usize, pre_effects: bool) {
+		let stems = (1..self.channels.len())
+			.map(|_| Stem {
+				post: Vec::with_capacity(2 * capacity),
+				pre: pre_effects.then(|| Vec::with_capacity(2 * capacity)),
+			})
+			.collect();
+		self.stems = Some(stems);
+	}
+
+	pub fn take_stems(&mut self) -> Option<Vec<Stem>> {
+		self.stems.take()
+	}
+
+	pub fn frames(&self) -> u64 {
+		self.frames
+	}
+
+	pub fn position(&self) -> Option<usize> {
+		self.position
+	}
+
+	pub fn playhead(&self) -> Arc<Playhead> {
+		Arc::clone(&self.playhead)
+	}
+
+	// Messages land on the exact sample they were scheduled for,
+	// by splitting the buffer wherever one is due.
+	pub fn process(&mut self, buffer_out: &mut [&mut [f32]; 2]) {
+		let len = buffer_out[0].len();
+		let mut start = 0;
+		while start < len {
+			self.apply_due_messages();
+			self.wrap_loop();
+			self.process_sequencers();
+			let mut end = match self.next_message() {
+				Some(time) => start + ((time - self.frames).min((len - start) as u64) as usize),
+				None => len,
+			};
+			if let Some(next) = self.next_sequencer_event() {
+				end = end.min(start + next);
+			}
+			if let Some(next) = self.next_transport_event() {
+				end = end.min(start + next);
+			}
+
+			self.update_transport();
+			self.update_record_gate();
+			let [l, r] = &mut *buffer_out;
+			self.process_segment(&mut [&mut l[start..end], &mut r[start..end]]);
+			self.frames += (end - start) as u64;
+			start = end;
+		}
+		self.playhead.store(self.position, self.loops);
+	}
+
+	fn to_samples(&self, time: f64) -> usize {
+		(time.max(0.0) * f64::from(self.sample_rate)) as usize
+	}
+
+	// Jump back when playback reaches the end of the loop, or is past it after a seek or
+	// a loop that was set behind the playhead.
+	// Sounding notes get a note off and chased ones start again, effect tails carry over.
+	fn wrap_loop(&mut self) {
+		if let Some(position) = self.position
+			&& let Some((loop_start, loop_end)) = self.loop_range
+			&& position >= loop_end
+		{
+			self.position = Some(loop_start);
+			self.loops += 1;
+			self.start_sequencers(loop_start);
+		}
+	}
+
+	// Samples until the end of the loop or a punch boundary.
+	// Runs after `wrap_loop`, so the end of the loop is always ahead.
+	fn next_transport_event(&self) -> Option<usize> {
+		let position = self.position?;
+		let loop_end = self.loop_range.map(|(_, end)| end.max(position + 1));
+		let punch_in = self.punch.map(|(start, _)| start);
+		let punch_out = self.punch.map(|(_, end)| end);
+		[loop_end, punch_in, punch_out]
+			.into_iter()
+			.flatten()
+			.filter(|&t| t > position)
+			.min()
+			.map(|t| t - position)
+	}
+
+	// Takes are written in one piece, so inputs record inside the punch range
+	// and stop at the first loop wrap
+	fn update_record_gate(&mut self) {
+		let record = match (self.position, self.punch) {
+			(Some(_), _) if self.loops > 0 => false,
+			(Some(position), Some((punch_in, punch_out))) => {
+				(punch_in..punch_out).contains(&position)
+			},
+			(Some(_), None) => true,
+			(None, _) => false,
+		};
+		let position = self.position.filter(|_| record);
+		for ch in &mut self.channels {
+			if let Some(audio_input) = &mut ch.input {
+				audio_input.set_record_gate(position);
+			}
+		}
+	}
+
+	// Every device sees the musical position at the start of the segment
+	fn update_transport(&mut self) {
+		let mut transport = self.tempo.transport(self.position, self.sample_rate);
+		transport.frames = self.frames;
+		let beats =
+			|samples: usize| self.tempo.beats_at(samples as f64 / f64::from(self.sample_rate));
+		transport.cycle = self.loop_range.map(|(start, end)| (beats(start), beats(end)));
+		for ch in &mut self.channels {
+			if let Some(instrument) = &mut ch.instrument {
+				instrument.instrument.set_transport(&transport);
+			}
+			for fx in &mut ch.effects {
+				fx.effect.set_transport(&transport);
+			}
+		}
+	}
+
+	pub fn set_tempo(&mut self, tempo: Box<TempoMap>) -> Box<TempoMap> {
+		std::mem::replace(&mut self.tempo, tempo)
+	}
+
+	fn process_sequencers(&mut self) {
+		let Some(position) = self.position else {
+			return;
+		};
+		for ch in &mut self.channels {
+			if let Some(instrument) = &mut ch.instrument {
+				ch.sequencer.process(position, instrument);
+			}
+		}
+	}
+
+	// Samples until the next note event on any channel
+	fn next_sequencer_event(&self) -> Option<usize> {
+		let position = self.position?;
+		self.channels
+			.iter()
+			.filter_map(|ch| ch.sequencer.next_event(position))
+			.min()
+	}
+
+	fn start_sequencers(&mut self, position: usize) {
+		for ch in &mut self.channels {
+			if let Some(instrument) = &mut ch.instrument {
+				ch.sequencer.start(position, self.chase, instrument);
+			}
+		}
+	}
+
+	fn stop_sequencers(&mut self) {
+		for ch in &mut self.channels {
+			if let Some(instrument) = &mut ch.instrument {
+				ch.sequencer.stop(instrument);
+			}
+		}
+	}
+
+	// Swap in new notes for a channel, picking up at the current position when playing
+	pub fn set_sequence(&mut self, channel_index: usize, sequence: Box<Sequence>) -> Box<Sequence> {
+		let ch = &mut self.channels[channel_index];
+		match (self.position, &mut ch.instrument) {
+			(Some(position), Some(instrument)) => {
+				ch.sequencer.replace(sequence, position, self.chase, instrument)
+			},
+			_ => std::mem::replace(&mut ch.sequencer.sequence, sequence),
+		}
+	}
+
+	fn process_segment(&mut self, buffer_out: &mut [&mut [f32]; 2]) {
 		if self.channels.is_empty() {
 			return;
 		}
 
 		let len = buffer_out[0].len();
 
+		if let Some(position) = self.position {
+			self.apply_automation(position);
+		}
+		self.update_latency();
+		self.read_input(len);
+		let input = &self.input_buffer;
+		let position = self.position;
+
 		let (l, r) = self.buffer.split_at_mut(1);
-		let buffer_in = &mut [&mut l[0][..len], &mut r[0][..len]];
+		let buffer = &mut [&mut l[0][..len], &mut r[0][..len]];
 
-		// Zero buffer
-		for sample in buffer_out.iter_mut().flat_map(|s| s.iter_mut()) {
-			*sample = 0.0;
+		for bus in self.buses.iter_mut().chain(std::iter::once(&mut self.cue)) {
+			bus[0][..len].fill(0.0);
+			bus[1][..len].fill(0.0);
 		}
 
-		// Process all channels
-		for ch in &mut self.channels[1..] {
-			ch.process(buffer_in, buffer_out);
+		// Process all channels, the result of master is left in buffer
+		for &index in &self.order {
+			let ch = &mut self.channels[index];
+			ch.process_instrument(buffer, input, position);
+
+			// Add everything that is routed to this channel
+			let bus = &self.buses[index];
+			for (b, bus) in buffer.iter_mut().zip(bus) {
+				for (s, x) in b.iter_mut().zip(bus) {
+					*s += x;
+				}
+			}
+
+			let mut stem = match &mut self.stems {
+				Some(stems) if index > 0 => stems.get_mut(index - 1),
+				_ => None,
+			};
+			if let Some(stem) = &mut stem
+				&& let Some(pre) = &mut stem.pre
+			{
+				interlace_into(pre, buffer);
+			}
+
+			ch.process_effects(buffer, &self.outputs);
+			ch.process_sends(buffer, &mut self.buses, true);
+			ch.process_cue(buffer, &mut self.cue);
+			ch.fader(buffer);
+			ch.process_sends(buffer, &mut self.buses, false);
+
+			if let Some(stem) = stem {
+				interlace_into(&mut stem.post, buffer);
+			}
+
+			let output = &mut self.outputs[index];
+			output[0][..len].copy_from_slice(buffer[0]);
+			output[1][..len].copy_from_slice(buffer[1]);
+
+			if index > 0 {
+				ch.write_output(buffer, &mut self.buses[ch.output]);
+			}
 		}
 
-		// swap buffers for master
-		std::mem::swap(buffer_in, buffer_out);
-		for buf in buffer_out.iter_mut() {
-			buf.fill(0.);
+		buffer_out[0].copy_from_slice(buffer[0]);
+		buffer_out[1].copy_from_slice(buffer[1]);
+
+		if let Some(position) = &mut self.position {
+			*position += len;
 		}
-		self.channels[0].process(buffer_in, buffer_out);
 
 		// Send everything to scope.
 		for s in buffer_out[0].iter() {
@@ -157,94 +689,176 @@ impl Render {
 	}
 
 	pub fn parse_messages(&mut self) {
+		self.apply_due_messages();
+		self.receive_responses();
+	}
+
+	// Apply queued messages up to the current time.
+	// The first one that is not due yet is held back until we get there.
+	fn apply_due_messages(&mut self) {
+		while let Some(event) = self.pending.take().or_else(|| self.audio_rx.try_pop()) {
+			if event.time > self.frames {
+				self.pending = Some(event);
+				break;
+			}
+			self.apply_message(event.message);
+		}
+		while let Some(event) = self
+			.live_pending
+			.take()
+			.or_else(|| self.live_rx.as_mut().and_then(|rx| rx.try_pop()))
+		{
+			if event.time > self.frames {
+				self.live_pending = Some(event);
+				break;
+			}
+			self.apply_message(event.message);
+		}
+	}
+
+	// Time of the first held back message
+	fn next_message(&self) -> Option<u64> {
+		let pending = self.pending.as_ref().map(|e| e.time);
+		let live = self.live_pending.as_ref().map(|e| e.time);
+		match (pending, live) {
+			(Some(a), Some(b)) => Some(a.min(b)),
+			(a, b) => a.or(b),
+		}
+	}
+
+	pub fn set_live_input(&mut self, live_rx: HeapCons<AudioEvent>) {
+		self.live_rx = Some(live_rx);
+		self.live_pending = None;
+	}
+
+	fn apply_message(&mut self, m: AudioMessage) {
 		use AudioMessage::*;
-		while let Some(m) = self.audio_rx.try_pop() {
-			match m {
-				AllNotesOff => {
-					for ch in &mut self.channels {
-						if let Some(instrument) = &mut ch.instrument {
-							instrument.all_notes_off();
-						}
-					}
-				},
-				NoteOn(ch_index, token, pitch, offset, vel) => {
-					let ch = &mut self.channels[ch_index];
-					if let Some(instrument) = &mut ch.instrument {
-						instrument.note_on(token, pitch, offset, vel);
-					}
-				},
-				NoteOff(ch_index, token) => {
-					let ch = &mut self.channels[ch_index];
-					if let Some(instrument) = &mut ch.instrument {
-						instrument.note_off(token);
-					}
-				},
-				Pitch(ch_index, token, pitch) => {
-					let ch = &mut self.channels[ch_index];
-					if let Some(instrument) = &mut ch.instrument {
-						instrument.pitch(token, pitch);
-					}
-				},
-				Pressure(ch_index, token, pressure) => {
-					let ch = &mut self.channels[ch_index];
-					if let Some(instrument) = &mut ch.instrument {
-						instrument.pressure(token, pressure);
-					}
-				},
-				Sustain(ch_index, sustain) => {
-					let ch = &mut self.channels[ch_index];
+		match m {
+			AllNotesOff => {
+				for ch in &mut self.channels {
 					if let Some(instrument) = &mut ch.instrument {
-						instrument.sustain(sustain);
-					}
-				},
-				Parameter(channel_index, device_index, index, val) => {
-					let ch = &mut self.channels[channel_index];
-
-					let request_data = if device_index == 0
-						&& let Some(instrument) = &mut ch.instrument
-					{
-						instrument.instrument.set_parameter(index, val)
-					} else {
-						ch.effects[device_index - 1].effect.set_parameter(index, val)
-					};
-
-					if let Some(data) = request_data {
-						let request = Request::LoadRequest { channel_index, device_index, data };
-						// Handle request
-						if let Err(e) = self.worker_tx.try_send(request) {
-							log_error!("{e}");
-						}
-					}
-				},
-				ChannelMute(ch_index, mute) => self.channels[ch_index].set_mute(mute),
-				ChannelGain(ch_index, gain) => self.channels[ch_index].set_gain(gain),
-				DeviceMute(ch_index, device_index, mute) => {
-					let ch = &mut self.channels[ch_index];
-					if device_index == 0
-						&& let Some(instrument) = &mut ch.instrument
-					{
-						instrument.set_mute(mute);
-					} else {
-						ch.effects[device_index - 1].set_mute(mute);
+						instrument.all_notes_off();
 					}
-				},
-				ReorderEffect(ch_index, old_index, new_index) => {
-					let ch = &mut self.channels[ch_index];
-					let e = ch.effects.remove(old_index);
-					ch.effects.insert(new_index, e);
-				},
-				Metronome(accent) => {
-					self.metronome.trigger(accent);
-				},
-				AudioMessage::Panic => panic!("oof"),
-			}
+				}
+			},
+			NoteOn(ch_index, token, pitch, offset, vel) => {
+				// Live input can still be on its way when a channel gets removed
+				if let Some(ch) = self.channels.get_mut(ch_index)
+					&& let Some(instrument) = &mut ch.instrument
+				{
+					instrument.note_on(token, pitch, offset, vel);
+				}
+			},
+			NoteOff(ch_index, token) => {
+				if let Some(ch) = self.channels.get_mut(ch_index)
+					&& let Some(instrument) = &mut ch.instrument
+				{
+					instrument.note_off(token);
+				}
+			},
+			Pitch(ch_index, token, pitch) => {
+				if let Some(ch) = self.channels.get_mut(ch_index)
+					&& let Some(instrument) = &mut ch.instrument
+				{
+					instrument.pitch(token, pitch);
+				}
+			},
+			Pressure(ch_index, token, pressure) => {
+				if let Some(ch) = self.channels.get_mut(ch_index)
+					&& let Some(instrument) = &mut ch.instrument
+				{
+					instrument.pressure(token, pressure);
+				}
+			},
+			Sustain(ch_index, sustain) => {
+				if let Some(ch) = self.channels.get_mut(ch_index)
+					&& let Some(instrument) = &mut ch.instrument
+				{
+					instrument.sustain(sustain);
+				}
+			},
+			Parameter(channel_index, device_index, index, val) => {
+				// Controller mappings can be out of date when a device was just removed
+				if self.device_exists(channel_index, device_index) {
+					self.set_parameter(channel_index, device_index, index, val);
+				}
+			},
+			ChannelMute(ch_index, mute) => self.set_channel_mute(ch_index, mute),
+			ChannelGain(ch_index, gain) => self.set_channel_gain(ch_index, gain),
+			DeviceMute(ch_index, device_index, mute) => {
+				self.set_device_mute(ch_index, device_index, mute);
+			},
+			SetRouting(routing) => {
+				let old = self.set_routing(routing);
+				if let Err(e) = self.worker_tx.try_send(Request::Garbage(old)) {
+					log_error!("{e}");
+				}
+			},
+			ReorderEffect(ch_index, old_index, new_index) => {
+				let ch = &mut self.channels[ch_index];
+				let e = ch.effects.remove(old_index);
+				ch.effects.insert(new_index, e);
+			},
+			Play(time, chase) => {
+				let position = self.to_samples(time);
+				self.position = Some(position);
+				self.chase = chase;
+				self.loops = 0;
+				self.start_sequencers(position);
+			},
+			Seek(time) => {
+				if self.position.is_some() {
+					let position = self.to_samples(time);
+					self.position = Some(position);
+					self.start_sequencers(position);
+				}
+			},
+			Stop => {
+				self.position = None;
+				self.stop_sequencers();
+			},
+			SetLoop(range) => {
+				self.loop_range = range
+					.map(|(start, end)| (self.to_samples(start), self.to_samples(end)))
+					.filter(|(start, end)| end > start);
+			},
+			SetPunch(range) => {
+				self.punch = range
+					.map(|(start, end)| (self.to_samples(start), self.to_samples(end)))
+					.filter(|(start, end)| end > start);
+			},
+			AutomationBypass(ch_index, lane_index, bypass) => {
+				if let Some(lane) = self.channels[ch_index].automation.lanes.get_mut(lane_index) {
+					lane.bypass = bypass;
+				}
+			},
+			SendGain(ch_index, send_index, gain) => {
+				self.set_send_gain(ch_index, send_index, gain);
+			},
+			CueGain(ch_index, gain) => {
+				self.channels[ch_index].set_cue_gain(gain);
+			},
+			Metronome(accent) => {
+				self.metronome.trigger(accent);
+			},
+			AudioMessage::Panic => panic!("oof"),
 		}
+	}
 
+	fn receive_responses(&mut self) {
 		while let Ok(response) = self.worker_rx.try_recv() {
+			self.pending_requests = self.pending_requests.saturating_sub(1);
+			if let ResponseData::Failed = response.data {
+				continue;
+			}
 			let device_index = response.device_index;
-			let channel = &mut self.channels[response.channel_index];
+			let Some(channel) = self.channels.get_mut(response.channel_index) else {
+				continue;
+			};
 
-			let garbage = if device_index == 0
+			let garbage = if let ResponseData::Clips(clips) = response.data {
+				Some(channel.clips.set_clips(clips) as Box<dyn std::any::Any + Send>)
+			} else if device_index == 0
 				&& let Some(instrument) = &mut channel.instrument
 			{
 				instrument.instrument.receive_data(response.data)
@@ -261,6 +875,119 @@ impl Render {
 		}
 	}
 
+	fn device_exists(&self, channel_index: usize, device_index: usize) -> bool {
+		let Some(ch) = self.channels.get(channel_index) else {
+			return false;
+		};
+		if device_index == 0 {
+			ch.instrument.is_some() || ch.input.is_some()
+		} else {
+			device_index <= ch.effects.len()
+		}
+	}
+
+	pub fn set_parameter(
+		&mut self,
+		channel_index: usize,
+		device_index: usize,
+		index: usize,
+		val: f32,
+	) {
+		let ch = &mut self.channels[channel_index];
+
+		let request_data = if device_index == 0
+			&& let Some(instrument) = &mut ch.instrument
+		{
+			instrument.set_parameter(index, val)
+		} else if device_index == 0
+			&& let Some(audio_input) = &mut ch.input
+		{
+			audio_input.set_parameter(index, val);
+			None
+		} else {
+			ch.effects[device_index - 1].set_parameter(index, val)
+		};
+
+		if let Some(data) = request_data {
+			self.request(channel_index, device_index, data);
+		}
+	}
+
+	fn request(&mut self, channel_index: usize, device_index: usize, data: RequestData) {
+		let request = Request::LoadRequest { channel_index, device_index, data };
+		match self.worker_tx.try_send(request) {
+			Ok(()) => self.pending_requests += 1,
+			Err(e) => log_error!("{e}"),
+		}
+	}
+
+	// Rebuild everything at a new sample rate, keeping the project state.
+	// Allocates, so only call this while the stream is stopped.
+	pub fn set_sample_rate(&mut self, sample_rate: f32) {
+		let old_rate = self.sample_rate;
+		self.sample_rate = sample_rate;
+		self.metronome = Metronome::new(sample_rate);
+		let rescale = |p: usize| (p as f64 * f64::from(sample_rate) / f64::from(old_rate)) as usize;
+		self.position = self.position.map(rescale);
+		self.loop_range = self.loop_range.map(|(start, end)| (rescale(start), rescale(end)));
+		self.punch = self.punch.map(|(start, end)| (rescale(start), rescale(end)));
+
+		// Goes through the same queue, so the worker switches before handling the requests below
+		if let Err(e) = self.worker_tx.try_send(Request::SetSampleRate(sample_rate as u32)) {
+			log_error!("{e}");
+		}
+
+		for channel_index in 0..self.channels.len() {
+			let ch = &mut self.channels[channel_index];
+			let requests = ch.set_sample_rate(sample_rate);
+
+			// Clip data is resampled on load, so the old data is useless now
+			let clips = std::mem::take(&mut ch.clips.requested);
+			drop(ch.clips.set_clips(Box::default()));
+
+			for (device_index, data) in requests {
+				self.request(channel_index, device_index, data);
+			}
+			if !clips.is_empty() {
+				self.load_clips(channel_index, clips);
+			}
+		}
+
+		// Instruments were recreated, pick the notes up again
+		if let Some(position) = self.position {
+			self.start_sequencers(position);
+		}
+	}
+
+	pub fn set_device_mute(&mut self, channel_index: usize, device_index: usize, mute: bool) {
+		let ch = &mut self.channels[channel_index];
+		if device_index == 0
+			&& let Some(instrument) = &mut ch.instrument
+		{
+			instrument.set_mute(mute);
+		} else if device_index == 0
+			&& let Some(audio_input) = &mut ch.input
+		{
+			audio_input.set_mute(mute);
+		} else {
+			ch.effects[device_index - 1].set_mute(mute);
+		}
+	}
+
+	pub fn set_channel_mute(&mut self, channel_index: usize, mute: bool) {
+		self.channels[channel_index].set_mute(mute);
+	}
+
+	pub fn set_channel_gain(&mut self, channel_index: usize, gain: f32) {
+		self.channels[channel_index].set_gain(gain);
+	}
+
+	// True while the worker still owes us a response.
+	// Requests that fail on the worker side get a failure response, so this always settles.
+	pub fn is_loading(&self) -> bool {
+		self.pending_requests > 0
+	}
+
 	pub fn flush(&mut self) {
 		for ch in &mut self.channels {
 			if let Some(instrument) = &mut ch.instrument {
@@ -272,3 +999,10 @@ impl Render {
 		}
 	}
 }
+
+fn interlace_into(dst: &mut Vec<f32>, buffer: &[&mut [f32]; 2]) {
+	for (l, r) in buffer[0].iter().zip(buffer[1].iter()) {
+		dst.push(*l);
+		dst.push(*r);
+	}
+}
@@ -0,0 +1,226 @@
+use crate::api::project;
+use crate::channel::PreparedRoute;
+use anyhow::{Result, bail};
+
+// Where the output of a channel goes. Indices are 0-based, 0 is master.
+#[derive(Debug, Clone, Default)]
+pub struct Route {
+	pub output: usize,
+	pub sends: Vec<SendRoute>,
+	pub sidechains: Vec<SidechainRoute>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SendRoute {
+	pub target: usize,
+	pub gain: f32,
+	pub pre_fader: bool,
+}
+
+// Effect on this channel that is keyed from the output of another channel
+#[derive(Debug, Clone, Copy)]
+pub struct SidechainRoute {
+	pub effect: usize,
+	pub source: usize,
+}
+
+impl Route {
+	// Converts from the 1-based indices used on the Lua side.
+	// `sidechains` is a list of (effect index, source channel index).
+	pub fn new(
+		output: Option<usize>,
+		sends: &[project::Send],
+		sidechains: impl IntoIterator<Item = (usize, usize)>,
+	) -> Self {
+		Self {
+			output: output.map_or(0, |i| i.saturating_sub(1)),
+			sends: sends
+				.iter()
+				.map(|s| SendRoute {
+					target: s.target.saturating_sub(1),
+					gain: s.gain as f32,
+					pre_fader: s.pre_fader,
+				})
+				.collect(),
+			sidechains: sidechains
+				.into_iter()
+				.map(|(effect, source)| SidechainRoute {
+					effect: effect.saturating_sub(1),
+					source: source.saturating_sub(1),
+				})
+				.collect(),
+		}
+	}
+}
+
+// Order in which channels have to be processed, so every channel is processed
+// after all channels that feed into it. Master has no output and always ends up last.
+pub fn processing_order(routes: &[Route]) -> Result<Vec<usize>> {
+	let n = routes.len();
+	if n == 0 {
+		return Ok(Vec::new());
+	}
+
+	// Edges go from a channel to its output and send targets
+	let mut edges = vec![Vec::new(); n];
+	let mut in_degree = vec![0; n];
+	for (i, route) in routes.iter().enumerate().skip(1) {
+		let targets = std::iter::once(route.output).chain(route.sends.iter().map(|s| s.target));
+		for target in targets {
+			if target >= n {
+				bail!("Channel {} routes to channel {} which does not exist.", i + 1, target + 1);
+			}
+			if target == i {
+				bail!("Channel {} routes to itself.", i + 1);
+			}
+			edges[i].push(target);
+			in_degree[target] += 1;
+		}
+	}
+
+	// A sidechain source has to be processed before the channel listening to it
+	for (i, route) in routes.iter().enumerate() {
+		for sidechain in &route.sidechains {
+			let source = sidechain.source;
+			if source >= n {
+				bail!(
+					"Sidechain of channel {} comes from channel {} which does not exist.",
+					i + 1,
+					source + 1
+				);
+			}
+			if source == i {
+				bail!("Channel {} uses itself as sidechain.", i + 1);
+			}
+			edges[source].push(i);
+			in_degree[i] += 1;
+		}
+	}
+
+	// Kahn's algorithm, keeping channels in index order where possible
+	let mut order = Vec::with_capacity(n);
+	let mut ready: Vec<usize> = (0..n).rev().filter(|&i| in_degree[i] == 0).collect();
+	while let Some(i) = ready.pop() {
+		order.push(i);
+		for &target in edges[i].iter().rev() {
+			in_degree[target] -= 1;
+			if in_degree[target] == 0 {
+				ready.push(target);
+			}
+		}
+	}
+
+	if order.len() < n {
+		bail!("Routing contains a feedback loop.");
+	}
+	debug_assert_eq!(order.last(), Some(&0));
+
+	Ok(order)
+}
+
+// Routing of all channels, built on the main thread so the audio thread only swaps it in
+pub struct Routing {
+	pub order: Vec<usize>,
+	pub routes: Vec<PreparedRoute>,
+}
+
+impl Routing {
+	// Fails on feedback loops and routes to channels that don't exist
+	pub fn new(routes: &[Route], sample_rate: f32) -> Result<Self> {
+		let order = processing_order(routes)?;
+		let routes = routes.iter().map(|r| PreparedRoute::new(r, sample_rate)).collect();
+		Ok(Self { order, routes })
+	}
+}
+
+impl std::fmt::Debug for Routing {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Routing")
+			.field("order", &self.order)
+			.finish_non_exhaustive()
+	}
+}
+
+// Which signals go to which stereo pair of the output device.
+// Pairs are 0-based, so pair 1 is outputs 3/4.
+#[derive(Debug, Clone)]
+pub struct OutputMap {
+	pub master: Option<usize>,
+	// Headphone mix, built from the cue level of every channel
+	pub cue: Option<usize>,
+	// Direct outs, a copy of the post-fader channel as (channel, pair)
+	pub channels: Vec<(usize, usize)>,
+}
+
+impl Default for OutputMap {
+	fn default() -> Self {
+		Self { master: Some(0), cue: None, channels: Vec::new() }
+	}
+}
+
+// Everything goes straight to master
+pub fn default_routes(n: usize) -> Vec<Route> {
+	vec![Route::default(); n]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn route(output: usize, sends: &[usize]) -> Route {
+		let sends = sends
+			.iter()
+			.map(|&target| SendRoute { target, gain: 1.0, pre_fader: false })
+			.collect();
+		Route { output, sends, sidechains: Vec::new() }
+	}
+
+	fn position(order: &[usize], channel: usize) -> usize {
+		order.iter().position(|&i| i == channel).unwrap()
+	}
+
+	#[test]
+	fn test_send_cycle() {
+		let routes = [Route::default(), route(0, &[2]), route(0, &[1])];
+		assert!(processing_order(&routes).is_err());
+
+		// Through a bus
+		let routes = [Route::default(), route(2, &[]), route(0, &[1])];
+		assert!(processing_order(&routes).is_err());
+	}
+
+	#[test]
+	fn test_sidechain_source_first() {
+		let mut routes = [Route::default(), route(0, &[]), route(0, &[])];
+		routes[1].sidechains.push(SidechainRoute { effect: 0, source: 2 });
+		let order = processing_order(&routes).unwrap();
+		assert!(position(&order, 2) < position(&order, 1));
+
+		// Keying a channel from the bus it goes into is a loop
+		routes[1].output = 2;
+		assert!(processing_order(&routes).is_err());
+	}
+
+	#[test]
+	fn test_master_last() {
+		// Channel 1 goes to the bus on channel 3, which comes after it in the list
+		let routes = [Route::default(), route(3, &[]), route(0, &[3]), route(0, &[])];
+		let order = processing_order(&routes).unwrap();
+		assert_eq!(order.last(), Some(&0));
+		assert!(position(&order, 1) < position(&order, 3));
+		assert!(position(&order, 2) < position(&order, 3));
+	}
+
+	#[test]
+	fn test_every_channel_processed() {
+		// Nothing goes into the bus on channel 2, it still has to run
+		let routes = [Route::default(), route(0, &[]), route(0, &[])];
+		let mut order = processing_order(&routes).unwrap();
+		assert_eq!(order.len(), routes.len());
+		order.sort_unstable();
+		assert_eq!(order, [0, 1, 2]);
+
+		assert_eq!(processing_order(&[Route::default()]).unwrap(), [0]);
+		assert!(processing_order(&[]).unwrap().is_empty());
+	}
+}
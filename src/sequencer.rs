@@ -0,0 +1,566 @@
+use crate::api::project::{Control, Note};
+use crate::voice_manager::{Token, VoiceManager};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Tokens for sequenced notes live in the upper half, so they never collide with the ones handed out to Lua
+const TOKEN_BASE: Token = 1 << 31;
+// Overlapping notes we keep track of per channel, more are dropped
+const MAX_VOICES: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+struct Point {
+	// Position in samples
+	time: usize,
+	offset: f32,
+	pressure: f32,
+}
+
+#[derive(Debug)]
+struct SequenceNote {
+	start: usize,
+	end: usize,
+	pitch: f32,
+	vel: f32,
+	points: Vec<Point>,
+}
+
+// Notes and sustain events of a channel with times converted to samples.
+// Immutable once built, edits send a new one.
+#[derive(Debug, Default)]
+pub struct Sequence {
+	notes: Vec<SequenceNote>,
+	sustain: Vec<(usize, bool)>,
+	// Data as received from Lua, so it can be converted again at another sample rate
+	source: Vec<Note>,
+	pitches: Vec<f32>,
+	control: Control,
+}
+
+impl Sequence {
+	// `pitches` are the tuned pitches of `notes`, which only Lua knows about
+	pub fn new(notes: Vec<Note>, pitches: Vec<f32>, control: Control, sample_rate: f32) -> Self {
+		let to_samples = |t: f64| (t.max(0.0) * f64::from(sample_rate)).round() as usize;
+
+		let mut sequence_notes: Vec<SequenceNote> = notes
+			.iter()
+			.zip(&pitches)
+			.filter(|(note, _)| !note.verts.is_empty())
+			.map(|(note, &pitch)| {
+				let points: Vec<Point> = note
+					.verts
+					.iter()
+					.map(|v| Point {
+						time: to_samples(note.time + v.x),
+						offset: v.y as f32,
+						pressure: v.w as f32,
+					})
+					.collect();
+				SequenceNote {
+					start: points[0].time,
+					end: points[points.len() - 1].time,
+					pitch,
+					vel: note.vel as f32,
+					points,
+				}
+			})
+			.collect();
+		sequence_notes.sort_by_key(|n| n.start);
+
+		let mut sustain: Vec<_> = control
+			.sustain
+			.iter()
+			.map(|e| (to_samples(e.time), e.value))
+			.collect();
+		sustain.sort_by_key(|e| e.0);
+
+		Self { notes: sequence_notes, sustain, source: notes, pitches, control }
+	}
+
+	pub fn with_sample_rate(&self, sample_rate: f32) -> Self {
+		Self::new(self.source.clone(), self.pitches.clone(), self.control.clone(), sample_rate)
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Voice {
+	note: usize,
+	// Index of the curve point we are past
+	point: usize,
+	token: Token,
+}
+
+// What a sequencer plays into, the VoiceManager of a channel
+pub trait Voices {
+	fn note_on(&mut self, token: Token, pitch: f32, offset: f32, vel: f32);
+	fn note_off(&mut self, token: Token);
+	fn pitch(&mut self, token: Token, offset: f32);
+	fn pressure(&mut self, token: Token, pressure: f32);
+	fn sustain(&mut self, sustain: bool);
+}
+
+impl Voices for VoiceManager {
+	fn note_on(&mut self, token: Token, pitch: f32, offset: f32, vel: f32) {
+		VoiceManager::note_on(self, token, pitch, offset, vel);
+	}
+
+	fn note_off(&mut self, token: Token) {
+		VoiceManager::note_off(self, token);
+	}
+
+	fn pitch(&mut self, token: Token, offset: f32) {
+		VoiceManager::pitch(self, token, offset);
+	}
+
+	fn pressure(&mut self, token: Token, pressure: f32) {
+		VoiceManager::pressure(self, token, pressure);
+	}
+
+	fn sustain(&mut self, sustain: bool) {
+		VoiceManager::sustain(self, sustain);
+	}
+}
+
+// Plays back a Sequence into the instrument of a channel
+pub struct Sequencer {
+	pub sequence: Box<Sequence>,
+	next_note: usize,
+	next_sustain: usize,
+	voices: Vec<Voice>,
+	token: Token,
+	playing: bool,
+}
+
+impl Sequencer {
+	pub fn new() -> Self {
+		Self {
+			sequence: Box::default(),
+			next_note: 0,
+			next_sustain: 0,
+			voices: Vec::with_capacity(MAX_VOICES),
+			token: TOKEN_BASE,
+			playing: false,
+		}
+	}
+
+	// Jump to `position`. With `chase`, notes that are already sounding there are started as well.
+	pub fn start(&mut self, position: usize, chase: bool, instrument: &mut impl Voices) {
+		self.stop(instrument);
+		self.playing = true;
+
+		let notes = &self.sequence.notes;
+		self.next_note = if chase {
+			// Notes are sorted by start, so long notes can end after later short ones
+			notes.iter().position(|n| n.end > position).unwrap_or(notes.len())
+		} else {
+			notes.partition_point(|n| n.start < position)
+		};
+
+		self.seek_sustain(position, instrument);
+	}
+
+	// Restore the sustain state at `position`
+	fn seek_sustain(&mut self, position: usize, instrument: &mut impl Voices) {
+		let sustain = &self.sequence.sustain;
+		self.next_sustain = sustain.partition_point(|e| e.0 < position);
+		if let Some(&(_, value)) = self.next_sustain.checked_sub(1).map(|i| &sustain[i]) {
+			instrument.sustain(value);
+		}
+	}
+
+	pub fn stop(&mut self, instrument: &mut impl Voices) {
+		for v in &self.voices {
+			instrument.note_off(v.token);
+		}
+		self.voices.clear();
+		self.playing = false;
+	}
+
+	// Samples from `position` until the next note on, note off or sustain event
+	pub fn next_event(&self, position: usize) -> Option<usize> {
+		if !self.playing {
+			return None;
+		}
+		let notes = &self.sequence.notes;
+		let note_on = notes.get(self.next_note).map(|n| n.start);
+		let sustain = self.sequence.sustain.get(self.next_sustain).map(|e| e.0);
+		let note_off = self.voices.iter().map(|v| notes[v.note].end).min();
+
+		// Anything at `position` itself was handled by `process` already
+		[note_on, sustain, note_off]
+			.into_iter()
+			.flatten()
+			.filter(|&t| t > position)
+			.min()
+			.map(|t| t - position)
+	}
+
+	// Send everything that is due at `position`, and update pitch and pressure curves
+	pub fn process(&mut self, position: usize, instrument: &mut impl Voices) {
+		if !self.playing {
+			return;
+		}
+		let notes = &self.sequence.notes;
+
+		while let Some(&(time, value)) = self.sequence.sustain.get(self.next_sustain)
+			&& time <= position
+		{
+			instrument.sustain(value);
+			self.next_sustain += 1;
+		}
+
+		// Note offs go first, so a note can be retriggered on the same sample
+		let mut i = 0;
+		while i < self.voices.len() {
+			if notes[self.voices[i].note].end <= position {
+				instrument.note_off(self.voices[i].token);
+				self.voices.swap_remove(i);
+			} else {
+				i += 1;
+			}
+		}
+
+		while let Some(note) = notes.get(self.next_note)
+			&& note.start <= position
+		{
+			// Skip notes that were chased but already ended
+			if note.end > position && self.voices.len() < MAX_VOICES {
+				self.token = self.token.wrapping_add(1) | TOKEN_BASE;
+				instrument.note_on(self.token, note.pitch, note.points[0].offset, note.vel);
+				self.voices
+					.push(Voice { note: self.next_note, point: 0, token: self.token });
+			}
+			self.next_note += 1;
+		}
+
+		for v in &mut self.voices {
+			let points = &notes[v.note].points;
+			while v.point + 1 < points.len() && points[v.point + 1].time <= position {
+				v.point += 1;
+			}
+			let a = points[v.point];
+			let Some(b) = points.get(v.point + 1) else {
+				continue;
+			};
+			let t = position.saturating_sub(a.time) as f32 / (b.time - a.time) as f32;
+			instrument.pitch(v.token, a.offset + t * (b.offset - a.offset));
+			instrument.pressure(v.token, a.pressure + t * (b.pressure - a.pressure));
+		}
+	}
+
+	// Swap in new notes while playing at `position`. Voices of notes that are still there keep
+	// sounding, the others are stopped. Returns the old sequence.
+	pub fn replace(
+		&mut self,
+		sequence: Box<Sequence>,
+		position: usize,
+		chase: bool,
+		instrument: &mut impl Voices,
+	) -> Box<Sequence> {
+		let old = std::mem::replace(&mut self.sequence, sequence);
+		if !self.playing {
+			self.start(position, chase, instrument);
+			return old;
+		}
+		let notes = &self.sequence.notes;
+
+		let mut i = 0;
+		while i < self.voices.len() {
+			let played = &old.notes[self.voices[i].note];
+			let first = notes.partition_point(|n| n.start < played.start);
+			let same = (first..notes.len())
+				.take_while(|&j| notes[j].start == played.start)
+				.find(|&j| {
+					notes[j].pitch == played.pitch && !self.voices[..i].iter().any(|v| v.note == j)
+				});
+			match same {
+				Some(j) => {
+					self.voices[i].note = j;
+					self.voices[i].point = 0;
+					i += 1;
+				},
+				None => {
+					instrument.note_off(self.voices[i].token);
+					self.voices.swap_remove(i);
+				},
+			}
+		}
+
+		// Notes starting at `position` are left to `process`
+		self.next_note = notes.partition_point(|n| n.start < position);
+		if chase {
+			for (j, note) in notes[..self.next_note].iter().enumerate() {
+				let sounding = self.voices.iter().any(|v| v.note == j);
+				if note.end > position && !sounding && self.voices.len() < MAX_VOICES {
+					self.token = self.token.wrapping_add(1) | TOKEN_BASE;
+					instrument.note_on(self.token, note.pitch, note.points[0].offset, note.vel);
+					self.voices.push(Voice { note: j, point: 0, token: self.token });
+				}
+			}
+		}
+		self.seek_sustain(position, instrument);
+		old
+	}
+
+	// Call `stop` first, the voices belong to the instrument at the old rate
+	pub fn set_sample_rate(&mut self, sample_rate: f32) {
+		debug_assert!(self.voices.is_empty());
+		*self.sequence = self.sequence.with_sample_rate(sample_rate);
+		self.voices.clear();
+		self.playing = false;
+	}
+}
+
+// Transport position shared with the main thread
+pub struct Playhead {
+	position: AtomicU64,
+	// Times playback wrapped around the loop since it started
+	loops: AtomicU64,
+}
+
+impl Playhead {
+	const STOPPED: u64 = u64::MAX;
+
+	pub fn new() -> Self {
+		Self { position: AtomicU64::new(Self::STOPPED), loops: AtomicU64::new(0) }
+	}
+
+	pub fn store(&self, position: Option<usize>, loops: u64) {
+		let value = position.map_or(Self::STOPPED, |p| p as u64);
+		self.position.store(value, Ordering::Relaxed);
+		self.loops.store(loops, Ordering::Release);
+	}
+
+	pub fn load(&self) -> Option<usize> {
+		let value = self.position.load(Ordering::Relaxed);
+		(value != Self::STOPPED).then_some(value as usize)
+	}
+
+	// Load this before the position, then the position is never older than the wrap
+	pub fn loops(&self) -> u64 {
+		self.loops.load(Ordering::Acquire)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::project::{SustainEvent, Vertex};
+
+	// One sample per millisecond, so note times read as samples
+	const SAMPLE_RATE: f32 = 1000.0;
+
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	enum Event {
+		On(Token, f32),
+		Off(Token),
+		Pitch(Token, f32),
+		Pressure(Token, f32),
+		Sustain(bool),
+	}
+
+	#[derive(Default)]
+	struct Recorder {
+		events: Vec<Event>,
+	}
+
+	impl Voices for Recorder {
+		fn note_on(&mut self, token: Token, pitch: f32, _offset: f32, _vel: f32) {
+			self.events.push(Event::On(token, pitch));
+		}
+
+		fn note_off(&mut self, token: Token) {
+			self.events.push(Event::Off(token));
+		}
+
+		fn pitch(&mut self, token: Token, offset: f32) {
+			self.events.push(Event::Pitch(token, offset));
+		}
+
+		fn pressure(&mut self, token: Token, pressure: f32) {
+			self.events.push(Event::Pressure(token, pressure));
+		}
+
+		fn sustain(&mut self, sustain: bool) {
+			self.events.push(Event::Sustain(sustain));
+		}
+	}
+
+	impl Recorder {
+		fn take(&mut self) -> Vec<Event> {
+			std::mem::take(&mut self.events)
+		}
+
+		// Note ons and offs only, without the curves
+		fn take_notes(&mut self) -> Vec<Event> {
+			self.take()
+				.into_iter()
+				.filter(|e| matches!(e, Event::On(..) | Event::Off(_)))
+				.collect()
+		}
+	}
+
+	// Start and end in samples, with a flat curve
+	fn note(start: usize, end: usize) -> Note {
+		let time = start as f64 / f64::from(SAMPLE_RATE);
+		let length = (end - start) as f64 / f64::from(SAMPLE_RATE);
+		Note {
+			time,
+			interval: vec![0],
+			vel: 0.5,
+			verts: vec![Vertex { x: 0.0, y: 0.0, w: 0.0 }, Vertex { x: length, y: 0.0, w: 0.0 }],
+		}
+	}
+
+	fn sequencer(notes: Vec<Note>, pitches: Vec<f32>, control: Control) -> Sequencer {
+		let mut sequencer = Sequencer::new();
+		sequencer.sequence = Box::new(Sequence::new(notes, pitches, control, SAMPLE_RATE));
+		sequencer
+	}
+
+	#[test]
+	fn test_chase() {
+		let notes = vec![note(0, 100), note(50, 60), note(200, 300)];
+		let mut sequencer = sequencer(notes, vec![60.0, 62.0, 64.0], Control::default());
+		let mut voices = Recorder::default();
+
+		sequencer.start(80, true, &mut voices);
+		sequencer.process(80, &mut voices);
+		// Only the note that is still sounding, not the one that already ended
+		let events = voices.take_notes();
+		assert!(matches!(events[..], [Event::On(_, 60.0)]));
+		assert_eq!(sequencer.next_event(80), Some(20));
+
+		// Without chase the note in progress is left out
+		sequencer.start(80, false, &mut voices);
+		sequencer.process(80, &mut voices);
+		let events = voices.take_notes();
+		assert!(matches!(events[..], [Event::Off(_)]));
+		assert_eq!(sequencer.next_event(80), Some(120));
+
+		// A note that ends right where playback starts is not chased
+		sequencer.start(100, true, &mut voices);
+		sequencer.process(100, &mut voices);
+		assert!(voices.take_notes().is_empty());
+	}
+
+	#[test]
+	fn test_note_off_first() {
+		let notes = vec![note(0, 100), note(100, 200)];
+		let mut sequencer = sequencer(notes, vec![60.0, 60.0], Control::default());
+		let mut voices = Recorder::default();
+
+		sequencer.start(0, false, &mut voices);
+		sequencer.process(0, &mut voices);
+		let Event::On(first, _) = voices.take_notes()[0] else {
+			panic!("expected a note on");
+		};
+		assert_eq!(sequencer.next_event(0), Some(100));
+
+		sequencer.process(100, &mut voices);
+		let events = voices.take_notes();
+		assert_eq!(events.len(), 2);
+		assert_eq!(events[0], Event::Off(first));
+		assert!(matches!(events[1], Event::On(second, 60.0) if second != first));
+	}
+
+	#[test]
+	fn test_curves() {
+		let mut n = note(0, 200);
+		n.verts = vec![
+			Vertex { x: 0.0, y: 0.0, w: 0.0 },
+			Vertex { x: 0.1, y: 1.0, w: 0.5 },
+			Vertex { x: 0.2, y: 0.0, w: 1.0 },
+		];
+		let mut sequencer = sequencer(vec![n], vec![60.0], Control::default());
+		let mut voices = Recorder::default();
+
+		sequencer.start(0, false, &mut voices);
+		sequencer.process(0, &mut voices);
+		let Event::On(token, _) = voices.take()[0] else {
+			panic!("expected a note on");
+		};
+
+		let mut curves_at = |position| {
+			sequencer.process(position, &mut voices);
+			voices.take()
+		};
+		assert_eq!(curves_at(50), [Event::Pitch(token, 0.5), Event::Pressure(token, 0.25)]);
+		// On a point, and past it into the next segment
+		assert_eq!(curves_at(100), [Event::Pitch(token, 1.0), Event::Pressure(token, 0.5)]);
+		assert_eq!(curves_at(150), [Event::Pitch(token, 0.5), Event::Pressure(token, 0.75)]);
+		assert_eq!(curves_at(200), [Event::Off(token)]);
+	}
+
+	#[test]
+	fn test_voice_cap() {
+		let notes: Vec<Note> = (0..MAX_VOICES + 10).map(|_| note(0, 100)).collect();
+		let pitches = vec![60.0; notes.len()];
+		let mut sequencer = sequencer(notes, pitches, Control::default());
+		let mut voices = Recorder::default();
+
+		sequencer.start(0, false, &mut voices);
+		sequencer.process(0, &mut voices);
+		let ons = voices.take_notes();
+		assert_eq!(ons.len(), MAX_VOICES);
+		let mut tokens: Vec<Token> = ons
+			.iter()
+			.map(|e| match e {
+				Event::On(token, _) => *token,
+				_ => panic!("expected a note on"),
+			})
+			.collect();
+		assert!(tokens.iter().all(|&t| t >= TOKEN_BASE));
+		tokens.sort_unstable();
+		tokens.dedup();
+		assert_eq!(tokens.len(), MAX_VOICES);
+
+		// The dropped notes don't leave anything hanging
+		sequencer.process(100, &mut voices);
+		let offs = voices.take_notes();
+		assert_eq!(offs.len(), MAX_VOICES);
+		assert_eq!(sequencer.next_event(100), None);
+	}
+
+	#[test]
+	fn test_replace_keeps_voices() {
+		let mut sequencer =
+			sequencer(vec![note(0, 100), note(0, 100)], vec![60.0, 64.0], Control::default());
+		let mut voices = Recorder::default();
+		sequencer.start(0, false, &mut voices);
+		sequencer.process(0, &mut voices);
+		voices.take();
+
+		// The first note gets longer, the second one is deleted
+		let notes = vec![note(0, 150), note(120, 130)];
+		let control = Control { sustain: vec![SustainEvent { time: 0.01, value: true }] };
+		let sequence = Sequence::new(notes, vec![60.0, 67.0], control, SAMPLE_RATE);
+		sequencer.replace(Box::new(sequence), 50, false, &mut voices);
+		let events = voices.take();
+		assert!(matches!(events[..], [Event::Off(_), Event::Sustain(true)]));
+		assert_eq!(sequencer.next_event(50), Some(70));
+
+		sequencer.process(120, &mut voices);
+		assert!(matches!(voices.take_notes()[..], [Event::On(_, 67.0)]));
+	}
+
+	#[test]
+	fn test_sample_rate() {
+		let mut sequencer = sequencer(vec![note(0, 100)], vec![60.0], Control::default());
+		let mut voices = Recorder::default();
+		sequencer.start(0, false, &mut voices);
+		sequencer.process(0, &mut voices);
+		let Event::On(token, _) = voices.take()[0] else {
+			panic!("expected a note on");
+		};
+
+		sequencer.stop(&mut voices);
+		sequencer.set_sample_rate(2.0 * SAMPLE_RATE);
+		assert_eq!(voices.take(), [Event::Off(token)]);
+
+		sequencer.start(0, false, &mut voices);
+		assert_eq!(sequencer.next_event(0), None);
+		sequencer.process(0, &mut voices);
+		voices.take();
+		assert_eq!(sequencer.next_event(0), Some(200));
+	}
+}
@@ -0,0 +1,1101 @@
+// Standard MIDI Files.
+// Imported channel events go through the same input processing as a live controller,
+// so bend ranges, MPE zones and sustain end up in the notes the same way as when recording.
+// Exported channels either spread their notes over the member channels of an MPE zone,
+// or play them all on one channel where the pitch bend follows the latest note.
+
+use crate::api::lua_serde;
+use crate::api::project::{Control, Note, SustainEvent, Tempo, TempoPoint, TimeSignature, Vertex};
+use crate::midi::Event;
+use crate::midi_input::{InputConfig, InputEvent, InputProcessor};
+use crate::tempo::TempoMap;
+use crate::voice_manager::Token;
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+
+// Microseconds per quarter note when a file doesn't set the tempo (120 bpm)
+const DEFAULT_TEMPO: u32 = 500_000;
+
+// Vertices closer together than this get merged, same as when recording
+const MIN_VERTEX_TIME: f64 = 0.008;
+const DEFAULT_PRESSURE: f64 = 0.0;
+
+const META: u8 = 0xff;
+const SYSEX: u8 = 0xf0;
+const SYSEX_ESCAPE: u8 = 0xf7;
+
+const META_TRACK_NAME: u8 = 0x03;
+const META_END_OF_TRACK: u8 = 0x2f;
+const META_TEMPO: u8 = 0x51;
+const META_TIME_SIGNATURE: u8 = 0x58;
+
+const RPN_MSB: u8 = 101;
+const RPN_LSB: u8 = 100;
+const RPN_MPE_CONFIGURATION: u8 = 6;
+const RPN_BEND_RANGE: u8 = 0;
+const RPN_NULL: u8 = 0x7f;
+const DATA_ENTRY: u8 = 6;
+const SUSTAIN: u8 = 64;
+
+// Ticks per quarter note of exported files
+const PPQ: u16 = 480;
+// Tempo ramps get written as steps of this many quarter notes
+const RAMP_STEP: f64 = 0.25;
+// Member channels of the lower zone, the manager channel is the first one
+const MPE_MEMBERS: usize = 15;
+// Default bend ranges in semitones
+const MPE_BEND_RANGE: u8 = 48;
+const BEND_RANGE: u8 = 2;
+
+// Channel of an imported file, notes still have to be mapped to the tuning by Lua
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportTrack {
+	pub name: String,
+	pub notes: Vec<ImportNote>,
+	pub control: Control,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportNote {
+	// In seconds
+	pub time: f64,
+	pub key: u8,
+	pub vel: f64,
+	// Offset from the key in semitones, and pressure
+	pub verts: Vec<Vertex>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Import {
+	pub tempo: Tempo,
+	pub tracks: Vec<ImportTrack>,
+}
+
+lua_serde!(Import);
+
+// Notes of a channel with the tuned pitch of every note, same as a sequence for the backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportChannel {
+	pub name: String,
+	#[serde(default)]
+	pub notes: Vec<Note>,
+	#[serde(default)]
+	pub pitches: Vec<f32>,
+	#[serde(default)]
+	pub control: Control,
+}
+
+lua_serde!(ExportChannel);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportOptions {
+	// One member channel per note, otherwise all notes share the first channel
+	pub mpe: bool,
+	// Seconds between pitch bend and pressure messages, on top of one at every vertex
+	pub resolution: f64,
+	// In semitones, the default depends on `mpe`
+	pub bend_range: Option<u8>,
+}
+
+impl Default for ExportOptions {
+	fn default() -> Self {
+		Self { mpe: true, resolution: 0.01, bend_range: None }
+	}
+}
+
+lua_serde!(ExportOptions);
+
+#[derive(Debug, Clone, Copy)]
+enum Division {
+	// Ticks per quarter note
+	Metrical(u16),
+	// Frames per second and ticks per frame
+	Timecode(f64, u16),
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+	Channel([u8; 3], usize),
+	Tempo(u32),
+	TimeSignature(u32, u32),
+	Name(String),
+}
+
+#[derive(Debug, Clone)]
+struct TrackEvent {
+	tick: u64,
+	kind: Kind,
+}
+
+struct Track {
+	events: Vec<TrackEvent>,
+	end: u64,
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Reader<'a> {
+	fn new(data: &'a [u8]) -> Self {
+		Self { data, pos: 0 }
+	}
+
+	fn done(&self) -> bool {
+		self.pos >= self.data.len()
+	}
+
+	fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+		if self.pos + len > self.data.len() {
+			bail!("Unexpected end of file.");
+		}
+		let bytes = &self.data[self.pos..self.pos + len];
+		self.pos += len;
+		Ok(bytes)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.bytes(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		let b = self.bytes(2)?;
+		Ok(u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		let b = self.bytes(4)?;
+		Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+
+	// Variable length quantity, 7 bits per byte with the high bit set on all but the last
+	fn vlq(&mut self) -> Result<u32> {
+		let mut value: u32 = 0;
+		for _ in 0..4 {
+			let b = self.u8()?;
+			value = (value << 7) | u32::from(b & 0x7f);
+			if b & 0x80 == 0 {
+				return Ok(value);
+			}
+		}
+		bail!("Variable length value is too long.");
+	}
+}
+
+fn read_track(data: &[u8]) -> Result<Track> {
+	let mut r = Reader::new(data);
+	let mut events = Vec::new();
+	let mut tick: u64 = 0;
+	let mut running: Option<u8> = None;
+
+	while !r.done() {
+		tick += u64::from(r.vlq()?);
+		let status = r.u8()?;
+		match status {
+			META => {
+				let kind = r.u8()?;
+				let len = r.vlq()? as usize;
+				let data = r.bytes(len)?;
+				running = None;
+				match kind {
+					META_END_OF_TRACK => break,
+					META_TEMPO if len == 3 => {
+						let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+						events.push(TrackEvent { tick, kind: Kind::Tempo(tempo) });
+					},
+					META_TIME_SIGNATURE if len >= 2 => {
+						let signature =
+							Kind::TimeSignature(u32::from(data[0]), 1 << data[1].min(6));
+						events.push(TrackEvent { tick, kind: signature });
+					},
+					META_TRACK_NAME => {
+						let name = String::from_utf8_lossy(data).trim().to_string();
+						events.push(TrackEvent { tick, kind: Kind::Name(name) });
+					},
+					_ => {},
+				}
+			},
+			SYSEX | SYSEX_ESCAPE => {
+				let len = r.vlq()? as usize;
+				r.bytes(len)?;
+				running = None;
+			},
+			0xf1..=0xfe => bail!("Unexpected status byte {status:#x}."),
+			_ => {
+				// Running status: the previous status byte repeats and this was already data
+				let (status, first) = if status & 0x80 == 0 {
+					let Some(running) = running else {
+						bail!("Data byte without a status byte.");
+					};
+					(running, Some(status))
+				} else {
+					running = Some(status);
+					(status, None)
+				};
+				let len = match status >> 4 {
+					0xc | 0xd => 2,
+					_ => 3,
+				};
+				let mut bytes = [status, 0, 0];
+				let mut i = 1;
+				if let Some(first) = first {
+					bytes[1] = first;
+					i = 2;
+				}
+				while i < len {
+					bytes[i] = r.u8()?;
+					i += 1;
+				}
+				events.push(TrackEvent { tick, kind: Kind::Channel(bytes, len) });
+			},
+		}
+	}
+	Ok(Track { events, end: tick })
+}
+
+fn read_file(data: &[u8]) -> Result<(Division, Vec<Track>)> {
+	let mut r = Reader::new(data);
+	let mut header = None;
+	let mut tracks = Vec::new();
+
+	while !r.done() {
+		let id = r.bytes(4)?;
+		let len = r.u32()? as usize;
+		let chunk = r.bytes(len)?;
+		match id {
+			b"MThd" => {
+				let mut h = Reader::new(chunk);
+				let format = h.u16()?;
+				let _n_tracks = h.u16()?;
+				let division = h.u16()?;
+				if format > 1 {
+					bail!("MIDI file format {format} is not supported.");
+				}
+				let division = if division & 0x8000 == 0 {
+					Division::Metrical(division.max(1))
+				} else {
+					// Negative frame rate in the high byte, 29 means 29.97 drop frame
+					let fps = match 256 - (division >> 8) {
+						29 => 29.97,
+						fps => f64::from(fps),
+					};
+					Division::Timecode(fps, (division & 0xff).max(1))
+				};
+				header = Some(division);
+			},
+			b"MTrk" => tracks.push(read_track(chunk)?),
+			// Unknown chunks are allowed, and should be skipped
+			_ => {},
+		}
+	}
+
+	let Some(division) = header else {
+		bail!("Not a MIDI file.");
+	};
+	Ok((division, tracks))
+}
+
+// Converts ticks to seconds, following the tempo changes
+struct TickClock {
+	division: Division,
+	// Tick, time in seconds and tempo from there on
+	changes: Vec<(u64, f64, u32)>,
+}
+
+impl TickClock {
+	fn new(division: Division, tempos: &[(u64, u32)]) -> Self {
+		let mut changes = vec![(0, 0.0, DEFAULT_TEMPO)];
+		for &(tick, tempo) in tempos {
+			let time = Self::time_in(division, changes[changes.len() - 1], tick);
+			let last = changes.len() - 1;
+			if changes[last].0 == tick {
+				changes[last].2 = tempo;
+			} else {
+				changes.push((tick, time, tempo));
+			}
+		}
+		Self { division, changes }
+	}
+
+	fn time_in(division: Division, (start, time, tempo): (u64, f64, u32), tick: u64) -> f64 {
+		let ticks = (tick - start) as f64;
+		match division {
+			Division::Metrical(ppq) => time + ticks * f64::from(tempo) * 1e-6 / f64::from(ppq),
+			// Tempo changes don't move anything, but a change still starts a new segment here
+			Division::Timecode(fps, per_frame) => time + ticks / (fps * f64::from(per_frame)),
+		}
+	}
+
+	fn seconds(&self, tick: u64) -> f64 {
+		let i = self.changes.partition_point(|c| c.0 <= tick).max(1) - 1;
+		Self::time_in(self.division, self.changes[i], tick)
+	}
+}
+
+// Collects the notes of one channel from processed input events
+#[derive(Default)]
+struct TrackBuilder {
+	notes: Vec<ImportNote>,
+	active: Vec<(Token, usize)>,
+	sustain: Vec<SustainEvent>,
+}
+
+impl TrackBuilder {
+	fn event(&mut self, time: f64, event: InputEvent) {
+		match event {
+			InputEvent::NoteOn { token, note, offset, vel, .. } => {
+				self.active.push((token, self.notes.len()));
+				self.notes.push(ImportNote {
+					time,
+					key: note,
+					vel: f64::from(vel),
+					verts: vec![Vertex { x: 0.0, y: f64::from(offset), w: DEFAULT_PRESSURE }],
+				});
+			},
+			InputEvent::NoteOff { token } => {
+				if let Some(i) = self.active.iter().position(|a| a.0 == token) {
+					let (_, n) = self.active.remove(i);
+					self.close(n, time);
+				}
+			},
+			InputEvent::Pitch { token, offset, .. } => {
+				self.vertex(token, time, Some(f64::from(offset)), None);
+			},
+			InputEvent::Pressure { token, pressure } => {
+				self.vertex(token, time, None, Some(f64::from(pressure)));
+			},
+			InputEvent::Sustain(value) => {
+				// Half pedal sends a stream of values, only keep the changes
+				if self.sustain.last().is_none_or(|s| s.value != value) {
+					self.sustain.push(SustainEvent { time, value });
+				}
+			},
+			_ => {},
+		}
+	}
+
+	fn vertex(&mut self, token: Token, time: f64, offset: Option<f64>, pressure: Option<f64>) {
+		let Some(&(_, n)) = self.active.iter().find(|a| a.0 == token) else {
+			return;
+		};
+		let note = &mut self.notes[n];
+		let x = time - note.time;
+		let last = note.verts.last_mut().unwrap();
+		if x - last.x >= MIN_VERTEX_TIME {
+			let v = Vertex { x, y: offset.unwrap_or(last.y), w: pressure.unwrap_or(last.w) };
+			note.verts.push(v);
+		} else {
+			last.y = offset.unwrap_or(last.y);
+			last.w = pressure.unwrap_or(last.w);
+		}
+	}
+
+	fn close(&mut self, n: usize, time: f64) {
+		let note = &mut self.notes[n];
+		let y = note.verts.last().map_or(0.0, |v| v.y);
+		note.verts
+			.push(Vertex { x: time - note.time, y, w: DEFAULT_PRESSURE });
+	}
+
+	fn finish(mut self, name: String, end: f64) -> ImportTrack {
+		// Notes that never got a note off
+		for (_, n) in std::mem::take(&mut self.active) {
+			self.close(n, end);
+		}
+		ImportTrack { name, notes: self.notes, control: Control { sustain: self.sustain } }
+	}
+}
+
+// Files with an MPE configuration message keep all channels of a track together,
+// since the notes of a zone are spread over its member channels
+fn configures_mpe(track: &Track) -> bool {
+	let mut rpn_msb = [0x7f_u8; 16];
+	for e in &track.events {
+		if let Kind::Channel(bytes, _) = e.kind
+			&& bytes[0] >> 4 == 0xb
+		{
+			let channel = usize::from(bytes[0] & 0x0f);
+			match bytes[1] {
+				RPN_MSB => rpn_msb[channel] = bytes[2],
+				RPN_LSB if bytes[2] == RPN_MPE_CONFIGURATION && rpn_msb[channel] == 0 => {
+					return true;
+				},
+				_ => {},
+			}
+		}
+	}
+	false
+}
+
+pub fn import(path: &Path, config: &InputConfig) -> Result<Import> {
+	let data = std::fs::read(path)?;
+	import_data(&data, config)
+}
+
+fn import_data(data: &[u8], config: &InputConfig) -> Result<Import> {
+	let (division, tracks) = read_file(data)?;
+
+	let mut tempos = Vec::new();
+	let mut signatures = Vec::new();
+	for track in &tracks {
+		for e in &track.events {
+			match e.kind {
+				Kind::Tempo(tempo) => tempos.push((e.tick, tempo)),
+				Kind::TimeSignature(numerator, denominator) => {
+					signatures.push((e.tick, numerator, denominator));
+				},
+				_ => {},
+			}
+		}
+	}
+	tempos.sort_by_key(|t| t.0);
+	signatures.sort_by_key(|s| s.0);
+	let clock = TickClock::new(division, &tempos);
+
+	let tempo = match division {
+		Division::Metrical(ppq) => {
+			let beat = |tick: u64| tick as f64 / f64::from(ppq);
+			let mut points: Vec<TempoPoint> = clock
+				.changes
+				.iter()
+				.map(|&(tick, _, tempo)| TempoPoint {
+					beat: beat(tick),
+					bpm: 60e6 / f64::from(tempo.max(1)),
+					ramp: false,
+				})
+				.collect();
+			points.dedup_by(|b, a| a.bpm == b.bpm);
+			let mut signatures: Vec<TimeSignature> = signatures
+				.iter()
+				.map(|&(tick, numerator, denominator)| TimeSignature {
+					beat: beat(tick),
+					numerator,
+					denominator,
+				})
+				.collect();
+			if signatures.first().is_none_or(|s| s.beat > 0.0) {
+				signatures.insert(0, TimeSignature { beat: 0.0, numerator: 4, denominator: 4 });
+			}
+			Tempo { offset: 0.0, points, signatures }
+		},
+		// No musical time in the file
+		Division::Timecode(..) => Tempo::default(),
+	};
+
+	let tokens = Arc::new(AtomicU32::new(0));
+	let mut result = Vec::new();
+	for (track_index, track) in tracks.iter().enumerate() {
+		let name = track
+			.events
+			.iter()
+			.find_map(|e| match &e.kind {
+				Kind::Name(name) if !name.is_empty() => Some(name.clone()),
+				_ => None,
+			})
+			.unwrap_or_else(|| format!("Track {}", track_index + 1));
+		let end = clock.seconds(track.end);
+
+		if config.mpe || configures_mpe(track) {
+			let builder = process(track, None, &clock, config, &tokens);
+			result.push(builder.finish(name, end));
+			continue;
+		}
+
+		// Every MIDI channel is its own instrument
+		let mut channels: Vec<u8> = track
+			.events
+			.iter()
+			.filter_map(|e| match e.kind {
+				Kind::Channel(bytes, _) => Some(bytes[0] & 0x0f),
+				_ => None,
+			})
+			.collect();
+		channels.sort_unstable();
+		channels.dedup();
+		let several = channels.len() > 1;
+		for channel in channels {
+			let builder = process(track, Some(channel), &clock, config, &tokens);
+			let name = if several { format!("{name} {}", channel + 1) } else { name.clone() };
+			result.push(builder.finish(name, end));
+		}
+	}
+	result.retain(|t| !t.notes.is_empty());
+
+	Ok(Import { tempo, tracks: result })
+}
+
+fn process(
+	track: &Track,
+	channel: Option<u8>,
+	clock: &TickClock,
+	config: &InputConfig,
+	tokens: &Arc<AtomicU32>,
+) -> TrackBuilder {
+	let mut processor = InputProcessor::new(config.clone(), Arc::clone(tokens));
+	let mut builder = TrackBuilder::default();
+	for e in &track.events {
+		let Kind::Channel(bytes, len) = e.kind else {
+			continue;
+		};
+		if channel.is_some_and(|c| c != bytes[0] & 0x0f) {
+			continue;
+		}
+		let Some(event) = Event::from_bytes(&bytes[..len]) else {
+			continue;
+		};
+		let time = clock.seconds(e.tick);
+		processor.process(&event, &mut |input| builder.event(time, input));
+	}
+	builder
+}
+
+// Order of messages at the same tick, so a note can end and start again on one channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Order {
+	NoteOff,
+	Control,
+	NoteOn,
+	// Polyphonic aftertouch only applies to keys that are already down
+	Aftertouch,
+}
+
+struct WriteEvent {
+	tick: u64,
+	order: Order,
+	bytes: Vec<u8>,
+}
+
+fn write_vlq(out: &mut Vec<u8>, value: u64) {
+	let value = value.min(0x0fff_ffff);
+	for shift in [21, 14, 7] {
+		if value >> shift > 0 {
+			out.push(0x80 | ((value >> shift) & 0x7f) as u8);
+		}
+	}
+	out.push((value & 0x7f) as u8);
+}
+
+#[derive(Default)]
+struct TrackWriter {
+	events: Vec<WriteEvent>,
+}
+
+impl TrackWriter {
+	fn push(&mut self, tick: u64, order: Order, bytes: &[u8]) {
+		self.events.push(WriteEvent { tick, order, bytes: bytes.to_vec() });
+	}
+
+	fn meta(&mut self, tick: u64, kind: u8, data: &[u8]) {
+		let mut bytes = vec![META, kind];
+		write_vlq(&mut bytes, data.len() as u64);
+		bytes.extend_from_slice(data);
+		self.events.push(WriteEvent { tick, order: Order::Control, bytes });
+	}
+
+	fn control(&mut self, tick: u64, channel: u8, controller: u8, value: u8) {
+		self.push(tick, Order::Control, &[0xb0 | channel, controller, value]);
+	}
+
+	fn rpn(&mut self, tick: u64, channel: u8, rpn: u8, value: u8) {
+		self.control(tick, channel, RPN_MSB, 0);
+		self.control(tick, channel, RPN_LSB, rpn);
+		self.control(tick, channel, DATA_ENTRY, value);
+		// Deselect it again, so stray data entry messages don't change it
+		self.control(tick, channel, RPN_MSB, RPN_NULL);
+		self.control(tick, channel, RPN_LSB, RPN_NULL);
+	}
+
+	fn finish(mut self) -> Vec<u8> {
+		// Stable, so events of the same kind at one tick keep their order
+		self.events.sort_by_key(|e| (e.tick, e.order));
+		let mut data = Vec::new();
+		let mut tick = 0;
+		for e in &self.events {
+			write_vlq(&mut data, e.tick - tick);
+			data.extend_from_slice(&e.bytes);
+			tick = e.tick;
+		}
+		write_vlq(&mut data, 0);
+		data.extend_from_slice(&[META, META_END_OF_TRACK, 0]);
+
+		let mut chunk = b"MTrk".to_vec();
+		chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+		chunk.extend(data);
+		chunk
+	}
+}
+
+fn beat_tick(beats: f64) -> u64 {
+	(beats * f64::from(PPQ)).round().max(0.0) as u64
+}
+
+// Notes before the start of the tempo map end up at the start of the file
+fn seconds_tick(map: &TempoMap, seconds: f64) -> u64 {
+	beat_tick(map.beats_at(seconds))
+}
+
+fn tempo_track(map: &TempoMap) -> Vec<u8> {
+	let mut track = TrackWriter::default();
+	for (beat, numerator, denominator) in map.signature_changes() {
+		// Only powers of two can be written
+		let data = [numerator.min(255) as u8, denominator.ilog2() as u8, 24, 8];
+		track.meta(beat_tick(beat), META_TIME_SIGNATURE, &data);
+	}
+	for (i, (beat, bpm)) in map.tempo_steps(RAMP_STEP).into_iter().enumerate() {
+		// The first tempo also holds before the first point
+		let tick = if i == 0 { 0 } else { beat_tick(beat) };
+		let tempo = (60e6 / bpm).round().clamp(1.0, 16_777_215.0) as u32;
+		track.meta(tick, META_TEMPO, &tempo.to_be_bytes()[1..]);
+	}
+	track.finish()
+}
+
+struct WriteNote<'a> {
+	start: u64,
+	end: u64,
+	// In seconds
+	time: f64,
+	pitch: f64,
+	key: u8,
+	vel: u8,
+	verts: &'a [Vertex],
+}
+
+// Offset and pressure of a note at `x` seconds from its start
+fn curve_at(verts: &[Vertex], x: f64) -> (f64, f64) {
+	let i = verts.partition_point(|v| v.x <= x);
+	let Some(a) = i.checked_sub(1).map(|i| &verts[i]) else {
+		return (verts[0].y, verts[0].w);
+	};
+	let Some(b) = verts.get(i) else {
+		return (a.y, a.w);
+	};
+	let t = (x - a.x) / (b.x - a.x);
+	(a.y + t * (b.y - a.y), a.w + t * (b.w - a.w))
+}
+
+// Points where the curves of a note get sampled: every vertex and every `resolution` seconds
+fn sample_points(verts: &[Vertex], resolution: f64) -> Vec<f64> {
+	let first = verts[0].x;
+	let last = verts[verts.len() - 1].x;
+	let mut points: Vec<f64> = verts.iter().map(|v| v.x).collect();
+	if resolution > 0.0 {
+		let n = ((last - first) / resolution).ceil() as usize;
+		points.extend((1..n).map(|k| first + k as f64 * resolution));
+	}
+	points.sort_by(f64::total_cmp);
+	points.dedup();
+	points
+}
+
+// Spreads notes over the member channels, so every note bends on its own.
+// Channels are taken round robin to let releases ring out,
+// when all of them are busy the oldest note is cut short.
+fn allocate_members(notes: &mut [WriteNote]) -> Vec<u8> {
+	let mut owner: [Option<usize>; MPE_MEMBERS] = [None; MPE_MEMBERS];
+	let mut next = 0;
+	let mut channels = Vec::with_capacity(notes.len());
+	for i in 0..notes.len() {
+		let start = notes[i].start;
+		let free = (0..MPE_MEMBERS)
+			.map(|k| (next + k) % MPE_MEMBERS)
+			.find(|&c| owner[c].is_none_or(|n| notes[n].end <= start));
+		let c = free.unwrap_or_else(|| {
+			(0..MPE_MEMBERS)
+				.min_by_key(|&c| owner[c].map_or(0, |n| notes[n].start))
+				.unwrap()
+		});
+		if let Some(n) = owner[c]
+			&& notes[n].end > start
+		{
+			// A note that started on this very tick is dropped
+			notes[n].end = start;
+		}
+		owner[c] = Some(i);
+		next = (c + 1) % MPE_MEMBERS;
+		channels.push(c as u8 + 1);
+	}
+	channels
+}
+
+fn channel_track(channel: &ExportChannel, map: &TempoMap, options: &ExportOptions) -> Vec<u8> {
+	let mut notes: Vec<WriteNote> = channel
+		.notes
+		.iter()
+		.zip(&channel.pitches)
+		.filter(|(note, _)| !note.verts.is_empty())
+		.map(|(note, &pitch)| {
+			let pitch = f64::from(pitch);
+			let first = &note.verts[0];
+			let last = &note.verts[note.verts.len() - 1];
+			let start = seconds_tick(map, note.time + first.x);
+			WriteNote {
+				start,
+				end: seconds_tick(map, note.time + last.x).max(start + 1),
+				time: note.time,
+				pitch,
+				key: (pitch + first.y).round().clamp(0.0, 127.0) as u8,
+				vel: (note.vel * 127.0).round().clamp(1.0, 127.0) as u8,
+				verts: &note.verts,
+			}
+		})
+		.collect();
+	notes.sort_by_key(|n| n.start);
+
+	let mut track = TrackWriter::default();
+	track.meta(0, META_TRACK_NAME, channel.name.as_bytes());
+
+	let default_range = if options.mpe { MPE_BEND_RANGE } else { BEND_RANGE };
+	let range = options.bend_range.unwrap_or(default_range).clamp(1, 127);
+	let members = if options.mpe {
+		track.rpn(0, 0, RPN_MPE_CONFIGURATION, MPE_MEMBERS as u8);
+		for c in 1..=MPE_MEMBERS as u8 {
+			track.rpn(0, c, RPN_BEND_RANGE, range);
+		}
+		allocate_members(&mut notes)
+	} else {
+		track.rpn(0, 0, RPN_BEND_RANGE, range);
+		vec![0; notes.len()]
+	};
+
+	let mut bends = [None; 16];
+	let mut pressures = [None; 16];
+	for (i, note) in notes.iter().enumerate() {
+		if note.end <= note.start {
+			continue;
+		}
+		let c = members[i];
+		// Without MPE the bend of the channel follows the latest note
+		let bend_end = match notes.get(i + 1) {
+			Some(next) if !options.mpe => next.start.min(note.end),
+			_ => note.end,
+		};
+		let mut note_pressure = None;
+
+		for x in sample_points(note.verts, options.resolution) {
+			let tick = seconds_tick(map, note.time + x);
+			if tick >= note.end {
+				break;
+			}
+			let (offset, pressure) = curve_at(note.verts, x);
+			if tick < bend_end {
+				let bend = (note.pitch + offset - f64::from(note.key)) / f64::from(range);
+				let bend = (bend * 8192.0 + 8192.0).round().clamp(0.0, 16383.0) as u16;
+				if bends[usize::from(c)] != Some(bend) {
+					bends[usize::from(c)] = Some(bend);
+					let bytes = [0xe0 | c, (bend & 0x7f) as u8, (bend >> 7) as u8];
+					track.push(tick, Order::Control, &bytes);
+				}
+			}
+			let pressure = (pressure * 127.0).round().clamp(0.0, 127.0) as u8;
+			if options.mpe {
+				if pressures[usize::from(c)] != Some(pressure) {
+					pressures[usize::from(c)] = Some(pressure);
+					track.push(tick, Order::Control, &[0xd0 | c, pressure]);
+				}
+			} else if note_pressure != Some(pressure) {
+				note_pressure = Some(pressure);
+				track.push(tick, Order::Aftertouch, &[0xa0 | c, note.key, pressure]);
+			}
+		}
+
+		track.push(note.start, Order::NoteOn, &[0x90 | c, note.key, note.vel]);
+		track.push(note.end, Order::NoteOff, &[0x80 | c, note.key, 64]);
+	}
+
+	// The manager channel applies to the whole zone
+	for e in &channel.control.sustain {
+		let value = if e.value { 127 } else { 0 };
+		track.control(seconds_tick(map, e.time), 0, SUSTAIN, value);
+	}
+
+	track.finish()
+}
+
+pub fn export(
+	path: &Path,
+	channels: &[ExportChannel],
+	tempo: &Tempo,
+	options: &ExportOptions,
+) -> Result<()> {
+	let data = export_data(channels, tempo, options)?;
+	std::fs::write(path, data)?;
+	Ok(())
+}
+
+fn export_data(
+	channels: &[ExportChannel],
+	tempo: &Tempo,
+	options: &ExportOptions,
+) -> Result<Vec<u8>> {
+	let Ok(n_tracks) = u16::try_from(channels.len() + 1) else {
+		bail!("Too many channels.");
+	};
+	let map = TempoMap::new(tempo);
+
+	let mut data = b"MThd".to_vec();
+	data.extend_from_slice(&6_u32.to_be_bytes());
+	// Format 1: tracks play at the same time, the first one holds the tempo map
+	data.extend_from_slice(&1_u16.to_be_bytes());
+	data.extend_from_slice(&n_tracks.to_be_bytes());
+	data.extend_from_slice(&PPQ.to_be_bytes());
+
+	data.extend(tempo_track(&map));
+	for channel in channels {
+		data.extend(channel_track(channel, &map, options));
+	}
+	Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Format 1 file around raw track data
+	fn file(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+		let mut data = b"MThd".to_vec();
+		data.extend_from_slice(&6_u32.to_be_bytes());
+		data.extend_from_slice(&1_u16.to_be_bytes());
+		data.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+		data.extend_from_slice(&division.to_be_bytes());
+		for track in tracks {
+			data.extend_from_slice(b"MTrk");
+			data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+			data.extend_from_slice(track);
+		}
+		data
+	}
+
+	fn assert_close(a: f64, b: f64) {
+		assert!((a - b).abs() < 1e-9, "{a} != {b}");
+	}
+
+	fn channel_events(track: &Track) -> Vec<(u64, Vec<u8>)> {
+		track
+			.events
+			.iter()
+			.filter_map(|e| match e.kind {
+				Kind::Channel(bytes, len) => Some((e.tick, bytes[..len].to_vec())),
+				_ => None,
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_vlq() {
+		// Examples from the specification
+		let cases: [(u64, &[u8]); 10] = [
+			(0, &[0x00]),
+			(0x40, &[0x40]),
+			(0x7f, &[0x7f]),
+			(0x80, &[0x81, 0x00]),
+			(0x2000, &[0xc0, 0x00]),
+			(0x3fff, &[0xff, 0x7f]),
+			(0x4000, &[0x81, 0x80, 0x00]),
+			(0x1f_ffff, &[0xff, 0xff, 0x7f]),
+			(0x20_0000, &[0x81, 0x80, 0x80, 0x00]),
+			(0x0fff_ffff, &[0xff, 0xff, 0xff, 0x7f]),
+		];
+		for (value, bytes) in cases {
+			let mut out = Vec::new();
+			write_vlq(&mut out, value);
+			assert_eq!(out, bytes, "{value:#x}");
+			assert_eq!(u64::from(Reader::new(bytes).vlq().unwrap()), value);
+		}
+
+		// Larger values are clamped to what fits in four bytes
+		let mut out = Vec::new();
+		write_vlq(&mut out, u64::MAX);
+		assert_eq!(out, [0xff, 0xff, 0xff, 0x7f]);
+		assert!(Reader::new(&[0x81, 0x80, 0x80, 0x80, 0x00]).vlq().is_err());
+		assert!(Reader::new(&[0x81]).vlq().is_err());
+	}
+
+	#[test]
+	fn test_running_status() {
+		let data = [
+			&[0x00, 0x90, 60, 100][..],
+			&[0x0a, 64, 100],
+			&[0x0a, 0x80, 60, 64],
+			&[0x00, 64, 64],
+			// Channel pressure only has one data byte
+			&[0x00, 0xd0, 30],
+			&[0x05, 40],
+			&[0x00, META, META_END_OF_TRACK, 0x00],
+		]
+		.concat();
+		let track = read_track(&data).unwrap();
+		let expected = [
+			(0, vec![0x90, 60, 100]),
+			(10, vec![0x90, 64, 100]),
+			(20, vec![0x80, 60, 64]),
+			(20, vec![0x80, 64, 64]),
+			(20, vec![0xd0, 30]),
+			(25, vec![0xd0, 40]),
+		];
+		assert_eq!(channel_events(&track), expected);
+		assert_eq!(track.end, 25);
+
+		// Meta and sysex events cancel running status
+		assert!(read_track(&[0x00, 60, 100]).is_err());
+		assert!(read_track(&[0x00, 0x90, 60, 100, 0x00, META, 0x01, 0x00, 0x00, 64, 100]).is_err());
+		assert!(
+			read_track(&[0x00, 0x90, 60, 100, 0x00, SYSEX, 0x01, 0xf7, 0x00, 64, 100]).is_err()
+		);
+	}
+
+	#[test]
+	fn test_garbage() {
+		let config = InputConfig::default();
+		assert!(import_data(b"", &config).is_err());
+		assert!(import_data(b"not a midi file", &config).is_err());
+		assert!(import_data(b"MThd\0\0\0\x06\0\x01", &config).is_err());
+		assert!(import_data(&file(96, &[&[0x00, 0xf4]]), &config).is_err());
+
+		let track = [0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, META, META_END_OF_TRACK, 0x00];
+		let data = file(96, &[&track]);
+		assert_eq!(import_data(&data, &config).unwrap().tracks[0].notes.len(), 1);
+		// Only a cut right after the header still leaves a valid file
+		for len in (0..data.len()).filter(|&len| len != 14) {
+			assert!(import_data(&data[..len], &config).is_err(), "{len}");
+		}
+
+		// Random track data never panics
+		let mut seed: u32 = 1;
+		for _ in 0..500 {
+			let track: Vec<u8> = (0..64)
+				.map(|_| {
+					seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+					(seed >> 24) as u8
+				})
+				.collect();
+			let _ = import_data(&file(96, &[&track]), &config);
+		}
+	}
+
+	#[test]
+	fn test_tempo_change() {
+		// 120 bpm, then 240 bpm from tick 960
+		let tempo = [
+			&[0x00, META, META_TEMPO, 0x03, 0x07, 0xa1, 0x20][..],
+			&[0x87, 0x40, META, META_TEMPO, 0x03, 0x03, 0xd0, 0x90],
+			&[0x00, META, META_END_OF_TRACK, 0x00],
+		]
+		.concat();
+		// Note from tick 1920 to 2400
+		let notes = [
+			&[0x8f, 0x00, 0x90, 60, 100][..],
+			&[0x83, 0x60, 0x80, 60, 0],
+			&[0x00, META, META_END_OF_TRACK, 0x00],
+		]
+		.concat();
+		let config = InputConfig::default();
+
+		let import = import_data(&file(480, &[&tempo, &notes]), &config).unwrap();
+		let points: Vec<_> = import.tempo.points.iter().map(|p| (p.beat, p.bpm)).collect();
+		assert_eq!(points.len(), 2);
+		assert_close(points[1].0, 2.0);
+		assert_close(points[1].1, 240.0);
+		let note = &import.tracks[0].notes[0];
+		assert_eq!(note.key, 60);
+		// Two quarter notes at 120 bpm and two at 240 bpm, then one more at 240 bpm
+		assert_close(note.time, 1.5);
+		assert_close(note.verts.last().unwrap().x, 0.25);
+
+		// 25 fps with 40 ticks per frame, a thousand ticks per second whatever the tempo
+		let import = import_data(&file(0xe728, &[&tempo, &notes]), &config).unwrap();
+		let note = &import.tracks[0].notes[0];
+		assert_close(note.time, 1.92);
+		assert_close(note.verts.last().unwrap().x, 0.48);
+	}
+
+	fn note(time: f64, verts: &[(f64, f64)]) -> Note {
+		let verts = verts.iter().map(|&(x, y)| Vertex { x, y, w: 0.0 }).collect();
+		Note { time, interval: Vec::new(), vel: 0.5, verts }
+	}
+
+	#[test]
+	fn test_export_round_trip() {
+		let channel = ExportChannel {
+			name: "Lead".into(),
+			notes: vec![
+				// Bends up a whole tone over a second
+				note(0.5, &[(0.0, 0.0), (1.0, 2.0)]),
+				note(1.0, &[(0.0, 0.0), (0.5, 0.0)]),
+				note(2.0, &[(0.0, 0.0), (0.25, 0.0)]),
+			],
+			// A quarter tone up needs a bend from the start
+			pitches: vec![60.0, 64.25, 67.0],
+			control: Control {
+				sustain: vec![
+					SustainEvent { time: 0.75, value: true },
+					SustainEvent { time: 1.75, value: false },
+				],
+			},
+		};
+		let options = ExportOptions::default();
+		let data = export_data(&[channel], &Tempo::default(), &options).unwrap();
+
+		// Overlapping notes get their own member channel, round robin
+		let (_, tracks) = read_file(&data).unwrap();
+		let note_ons: Vec<_> = channel_events(&tracks[1])
+			.into_iter()
+			.filter(|(_, bytes)| bytes[0] >> 4 == 0x9)
+			.map(|(tick, bytes)| (tick, bytes[0] & 0x0f, bytes[1]))
+			.collect();
+		assert_eq!(note_ons, [(480, 1, 60), (960, 2, 64), (1920, 3, 67)]);
+
+		let import = import_data(&data, &InputConfig::default()).unwrap();
+		assert_eq!(import.tracks.len(), 1);
+		let track = &import.tracks[0];
+		assert_eq!(track.name, "Lead");
+		let sustain: Vec<_> = track.control.sustain.iter().map(|s| (s.time, s.value)).collect();
+		assert_eq!(sustain, [(0.75, true), (1.75, false)]);
+
+		let notes = &track.notes;
+		assert_eq!(notes.iter().map(|n| n.key).collect::<Vec<_>>(), [60, 64, 67]);
+		for (n, time) in notes.iter().zip([0.5, 1.0, 2.0]) {
+			assert_close(n.time, time);
+			assert!((n.vel - 64.0 / 127.0).abs() < 1e-6);
+		}
+
+		// One step of the bend is 48 / 8192 semitones
+		let bend = &notes[0].verts;
+		assert!(bend.len() > 50);
+		assert!((bend[bend.len() - 1].x - 1.0).abs() < 1e-3);
+		for v in &bend[..bend.len() - 1] {
+			assert!((v.y - 2.0 * v.x).abs() < 0.01, "{v:?}");
+		}
+		assert!(notes[1].verts.iter().all(|v| (v.y - 0.25).abs() < 0.01));
+		assert!(notes[2].verts.iter().all(|v| v.y.abs() < 0.01));
+	}
+
+	#[test]
+	fn test_allocate_members() {
+		let write_note = |start, end| WriteNote {
+			start,
+			end,
+			time: 0.0,
+			pitch: 60.0,
+			key: 60,
+			vel: 100,
+			verts: &[],
+		};
+
+		// Notes that already ended still rotate, so their releases can ring out
+		let mut notes = vec![write_note(0, 10), write_note(10, 20), write_note(20, 30)];
+		assert_eq!(allocate_members(&mut notes), [1, 2, 3]);
+
+		// With all channels busy the oldest note is cut where the new one starts
+		let mut notes: Vec<_> = (0..17).map(|i| write_note(i, 100)).collect();
+		let channels = allocate_members(&mut notes);
+		assert_eq!(channels[..15], (1..=15).collect::<Vec<u8>>()[..]);
+		assert_eq!(channels[15..], [1, 2]);
+		assert_eq!((notes[0].end, notes[1].end, notes[2].end), (15, 16, 100));
+	}
+}
@@ -0,0 +1,321 @@
+use crate::api::project;
+
+// Musical time as seen by devices, all positions in quarter notes
+#[derive(Debug, Clone, Copy)]
+pub struct TransportInfo {
+	pub playing: bool,
+	// Position in samples
+	pub position: usize,
+	pub beats: f64,
+	// Start of the current bar
+	pub bar_start: f64,
+	pub tempo: f64,
+	pub numerator: u32,
+	pub denominator: u32,
+	// Frames rendered since the engine started, keeps counting while stopped and across loops
+	pub frames: u64,
+	// Loop range while looping
+	pub cycle: Option<(f64, f64)>,
+}
+
+impl Default for TransportInfo {
+	fn default() -> Self {
+		Self {
+			playing: false,
+			position: 0,
+			beats: 0.0,
+			bar_start: 0.0,
+			tempo: 120.0,
+			numerator: 4,
+			denominator: 4,
+			frames: 0,
+			cycle: None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+	// Start in quarter notes and in seconds
+	beat: f64,
+	time: f64,
+	bpm: f64,
+	// Change in bpm per quarter note, zero for constant tempo
+	slope: f64,
+}
+
+impl Segment {
+	// Seconds it takes to play `beats` quarter notes from the start of the segment
+	fn seconds(&self, beats: f64) -> f64 {
+		if beats < 0.0 || self.slope == 0.0 {
+			return beats * 60.0 / self.bpm;
+		}
+		60.0 / self.slope * (1.0 + self.slope * beats / self.bpm).ln()
+	}
+
+	// Inverse of `seconds`
+	fn beats(&self, seconds: f64) -> f64 {
+		if seconds < 0.0 || self.slope == 0.0 {
+			return seconds * self.bpm / 60.0;
+		}
+		self.bpm / self.slope * ((self.slope * seconds / 60.0).exp() - 1.0)
+	}
+
+	fn bpm(&self, beats: f64) -> f64 {
+		if beats < 0.0 {
+			return self.bpm;
+		}
+		self.bpm + self.slope * beats
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Signature {
+	beat: f64,
+	// Bars before this signature
+	bar: f64,
+	numerator: u32,
+	denominator: u32,
+}
+
+impl Signature {
+	// Length of a bar in quarter notes
+	fn length(&self) -> f64 {
+		f64::from(self.numerator) * 4.0 / f64::from(self.denominator)
+	}
+}
+
+// Tempo and time signature changes of a project.
+// Before the first point the first tempo continues, after the last point the last one does.
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+	segments: Vec<Segment>,
+	signatures: Vec<Signature>,
+}
+
+impl Default for TempoMap {
+	fn default() -> Self {
+		Self::new(&project::Tempo::default())
+	}
+}
+
+impl TempoMap {
+	pub fn new(tempo: &project::Tempo) -> Self {
+		let mut points: Vec<_> = tempo.points.iter().filter(|p| p.bpm > 0.0).collect();
+		points.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+
+		let mut segments: Vec<Segment> = Vec::with_capacity(points.len());
+		for (i, p) in points.iter().enumerate() {
+			let time = match segments.last() {
+				Some(s) => s.time + s.seconds(p.beat - s.beat),
+				None => tempo.offset + p.beat * 60.0 / p.bpm,
+			};
+			let slope = match points.get(i + 1) {
+				Some(next) if p.ramp && next.beat > p.beat => {
+					(next.bpm - p.bpm) / (next.beat - p.beat)
+				},
+				_ => 0.0,
+			};
+			segments.push(Segment { beat: p.beat, time, bpm: p.bpm, slope });
+		}
+		if segments.is_empty() {
+			segments.push(Segment { beat: 0.0, time: tempo.offset, bpm: 120.0, slope: 0.0 });
+		}
+
+		let mut list: Vec<_> = tempo
+			.signatures
+			.iter()
+			.filter(|s| s.numerator > 0 && s.denominator > 0)
+			.collect();
+		list.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+
+		let mut signatures: Vec<Signature> = Vec::with_capacity(list.len());
+		for s in list {
+			let bar = match signatures.last() {
+				Some(prev) => prev.bar + (s.beat - prev.beat) / prev.length(),
+				None => 0.0,
+			};
+			signatures.push(Signature {
+				beat: s.beat,
+				bar,
+				numerator: s.numerator,
+				denominator: s.denominator,
+			});
+		}
+		if signatures.is_empty() {
+			signatures.push(Signature { beat: 0.0, bar: 0.0, numerator: 4, denominator: 4 });
+		}
+
+		Self { segments, signatures }
+	}
+
+	fn segment_at_time(&self, seconds: f64) -> &Segment {
+		let i = self.segments.partition_point(|s| s.time <= seconds);
+		&self.segments[i.saturating_sub(1)]
+	}
+
+	fn signature_at(&self, beats: f64) -> &Signature {
+		let i = self.signatures.partition_point(|s| s.beat <= beats);
+		&self.signatures[i.saturating_sub(1)]
+	}
+
+	pub fn beats_at(&self, seconds: f64) -> f64 {
+		let s = self.segment_at_time(seconds);
+		s.beat + s.beats(seconds - s.time)
+	}
+
+	fn bpm_at(&self, seconds: f64) -> f64 {
+		let s = self.segment_at_time(seconds);
+		s.bpm(s.beats(seconds - s.time))
+	}
+
+	// Bar number counted from beat 0, with the fraction of the bar that has passed
+	fn bars_at(&self, beats: f64) -> f64 {
+		let s = self.signature_at(beats);
+		s.bar + (beats - s.beat) / s.length()
+	}
+
+	fn beats_at_bar(&self, bars: f64) -> f64 {
+		let i = self.signatures.partition_point(|s| s.bar <= bars);
+		let s = &self.signatures[i.saturating_sub(1)];
+		s.beat + (bars - s.bar) * s.length()
+	}
+
+	// Constant tempo steps as (quarter note, bpm), for formats that can't ramp.
+	// Ramps are split into steps of `step` quarter notes with the average tempo of the step,
+	// so every step boundary still falls on the exact time.
+	pub fn tempo_steps(&self, step: f64) -> Vec<(f64, f64)> {
+		let mut steps = Vec::new();
+		for (i, s) in self.segments.iter().enumerate() {
+			match self.segments.get(i + 1) {
+				Some(next) if s.slope != 0.0 => {
+					let mut beat = s.beat;
+					while beat < next.beat {
+						let end = (beat + step).min(next.beat);
+						let seconds = s.seconds(end - s.beat) - s.seconds(beat - s.beat);
+						steps.push((beat, (end - beat) * 60.0 / seconds));
+						beat = end;
+					}
+				},
+				_ => steps.push((s.beat, s.bpm)),
+			}
+		}
+		steps
+	}
+
+	// Time signature changes as (quarter note, numerator, denominator)
+	pub fn signature_changes(&self) -> impl Iterator<Item = (f64, u32, u32)> + '_ {
+		self.signatures.iter().map(|s| (s.beat, s.numerator, s.denominator))
+	}
+
+	pub fn transport(&self, position: Option<usize>, sample_rate: f32) -> TransportInfo {
+		let playing = position.is_some();
+		let position = position.unwrap_or(0);
+		let seconds = position as f64 / f64::from(sample_rate);
+		let beats = self.beats_at(seconds);
+		let signature = self.signature_at(beats);
+		TransportInfo {
+			playing,
+			position,
+			beats,
+			bar_start: self.beats_at_bar(self.bars_at(beats).floor()),
+			tempo: self.bpm_at(seconds),
+			numerator: signature.numerator,
+			denominator: signature.denominator,
+			frames: 0,
+			cycle: None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::project::{Tempo, TempoPoint, TimeSignature};
+
+	fn assert_close(a: f64, b: f64) {
+		assert!((a - b).abs() < 1e-9, "{a} != {b}");
+	}
+
+	fn tempo(points: &[(f64, f64, bool)], signatures: &[(f64, u32, u32)]) -> TempoMap {
+		TempoMap::new(&Tempo {
+			offset: 0.0,
+			points: points
+				.iter()
+				.map(|&(beat, bpm, ramp)| TempoPoint { beat, bpm, ramp })
+				.collect(),
+			signatures: signatures
+				.iter()
+				.map(|&(beat, numerator, denominator)| TimeSignature {
+					beat,
+					numerator,
+					denominator,
+				})
+				.collect(),
+		})
+	}
+
+	#[test]
+	fn test_constant_tempo() {
+		let s = Segment { beat: 0.0, time: 0.0, bpm: 120.0, slope: 0.0 };
+		assert_close(s.seconds(4.0), 2.0);
+		assert_close(s.beats(2.0), 4.0);
+		assert_close(s.bpm(100.0), 120.0);
+		// Before the segment the tempo continues
+		assert_close(s.seconds(-1.0), -0.5);
+
+		let map = tempo(&[(0.0, 120.0, false), (8.0, 60.0, false)], &[]);
+		assert_close(map.beats_at(4.0), 8.0);
+		assert_close(map.beats_at(5.0), 9.0);
+		assert_close(map.bpm_at(5.0), 60.0);
+		assert_eq!(map.tempo_steps(0.25), vec![(0.0, 120.0), (8.0, 60.0)]);
+	}
+
+	#[test]
+	fn test_ramp() {
+		// 60 to 120 bpm over 4 quarter notes, 15 bpm per quarter note
+		let map = tempo(&[(0.0, 60.0, true), (4.0, 120.0, false)], &[]);
+		let s = map.segments[0];
+		assert_close(s.slope, 15.0);
+
+		// t(b) = 60 / slope * ln(1 + slope * b / bpm)
+		let end = 4.0 * 2.0f64.ln();
+		assert_close(s.seconds(4.0), end);
+		assert_close(s.beats(end), 4.0);
+		assert_close(s.seconds(2.0), 4.0 * 1.5f64.ln());
+		assert_close(map.segments[1].time, end);
+		assert_close(map.bpm_at(end / 2.0), 60.0 * 2.0f64.sqrt());
+		assert_close(map.beats_at(end + 1.0), 6.0);
+
+		// Each step has the logarithmic mean tempo of its ends, so the steps add up to the ramp
+		let steps = map.tempo_steps(1.0);
+		assert_eq!(steps.len(), 5);
+		for (i, &(beat, bpm)) in steps[..4].iter().enumerate() {
+			let (a, b) = (60.0 + 15.0 * i as f64, 75.0 + 15.0 * i as f64);
+			assert_close(beat, i as f64);
+			assert_close(bpm, (b - a) / (b / a).ln());
+		}
+		let total: f64 = steps[..4].iter().map(|&(_, bpm)| 60.0 / bpm).sum();
+		assert_close(total, end);
+		assert_eq!(steps[4], (4.0, 120.0));
+	}
+
+	#[test]
+	fn test_signature_mid_bar() {
+		// 3/4 starts halfway through the second bar of 4/4
+		let map = tempo(&[(0.0, 120.0, false)], &[(0.0, 4, 4), (6.0, 3, 4)]);
+		assert_close(map.bars_at(4.0), 1.0);
+		assert_close(map.bars_at(6.0), 1.5);
+		assert_close(map.bars_at(9.0), 2.5);
+		assert_close(map.beats_at_bar(1.0), 4.0);
+		assert_close(map.beats_at_bar(2.0), 7.5);
+		assert_close(map.beats_at_bar(2.5), 9.0);
+
+		// 4.5 seconds is beat 9
+		let transport = map.transport(Some(4 * 48000 + 24000), 48000.0);
+		assert_close(transport.beats, 9.0);
+		assert_close(transport.bar_start, 7.5);
+		assert_eq!((transport.numerator, transport.denominator), (3, 4));
+		assert_eq!(map.signature_changes().collect::<Vec<_>>(), vec![(0.0, 4, 4), (6.0, 3, 4)]);
+	}
+}
@@ -1,7 +1,10 @@
 use crate::audio::MAX_BUF_SIZE;
 use crate::dsp::{MuteState, PeakMeter, time_constant};
+use crate::instrument;
 use crate::instrument::Instrument;
 use crate::meters::MeterHandle;
+use crate::parameters::ParameterCache;
+use crate::worker::RequestData;
 use std::collections::VecDeque;
 
 pub type Token = u32;
@@ -30,6 +33,8 @@ impl Voice {
 
 pub struct VoiceManager {
 	pub instrument: Box<dyn Instrument + Send>,
+	name: String,
+	parameters: ParameterCache,
 	voices: Vec<Voice>,
 	queue: VecDeque<Voice>,
 	sustain: bool,
@@ -44,14 +49,13 @@ pub struct VoiceManager {
 }
 
 impl VoiceManager {
-	pub fn new(
-		sample_rate: f32,
-		instrument: Box<dyn Instrument + Send>,
-		meter_handle: MeterHandle,
-	) -> Self {
+	pub fn new(sample_rate: f32, name: &str, meter_handle: MeterHandle) -> Self {
+		let instrument = instrument::new(sample_rate, name);
 		let voice_count = instrument.voice_count();
 		Self {
 			instrument,
+			name: name.to_string(),
+			parameters: ParameterCache::new(),
 			voices: vec![Voice::default(); voice_count],
 			queue: VecDeque::with_capacity(8),
 			sustain: false,
@@ -196,6 +200,38 @@ impl VoiceManager {
 		self.state = MuteState::Transition;
 	}
 
+	pub fn set_parameter(&mut self, index: usize, val: f32) -> Option<RequestData> {
+		self.parameters.set(index, val);
+		self.instrument.set_parameter(index, val)
+	}
+
+	// Recreate the instrument at the new rate with the same parameters.
+	// Returns the data the instrument needs to load again.
+	pub fn set_sample_rate(&mut self, sample_rate: f32) -> Vec<RequestData> {
+		self.all_notes_off();
+
+		let mut voice_manager =
+			VoiceManager::new(sample_rate, &self.name, self.meter_handle.clone());
+		let mut requests = Vec::new();
+		if self.instrument.set_sample_rate(sample_rate) {
+			// Reconfigured in place, so it keeps its own state
+			std::mem::swap(&mut voice_manager.instrument, &mut self.instrument);
+			std::mem::swap(&mut voice_manager.parameters, &mut self.parameters);
+		} else {
+			for (index, val) in self.parameters.iter() {
+				requests.extend(voice_manager.set_parameter(index, val));
+			}
+		}
+
+		if self.mute {
+			voice_manager.mute = true;
+			voice_manager.state = MuteState::Off;
+			voice_manager.gain = 0.0;
+		}
+		*self = voice_manager;
+		requests
+	}
+
 	pub fn process(&mut self, buffer: &mut [&mut [f32]; 2]) {
 		match self.state {
 			MuteState::Off => {},
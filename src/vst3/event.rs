@@ -1,6 +1,7 @@
 use std::cell::UnsafeCell;
+use vst3::Steinberg::Vst::DataEvent_::DataTypes_;
 use vst3::Steinberg::Vst::Event_::EventTypes_;
-use vst3::Steinberg::Vst::NoteOffEvent;
+use vst3::Steinberg::Vst::{DataEvent, NoteOffEvent};
 use vst3::Steinberg::Vst::{Event, Event__type0, IEventList, IEventListTrait, NoteOnEvent};
 use vst3::Steinberg::{kResultOk, tresult};
 use vst3::{Class, ComPtr, ComWrapper};
@@ -99,3 +100,21 @@ pub fn note_off(id: usize, pitch: i16) -> Event {
 		},
 	}
 }
+
+// The event only points to `data`, which has to stay alive until the plugin processed it
+pub fn sysex(data: &[u8]) -> Event {
+	Event {
+		busIndex: 0,
+		sampleOffset: 0,
+		ppqPosition: 0.0,
+		flags: 0,
+		r#type: EventTypes_::kDataEvent as u16,
+		__field0: Event__type0 {
+			data: DataEvent {
+				size: data.len() as u32,
+				r#type: DataTypes_::kMidiSysEx as u32,
+				bytes: data.as_ptr(),
+			},
+		},
+	}
+}
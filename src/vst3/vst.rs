@@ -1,4 +1,5 @@
 use crate::audio::MAX_BUF_SIZE;
+use crate::tempo::TransportInfo;
 use crate::vst3::error::ToResultExt;
 use crate::vst3::event::Events;
 use crate::vst3::parameter::Parameters;
@@ -13,6 +14,7 @@ use std::ffi::c_void;
 use std::mem::MaybeUninit;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::SyncSender;
 use vst3::Steinberg::Vst;
 use vst3::Steinberg::Vst::ProcessContext_::StatesAndFlags_;
@@ -43,6 +45,7 @@ const AUDIO: EnumType = Vst::MediaTypes_::kAudio as EnumType;
 const OUTPUT: EnumType = Vst::BusDirections_::kOutput as EnumType;
 const REALTIME: EnumType = Vst::ProcessModes_::kRealtime as EnumType;
 const SAMPLE_32: EnumType = Vst::SymbolicSampleSizes_::kSample32 as EnumType;
+const LATENCY_CHANGED: i32 = Vst::RestartFlags_::kLatencyChanged as i32;
 
 struct PluginHost;
 
@@ -67,8 +70,11 @@ impl IHostApplicationTrait for PluginHost {
 	}
 }
 
-// Stub implementation for IComponentHandler
-pub struct ComponentHandler;
+// Stub implementation for IComponentHandler, except for latency changes
+pub struct ComponentHandler {
+	// Set from the UI thread, the processor queries the latency again on the audio thread
+	latency_changed: Arc<AtomicBool>,
+}
 
 impl Class for ComponentHandler {
 	type Interfaces = (IComponentHandler,);
@@ -87,7 +93,10 @@ impl IComponentHandlerTrait for ComponentHandler {
 		kResultOk
 	}
 
-	unsafe fn restartComponent(&self, _flags: i32) -> tresult {
+	unsafe fn restartComponent(&self, flags: i32) -> tresult {
+		if flags & LATENCY_CHANGED != 0 {
+			self.latency_changed.store(true, Ordering::Relaxed);
+		}
 		kResultOk
 	}
 }
@@ -191,6 +200,9 @@ pub struct Vst3Processor {
 	audio_processor: ComPtr<IAudioProcessor>,
 	component: ComPtr<IComponent>,
 	lib: Arc<Vst3Library>,
+	latency: usize,
+	latency_changed: Arc<AtomicBool>,
+	transport: TransportInfo,
 }
 
 pub fn load(
@@ -232,7 +244,9 @@ pub fn load(
 		.cast::<IAudioProcessor>()
 		.expect("Component does not implement IAudioProcessor");
 
-	let component_handler = ComWrapper::new(ComponentHandler);
+	let latency_changed = Arc::new(AtomicBool::new(false));
+	let component_handler =
+		ComWrapper::new(ComponentHandler { latency_changed: Arc::clone(&latency_changed) });
 	let handler_ptr = component_handler.to_com_ptr::<IComponentHandler>().unwrap();
 
 	// Setup editor
@@ -343,6 +357,9 @@ pub fn load(
 		.as_result()
 		.context("Failed to set audio processing state")?;
 
+	// Queried again when the plugin reports a change with restartComponent
+	let latency = unsafe { audio_processor.getLatencySamples() } as usize;
+
 	let midi_mapping = edit_controller
 		.cast::<IMidiMapping>()
 		.ok_or_else(|| anyhow!("Plugin doesn't support IMidiMapping."))?;
@@ -368,6 +385,9 @@ pub fn load(
 		audio_processor,
 		component,
 		lib: Arc::clone(&lib),
+		latency,
+		latency_changed,
+		transport: TransportInfo::default(),
 	};
 
 	Ok((editor, processor))
@@ -484,7 +504,26 @@ impl Vst3Processor {
 		Ok(())
 	}
 
+	pub fn latency(&self) -> usize {
+		self.latency
+	}
+
+	// Plugins can change their latency at any time, e.g. when switching lookahead.
+	// The host is not required to reactivate the plugin for this, so it keeps processing.
+	fn update_latency(&mut self) {
+		if self.latency_changed.swap(false, Ordering::Relaxed) {
+			self.latency = unsafe { self.audio_processor.getLatencySamples() } as usize;
+		}
+	}
+
+	// Musical position for the next process call
+	pub fn set_transport(&mut self, transport: &TransportInfo) {
+		self.transport = *transport;
+	}
+
 	pub fn process(&mut self, left_buf: &mut [f32], right_buf: &mut [f32]) {
+		self.update_latency();
+
 		// VST3 wants a pointer to an array of channel pointers
 		let mut channels = [left_buf.as_mut_ptr(), right_buf.as_mut_ptr()];
 
@@ -498,33 +537,42 @@ impl Vst3Processor {
 			__field0: AudioBusBuffers__type0 { channelBuffers32: channels.as_mut_ptr() },
 		};
 
+		let transport = &self.transport;
+		let mut state = (StatesAndFlags_::kTempoValid as u32)
+			| (StatesAndFlags_::kProjectTimeMusicValid as u32)
+			| (StatesAndFlags_::kBarPositionValid as u32)
+			| (StatesAndFlags_::kTimeSigValid as u32)
+			| (StatesAndFlags_::kContTimeValid as u32);
+		if transport.playing {
+			state |= StatesAndFlags_::kPlaying as u32;
+		}
+		let (cycle_start, cycle_end) = transport.cycle.unwrap_or_default();
+		if transport.cycle.is_some() {
+			state |= (StatesAndFlags_::kCycleActive as u32) | (StatesAndFlags_::kCycleValid as u32);
+		}
+
 		let mut context = ProcessContext {
-			// TODO: Send correct playing flag
-			state: (StatesAndFlags_::kPlaying as u32)
-				| (StatesAndFlags_::kTempoValid as u32)
-				| (StatesAndFlags_::kProjectTimeMusicValid as u32),
+			state,
 
 			sampleRate: f64::from(self.sample_rate),
 
-			// TODO: hardcoded for now
-			tempo: 120.0,
+			tempo: transport.tempo,
 
-			// TODO (Transport quarter notes)
-			projectTimeMusic: 0.0,
-			projectTimeSamples: 0,
+			projectTimeMusic: transport.beats,
+			projectTimeSamples: transport.position as i64,
+			barPositionMusic: transport.bar_start,
+			timeSigNumerator: transport.numerator as i32,
+			timeSigDenominator: transport.denominator as i32,
+			continousTimeSamples: transport.frames as i64,
+			cycleStartMusic: cycle_start,
+			cycleEndMusic: cycle_end,
 
 			// Other fields we don't care about
-			barPositionMusic: 0.0,
-			cycleStartMusic: 0.0,
-			cycleEndMusic: 0.0,
-			timeSigNumerator: 4,
-			timeSigDenominator: 4,
 			systemTime: 0,
 			smpteOffsetSubframes: 0,
 			frameRate: FrameRate { framesPerSecond: 60, flags: 0 },
 			chord: Chord { keyNote: 0, rootNote: 0, chordMask: 0 },
 			samplesToNextClock: 0,
-			continousTimeSamples: 0,
 		};
 
 		// Populate buffer process data
@@ -571,6 +619,34 @@ impl Vst3Processor {
 		unsafe { self.audio_processor.setProcessing(1) }.as_result()?;
 		Ok(())
 	}
+
+	// The plugin has to be deactivated before it accepts new settings
+	pub fn set_sample_rate(&mut self, sample_rate: f32) -> Result<()> {
+		unsafe { self.audio_processor.setProcessing(0) }.as_result()?;
+		unsafe { self.component.setActive(0) }.as_result()?;
+
+		let mut setup = ProcessSetup {
+			processMode: REALTIME,
+			symbolicSampleSize: SAMPLE_32,
+			maxSamplesPerBlock: MAX_BUF_SIZE as i32,
+			sampleRate: f64::from(sample_rate),
+		};
+		unsafe { self.audio_processor.setupProcessing(&mut setup) }
+			.as_result()
+			.context("Failed to setup processing")?;
+
+		unsafe { self.component.setActive(1) }
+			.as_result()
+			.context("Failed to set component active")?;
+		unsafe { self.audio_processor.setProcessing(1) }
+			.as_result()
+			.context("Failed to set audio processing state")?;
+
+		self.sample_rate = sample_rate;
+		// Latency usually depends on the sample rate
+		self.latency = unsafe { self.audio_processor.getLatencySamples() } as usize;
+		Ok(())
+	}
 }
 
 impl Drop for Vst3Processor {
@@ -1,4 +1,6 @@
+use crate::api::project::Clip;
 use crate::audio::MAX_BUF_SIZE;
+use crate::clip::{ClipData, ClipList};
 use crate::dsp::resample::Resampler;
 use crate::embed::Asset;
 use crate::log::*;
@@ -35,16 +37,37 @@ struct Worker {
 
 	wavetables: HashMap<String, Arc<Vec<f32>>>,
 	samples: HashMap<String, Arc<[Vec<f32>; 2]>>,
+	// impulse responses, resampled to the stream rate
+	irs: HashMap<String, Arc<[Vec<f32>; 2]>>,
+	// audio files from disk, resampled to the stream rate
+	files: HashMap<String, Arc<[Vec<f32>; 2]>>,
 }
 
 impl Worker {
 	fn new(sample_rate: u32, tx: mpsc::SyncSender<Response>) -> Self {
-		Self { sample_rate, tx, wavetables: HashMap::new(), samples: HashMap::new() }
+		Self {
+			sample_rate,
+			tx,
+			wavetables: HashMap::new(),
+			samples: HashMap::new(),
+			irs: HashMap::new(),
+			files: HashMap::new(),
+		}
 	}
 
 	fn handle_request(&mut self, req: Request) {
 		match req {
-			Request::Garbage(_) => {}, // drop
+			Request::Garbage(garbage) => {
+				// Replaced clip lists end up here, so files can be released after them
+				drop(garbage);
+				self.evict_files();
+			},
+			Request::SetSampleRate(sample_rate) => {
+				// Resampled data is only valid for one rate, load it again when requested
+				self.sample_rate = sample_rate;
+				self.irs.clear();
+				self.files.clear();
+			},
 			Request::LoadRequest { channel_index, device_index, data } => {
 				if let Err(e) = match data {
 					RequestData::Wavetable(path) => {
@@ -56,11 +79,49 @@ impl Worker {
 					RequestData::IR(path) => self.handle_ir(channel_index, device_index, path),
 				} {
 					log_error!("Worker Error: {e}");
+					// Still respond, the renderer counts the requests it is waiting for
+					if let Err(e) = self.send(channel_index, device_index, ResponseData::Failed) {
+						log_error!("Worker Error: {e}");
+					}
+				}
+			},
+			Request::LoadClips { channel_index, clips } => {
+				if let Err(e) = self.handle_clips(channel_index, &clips) {
+					log_error!("Worker Error: {e}");
+					if let Err(e) = self.send(channel_index, 0, ResponseData::Failed) {
+						log_error!("Worker Error: {e}");
+					}
 				}
 			},
 		}
 	}
 
+	fn handle_clips(&mut self, ch: usize, clips: &[Clip]) -> Result<()> {
+		let mut list = Vec::with_capacity(clips.len());
+		for clip in clips {
+			let data = match self.files.entry(clip.path.clone()) {
+				Entry::Occupied(e) => e.get().clone(),
+				Entry::Vacant(e) => match load_file(&clip.path, self.sample_rate as f32) {
+					Ok(sample) => e.insert(Arc::new(sample)).clone(),
+					Err(err) => {
+						// Skip missing files, but still play the rest
+						log_error!("Failed to load \"{}\": {err}", clip.path);
+						continue;
+					},
+				},
+			};
+			list.push(ClipData::new(clip, data, self.sample_rate as f32));
+		}
+		self.evict_files();
+		// Always respond, even if empty, so the old clips get replaced
+		self.send(ch, 0, ResponseData::Clips(Box::new(ClipList(list))))
+	}
+
+	// Forget files that no clip holds on to anymore
+	fn evict_files(&mut self) {
+		self.files.retain(|_, data| Arc::strong_count(data) > 1);
+	}
+
 	fn handle_wavetable(&mut self, ch: usize, dev: usize, path: &'static str) -> Result<()> {
 		let data = match self.wavetables.entry(path.to_string()) {
 			Entry::Occupied(e) => e.get().clone(),
@@ -89,7 +150,7 @@ impl Worker {
 	}
 
 	fn handle_ir(&mut self, ch: usize, dev: usize, path: &'static str) -> Result<()> {
-		let sample = match self.samples.entry(path.to_string()) {
+		let sample = match self.irs.entry(path.to_string()) {
 			Entry::Occupied(e) => e.get().clone(),
 			Entry::Vacant(e) => {
 				let mut sample = load_and_resample(path, self.sample_rate as f32)?;
@@ -144,6 +205,35 @@ pub fn load_and_resample(path: &str, target_rate: f32) -> Result<[Vec<f32>; 2]>
 	Ok(sample)
 }
 
+// Load a wav file from disk
+pub fn load_file(path: &str, target_rate: f32) -> Result<[Vec<f32>; 2]> {
+	let reader = hound::WavReader::open(path)?;
+	let spec = reader.spec();
+
+	if spec.channels == 0 {
+		bail!("File has no channels.");
+	}
+
+	let samples = read_samples(reader, spec)?;
+
+	// Take the first two channels, duplicate mono
+	let channels = usize::from(spec.channels);
+	let mut left = Vec::with_capacity(samples.len() / channels);
+	let mut right = Vec::with_capacity(samples.len() / channels);
+	for frame in samples.chunks_exact(channels) {
+		left.push(frame[0]);
+		right.push(if channels > 1 { frame[1] } else { frame[0] });
+	}
+
+	let source_rate = spec.sample_rate as f32;
+	if (source_rate - target_rate).abs() > 1.0 {
+		let resampler = Resampler::new(source_rate, target_rate);
+		return Ok([resampler.process(&left), resampler.process(&right)]);
+	}
+
+	Ok([left, right])
+}
+
 pub fn load_sample(path: &str) -> Result<([Vec<f32>; 2], u32)> {
 	let file_data: &[u8] = &Asset::get(path).ok_or_else(|| anyhow!("Could not find {path}"))?.data;
 	let reader = hound::WavReader::new(file_data)?;
@@ -176,7 +266,7 @@ pub fn load_sample(path: &str) -> Result<([Vec<f32>; 2], u32)> {
 	Ok(([left, right], spec.sample_rate))
 }
 
-fn read_samples(reader: WavReader<&[u8]>, spec: hound::WavSpec) -> Result<Vec<f32>> {
+fn read_samples<R: std::io::Read>(reader: WavReader<R>, spec: hound::WavSpec) -> Result<Vec<f32>> {
 	let capacity = reader.len() as usize;
 	let mut samples = Vec::with_capacity(capacity);
 
@@ -223,6 +313,8 @@ fn normalize_power(sample: &mut [Vec<f32>; 2]) {
 #[derive(Debug)]
 pub enum Request {
 	LoadRequest { channel_index: usize, device_index: usize, data: RequestData },
+	LoadClips { channel_index: usize, clips: Vec<Clip> },
+	SetSampleRate(u32),
 	Garbage(Box<dyn std::any::Any + Send>),
 }
 
@@ -243,4 +335,7 @@ pub enum ResponseData {
 	Sample(Arc<[Vec<f32>; 2]>),
 	Wavetable(Arc<Vec<f32>>),
 	IR(Box<[TwoStageFFTConvolver; 2]>),
+	Clips(Box<ClipList>),
+	// The request could not be loaded, the device keeps what it had
+	Failed,
 }
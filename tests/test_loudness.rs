@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+
+	use tessera::dsp::loudness::integrated_loudness;
+
+	fn sine(freq: f32, amplitude: f32, sample_rate: f32, len: usize) -> Vec<f32> {
+		(0..len)
+			.map(|i| amplitude * (std::f32::consts::TAU * freq * i as f32 / sample_rate).sin())
+			.collect()
+	}
+
+	#[test]
+	fn test_loudness_reference_tone() {
+		// A 0dBFS 1kHz sine in one channel should measure -3.01 LUFS
+		for sample_rate in [44100.0, 48000.0] {
+			let left = sine(1000.0, 1.0, sample_rate, 5 * sample_rate as usize);
+			let right = vec![0.0; left.len()];
+
+			let loudness = integrated_loudness(&[&left, &right], sample_rate);
+			println!("{sample_rate}Hz: {loudness:.3} LUFS");
+			assert!((loudness + 3.01).abs() < 0.05);
+		}
+	}
+
+	#[test]
+	fn test_loudness_gating() {
+		let sample_rate = 48000.0;
+		let len = 5 * sample_rate as usize;
+
+		let silence = vec![0.0; len];
+		assert_eq!(integrated_loudness(&[&silence, &silence], sample_rate), f64::NEG_INFINITY);
+
+		// Silence after the tone is gated out, only the blocks straddling the edge lower the measurement.
+		// Without gating this would be 3dB lower.
+		let tone = sine(1000.0, 0.5, sample_rate, len);
+		let mut padded = tone.clone();
+		padded.extend_from_slice(&silence);
+
+		let a = integrated_loudness(&[&tone, &tone], sample_rate);
+		let b = integrated_loudness(&[&padded, &padded], sample_rate);
+		assert!((a - b).abs() < 0.2);
+	}
+}